@@ -13,6 +13,16 @@ pub use futures::io::AsyncReadExt;
 #[cfg(feature = "tokio")]
 pub use tokio::io::AsyncReadExt;
 
+#[cfg(feature = "async-std")]
+pub use futures::io::AsyncSeek;
+#[cfg(feature = "tokio")]
+pub use tokio::io::AsyncSeek;
+
+#[cfg(feature = "async-std")]
+pub use futures::io::AsyncSeekExt;
+#[cfg(feature = "tokio")]
+pub use tokio::io::AsyncSeekExt;
+
 #[cfg(feature = "async-std")]
 pub use futures::io::AsyncBufReadExt;
 #[cfg(feature = "tokio")]
@@ -89,6 +99,11 @@ pub use async_std::task::spawn_blocking;
 #[cfg(feature = "tokio")]
 pub use tokio::task::spawn_blocking;
 
+#[cfg(feature = "async-std")]
+pub use async_std::task::sleep;
+#[cfg(feature = "tokio")]
+pub use tokio::time::sleep;
+
 #[cfg(feature = "async-std")]
 pub use async_std::task::JoinHandle;
 #[cfg(feature = "async-std")]
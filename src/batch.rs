@@ -0,0 +1,421 @@
+//! Functions for writing several entries at once.
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use ssri::Integrity;
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+use crate::async_lib::AsyncWriteExt;
+use crate::errors::{Error, IoErrorExt, Result};
+use crate::index;
+use crate::put::WriteOpts;
+
+/// A set of writes and removals that readers see take effect all at once.
+///
+/// Nothing is written until the batch is committed. Committing writes the
+/// content for every staged write first, and if any of them fails, none of
+/// the batch's index changes are made. Otherwise, they're committed together
+/// through a journal, so that readers looking up keys in the batch never see
+/// some of its changes without the others. Staged writes may use
+/// [`WriteOpts::if_integrity`] and [`WriteOpts::if_absent`], in which case
+/// the whole batch fails, with [`Error::Conflict`] or
+/// [`Error::EntryNotFound`], if any of them doesn't hold.
+///
+/// Only lookups by key, like [`crate::read`] and [`crate::metadata`], are
+/// guaranteed to see the batch as a whole. Listing the cache, or looking at
+/// a key's [`index::history`], can catch a batch halfway through being
+/// applied to the index. If the batch fails, content it wrote that wasn't
+/// in the cache before, and that nothing refers to, is removed again.
+/// Packed content is left for [`crate::repack`] to reclaim.
+///
+/// If the cache has a maximum size, entries written by the batch are never
+/// evicted to make room for the batch itself.
+///
+/// ## Example
+/// ```no_run
+/// use async_attributes;
+///
+/// #[async_attributes::main]
+/// async fn main() -> cacache::Result<()> {
+///     cacache::Batch::new()
+///         .write("manifest", b"file-a\nfile-b")
+///         .write("file-a", b"hello")
+///         .write("file-b", b"world")
+///         .remove("file-c")
+///         .commit("./my-cache")
+///         .await?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Default)]
+pub struct Batch {
+    ops: Vec<Op>,
+}
+
+/// A staged change. Removals have no data.
+struct Op {
+    key: String,
+    opts: Option<WriteOpts>,
+    data: Option<Vec<u8>>,
+}
+
+impl Batch {
+    /// Creates an empty batch.
+    pub fn new() -> Batch {
+        Default::default()
+    }
+
+    /// Stages a write of `data`, indexed under `key`.
+    pub fn write<K, D>(mut self, key: K, data: D) -> Self
+    where
+        K: AsRef<str>,
+        D: AsRef<[u8]>,
+    {
+        self.ops.push(Op {
+            key: key.as_ref().to_string(),
+            opts: None,
+            data: Some(data.as_ref().to_vec()),
+        });
+        self
+    }
+
+    /// Stages a write of `data`, indexed under `key`, using `opts`.
+    pub fn write_with_opts<K, D>(mut self, opts: WriteOpts, key: K, data: D) -> Self
+    where
+        K: AsRef<str>,
+        D: AsRef<[u8]>,
+    {
+        self.ops.push(Op {
+            key: key.as_ref().to_string(),
+            opts: Some(opts),
+            data: Some(data.as_ref().to_vec()),
+        });
+        self
+    }
+
+    /// Stages the removal of the index entry for `key`. Its content is left
+    /// alone, like with [`crate::remove`].
+    pub fn remove<K: AsRef<str>>(mut self, key: K) -> Self {
+        self.ops.push(Op {
+            key: key.as_ref().to_string(),
+            opts: None,
+            data: None,
+        });
+        self
+    }
+
+    /// Returns the number of writes and removals staged so far.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns true if nothing has been staged.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Returns the number of bytes of content staged to be written.
+    fn added(&self) -> u64 {
+        self.ops
+            .iter()
+            .filter_map(|op| op.data.as_ref())
+            .map(|data| data.len() as u64)
+            .sum()
+    }
+
+    /// Commits every staged change to `cache`, returning the integrity of
+    /// each staged write, in the order they were staged.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn commit<P: AsRef<Path>>(self, cache: P) -> Result<Vec<Integrity>> {
+        self.commit_with(cache.as_ref(), WriteOpts::new()).await
+    }
+
+    /// Synchronously commits every staged change to `cache`. See
+    /// [`Batch::commit`] for details.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// fn main() -> cacache::Result<()> {
+    ///     cacache::Batch::new()
+    ///         .write("manifest", b"file-a")
+    ///         .write("file-a", b"hello")
+    ///         .commit_sync("./my-cache")?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn commit_sync<P: AsRef<Path>>(self, cache: P) -> Result<Vec<Integrity>> {
+        self.commit_with_sync(cache.as_ref(), WriteOpts::new())
+    }
+
+    /// Commits the batch, with `defaults` for writes staged without options.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub(crate) async fn commit_with(
+        self,
+        cache: &Path,
+        defaults: WriteOpts,
+    ) -> Result<Vec<Integrity>> {
+        let mut created = Vec::new();
+        let (added, limit) = (self.added(), defaults.max_size);
+        let committed = self.write_and_index(cache, defaults, &mut created).await;
+        let cache = cache.to_path_buf();
+        crate::async_lib::unwrap_joinhandle_value(
+            crate::async_lib::spawn_blocking(move || match committed {
+                Ok((written, keys)) => {
+                    crate::evict::enforce_max_size_sparing(&cache, limit, added, &keys)?;
+                    Ok(written)
+                }
+                Err(err) => {
+                    roll_back(&cache, &created);
+                    Err(err)
+                }
+            })
+            .await,
+        )
+    }
+
+    /// Writes the content for every staged write, then indexes the whole
+    /// batch, returning the integrity of each write and the keys in the
+    /// batch. Content files that weren't in the cache before are added to
+    /// `created` as they're written, along with their integrity.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    async fn write_and_index(
+        self,
+        cache: &Path,
+        defaults: WriteOpts,
+        created: &mut Vec<(Integrity, PathBuf)>,
+    ) -> Result<(Vec<Integrity>, HashSet<String>)> {
+        let mut entries = Vec::with_capacity(self.ops.len());
+        let mut written = Vec::new();
+        // Held until the batch is indexed, so repacking can't drop what it
+        // packed in the meantime.
+        let mut pack_locks = Vec::new();
+        for Op { key, opts, data } in self.ops {
+            let opts = match data {
+                Some(data) => {
+                    let mut opts = opts.unwrap_or_else(|| defaults.clone());
+                    opts.size = opts.size.or(Some(data.len()));
+                    let mut writer = opts.open(cache, &key).await?;
+                    writer.write_all(&data).await.with_context(|| {
+                        format!(
+                            "Failed to write to cache data for key {key} for cache at {cache:?}"
+                        )
+                    })?;
+                    let (opts, sri, pack_lock, cpath) = writer.finish().await?;
+                    created.extend(cpath.map(|cpath| (sri.clone(), cpath)));
+                    pack_locks.extend(pack_lock);
+                    written.push(sri);
+                    opts
+                }
+                None => removal(cache, &defaults)?,
+            };
+            entries.push((key, opts));
+        }
+        let keys = entries.iter().map(|(key, _)| key.clone()).collect();
+        let cache = cache.to_path_buf();
+        crate::async_lib::unwrap_joinhandle_value(
+            crate::async_lib::spawn_blocking(move || index::insert_all(&cache, entries)).await,
+        )?;
+        Ok((written, keys))
+    }
+
+    /// Synchronously commits the batch, with `defaults` for writes staged
+    /// without options.
+    pub(crate) fn commit_with_sync(
+        self,
+        cache: &Path,
+        defaults: WriteOpts,
+    ) -> Result<Vec<Integrity>> {
+        let mut created = Vec::new();
+        let (added, limit) = (self.added(), defaults.max_size);
+        match self.write_and_index_sync(cache, defaults, &mut created) {
+            Ok((written, keys)) => {
+                crate::evict::enforce_max_size_sparing(cache, limit, added, &keys)?;
+                Ok(written)
+            }
+            Err(err) => {
+                roll_back(cache, &created);
+                Err(err)
+            }
+        }
+    }
+
+    /// Synchronously writes and indexes the batch. See
+    /// [`Batch::write_and_index`].
+    fn write_and_index_sync(
+        self,
+        cache: &Path,
+        defaults: WriteOpts,
+        created: &mut Vec<(Integrity, PathBuf)>,
+    ) -> Result<(Vec<Integrity>, HashSet<String>)> {
+        let mut entries = Vec::with_capacity(self.ops.len());
+        let mut written = Vec::new();
+        // Held until the batch is indexed, so repacking can't drop what it
+        // packed in the meantime.
+        let mut pack_locks = Vec::new();
+        for Op { key, opts, data } in self.ops {
+            let opts = match data {
+                Some(data) => {
+                    let mut opts = opts.unwrap_or_else(|| defaults.clone());
+                    opts.size = opts.size.or(Some(data.len()));
+                    let mut writer = opts.open_sync(cache, &key)?;
+                    writer.write_all(&data).with_context(|| {
+                        format!(
+                            "Failed to write to cache data for key {key} for cache at {cache:?}"
+                        )
+                    })?;
+                    let (opts, sri, pack_lock, cpath) = writer.finish()?;
+                    created.extend(cpath.map(|cpath| (sri.clone(), cpath)));
+                    pack_locks.extend(pack_lock);
+                    written.push(sri);
+                    opts
+                }
+                None => removal(cache, &defaults)?,
+            };
+            entries.push((key, opts));
+        }
+        let keys = entries.iter().map(|(key, _)| key.clone()).collect();
+        index::insert_all(cache, entries)?;
+        Ok((written, keys))
+    }
+}
+
+/// Removes the content files a failed batch wrote that weren't in the cache
+/// before, as long as no index entry refers to their content by now. Packed content is left for
+/// [`crate::repack`] to reclaim, and anything else that can't be removed for
+/// [`crate::verify`]. Errors are ignored, since it's the batch's own error
+/// that's worth returning.
+fn roll_back(cache: &Path, created: &[(Integrity, PathBuf)]) {
+    if created.is_empty() {
+        return;
+    }
+    // If the index can't be listed, there's no telling what's still in use.
+    let Ok(referenced) = index::ls(cache)
+        .map(|entry| entry.map(|entry| entry.integrity))
+        .collect::<Result<Vec<_>>>()
+    else {
+        return;
+    };
+    for (sri, cpath) in created {
+        if referenced.iter().any(|other| other.matches(sri).is_some()) {
+            continue;
+        }
+        crate::memo::forget_content(cache, sri);
+        let _ = fs::remove_file(cpath);
+    }
+}
+
+/// Returns the options for an index entry that removes its key.
+fn removal(cache: &Path, defaults: &WriteOpts) -> Result<WriteOpts> {
+    if defaults.read_only {
+        return Err(Error::ReadOnly(cache.to_path_buf()));
+    }
+    Ok(WriteOpts::new().durability(defaults.durability))
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "async-std")]
+    use async_attributes::test as async_test;
+    #[cfg(feature = "tokio")]
+    use tokio::test as async_test;
+
+    #[test]
+    fn commits_writes_and_removals() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        crate::write_sync(dir, "old", b"old").unwrap();
+        let written = crate::Batch::new()
+            .write("manifest", b"a")
+            .write("a", b"hello")
+            .remove("old")
+            .commit_sync(dir)
+            .unwrap();
+        assert_eq!(
+            written,
+            vec![ssri::Integrity::from(b"a"), ssri::Integrity::from(b"hello")]
+        );
+        assert_eq!(crate::read_sync(dir, "manifest").unwrap(), b"a");
+        assert_eq!(crate::read_sync(dir, "a").unwrap(), b"hello");
+        assert!(crate::metadata_sync(dir, "old").unwrap().is_none());
+        assert!(!dir.join("journal-v1").exists());
+    }
+
+    #[test]
+    fn fails_as_a_whole() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        crate::write_sync(dir, "taken", b"first").unwrap();
+        let res = crate::Batch::new()
+            .write("manifest", b"taken")
+            .write_with_opts(crate::WriteOpts::new().if_absent(), "taken", b"second")
+            .commit_sync(dir);
+        assert!(matches!(res, Err(crate::Error::Conflict(_, _))));
+        assert!(crate::metadata_sync(dir, "manifest").unwrap().is_none());
+        assert_eq!(crate::read_sync(dir, "taken").unwrap(), b"first");
+
+        let res = crate::Batch::new()
+            .write("manifest", b"other")
+            .write_with_opts(
+                crate::WriteOpts::new().integrity(ssri::Integrity::from(b"expected")),
+                "bad",
+                b"actual",
+            )
+            .commit_sync(dir);
+        assert!(matches!(res, Err(crate::Error::IntegrityError(_))));
+        assert!(crate::metadata_sync(dir, "manifest").unwrap().is_none());
+    }
+
+    #[test]
+    fn rolls_back_new_content() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        crate::write_sync(dir, "taken", b"shared").unwrap();
+        let unindexed = crate::write_hash_sync(dir, b"unindexed").unwrap();
+        let res = crate::Batch::new()
+            .write("fresh", b"fresh")
+            .write("also-shared", b"shared")
+            .write("found", b"unindexed")
+            .write_with_opts(crate::WriteOpts::new().if_absent(), "taken", b"second")
+            .commit_sync(dir);
+        assert!(matches!(res, Err(crate::Error::Conflict(_, _))));
+        assert!(!crate::exists_sync(dir, &ssri::Integrity::from(b"fresh")));
+        assert!(!crate::exists_sync(dir, &ssri::Integrity::from(b"second")));
+        assert!(crate::exists_sync(dir, &ssri::Integrity::from(b"shared")));
+        // Content that was already there stays, even if nothing indexes it.
+        assert!(crate::exists_sync(dir, &unindexed));
+        assert_eq!(crate::read_sync(dir, "taken").unwrap(), b"shared");
+    }
+
+    #[test]
+    fn never_evicts_its_own_entries() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        crate::set_max_size_sync(dir, Some(10)).unwrap();
+        crate::write_sync(dir, "old", b"old").unwrap();
+        crate::Batch::new()
+            .write("a", b"aaaaaaaa")
+            .write("b", b"bbbbbbbb")
+            .commit_sync(dir)
+            .unwrap();
+        assert!(crate::metadata_sync(dir, "old").unwrap().is_none());
+        assert_eq!(crate::read_sync(dir, "a").unwrap(), b"aaaaaaaa");
+        assert_eq!(crate::read_sync(dir, "b").unwrap(), b"bbbbbbbb");
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    #[async_test]
+    async fn commits_async() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        crate::Batch::new()
+            .write("manifest", b"a")
+            .write("a", b"hello")
+            .commit(dir)
+            .await
+            .unwrap();
+        assert_eq!(crate::read(dir, "manifest").await.unwrap(), b"a");
+        assert_eq!(crate::read(dir, "a").await.unwrap(), b"hello");
+    }
+}
@@ -0,0 +1,580 @@
+//! An owned handle to a cache directory, with its own configuration.
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+use futures::stream::Stream;
+use ssri::{Algorithm, Integrity};
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+use crate::async_lib::AsyncWriteExt;
+use crate::batch::Batch;
+use crate::durability::Durability;
+use crate::errors::{Error, IoErrorExt, Result};
+use crate::get::ReadOpts;
+use crate::index::Metadata;
+use crate::lock::KeyLock;
+use crate::put::WriteOpts;
+use crate::repack::RepackStats;
+
+/// Builder for options used when opening a [`Cache`].
+#[derive(Clone, Debug, Default)]
+pub struct CacheOpts {
+    pub(crate) algorithm: Option<Algorithm>,
+    pub(crate) tmp_dir: Option<PathBuf>,
+    pub(crate) mmap_threshold: Option<usize>,
+    pub(crate) inline_threshold: Option<usize>,
+    pub(crate) pack_threshold: Option<usize>,
+    pub(crate) max_size: Option<u64>,
+    pub(crate) memo_size: Option<usize>,
+    pub(crate) read_only: bool,
+    pub(crate) durability: Durability,
+}
+
+impl CacheOpts {
+    /// Creates a default set of cache options.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Sets the algorithm data is hashed with when writing. Defaults to
+    /// [`Algorithm::Sha256`].
+    pub fn algorithm(mut self, algo: Algorithm) -> Self {
+        self.algorithm = Some(algo);
+        self
+    }
+
+    /// Sets the directory temporary files are written to. See
+    /// [`WriteOpts::tmp_dir`].
+    pub fn tmp_dir(mut self, tmp_dir: impl AsRef<Path>) -> Self {
+        self.tmp_dir = Some(tmp_dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets the largest write, in bytes, that goes through a memory map. See
+    /// [`WriteOpts::mmap_threshold`].
+    pub fn mmap_threshold(mut self, mmap_threshold: usize) -> Self {
+        self.mmap_threshold = Some(mmap_threshold);
+        self
+    }
+
+    /// Sets the largest keyed write, in bytes, that's stored inline in the
+    /// index. See [`WriteOpts::inline_threshold`].
+    pub fn inline_threshold(mut self, inline_threshold: usize) -> Self {
+        self.inline_threshold = Some(inline_threshold);
+        self
+    }
+
+    /// Sets the largest write, in bytes, that's appended to a shared pack
+    /// file. See [`WriteOpts::pack_threshold`].
+    pub fn pack_threshold(mut self, pack_threshold: usize) -> Self {
+        self.pack_threshold = Some(pack_threshold);
+        self
+    }
+
+    /// Sets how hard writes try to survive a crash or power loss. See
+    /// [`WriteOpts::durability`].
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Limits the cache to `max_size` bytes of content for writes made
+    /// through this handle, in place of any limit stored in the cache with
+    /// [`crate::set_max_size`]. The limit is only kept in the handle, so
+    /// other writers aren't affected by it. See [`crate::evict_to`] for how
+    /// entries are evicted.
+    pub fn max_size(mut self, max_size: u64) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// Keeps up to `memo_size` bytes of recently used entries and content in
+    /// memory once the cache is opened. See [`crate::set_memo_size`].
+    pub fn memo_size(mut self, memo_size: usize) -> Self {
+        self.memo_size = Some(memo_size);
+        self
+    }
+
+    /// Opens the cache read-only. Anything that would modify it returns
+    /// [`Error::ReadOnly`] instead.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Opens the cache at `path` with these options.
+    pub fn open<P: AsRef<Path>>(self, path: P) -> Result<Cache> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(memo_size) = self.memo_size {
+            crate::set_memo_size(&path, Some(memo_size));
+        }
+        Ok(Cache { path, opts: self })
+    }
+}
+
+/// A handle to a cache directory, carrying the options it was opened with.
+///
+/// Its methods mirror the free functions in this crate, which remain
+/// available for one-off use with default options.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache::Result<()> {
+///     let cache = cacache::CacheOpts::new()
+///         .algorithm(cacache::Algorithm::Xxh3)
+///         .open("./my-cache")?;
+///     cache.write_sync("my-key", b"hello")?;
+///     let data = cache.read_sync("my-key")?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Cache {
+    path: PathBuf,
+    opts: CacheOpts,
+}
+
+impl Cache {
+    /// Opens the cache at `path` with default options.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Cache> {
+        CacheOpts::new().open(path)
+    }
+
+    /// Directory this cache is stored in.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether this cache was opened read-only.
+    pub fn is_read_only(&self) -> bool {
+        self.opts.read_only
+    }
+
+    /// Returns write options preconfigured for this cache, for writes that
+    /// need more control than [`Cache::write`] offers.
+    pub fn write_opts(&self) -> WriteOpts {
+        let mut opts = WriteOpts::new();
+        opts.algorithm = self.opts.algorithm;
+        opts.tmp_dir = self.opts.tmp_dir.clone();
+        opts.mmap_threshold = self.opts.mmap_threshold;
+        opts.inline_threshold = self.opts.inline_threshold;
+        opts.pack_threshold = self.opts.pack_threshold;
+        opts.read_only = self.opts.read_only;
+        opts.durability = self.opts.durability;
+        opts.max_size = self.opts.max_size;
+        opts
+    }
+
+    /// Returns read options preconfigured for this cache. If it was opened
+    /// read-only, reads through them don't record accesses, which would
+    /// otherwise modify the index.
+    pub fn read_opts(&self) -> ReadOpts {
+        let mut opts = ReadOpts::new();
+        opts.read_only = self.opts.read_only;
+        opts
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.opts.read_only {
+            Err(Error::ReadOnly(self.path.clone()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Writes `data` to the cache, indexing it under `key`.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn write<K, D>(&self, key: K, data: D) -> Result<Integrity>
+    where
+        K: AsRef<str>,
+        D: AsRef<[u8]>,
+    {
+        let (key, data) = (key.as_ref(), data.as_ref());
+        let mut writer = self
+            .write_opts()
+            .size(data.len())
+            .open(&self.path, key)
+            .await?;
+        writer.write_all(data).await.with_context(|| {
+            format!(
+                "Failed to write to cache data for key {key} for cache at {:?}",
+                self.path
+            )
+        })?;
+        writer.commit().await
+    }
+
+    /// Writes `data` to the cache synchronously, indexing it under `key`.
+    pub fn write_sync<K, D>(&self, key: K, data: D) -> Result<Integrity>
+    where
+        K: AsRef<str>,
+        D: AsRef<[u8]>,
+    {
+        let (key, data) = (key.as_ref(), data.as_ref());
+        let mut writer = self
+            .write_opts()
+            .size(data.len())
+            .open_sync(&self.path, key)?;
+        std::io::Write::write_all(&mut writer, data).with_context(|| {
+            format!(
+                "Failed to write to cache data for key {key} for cache at {:?}",
+                self.path
+            )
+        })?;
+        writer.commit()
+    }
+
+    /// Writes `data` to the cache, skipping associating a key with it.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn write_hash<D: AsRef<[u8]>>(&self, data: D) -> Result<Integrity> {
+        let data = data.as_ref();
+        let mut writer = self
+            .write_opts()
+            .size(data.len())
+            .open_hash(&self.path)
+            .await?;
+        writer.write_all(data).await.with_context(|| {
+            format!("Failed to write to cache data for cache at {:?}", self.path)
+        })?;
+        writer.commit().await
+    }
+
+    /// Writes `data` to the cache synchronously, skipping associating a key
+    /// with it.
+    pub fn write_hash_sync<D: AsRef<[u8]>>(&self, data: D) -> Result<Integrity> {
+        let data = data.as_ref();
+        let mut writer = self
+            .write_opts()
+            .size(data.len())
+            .open_hash_sync(&self.path)?;
+        std::io::Write::write_all(&mut writer, data).with_context(|| {
+            format!("Failed to write to cache data for cache at {:?}", self.path)
+        })?;
+        writer.commit()
+    }
+
+    /// Reads the data indexed under `key`.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn read<K: AsRef<str>>(&self, key: K) -> Result<Vec<u8>> {
+        self.read_opts().read(&self.path, key).await
+    }
+
+    /// Reads the data indexed under `key` synchronously.
+    pub fn read_sync<K: AsRef<str>>(&self, key: K) -> Result<Vec<u8>> {
+        self.read_opts().read_sync(&self.path, key)
+    }
+
+    /// Reads data by its content address.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn read_hash(&self, sri: &Integrity) -> Result<Vec<u8>> {
+        self.read_opts().read_hash(&self.path, sri).await
+    }
+
+    /// Reads data by its content address synchronously.
+    pub fn read_hash_sync(&self, sri: &Integrity) -> Result<Vec<u8>> {
+        self.read_opts().read_hash_sync(&self.path, sri)
+    }
+
+    /// Gets the index entry for `key`.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn metadata<K: AsRef<str>>(&self, key: K) -> Result<Option<Metadata>> {
+        self.read_opts().metadata(&self.path, key).await
+    }
+
+    /// Gets the index entry for `key` synchronously.
+    pub fn metadata_sync<K: AsRef<str>>(&self, key: K) -> Result<Option<Metadata>> {
+        self.read_opts().metadata_sync(&self.path, key)
+    }
+
+    /// Returns true if the given hash exists in the cache.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn exists(&self, sri: &Integrity) -> bool {
+        crate::exists(&self.path, sri).await
+    }
+
+    /// Returns true if the given hash exists in the cache.
+    pub fn exists_sync(&self, sri: &Integrity) -> bool {
+        crate::exists_sync(&self.path, sri)
+    }
+
+    /// Removes the index entry for `key`, leaving its content in place.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn remove<K: AsRef<str>>(&self, key: K) -> Result<()> {
+        self.check_writable()?;
+        crate::RemoveOpts::new()
+            .durability(self.opts.durability)
+            .remove(&self.path, key)
+            .await
+    }
+
+    /// Removes the index entry for `key` synchronously, leaving its content
+    /// in place.
+    pub fn remove_sync<K: AsRef<str>>(&self, key: K) -> Result<()> {
+        self.check_writable()?;
+        crate::RemoveOpts::new()
+            .durability(self.opts.durability)
+            .remove_sync(&self.path, key)
+    }
+
+    /// Removes the content for `sri`.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn remove_hash(&self, sri: &Integrity) -> Result<()> {
+        self.check_writable()?;
+        crate::remove_hash(&self.path, sri).await
+    }
+
+    /// Removes the content for `sri` synchronously.
+    pub fn remove_hash_sync(&self, sri: &Integrity) -> Result<()> {
+        self.check_writable()?;
+        crate::remove_hash_sync(&self.path, sri)
+    }
+
+    /// Removes all entries and content from the cache.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn clear(&self) -> Result<()> {
+        self.check_writable()?;
+        crate::clear(&self.path).await
+    }
+
+    /// Removes all entries and content from the cache synchronously.
+    pub fn clear_sync(&self) -> Result<()> {
+        self.check_writable()?;
+        crate::clear_sync(&self.path)
+    }
+
+    /// Removes abandoned temporary files from this cache's temp directory,
+    /// returning how many were removed. See [`crate::clean_tmp`].
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn clean_tmp(&self, older_than: Duration) -> Result<usize> {
+        self.check_writable()?;
+        let tmp = self.tmp_dir();
+        crate::async_lib::unwrap_joinhandle_value(
+            crate::async_lib::spawn_blocking(move || crate::tmp::clean_dir(&tmp, older_than)).await,
+        )
+    }
+
+    /// Removes abandoned temporary files from this cache's temp directory
+    /// synchronously.
+    pub fn clean_tmp_sync(&self, older_than: Duration) -> Result<usize> {
+        self.check_writable()?;
+        crate::tmp::clean_dir(&self.tmp_dir(), older_than)
+    }
+
+    /// Rewrites this cache's pack files, moving content files up to its
+    /// [`CacheOpts::pack_threshold`] into them. See [`crate::repack`].
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn repack(&self) -> Result<RepackStats> {
+        self.check_writable()?;
+        crate::repack(&self.path, self.opts.pack_threshold.unwrap_or(0)).await
+    }
+
+    /// Rewrites this cache's pack files synchronously.
+    pub fn repack_sync(&self) -> Result<RepackStats> {
+        self.check_writable()?;
+        crate::repack_sync(&self.path, self.opts.pack_threshold.unwrap_or(0))
+    }
+
+    /// Takes an advisory lock on `key`. See [`crate::lock_key`].
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn lock_key<K: AsRef<str>>(&self, key: K) -> Result<KeyLock> {
+        self.check_writable()?;
+        crate::lock_key(&self.path, key).await
+    }
+
+    /// Takes an advisory lock on `key` synchronously.
+    pub fn lock_key_sync<K: AsRef<str>>(&self, key: K) -> Result<KeyLock> {
+        self.check_writable()?;
+        crate::lock_key_sync(&self.path, key)
+    }
+
+    /// Reads the data indexed under `key` and its integrity, calling `f` to
+    /// produce the data if it's missing. See [`crate::get_or_insert_with`].
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn get_or_insert_with<K, F, Fut, E>(
+        &self,
+        key: K,
+        f: F,
+    ) -> std::result::Result<(Vec<u8>, Integrity), E>
+    where
+        K: AsRef<str>,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = std::result::Result<Vec<u8>, E>>,
+        E: From<Error>,
+    {
+        self.check_writable()?;
+        let (read_opts, write_opts) = (self.read_opts(), self.write_opts());
+        crate::lock::get_or_insert_with_opts(&self.path, key.as_ref(), read_opts, write_opts, f)
+            .await
+    }
+
+    /// Reads the data indexed under `key` and its integrity synchronously,
+    /// calling `f` to produce the data if it's missing.
+    pub fn get_or_insert_with_sync<K, F, E>(
+        &self,
+        key: K,
+        f: F,
+    ) -> std::result::Result<(Vec<u8>, Integrity), E>
+    where
+        K: AsRef<str>,
+        F: FnOnce() -> std::result::Result<Vec<u8>, E>,
+        E: From<Error>,
+    {
+        self.check_writable()?;
+        let (read_opts, write_opts) = (self.read_opts(), self.write_opts());
+        crate::lock::get_or_insert_with_opts_sync(
+            &self.path,
+            key.as_ref(),
+            read_opts,
+            write_opts,
+            f,
+        )
+    }
+
+    /// Changes the metadata for `key` without touching its content. See
+    /// [`crate::update_metadata`].
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn update_metadata<K, F>(&self, key: K, f: F) -> Result<Metadata>
+    where
+        K: AsRef<str>,
+        F: FnOnce(Metadata) -> Metadata,
+    {
+        self.check_writable()?;
+        crate::update_metadata(&self.path, key, f).await
+    }
+
+    /// Changes the metadata for `key` synchronously.
+    pub fn update_metadata_sync<K, F>(&self, key: K, f: F) -> Result<Metadata>
+    where
+        K: AsRef<str>,
+        F: FnOnce(Metadata) -> Metadata,
+    {
+        self.check_writable()?;
+        crate::update_metadata_sync(&self.path, key, f)
+    }
+
+    /// Commits `batch` to this cache, using its write options for writes
+    /// that were staged without any. See [`Batch`].
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn commit(&self, batch: Batch) -> Result<Vec<Integrity>> {
+        self.check_writable()?;
+        batch.commit_with(&self.path, self.write_opts()).await
+    }
+
+    /// Commits `batch` to this cache synchronously.
+    pub fn commit_sync(&self, batch: Batch) -> Result<Vec<Integrity>> {
+        self.check_writable()?;
+        batch.commit_with_sync(&self.path, self.write_opts())
+    }
+
+    fn tmp_dir(&self) -> PathBuf {
+        self.opts
+            .tmp_dir
+            .clone()
+            .unwrap_or_else(|| self.path.join("tmp"))
+    }
+
+    /// Lists all index entries, skipping expired ones.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub fn list(&self) -> impl Stream<Item = Result<Metadata>> + Send + Unpin {
+        crate::list(self.path.clone())
+    }
+
+    /// Lists all index entries synchronously, skipping expired ones.
+    pub fn list_sync(&self) -> impl Iterator<Item = Result<Metadata>> {
+        crate::list_sync(self.path.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "async-std")]
+    use async_attributes::test as async_test;
+    #[cfg(feature = "tokio")]
+    use tokio::test as async_test;
+
+    #[test]
+    fn round_trip_sync() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = CacheOpts::new()
+            .algorithm(Algorithm::Sha512)
+            .tmp_dir(tmp.path().join("scratch"))
+            .open(tmp.path())
+            .unwrap();
+        let sri = cache.write_sync("my-key", b"hello").unwrap();
+        assert_eq!(sri.to_hex().0, Algorithm::Sha512);
+        assert!(tmp.path().join("scratch").exists());
+        assert_eq!(cache.read_sync("my-key").unwrap(), b"hello");
+        assert_eq!(cache.read_hash_sync(&sri).unwrap(), b"hello");
+        assert_eq!(cache.list_sync().count(), 1);
+
+        cache.remove_sync("my-key").unwrap();
+        assert!(cache.metadata_sync("my-key").unwrap().is_none());
+        assert!(cache.exists_sync(&sri));
+    }
+
+    #[test]
+    fn read_only() {
+        let tmp = tempfile::tempdir().unwrap();
+        let sri = crate::write_sync(tmp.path(), "my-key", b"hello").unwrap();
+        let accessed = crate::index::last_access(tmp.path(), "my-key");
+        std::thread::sleep(Duration::from_millis(20));
+        let cache = CacheOpts::new().read_only(true).open(tmp.path()).unwrap();
+        assert!(cache.is_read_only());
+        assert_eq!(cache.read_sync("my-key").unwrap(), b"hello");
+        // Reading didn't record an access in the index either.
+        assert_eq!(crate::index::last_access(tmp.path(), "my-key"), accessed);
+        assert!(matches!(
+            cache.write_sync("other", b"world"),
+            Err(Error::ReadOnly(_))
+        ));
+        assert!(matches!(
+            cache.write_opts().open_hash_sync(cache.path()),
+            Err(Error::ReadOnly(_))
+        ));
+        assert!(matches!(
+            cache.remove_sync("my-key"),
+            Err(Error::ReadOnly(_))
+        ));
+        assert!(matches!(
+            cache.remove_hash_sync(&sri),
+            Err(Error::ReadOnly(_))
+        ));
+        assert!(matches!(cache.clear_sync(), Err(Error::ReadOnly(_))));
+        assert!(cache.exists_sync(&sri));
+    }
+
+    #[test]
+    fn max_size_stays_with_the_handle() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = CacheOpts::new().max_size(10).open(tmp.path()).unwrap();
+        assert_eq!(crate::max_size_sync(tmp.path()).unwrap(), None);
+        cache.write_sync("a", b"aaaaa").unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        cache.write_sync("b", b"bbbbb").unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        cache.write_sync("c", b"ccccc").unwrap();
+        assert!(cache.metadata_sync("a").unwrap().is_none());
+        assert!(cache.metadata_sync("c").unwrap().is_some());
+        // Writes that don't go through the handle aren't limited by it.
+        crate::write_sync(tmp.path(), "d", b"ddddd").unwrap();
+        assert!(cache.metadata_sync("b").unwrap().is_some());
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    #[async_test]
+    async fn round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = Cache::open(tmp.path()).unwrap();
+        let sri = cache.write("my-key", b"hello").await.unwrap();
+        assert_eq!(cache.read("my-key").await.unwrap(), b"hello");
+        assert_eq!(cache.read_hash(&sri).await.unwrap(), b"hello");
+        assert!(cache.exists(&sri).await);
+        cache.remove_hash(&sri).await.unwrap();
+        assert!(cache.read("my-key").await.is_err());
+    }
+}
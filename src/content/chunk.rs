@@ -0,0 +1,407 @@
+use std::fs::{self, DirBuilder, File};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde_derive::{Deserialize, Serialize};
+use ssri::Integrity;
+use tempfile::NamedTempFile;
+
+use crate::content::path;
+use crate::durability::{self, Durability};
+
+/// How content written with [`crate::WriteOpts::chunking`] is split up.
+///
+/// Each chunk is stored, and checked, as content of its own, so chunks
+/// shared between similar blobs are only stored once, and range reads only
+/// need to verify the chunks they touch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Chunking {
+    /// Chunks of exactly this many bytes, except for the last one.
+    Fixed(usize),
+    /// Chunks averaging this many bytes, cut wherever the data itself looks
+    /// the same, so an insertion early on in a blob doesn't shift every
+    /// chunk after it. Chunks are between a quarter and four times the
+    /// average.
+    ContentDefined(usize),
+}
+
+impl Chunking {
+    fn min_size(self) -> usize {
+        match self {
+            Chunking::Fixed(size) => size.max(1),
+            Chunking::ContentDefined(avg) => (avg / 4).max(1),
+        }
+    }
+
+    fn max_size(self) -> usize {
+        match self {
+            Chunking::Fixed(size) => size.max(1),
+            Chunking::ContentDefined(avg) => avg.saturating_mul(4).max(1),
+        }
+    }
+
+    /// Mask over the top bits of the rolling hash that must all be zero to
+    /// cut a chunk, so cuts land roughly every `avg - min` bytes past the
+    /// minimum.
+    fn mask(self) -> u64 {
+        let spread = (self.max_size() / 4).saturating_sub(self.min_size()).max(1);
+        let bits = usize::BITS - spread.leading_zeros();
+        !(u64::MAX >> bits)
+    }
+}
+
+/// List of chunks making up a piece of content, stored where the content
+/// itself would be, with a `.chunks` extension.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Manifest {
+    size: u64,
+    chunks: Vec<ChunkRef>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ChunkRef {
+    integrity: String,
+    size: u64,
+}
+
+impl ChunkRef {
+    fn integrity(&self) -> io::Result<Integrity> {
+        self.integrity
+            .parse()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+fn read_manifest(fd: File) -> io::Result<Manifest> {
+    serde_json::from_reader(io::BufReader::new(fd))
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Integrities of every chunk listed in the manifest at `mpath`.
+pub fn chunk_integrities(mpath: &Path) -> io::Result<Vec<Integrity>> {
+    read_manifest(File::open(mpath)?)?
+        .chunks
+        .iter()
+        .map(ChunkRef::integrity)
+        .collect()
+}
+
+/// Splits data written to it into chunks, storing each one as it's cut, and
+/// writes the manifest listing them to the temp file it was created with
+/// once it's finished.
+pub struct ChunkWriter {
+    cache: PathBuf,
+    tmp_dir: PathBuf,
+    chunking: Chunking,
+    durability: Durability,
+    manifest_file: NamedTempFile,
+    manifest: Manifest,
+    buf: Vec<u8>,
+    hash: u64,
+}
+
+impl ChunkWriter {
+    pub fn new(
+        cache: &Path,
+        tmp_dir: &Path,
+        chunking: Chunking,
+        durability: Durability,
+        manifest_file: NamedTempFile,
+    ) -> Self {
+        ChunkWriter {
+            cache: cache.to_path_buf(),
+            tmp_dir: tmp_dir.to_path_buf(),
+            chunking,
+            durability,
+            manifest_file,
+            manifest: Manifest::default(),
+            buf: Vec::new(),
+            hash: 0,
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<NamedTempFile> {
+        if !self.buf.is_empty() {
+            self.cut()?;
+        }
+        serde_json::to_writer(&mut self.manifest_file, &self.manifest)?;
+        self.manifest_file.flush()?;
+        Ok(self.manifest_file)
+    }
+
+    fn is_boundary(&mut self, byte: u8) -> bool {
+        let len = self.buf.len();
+        match self.chunking {
+            Chunking::Fixed(_) => len >= self.chunking.max_size(),
+            Chunking::ContentDefined(_) => {
+                self.hash = (self.hash << 1).wrapping_add(GEAR[byte as usize]);
+                len >= self.chunking.max_size()
+                    || (len >= self.chunking.min_size() && self.hash & self.chunking.mask() == 0)
+            }
+        }
+    }
+
+    /// Stores everything buffered so far as a chunk, unless an identical
+    /// chunk has been stored already.
+    fn cut(&mut self) -> io::Result<()> {
+        let sri = Integrity::from(&self.buf);
+        let cpath = path::chunk_path(&self.cache, &sri);
+        if !cpath.exists() {
+            // Safe unwrap. cpath always has multiple segments
+            durability::create_dir_all(cpath.parent().unwrap(), self.durability)?;
+            DirBuilder::new().recursive(true).create(&self.tmp_dir)?;
+            let mut tmpfile = NamedTempFile::new_in(&self.tmp_dir)?;
+            crate::flock::lock(tmpfile.as_file())?;
+            tmpfile.write_all(&self.buf)?;
+            durability::sync_file(tmpfile.as_file(), self.durability, "chunk synced")?;
+            // Someone else storing the same chunk at the same time is fine.
+            if let Err(e) = tmpfile.persist(&cpath) {
+                if !cpath.exists() {
+                    return Err(e.error);
+                }
+            }
+            durability::sync_parent(&cpath, self.durability, "chunk dir synced")?;
+        }
+        self.manifest.size += self.buf.len() as u64;
+        self.manifest.chunks.push(ChunkRef {
+            integrity: sri.to_string(),
+            size: self.buf.len() as u64,
+        });
+        self.buf.clear();
+        self.hash = 0;
+        Ok(())
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.buf.push(byte);
+            if self.is_boundary(byte) {
+                self.cut()?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads chunked content back in order, checking each chunk as it's loaded.
+pub struct ChunkReader {
+    cache: PathBuf,
+    chunks: std::vec::IntoIter<ChunkRef>,
+    current: io::Cursor<Vec<u8>>,
+}
+
+impl ChunkReader {
+    pub fn new(cache: &Path, manifest: File) -> io::Result<Self> {
+        Ok(ChunkReader {
+            cache: cache.to_path_buf(),
+            chunks: read_manifest(manifest)?.chunks.into_iter(),
+            current: io::Cursor::new(Vec::new()),
+        })
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.chunks.next() {
+                Some(chunk) => self.current = io::Cursor::new(load(&self.cache, &chunk)?),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Reads the bytes from `start` up to `end` (or the end of the data),
+/// checking each chunk they span.
+pub fn read_range(
+    cache: &Path,
+    manifest: File,
+    start: u64,
+    end: Option<u64>,
+) -> io::Result<Vec<u8>> {
+    let manifest = read_manifest(manifest)?;
+    let end = end.unwrap_or(manifest.size).min(manifest.size);
+    let mut ret = Vec::new();
+    let mut offset = 0;
+    for chunk in &manifest.chunks {
+        let chunk_end = offset + chunk.size;
+        if chunk_end > start && offset < end {
+            let data = load(cache, chunk)?;
+            let from = start.saturating_sub(offset) as usize;
+            let to = (end - offset).min(chunk.size) as usize;
+            ret.extend_from_slice(&data[from..to]);
+        }
+        offset = chunk_end;
+        if offset >= end {
+            break;
+        }
+    }
+    Ok(ret)
+}
+
+fn load(cache: &Path, chunk: &ChunkRef) -> io::Result<Vec<u8>> {
+    let sri = chunk.integrity()?;
+    let data = fs::read(path::chunk_path(cache, &sri))?;
+    sri.check(&data)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    Ok(data)
+}
+
+/// Random values for the gear rolling hash used to find content-defined
+/// chunk boundaries, generated with splitmix64 so they never change between
+/// builds and chunk boundaries stay stable.
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state = 0u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, chunking: Chunking, data: &[u8]) -> Integrity {
+        let mut writer = crate::WriteOpts::new()
+            .chunking(chunking)
+            .open_sync(dir, "my-key")
+            .unwrap();
+        writer.write_all(data).unwrap();
+        writer.commit().unwrap()
+    }
+
+    /// Deterministic, incompressible-looking test data.
+    fn data(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    fn manifest(dir: &Path, sri: &Integrity) -> Manifest {
+        let (mpath, encoding) = path::find_content(dir, sri).unwrap();
+        assert!(encoding.chunked);
+        read_manifest(File::open(mpath).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn fixed_chunks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let data = data(10_000, 1);
+        let sri = write(dir, Chunking::Fixed(4096), &data);
+        assert_eq!(sri, Integrity::from(&data));
+        let manifest = manifest(dir, &sri);
+        assert_eq!(manifest.size, 10_000);
+        assert_eq!(
+            manifest.chunks.iter().map(|c| c.size).collect::<Vec<_>>(),
+            vec![4096, 4096, 1808]
+        );
+        assert_eq!(crate::read_sync(dir, "my-key").unwrap(), data);
+        assert_eq!(
+            crate::read_range_sync(dir, "my-key", 4000..4200).unwrap(),
+            &data[4000..4200]
+        );
+        assert_eq!(
+            crate::read_range_sync(dir, "my-key", 9000..).unwrap(),
+            &data[9000..]
+        );
+    }
+
+    #[test]
+    fn content_defined_chunks_dedup() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let original = data(64 * 1024, 2);
+        let mut edited = b"a small insertion".to_vec();
+        edited.extend_from_slice(&original);
+
+        let a = manifest(dir, &write(dir, Chunking::ContentDefined(4096), &original));
+        let b = manifest(dir, &write(dir, Chunking::ContentDefined(4096), &edited));
+        assert!(a.chunks.len() > 4);
+        // Only the last chunk can come up short.
+        let (_, rest) = a.chunks.split_last().unwrap();
+        assert!(rest.iter().all(|c| c.size >= 1024 && c.size <= 16 * 1024));
+        let shared = b
+            .chunks
+            .iter()
+            .filter(|c| a.chunks.iter().any(|o| o.integrity == c.integrity))
+            .count();
+        assert!(shared >= a.chunks.len() - 1);
+        assert_eq!(crate::read_sync(dir, "my-key").unwrap(), edited);
+    }
+
+    #[test]
+    fn corrupted_chunk() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let data = data(10_000, 3);
+        let sri = write(dir, Chunking::Fixed(4096), &data);
+        let chunk = manifest(dir, &sri).chunks[2].integrity().unwrap();
+        fs::write(path::chunk_path(dir, &chunk), b"corrupted").unwrap();
+
+        // Ranges that don't touch the bad chunk are still readable.
+        assert_eq!(
+            crate::read_range_sync(dir, "my-key", ..8192).unwrap(),
+            &data[..8192]
+        );
+        assert!(crate::read_range_sync(dir, "my-key", 8000..).is_err());
+        assert!(crate::read_sync(dir, "my-key").is_err());
+
+        // Verifying quarantines the bad chunk, and the content it breaks.
+        let stats = crate::verify_sync(dir).unwrap();
+        assert_eq!(stats.bad_content_count, 2);
+        assert!(crate::metadata_sync(dir, "my-key").unwrap().is_none());
+    }
+
+    #[test]
+    fn removing_content_keeps_chunks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let data = data(8192, 5);
+        write(dir, Chunking::Fixed(4096), &data);
+        let plain = crate::write_sync(dir, "plain", &data[..4096]).unwrap();
+        crate::remove_hash_sync(dir, &plain).unwrap();
+        assert_eq!(crate::read_sync(dir, "my-key").unwrap(), data);
+    }
+
+    #[test]
+    fn verify_keeps_live_chunks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let data = data(10_000, 4);
+        write(dir, Chunking::Fixed(4096), &data);
+        let stats = crate::verify_sync(dir).unwrap();
+        assert_eq!(stats.reclaimed_count, 0);
+        assert_eq!(stats.verified_content, 4);
+        assert_eq!(crate::read_sync(dir, "my-key").unwrap(), data);
+
+        // Once nothing points at the content, its chunks go too.
+        crate::remove_sync(dir, "my-key").unwrap();
+        let stats = crate::verify_sync(dir).unwrap();
+        assert_eq!(stats.reclaimed_count, 4);
+    }
+}
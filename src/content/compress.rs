@@ -0,0 +1,149 @@
+use std::io::{self, Read, Write};
+
+/// Compression applied to content when it's written to disk.
+///
+/// The [`crate::Integrity`] of compressed content still describes the
+/// uncompressed data, and reads decompress it transparently.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Compression {
+    /// Zstandard compression, at the default level.
+    #[cfg(feature = "zstd")]
+    Zstd,
+    /// Gzip compression, at the default level.
+    #[cfg(feature = "gzip")]
+    Gzip,
+}
+
+impl Compression {
+    /// Every compression format enabled in this build.
+    pub(crate) const ALL: &'static [Compression] = &[
+        #[cfg(feature = "zstd")]
+        Compression::Zstd,
+        #[cfg(feature = "gzip")]
+        Compression::Gzip,
+    ];
+
+    /// File extension appended to the content path of compressed content.
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => "zst",
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => "gz",
+        }
+    }
+
+    pub(crate) fn from_extension(ext: &str) -> Option<Compression> {
+        Compression::ALL
+            .iter()
+            .copied()
+            .find(|compression| compression.extension() == ext)
+    }
+}
+
+/// Destination for content being written, compressing it along the way if
+/// requested.
+pub enum Encoder<W: Write> {
+    Plain(W),
+    #[cfg(feature = "zstd")]
+    Zstd(Box<zstd::stream::write::Encoder<'static, W>>),
+    #[cfg(feature = "gzip")]
+    Gzip(Box<flate2::write::GzEncoder<W>>),
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(tmpfile: W, compression: Option<Compression>) -> io::Result<Encoder<W>> {
+        Ok(match compression {
+            None => Encoder::Plain(tmpfile),
+            #[cfg(feature = "zstd")]
+            Some(Compression::Zstd) => Encoder::Zstd(Box::new(zstd::stream::write::Encoder::new(
+                tmpfile,
+                zstd::DEFAULT_COMPRESSION_LEVEL,
+            )?)),
+            #[cfg(feature = "gzip")]
+            Some(Compression::Gzip) => Encoder::Gzip(Box::new(flate2::write::GzEncoder::new(
+                tmpfile,
+                flate2::Compression::default(),
+            ))),
+            #[allow(unreachable_patterns)]
+            Some(_) => unreachable!("no compression formats are enabled"),
+        })
+    }
+
+    /// Writes out any remaining compressed data, returning the underlying
+    /// writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Plain(tmpfile) => Ok(tmpfile),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.finish(),
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Plain(tmpfile) => tmpfile.write(buf),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.write(buf),
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Plain(tmpfile) => tmpfile.flush(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.flush(),
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Wraps a content file so reading from it yields the uncompressed data.
+pub fn decoder<R>(fd: R, compression: Option<Compression>) -> io::Result<Box<dyn Read + Send>>
+where
+    R: Read + Send + 'static,
+{
+    Ok(match compression {
+        None => Box::new(fd),
+        #[cfg(feature = "zstd")]
+        Some(Compression::Zstd) => Box::new(zstd::stream::read::Decoder::new(fd)?),
+        #[cfg(feature = "gzip")]
+        Some(Compression::Gzip) => Box::new(flate2::read::GzDecoder::new(fd)),
+        #[allow(unreachable_patterns)]
+        Some(_) => unreachable!("no compression formats are enabled"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for compression in Compression::ALL.iter().copied().map(Some).chain([None]) {
+            let tmp = tempfile::tempdir().unwrap();
+            let mut encoder = Encoder::new(
+                tempfile::NamedTempFile::new_in(tmp.path()).unwrap(),
+                compression,
+            )
+            .unwrap();
+            encoder.write_all(b"hello world").unwrap();
+            let tmpfile = encoder.finish().unwrap();
+
+            let mut data = Vec::new();
+            decoder(tmpfile.reopen().unwrap(), compression)
+                .unwrap()
+                .read_to_end(&mut data)
+                .unwrap();
+            assert_eq!(data, b"hello world");
+        }
+    }
+}
@@ -0,0 +1,371 @@
+use std::fmt;
+#[cfg(feature = "encryption")]
+use std::io::Write;
+use std::io::{self, Read};
+
+#[cfg(feature = "encryption")]
+use chacha20poly1305::aead::rand_core::RngCore;
+#[cfg(feature = "encryption")]
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+#[cfg(feature = "encryption")]
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+#[cfg(feature = "encryption")]
+use chacha20poly1305::XChaCha20Poly1305;
+#[cfg(feature = "encryption")]
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Size of each independently authenticated chunk of plaintext content.
+#[cfg(feature = "encryption")]
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Size of the authentication tag appended to each encrypted chunk.
+#[cfg(feature = "encryption")]
+const TAG_SIZE: usize = 16;
+/// XChaCha20's 24 byte nonce, minus the 5 bytes the stream construction
+/// reserves for its chunk counter.
+#[cfg(feature = "encryption")]
+const STREAM_NONCE_SIZE: usize = 19;
+/// XChaCha20's nonce size, used for sealing index metadata.
+#[cfg(feature = "encryption")]
+const NONCE_SIZE: usize = 24;
+
+/// A 256-bit key used to encrypt cache data at rest with XChaCha20-Poly1305.
+///
+/// The same key must be supplied when reading the data back.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+pub struct EncryptionKey([u8; 32]);
+
+#[cfg(feature = "encryption")]
+impl EncryptionKey {
+    /// Creates a key from raw bytes.
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        EncryptionKey(bytes)
+    }
+
+    /// Generates a new random key.
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        EncryptionKey(bytes)
+    }
+
+    /// Returns the raw bytes of this key, so it can be stored elsewhere.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.0.into())
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never print the key itself.
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+#[cfg(feature = "encryption")]
+fn decrypt_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "failed to decrypt cache data: wrong key, or the data is corrupted",
+    )
+}
+
+/// Options that ask for metadata to be encrypted without a key to do it
+/// with.
+pub fn missing_key() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "metadata can't be encrypted without an encryption key",
+    )
+}
+
+#[cfg(not(feature = "encryption"))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "cache data is encrypted, but cacache was built without the `encryption` feature",
+    )
+}
+
+/// Encrypts everything written to it in fixed-size chunks before passing it
+/// on to `inner`. Must be [`EncryptWriter::finish`]ed, or the data will be
+/// unreadable.
+#[cfg(feature = "encryption")]
+pub struct EncryptWriter<W> {
+    inner: W,
+    encryptor: EncryptorBE32<XChaCha20Poly1305>,
+    buf: Vec<u8>,
+}
+
+#[cfg(feature = "encryption")]
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(mut inner: W, key: &EncryptionKey) -> io::Result<Self> {
+        let mut nonce = [0u8; STREAM_NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        inner.write_all(&nonce)?;
+        Ok(EncryptWriter {
+            inner,
+            encryptor: EncryptorBE32::from_aead(key.cipher(), nonce.as_slice().into()),
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        })
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        let chunk = self
+            .encryptor
+            .encrypt_last(self.buf.as_slice())
+            .map_err(|_| io::Error::other("failed to encrypt cache data"))?;
+        self.inner.write_all(&chunk)?;
+        Ok(self.inner)
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        // Only write out a chunk once we know it isn't the last one, since
+        // the last chunk is sealed differently.
+        while self.buf.len() > CHUNK_SIZE {
+            let chunk = self
+                .encryptor
+                .encrypt_next(&self.buf[..CHUNK_SIZE])
+                .map_err(|_| io::Error::other("failed to encrypt cache data"))?;
+            self.inner.write_all(&chunk)?;
+            self.buf.drain(..CHUNK_SIZE);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts data written by an [`EncryptWriter`].
+#[cfg(feature = "encryption")]
+pub struct DecryptReader<R> {
+    inner: R,
+    decryptor: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    encrypted: Vec<u8>,
+    plain: io::Cursor<Vec<u8>>,
+}
+
+#[cfg(feature = "encryption")]
+impl<R: Read> DecryptReader<R> {
+    pub fn new(mut inner: R, key: &EncryptionKey) -> io::Result<Self> {
+        let mut nonce = [0u8; STREAM_NONCE_SIZE];
+        inner.read_exact(&mut nonce)?;
+        Ok(DecryptReader {
+            inner,
+            decryptor: Some(DecryptorBE32::from_aead(
+                key.cipher(),
+                nonce.as_slice().into(),
+            )),
+            encrypted: Vec::with_capacity(CHUNK_SIZE + TAG_SIZE + 1),
+            plain: io::Cursor::new(Vec::new()),
+        })
+    }
+
+    /// Decrypts the next chunk into `self.plain`. Reads one byte past the
+    /// chunk to find out whether it's the last one.
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let want = CHUNK_SIZE + TAG_SIZE + 1;
+        while self.encrypted.len() < want {
+            let start = self.encrypted.len();
+            self.encrypted.resize(want, 0);
+            let read = self.inner.read(&mut self.encrypted[start..]);
+            let read = match read {
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => 0,
+                Err(e) => {
+                    self.encrypted.truncate(start);
+                    return Err(e);
+                }
+            };
+            self.encrypted.truncate(start + read);
+            if read == 0 {
+                break;
+            }
+        }
+        let plain = if self.encrypted.len() == want {
+            let decryptor = self.decryptor.as_mut().expect("checked by caller");
+            let plain = decryptor
+                .decrypt_next(&self.encrypted[..want - 1])
+                .map_err(|_| decrypt_error())?;
+            self.encrypted.drain(..want - 1);
+            plain
+        } else {
+            let decryptor = self.decryptor.take().expect("checked by caller");
+            let plain = decryptor
+                .decrypt_last(self.encrypted.as_slice())
+                .map_err(|_| decrypt_error())?;
+            self.encrypted.clear();
+            plain
+        };
+        self.plain = io::Cursor::new(plain);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.plain.read(buf)?;
+            if read > 0 || buf.is_empty() || self.decryptor.is_none() {
+                return Ok(read);
+            }
+            self.next_chunk()?;
+        }
+    }
+}
+
+/// Wraps a stored content file so reading from it yields the decrypted data.
+pub fn decrypt<R>(fd: R, key: Option<&EncryptionKey>) -> io::Result<Box<dyn Read + Send>>
+where
+    R: Read + Send + 'static,
+{
+    #[cfg(feature = "encryption")]
+    {
+        let key = key.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "cache data is encrypted, but no encryption key was provided",
+            )
+        })?;
+        Ok(Box::new(DecryptReader::new(fd, key)?))
+    }
+    #[cfg(not(feature = "encryption"))]
+    {
+        let _ = (fd, key);
+        Err(unsupported())
+    }
+}
+
+/// The parts of an index entry that get sealed when metadata encryption is
+/// on.
+#[cfg(feature = "encryption")]
+#[derive(Deserialize, Serialize)]
+struct SealedMetadata {
+    metadata: Value,
+    raw_metadata: Option<Vec<u8>>,
+}
+
+/// Encrypts an entry's metadata into a hex string for storing in the index.
+#[cfg(feature = "encryption")]
+pub fn seal_metadata(
+    key: &EncryptionKey,
+    metadata: Value,
+    raw_metadata: Option<Vec<u8>>,
+) -> io::Result<String> {
+    let plain = serde_json::to_vec(&SealedMetadata {
+        metadata,
+        raw_metadata,
+    })?;
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let sealed = key
+        .cipher()
+        .encrypt(&nonce, plain.as_slice())
+        .map_err(|_| io::Error::other("failed to encrypt cache metadata"))?;
+    let mut out = nonce.to_vec();
+    out.extend(sealed);
+    Ok(hex::encode(out))
+}
+
+/// Reverses [`seal_metadata`].
+pub fn unseal_metadata(key: &EncryptionKey, sealed: &str) -> io::Result<(Value, Option<Vec<u8>>)> {
+    #[cfg(feature = "encryption")]
+    {
+        let sealed = hex::decode(sealed).map_err(|_| decrypt_error())?;
+        if sealed.len() < NONCE_SIZE {
+            return Err(decrypt_error());
+        }
+        let (nonce, sealed) = sealed.split_at(NONCE_SIZE);
+        let plain = key
+            .cipher()
+            .decrypt(nonce.into(), sealed)
+            .map_err(|_| decrypt_error())?;
+        let SealedMetadata {
+            metadata,
+            raw_metadata,
+        } = serde_json::from_slice(&plain)?;
+        Ok((metadata, raw_metadata))
+    }
+    #[cfg(not(feature = "encryption"))]
+    {
+        let _ = (key, sealed);
+        Err(unsupported())
+    }
+}
+
+#[cfg(all(test, feature = "encryption"))]
+mod tests {
+    use super::*;
+
+    fn round_trip(len: usize) {
+        let key = EncryptionKey::generate();
+        let data = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+        let mut writer = EncryptWriter::new(Vec::new(), &key).unwrap();
+        writer.write_all(&data).unwrap();
+        let encrypted = writer.finish().unwrap();
+        assert_ne!(&encrypted[STREAM_NONCE_SIZE..], data.as_slice());
+
+        let mut decrypted = Vec::new();
+        decrypt(io::Cursor::new(encrypted.clone()), Some(&key))
+            .unwrap()
+            .read_to_end(&mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, data);
+
+        let wrong = EncryptionKey::generate();
+        assert!(decrypt(io::Cursor::new(encrypted), Some(&wrong))
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .is_err());
+    }
+
+    #[test]
+    fn content_round_trip() {
+        for len in [
+            0,
+            1,
+            CHUNK_SIZE - 1,
+            CHUNK_SIZE,
+            CHUNK_SIZE + 1,
+            CHUNK_SIZE * 3,
+        ] {
+            round_trip(len);
+        }
+    }
+
+    #[test]
+    fn truncated_content() {
+        let key = EncryptionKey::generate();
+        let mut writer = EncryptWriter::new(Vec::new(), &key).unwrap();
+        writer.write_all(&[7u8; CHUNK_SIZE * 2]).unwrap();
+        let mut encrypted = writer.finish().unwrap();
+        encrypted.truncate(STREAM_NONCE_SIZE + CHUNK_SIZE + TAG_SIZE);
+        assert!(decrypt(io::Cursor::new(encrypted), Some(&key))
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .is_err());
+    }
+
+    #[test]
+    fn metadata_round_trip() {
+        let key = EncryptionKey::generate();
+        let sealed =
+            seal_metadata(&key, serde_json::json!({"secret": true}), Some(vec![1, 2])).unwrap();
+        assert!(!sealed.contains("secret"));
+        let (metadata, raw) = unseal_metadata(&key, &sealed).unwrap();
+        assert_eq!(metadata, serde_json::json!({"secret": true}));
+        assert_eq!(raw, Some(vec![1, 2]));
+        assert!(unseal_metadata(&EncryptionKey::generate(), &sealed).is_err());
+    }
+}
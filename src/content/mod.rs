@@ -1,5 +1,10 @@
+pub mod chunk;
+pub mod compress;
+pub mod encrypt;
+pub mod pack;
 pub mod path;
 pub mod read;
+pub mod resume;
 pub mod rm;
 pub mod write;
 
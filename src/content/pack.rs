@@ -0,0 +1,495 @@
+//! Small content appended back to back into shared pack files, so caches
+//! with lots of tiny objects don't need a file (and an inode) for each one.
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use serde_derive::{Deserialize, Serialize};
+use ssri::Integrity;
+use walkdir::WalkDir;
+
+use crate::durability::{self, Durability};
+use crate::errors::{IoErrorExt, Result};
+use crate::flock;
+use crate::index;
+
+const PACK_VERSION: &str = "1";
+
+/// Packs stop being appended to once they grow past this size.
+pub const MAX_PACK_SIZE: u64 = 64 * 1024 * 1024;
+
+// Current layout of packed content:
+//
+// ~/.my-cache/packs-v1/3.pack      object data, appended back to back
+// ~/.my-cache/packs-v1/lock        held shared by writers, exclusively by repack
+// ~/.my-cache/packs-v1/index/41/b0/9c2f51d43099d30919c41fba42f7cfea879a
+//
+// Index buckets are named like reverse index buckets, after a hash of the
+// integrity's strongest hash, and hold lines saying where each object
+// lives. Lines are only ever appended, and the last one for an integrity
+// wins, so objects are removed by appending a line without a location.
+
+/// Where a packed object's data lives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Location {
+    pub pack: u64,
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// A line in a pack index bucket.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackEntry {
+    pub integrity: String,
+    pub location: Option<Location>,
+}
+
+pub fn pack_dir(cache: &Path) -> PathBuf {
+    cache.join(format!("packs-v{PACK_VERSION}"))
+}
+
+pub fn pack_path(cache: &Path, pack: u64) -> PathBuf {
+    pack_dir(cache).join(format!("{pack}.pack"))
+}
+
+fn index_dir(cache: &Path) -> PathBuf {
+    pack_dir(cache).join("index")
+}
+
+pub fn bucket_path(cache: &Path, sri: &Integrity) -> Option<PathBuf> {
+    let hashed = index::hash_key(&sri.hashes.first()?.to_string());
+    Some(
+        index_dir(cache)
+            .join(&hashed[0..2])
+            .join(&hashed[2..4])
+            .join(&hashed[4..]),
+    )
+}
+
+/// Finds where the data for `sri` is packed, if it is.
+pub fn find(cache: &Path, sri: &Integrity) -> Result<Option<Location>> {
+    let Some(bucket) = bucket_path(cache, sri) else {
+        return Ok(None);
+    };
+    let contents = match fs::read(&bucket) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to read pack index at {bucket:?}"))
+        }
+    };
+    Ok(locate(&contents, sri))
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub async fn find_async(cache: &Path, sri: &Integrity) -> Result<Option<Location>> {
+    let Some(bucket) = bucket_path(cache, sri) else {
+        return Ok(None);
+    };
+    let contents = match crate::async_lib::read(&bucket).await {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to read pack index at {bucket:?}"))
+        }
+    };
+    Ok(locate(&contents, sri))
+}
+
+fn locate(contents: &[u8], sri: &Integrity) -> Option<Location> {
+    parse_bucket(contents)
+        .into_iter()
+        .rev()
+        .find(|entry| {
+            entry
+                .integrity
+                .parse::<Integrity>()
+                .is_ok_and(|other| other.matches(sri).is_some())
+        })
+        .and_then(|entry| entry.location)
+}
+
+/// Reads the packed data for `sri`, if it's packed. The data isn't checked
+/// against `sri`.
+///
+/// Readers don't take the packs lock. Repacking only removes old packs once
+/// the pack index points into the new ones, so if the pack turns out to be
+/// gone, the object is looked up again.
+pub fn read(cache: &Path, sri: &Integrity) -> Result<Option<Vec<u8>>> {
+    let mut tried = None;
+    loop {
+        let Some(location) = find(cache, sri)? else {
+            return Ok(None);
+        };
+        match try_read_at(cache, location) {
+            Err(err) if err.kind() == ErrorKind::NotFound && tried != Some(location) => {
+                tried = Some(location);
+            }
+            res => return res.map(Some).with_context(|| read_context(cache, location)),
+        }
+    }
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub async fn read_async(cache: &Path, sri: &Integrity) -> Result<Option<Vec<u8>>> {
+    let cache = cache.to_path_buf();
+    let sri = sri.clone();
+    crate::async_lib::unwrap_joinhandle_value(
+        crate::async_lib::spawn_blocking(move || read(&cache, &sri)).await,
+    )
+}
+
+pub fn read_at(cache: &Path, location: Location) -> Result<Vec<u8>> {
+    try_read_at(cache, location).with_context(|| read_context(cache, location))
+}
+
+fn try_read_at(cache: &Path, location: Location) -> std::io::Result<Vec<u8>> {
+    let mut data = vec![0; location.size as usize];
+    let mut fd = File::open(pack_path(cache, location.pack))?;
+    fd.seek(SeekFrom::Start(location.offset))?;
+    fd.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn read_context(cache: &Path, location: Location) -> String {
+    format!(
+        "Failed to read packed contents from {}",
+        pack_path(cache, location.pack).display()
+    )
+}
+
+/// Appends `data` to the current pack and records where it went, unless
+/// it's already packed. Returns a shared lock on the packs, which keeps
+/// repacking from dropping the object as unreferenced until it's dropped,
+/// so it should be held until the object has been indexed.
+pub fn insert(cache: &Path, sri: &Integrity, data: &[u8], durability: Durability) -> Result<File> {
+    let dir = pack_dir(cache);
+    durability::create_dir_all(&dir, durability)
+        .with_context(|| format!("Failed to create pack directory at {}", dir.display()))?;
+    let lock = lock_packs(cache, false)?;
+    if find(cache, sri)?.is_some() {
+        return Ok(lock);
+    }
+    let (pack, mut fd, offset) = current_pack(cache)?;
+    let ppath = pack_path(cache, pack);
+    fd.write_all(data)
+        .and_then(|_| durability::sync_file(&fd, durability, "pack synced"))
+        .and_then(|_| durability::sync_parent(&ppath, durability, "pack dir synced"))
+        .with_context(|| format!("Failed to write to pack at {}", ppath.display()))?;
+    drop(fd);
+    let location = Location {
+        pack,
+        offset,
+        size: data.len() as u64,
+    };
+    append(cache, sri, Some(location), durability)?;
+    Ok(lock)
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub async fn insert_async(
+    cache: &Path,
+    sri: &Integrity,
+    data: Vec<u8>,
+    durability: Durability,
+) -> Result<File> {
+    let cache = cache.to_path_buf();
+    let sri = sri.clone();
+    crate::async_lib::unwrap_joinhandle_value(
+        crate::async_lib::spawn_blocking(move || insert(&cache, &sri, &data, durability)).await,
+    )
+}
+
+/// Forgets about the packed data for `sri`, returning whether there was any.
+/// The space it takes up is only reclaimed by repacking.
+pub fn remove(cache: &Path, sri: &Integrity) -> Result<bool> {
+    if find(cache, sri)?.is_none() {
+        return Ok(false);
+    }
+    append(cache, sri, None, Durability::None)?;
+    Ok(true)
+}
+
+/// Opens the newest pack with room left in it, locked so nobody else
+/// appends to it until it's dropped, along with where new data will go.
+fn current_pack(cache: &Path) -> Result<(u64, File, u64)> {
+    let mut pack = packs(cache)?.into_iter().max().unwrap_or(0);
+    loop {
+        let ppath = pack_path(cache, pack);
+        let fd = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&ppath)
+            .and_then(|fd| {
+                flock::lock(&fd)?;
+                Ok(fd)
+            })
+            .with_context(|| format!("Failed to open pack at {}", ppath.display()))?;
+        let len = fd
+            .metadata()
+            .with_context(|| format!("Failed to read metadata for {}", ppath.display()))?
+            .len();
+        if len < MAX_PACK_SIZE {
+            return Ok((pack, fd, len));
+        }
+        pack += 1;
+    }
+}
+
+/// Writes objects into brand new packs, for repacking.
+pub struct PackWriter {
+    cache: PathBuf,
+    pack: u64,
+    fd: Option<File>,
+    offset: u64,
+}
+
+impl PackWriter {
+    /// Starts writing at pack number `first`, which mustn't exist yet.
+    pub fn new(cache: &Path, first: u64) -> Self {
+        PackWriter {
+            cache: cache.to_path_buf(),
+            pack: first,
+            fd: None,
+            offset: 0,
+        }
+    }
+
+    pub fn append(&mut self, data: &[u8]) -> Result<Location> {
+        if self.fd.is_some() && self.offset + data.len() as u64 > MAX_PACK_SIZE {
+            self.finish_pack()?;
+            self.pack += 1;
+            self.offset = 0;
+        }
+        let ppath = pack_path(&self.cache, self.pack);
+        let fd = match &mut self.fd {
+            Some(fd) => fd,
+            None => self.fd.insert(
+                OpenOptions::new()
+                    .create_new(true)
+                    .write(true)
+                    .open(&ppath)
+                    .with_context(|| format!("Failed to create pack at {}", ppath.display()))?,
+            ),
+        };
+        fd.write_all(data)
+            .with_context(|| format!("Failed to write to pack at {}", ppath.display()))?;
+        let location = Location {
+            pack: self.pack,
+            offset: self.offset,
+            size: data.len() as u64,
+        };
+        self.offset += location.size;
+        Ok(location)
+    }
+
+    /// Makes sure everything written so far is on disk.
+    pub fn finish(mut self) -> Result<()> {
+        self.finish_pack()
+    }
+
+    fn finish_pack(&mut self) -> Result<()> {
+        let Some(fd) = self.fd.take() else {
+            return Ok(());
+        };
+        let ppath = pack_path(&self.cache, self.pack);
+        fd.sync_all()
+            .and_then(|_| durability::sync_parent(&ppath, Durability::Full, "pack dir synced"))
+            .with_context(|| format!("Failed to sync pack at {}", ppath.display()))
+    }
+}
+
+/// The numbers of every pack in the cache.
+pub fn packs(cache: &Path) -> Result<Vec<u64>> {
+    let dir = pack_dir(cache);
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to read pack directory at {}", dir.display()))
+        }
+    };
+    let mut packs = Vec::new();
+    for entry in entries {
+        let entry =
+            entry.with_context(|| format!("Failed to read pack directory at {}", dir.display()))?;
+        let name = entry.file_name();
+        if let Some(pack) = name
+            .to_str()
+            .and_then(|name| name.strip_suffix(".pack"))
+            .and_then(|pack| pack.parse().ok())
+        {
+            packs.push(pack);
+        }
+    }
+    packs.sort_unstable();
+    Ok(packs)
+}
+
+fn append(
+    cache: &Path,
+    sri: &Integrity,
+    location: Option<Location>,
+    durability: Durability,
+) -> Result<()> {
+    let Some(bucket) = bucket_path(cache, sri) else {
+        return Ok(());
+    };
+    durability::create_dir_all(bucket.parent().unwrap(), durability).with_context(|| {
+        format!(
+            "Failed to create pack index directory: {:?}",
+            bucket.parent().unwrap()
+        )
+    })?;
+    let entry = PackEntry {
+        integrity: sri.to_string(),
+        location,
+    };
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&bucket)
+        .and_then(|mut buck| {
+            buck.write_all(format_line(&entry).as_bytes())?;
+            durability::sync_file(&buck, durability, "pack index synced")?;
+            durability::sync_parent(&bucket, durability, "pack index dir synced")
+        })
+        .with_context(|| format!("Failed to write to pack index at {bucket:?}"))
+}
+
+/// The latest entry for each object in every pack index bucket, including
+/// removed ones, grouped by bucket.
+pub fn buckets(cache: &Path) -> Result<HashMap<PathBuf, Vec<PackEntry>>> {
+    let dir = index_dir(cache);
+    let mut buckets = HashMap::new();
+    if !dir.exists() {
+        return Ok(buckets);
+    }
+    for entry in WalkDir::new(&dir) {
+        let entry = entry
+            .map_err(|e| crate::errors::io_error(e.to_string()))
+            .with_context(|| format!("Error while walking pack index at {}", dir.display()))?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let bucket = entry.into_path();
+        let contents = fs::read(&bucket)
+            .with_context(|| format!("Failed to read pack index at {bucket:?}"))?;
+        let mut latest: Vec<PackEntry> = Vec::new();
+        for entry in parse_bucket(&contents) {
+            latest.retain(|other| other.integrity != entry.integrity);
+            latest.push(entry);
+        }
+        buckets.insert(bucket, latest);
+    }
+    Ok(buckets)
+}
+
+/// Replaces a pack index bucket's contents with `entries`, removing it if
+/// there are none. The new contents show up all at once.
+pub fn write_bucket(bucket: &Path, entries: &[PackEntry]) -> Result<()> {
+    if entries.is_empty() {
+        return match fs::remove_file(bucket) {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                Err(err).with_context(|| format!("Failed to remove pack index at {bucket:?}"))
+            }
+            _ => Ok(()),
+        };
+    }
+    let parent = bucket.parent().unwrap();
+    fs::create_dir_all(parent)
+        .with_context(|| format!("Failed to create pack index directory: {parent:?}"))?;
+    let mut tmp = tempfile::NamedTempFile::new_in(parent)
+        .with_context(|| format!("Failed to create temp file in {parent:?}"))?;
+    for entry in entries {
+        tmp.write_all(format_line(entry).as_bytes())
+            .with_context(|| format!("Failed to write to temp file for {bucket:?}"))?;
+    }
+    tmp.as_file()
+        .sync_all()
+        .with_context(|| format!("Failed to sync temp file for {bucket:?}"))?;
+    tmp.persist(bucket)
+        .map_err(|e| e.error)
+        .with_context(|| format!("Failed to replace pack index at {bucket:?}"))?;
+    durability::sync_parent(bucket, Durability::Full, "pack index dir synced")
+        .with_context(|| format!("Failed to sync directory containing {bucket:?}"))?;
+    Ok(())
+}
+
+fn format_line(entry: &PackEntry) -> String {
+    let stringified = serde_json::to_string(entry).expect("pack entries always serialize");
+    format!("\n{}\t{}", index::hash_entry(&stringified), stringified)
+}
+
+/// Entries in a pack index bucket, oldest first. Lines that don't hash
+/// correctly, e.g. from an interrupted write, are skipped.
+fn parse_bucket(contents: &[u8]) -> Vec<PackEntry> {
+    String::from_utf8_lossy(contents)
+        .lines()
+        .filter_map(|line| match line.split('\t').collect::<Vec<&str>>()[..] {
+            [hash, stringified] if index::hash_entry(stringified) == hash => {
+                serde_json::from_str(stringified).ok()
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Takes the lock that keeps writers and repacking apart: shared for
+/// writers, and `exclusive` for repacking. It's held until the returned file
+/// is dropped.
+pub fn lock_packs(cache: &Path, exclusive: bool) -> Result<File> {
+    let lpath = pack_dir(cache).join("lock");
+    flock::open_locked(&lpath, exclusive)
+        .with_context(|| format!("Failed to lock packs at {}", lpath.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_find_remove() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let hello = Integrity::from(b"hello");
+        let world = Integrity::from(b"world!");
+        assert_eq!(read(dir, &hello).unwrap(), None);
+
+        insert(dir, &hello, b"hello", Durability::None).unwrap();
+        insert(dir, &world, b"world!", Durability::None).unwrap();
+        // Inserting the same data again doesn't pack it twice.
+        insert(dir, &hello, b"hello", Durability::None).unwrap();
+        assert_eq!(
+            find(dir, &world).unwrap(),
+            Some(Location {
+                pack: 0,
+                offset: 5,
+                size: 6
+            })
+        );
+        assert_eq!(read(dir, &hello).unwrap().unwrap(), b"hello");
+        assert_eq!(read(dir, &world).unwrap().unwrap(), b"world!");
+        assert_eq!(packs(dir).unwrap(), vec![0]);
+
+        assert!(remove(dir, &hello).unwrap());
+        assert!(!remove(dir, &hello).unwrap());
+        assert_eq!(read(dir, &hello).unwrap(), None);
+        assert_eq!(read(dir, &world).unwrap().unwrap(), b"world!");
+    }
+
+    #[test]
+    fn torn_index_lines_are_skipped() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let sri = Integrity::from(b"hello");
+        insert(dir, &sri, b"hello", Durability::None).unwrap();
+        let bucket = bucket_path(dir, &sri).unwrap();
+        let mut contents = fs::read(&bucket).unwrap();
+        contents.extend_from_slice(b"\nnot a real");
+        fs::write(&bucket, contents).unwrap();
+        assert_eq!(read(dir, &sri).unwrap().unwrap(), b"hello");
+    }
+}
@@ -1,7 +1,11 @@
 use ssri::Integrity;
+use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
 
+use crate::content::compress::Compression;
+
 const CONTENT_VERSION: &str = "2";
+const CHUNKS_VERSION: &str = "1";
 
 // Current format of content file path:
 //
@@ -9,10 +13,21 @@ const CONTENT_VERSION: &str = "2";
 // ~/.my-cache/content-v2/sha512/ba/da/55deadbeefc0ffee
 //
 pub fn content_path(cache: &Path, sri: &Integrity) -> PathBuf {
-    let mut path = PathBuf::new();
+    hashed_path(content_dir(cache), sri)
+}
+
+// Chunks of chunked content are laid out the same way, in a directory of
+// their own, so removing content never removes a chunk that happens to have
+// the same data:
+//
+// ~/.my-cache/chunks-v1/sha512/ba/da/55deadbeefc0ffee
+//
+pub fn chunk_path(cache: &Path, sri: &Integrity) -> PathBuf {
+    hashed_path(chunk_dir(cache), sri)
+}
+
+fn hashed_path(mut path: PathBuf, sri: &Integrity) -> PathBuf {
     let (algo, hex) = sri.to_hex();
-    path.push(cache);
-    path.push(format!("content-v{CONTENT_VERSION}"));
     path.push(algo.to_string());
     path.push(&hex[0..2]);
     path.push(&hex[2..4]);
@@ -20,6 +35,134 @@ pub fn content_path(cache: &Path, sri: &Integrity) -> PathBuf {
     path
 }
 
+// How a content file's data is encoded on disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Encoding {
+    pub compression: Option<Compression>,
+    pub encrypted: bool,
+    pub chunked: bool,
+}
+
+impl Encoding {
+    pub fn is_plain(&self) -> bool {
+        self.compression.is_none() && !self.encrypted && !self.chunked
+    }
+}
+
+// Encoded content lives next to where its plain version would, with
+// extensions naming the compression format and whether it's encrypted:
+//
+// ~/.my-cache/content-v2/sha512/ba/da/55deadbeefc0ffee.zst.enc
+//
+// Chunked content is stored as a manifest listing its chunks instead:
+//
+// ~/.my-cache/content-v2/sha512/ba/da/55deadbeefc0ffee.chunks
+//
+pub fn encoded_path(cache: &Path, sri: &Integrity, encoding: Encoding) -> PathBuf {
+    let mut path = content_path(cache, sri).into_os_string();
+    if let Some(compression) = encoding.compression {
+        path.push(".");
+        path.push(compression.extension());
+    }
+    if encoding.encrypted {
+        path.push(".enc");
+    }
+    if encoding.chunked {
+        path.push(".chunks");
+    }
+    path.into()
+}
+
+// Every path the content for `sri` could be stored at, in the order readers
+// should look for it.
+pub fn stored_paths(cache: &Path, sri: &Integrity) -> Vec<(PathBuf, Encoding)> {
+    let compressions = std::iter::once(None).chain(Compression::ALL.iter().copied().map(Some));
+    let chunked = Encoding {
+        chunked: true,
+        ..Default::default()
+    };
+    [false, true]
+        .into_iter()
+        .flat_map(|encrypted| {
+            compressions.clone().map(move |compression| Encoding {
+                compression,
+                encrypted,
+                chunked: false,
+            })
+        })
+        .chain(std::iter::once(chunked))
+        .map(|encoding| (encoded_path(cache, sri, encoding), encoding))
+        .collect()
+}
+
+// Finds where the content for `sri` is actually stored, if anywhere.
+pub fn find_content(cache: &Path, sri: &Integrity) -> Option<(PathBuf, Encoding)> {
+    stored_paths(cache, sri)
+        .into_iter()
+        .find(|(cpath, _)| cpath.exists())
+}
+
+// Splits a stored content path into the plain `content_path` it stands for
+// and the encoding of its data.
+pub fn split_encoding(cpath: &Path) -> (PathBuf, Encoding) {
+    let mut cpath = cpath.to_path_buf();
+    let mut encoding = Encoding::default();
+    if cpath.extension() == Some(OsStr::new("chunks")) {
+        encoding.chunked = true;
+        cpath.set_extension("");
+    }
+    if cpath.extension() == Some(OsStr::new("enc")) {
+        encoding.encrypted = true;
+        cpath.set_extension("");
+    }
+    if let Some(compression) = cpath
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(Compression::from_extension)
+    {
+        encoding.compression = Some(compression);
+        cpath.set_extension("");
+    }
+    (cpath, encoding)
+}
+
+// Root directory holding all content files, regardless of algorithm.
+pub fn content_dir(cache: &Path) -> PathBuf {
+    cache.join(format!("content-v{CONTENT_VERSION}"))
+}
+
+// Root directory holding all chunks.
+pub fn chunk_dir(cache: &Path) -> PathBuf {
+    cache.join(format!("chunks-v{CHUNKS_VERSION}"))
+}
+
+// Inverse of `content_path` and `encoded_path`: recovers the integrity a
+// content file is stored under from its location inside the content
+// directory.
+pub fn path_integrity(cache: &Path, cpath: &Path) -> Option<Integrity> {
+    let (cpath, _) = split_encoding(cpath);
+    integrity_under(&content_dir(cache), &cpath)
+}
+
+// Inverse of `chunk_path`.
+pub fn chunk_integrity(cache: &Path, cpath: &Path) -> Option<Integrity> {
+    integrity_under(&chunk_dir(cache), cpath)
+}
+
+fn integrity_under(dir: &Path, cpath: &Path) -> Option<Integrity> {
+    let rel = cpath.strip_prefix(dir).ok()?;
+    let parts = rel
+        .iter()
+        .map(|part| part.to_str())
+        .collect::<Option<Vec<_>>>()?;
+    match parts[..] {
+        [algo, first, second, rest] => {
+            Integrity::from_hex(format!("{first}{second}{rest}"), algo.parse().ok()?).ok()
+        }
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -39,4 +182,37 @@ mod tests {
         wanted.push("27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9");
         assert_eq!(cpath.to_str().unwrap(), wanted.to_str().unwrap());
     }
+
+    #[test]
+    fn path_integrity_round_trip() {
+        let cache = Path::new("~/.my-cache");
+        let sri = Integrity::from(b"hello world");
+        let cpath = content_path(cache, &sri);
+        assert_eq!(path_integrity(cache, &cpath), Some(sri.clone()));
+        assert_eq!(path_integrity(cache, &content_dir(cache)), None);
+
+        let cpath = chunk_path(cache, &sri);
+        assert_eq!(chunk_integrity(cache, &cpath), Some(sri));
+        assert_eq!(path_integrity(cache, &cpath), None);
+    }
+
+    #[test]
+    fn encoded_path_round_trip() {
+        let cache = Path::new("~/.my-cache");
+        let sri = Integrity::from(b"hello world");
+        for (cpath, encoding) in stored_paths(cache, &sri) {
+            assert_eq!(
+                split_encoding(&cpath),
+                (content_path(cache, &sri), encoding)
+            );
+            assert_eq!(path_integrity(cache, &cpath), Some(sri.clone()));
+        }
+        let encoding = Encoding {
+            compression: None,
+            encrypted: true,
+            chunked: false,
+        };
+        let cpath = encoded_path(cache, &sri, encoding);
+        assert_eq!(cpath.extension().unwrap(), "enc");
+    }
 }
@@ -1,44 +1,219 @@
-use std::fs::{self, File};
-use std::io::Read;
-use std::path::Path;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 use std::pin::Pin;
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 use std::task::{Context, Poll};
 
 #[cfg(any(feature = "async-std", feature = "tokio"))]
-use crate::async_lib::AsyncReadExt;
+use crate::async_lib::{AsyncReadExt, AsyncSeekExt};
 
 use ssri::{Algorithm, Integrity, IntegrityChecker};
 
 #[cfg(any(feature = "async-std", feature = "tokio"))]
-use crate::async_lib::AsyncRead;
-use crate::content::path;
+use crate::async_lib::{AsyncRead, AsyncSeek};
+use crate::content::chunk;
+use crate::content::compress;
+use crate::content::encrypt::{self, EncryptionKey};
+use crate::content::pack;
+use crate::content::path::{self, Encoding};
 use crate::errors::{IoErrorExt, Result};
 
 pub struct Reader {
-    fd: File,
-    checker: IntegrityChecker,
+    fd: Source,
+    verifier: Verifier,
+}
+
+/// Plain content is read straight from its file, and inline or packed data
+/// from memory, so both can be seeked. Anything else goes through a decoder,
+/// which can only be read in order.
+enum Source {
+    File(File),
+    Memory(io::Cursor<Vec<u8>>),
+    Decoded(Box<dyn Read + Send>),
 }
 
 impl std::io::Read for Reader {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let amt = self.fd.read(buf)?;
-        self.checker.input(&buf[..amt]);
+        let amt = match &mut self.fd {
+            Source::File(fd) => fd.read(buf)?,
+            Source::Memory(data) => Read::read(data, buf)?,
+            Source::Decoded(fd) => fd.read(buf)?,
+        };
+        self.verifier.input(&buf[..amt]);
         Ok(amt)
     }
 }
 
+impl Seek for Reader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match &mut self.fd {
+            Source::File(fd) => fd.seek(pos)?,
+            Source::Memory(data) => Seek::seek(data, pos)?,
+            Source::Decoded(_) => return Err(unseekable()),
+        };
+        self.verifier.moved_to(pos);
+        Ok(pos)
+    }
+}
+
 impl Reader {
+    /// Reads data that's already in memory, like inline or packed data.
+    pub fn in_memory(data: Vec<u8>, sri: Integrity) -> Self {
+        Reader {
+            fd: Source::Memory(io::Cursor::new(data)),
+            verifier: Verifier::new(sri),
+        }
+    }
+
     pub fn check(self) -> Result<Algorithm> {
-        Ok(self.checker.result()?)
+        self.verifier.check()
+    }
+}
+
+/// Checks data against its integrity as it's read, for as long as it's read
+/// in order from the start.
+struct Verifier {
+    sri: Integrity,
+    checker: Option<IntegrityChecker>,
+    pos: u64,
+}
+
+impl Verifier {
+    fn new(sri: Integrity) -> Self {
+        Verifier {
+            checker: Some(IntegrityChecker::new(sri.clone())),
+            sri,
+            pos: 0,
+        }
+    }
+
+    fn input(&mut self, data: &[u8]) {
+        if let Some(checker) = &mut self.checker {
+            checker.input(data);
+        }
+        self.pos += data.len() as u64;
+    }
+
+    /// Seeking back to the start begins verification again, while seeking
+    /// anywhere else gives up on it.
+    fn moved_to(&mut self, pos: u64) {
+        if pos == 0 {
+            self.checker = Some(IntegrityChecker::new(self.sri.clone()));
+        } else if pos != self.pos {
+            self.checker = None;
+        }
+        self.pos = pos;
+    }
+
+    fn check(self) -> Result<Algorithm> {
+        match self.checker {
+            Some(checker) => Ok(checker.result()?),
+            None => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "data was read out of order after seeking",
+            ))
+            .with_context(|| format!("Failed to verify cache contents for {}", self.sri)),
+        }
     }
 }
 
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 pub struct AsyncReader {
-    fd: crate::async_lib::File,
-    checker: IntegrityChecker,
+    fd: AsyncSource,
+    verifier: Verifier,
+    #[cfg(feature = "tokio")]
+    seeking: bool,
+}
+
+/// Like [`Source`]. Decoders only speak synchronous IO, so decoded content
+/// is piped over from a blocking thread instead.
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+enum AsyncSource {
+    File(crate::async_lib::File),
+    Memory(io::Cursor<Vec<u8>>),
+    Decoded(Piped),
+}
+
+/// How much decoded data a [`Piped`] reader's thread reads at a time.
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+const PIPE_BLOCK_SIZE: usize = 64 * 1024;
+
+/// How many blocks a [`Piped`] reader's thread gets ahead of the reader
+/// before it waits for it to catch up.
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+const PIPE_BLOCKS: usize = 4;
+
+/// Data read from a synchronous reader on a blocking thread, which stays at
+/// most a few blocks ahead. The thread stops once the reader is dropped.
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+struct Piped {
+    blocks: futures::channel::mpsc::Receiver<io::Result<Vec<u8>>>,
+    block: io::Cursor<Vec<u8>>,
+    #[cfg(feature = "tokio")]
+    position: u64,
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+impl Piped {
+    fn spawn(mut fd: Box<dyn Read + Send>) -> Self {
+        use futures::SinkExt;
+
+        let (mut tx, blocks) = futures::channel::mpsc::channel(PIPE_BLOCKS);
+        // Nothing waits on it: it finishes on its own once the data or the
+        // reader runs out.
+        drop(crate::async_lib::spawn_blocking(move || loop {
+            let mut block = vec![0; PIPE_BLOCK_SIZE];
+            let res = match fd.read(&mut block) {
+                Ok(0) => return,
+                Ok(amt) => {
+                    block.truncate(amt);
+                    Ok(block)
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => Err(err),
+            };
+            let failed = res.is_err();
+            if futures::executor::block_on(tx.send(res)).is_err() || failed {
+                return;
+            }
+        }));
+        Piped {
+            blocks,
+            block: io::Cursor::new(Vec::new()),
+            #[cfg(feature = "tokio")]
+            position: 0,
+        }
+    }
+
+    fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        use futures::StreamExt;
+
+        loop {
+            let amt = Read::read(&mut self.block, buf)?;
+            if amt > 0 || buf.is_empty() {
+                #[cfg(feature = "tokio")]
+                {
+                    self.position += amt as u64;
+                }
+                return Poll::Ready(Ok(amt));
+            }
+            match futures::ready!(self.blocks.poll_next_unpin(cx)) {
+                Some(Ok(block)) => self.block = io::Cursor::new(block),
+                Some(Err(err)) => return Poll::Ready(Err(err)),
+                None => return Poll::Ready(Ok(0)),
+            }
+        }
+    }
+}
+
+/// Decoded content can only be read in order, so it can't be seeked.
+fn unseekable() -> io::Error {
+    io::Error::new(
+        ErrorKind::Unsupported,
+        "content is stored compressed, encrypted or chunked, and can't be seeked",
+    )
 }
 
 #[cfg(any(feature = "async-std", feature = "tokio"))]
@@ -49,8 +224,12 @@ impl AsyncRead for AsyncReader {
         cx: &mut Context<'_>,
         buf: &mut [u8],
     ) -> Poll<std::io::Result<usize>> {
-        let amt = futures::ready!(Pin::new(&mut self.fd).poll_read(cx, buf))?;
-        self.checker.input(&buf[..amt]);
+        let amt = match &mut self.fd {
+            AsyncSource::File(fd) => futures::ready!(Pin::new(fd).poll_read(cx, buf))?,
+            AsyncSource::Memory(data) => Read::read(data, buf)?,
+            AsyncSource::Decoded(piped) => futures::ready!(piped.poll_read(cx, buf))?,
+        };
+        self.verifier.input(&buf[..amt]);
         Poll::Ready(Ok(amt))
     }
 
@@ -61,77 +240,421 @@ impl AsyncRead for AsyncReader {
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> Poll<tokio::io::Result<()>> {
         let pre_len = buf.filled().len();
-        futures::ready!(Pin::new(&mut self.fd).poll_read(cx, buf))?;
+        match &mut self.fd {
+            AsyncSource::File(fd) => futures::ready!(Pin::new(fd).poll_read(cx, buf))?,
+            AsyncSource::Memory(data) => {
+                let amt = Read::read(data, buf.initialize_unfilled())?;
+                buf.advance(amt);
+            }
+            AsyncSource::Decoded(piped) => {
+                let amt = futures::ready!(piped.poll_read(cx, buf.initialize_unfilled()))?;
+                buf.advance(amt);
+            }
+        }
         let post_len = buf.filled().len();
         if post_len - pre_len == 0 {
             return Poll::Ready(Ok(()));
         }
-        self.checker.input(&buf.filled()[pre_len..]);
+        self.verifier.input(&buf.filled()[pre_len..]);
         Poll::Ready(Ok(()))
     }
 }
 
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+impl AsyncSeek for AsyncReader {
+    #[cfg(feature = "async-std")]
+    fn poll_seek(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        pos: SeekFrom,
+    ) -> Poll<io::Result<u64>> {
+        let pos = match &mut self.fd {
+            AsyncSource::File(fd) => futures::ready!(Pin::new(fd).poll_seek(cx, pos))?,
+            AsyncSource::Memory(data) => Seek::seek(data, pos)?,
+            AsyncSource::Decoded(_) => return Poll::Ready(Err(unseekable())),
+        };
+        self.verifier.moved_to(pos);
+        Poll::Ready(Ok(pos))
+    }
+
+    #[cfg(feature = "tokio")]
+    fn start_seek(mut self: Pin<&mut Self>, pos: SeekFrom) -> io::Result<()> {
+        match &mut self.fd {
+            AsyncSource::File(fd) => Pin::new(fd).start_seek(pos)?,
+            AsyncSource::Memory(data) => {
+                Seek::seek(data, pos)?;
+            }
+            AsyncSource::Decoded(_) => return Err(unseekable()),
+        }
+        self.seeking = true;
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let pos = match &mut self.fd {
+            AsyncSource::File(fd) => futures::ready!(Pin::new(fd).poll_complete(cx))?,
+            AsyncSource::Memory(data) => data.position(),
+            // Seeking fails, so this only ever gets asked where we are.
+            AsyncSource::Decoded(piped) => piped.position,
+        };
+        // This also gets polled before seeking starts, when there's no new
+        // position to take note of.
+        if std::mem::take(&mut self.seeking) {
+            self.verifier.moved_to(pos);
+        }
+        Poll::Ready(Ok(pos))
+    }
+}
+
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 impl AsyncReader {
+    /// Reads data that's already in memory, like inline or packed data.
+    pub fn in_memory(data: Vec<u8>, sri: Integrity) -> Self {
+        AsyncReader {
+            fd: AsyncSource::Memory(io::Cursor::new(data)),
+            verifier: Verifier::new(sri),
+            #[cfg(feature = "tokio")]
+            seeking: false,
+        }
+    }
+
     pub fn check(self) -> Result<Algorithm> {
-        Ok(self.checker.result()?)
+        self.verifier.check()
+    }
+}
+
+/// Where the content for `sri` is stored: in a file of its own, or packed
+/// together with other small content.
+enum Stored {
+    File(PathBuf, Encoding),
+    Packed(Vec<u8>),
+}
+
+/// Finds where the content for `sri` is stored, falling back to the plain
+/// location if it can't be found so callers report a sensible missing path.
+fn locate(cache: &Path, sri: &Integrity) -> Result<Stored> {
+    if let Some((cpath, encoding)) = path::find_content(cache, sri) {
+        return Ok(Stored::File(cpath, encoding));
+    }
+    if let Some(data) = pack::read(cache, sri)? {
+        return Ok(Stored::Packed(data));
     }
+    Ok(Stored::File(
+        path::content_path(cache, sri),
+        Encoding::default(),
+    ))
 }
 
-pub fn open(cache: &Path, sri: Integrity) -> Result<Reader> {
-    let cpath = path::content_path(cache, &sri);
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+async fn locate_async(cache: &Path, sri: &Integrity) -> Result<Stored> {
+    for (cpath, encoding) in path::stored_paths(cache, sri) {
+        if crate::async_lib::metadata(&cpath).await.is_ok() {
+            return Ok(Stored::File(cpath, encoding));
+        }
+    }
+    if let Some(data) = pack::read_async(cache, sri).await? {
+        return Ok(Stored::Packed(data));
+    }
+    Ok(Stored::File(
+        path::content_path(cache, sri),
+        Encoding::default(),
+    ))
+}
+
+/// The bytes of in-memory `data` between `start` and `end`, if there are any.
+pub fn slice_range(mut data: Vec<u8>, start: u64, end: Option<u64>) -> Vec<u8> {
+    let len = data.len() as u64;
+    let end = end.unwrap_or(len).min(len);
+    let start = start.min(end);
+    data.truncate(end as usize);
+    data.drain(..start as usize);
+    data
+}
+
+/// Content that can't be linked to directly, since the bytes on disk aren't
+/// the data the caller asked for.
+fn unlinkable(cpath: &Path) -> Result<()> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "content is stored compressed, encrypted or chunked",
+    ))
+    .with_context(|| format!("Failed to link to cache contents at {}", cpath.display()))
+}
+
+/// Wraps a stored content file so reading from it yields the original data.
+pub fn decoder(
+    cache: &Path,
+    fd: File,
+    encoding: Encoding,
+    key: Option<&EncryptionKey>,
+) -> io::Result<Box<dyn Read + Send>> {
+    if encoding.chunked {
+        return Ok(Box::new(chunk::ChunkReader::new(cache, fd)?));
+    }
+    let fd: Box<dyn Read + Send> = if encoding.encrypted {
+        encrypt::decrypt(fd, key)?
+    } else {
+        Box::new(fd)
+    };
+    compress::decoder(fd, encoding.compression)
+}
+
+pub fn open(cache: &Path, sri: Integrity, key: Option<&EncryptionKey>) -> Result<Reader> {
+    let (cpath, encoding) = match locate(cache, &sri)? {
+        Stored::File(cpath, encoding) => (cpath, encoding),
+        Stored::Packed(data) => return Ok(Reader::in_memory(data, sri)),
+    };
+    let fd = File::open(&cpath)
+        .and_then(|fd| {
+            if encoding.is_plain() {
+                Ok(Source::File(fd))
+            } else {
+                decoder(cache, fd, encoding, key).map(Source::Decoded)
+            }
+        })
+        .with_context(|| format!("Failed to open reader to {}", cpath.display()))?;
     Ok(Reader {
-        fd: File::open(cpath).with_context(|| {
-            format!(
-                "Failed to open reader to {}",
-                path::content_path(cache, &sri).display()
-            )
-        })?,
-        checker: IntegrityChecker::new(sri),
+        fd,
+        verifier: Verifier::new(sri),
     })
 }
 
 #[cfg(any(feature = "async-std", feature = "tokio"))]
-pub async fn open_async(cache: &Path, sri: Integrity) -> Result<AsyncReader> {
-    let cpath = path::content_path(cache, &sri);
+pub async fn open_async(
+    cache: &Path,
+    sri: Integrity,
+    key: Option<&EncryptionKey>,
+) -> Result<AsyncReader> {
+    let (cpath, encoding) = match locate_async(cache, &sri).await? {
+        Stored::File(cpath, encoding) => (cpath, encoding),
+        Stored::Packed(data) => return Ok(AsyncReader::in_memory(data, sri)),
+    };
+    let fd = if !encoding.is_plain() {
+        let cache = cache.to_path_buf();
+        let key = key.cloned();
+        let fd = crate::async_lib::unwrap_joinhandle_value(
+            crate::async_lib::spawn_blocking(move || {
+                File::open(&cpath)
+                    .and_then(|fd| decoder(&cache, fd, encoding, key.as_ref()))
+                    .with_context(|| format!("Failed to open reader to {}", cpath.display()))
+            })
+            .await,
+        )?;
+        AsyncSource::Decoded(Piped::spawn(fd))
+    } else {
+        AsyncSource::File(
+            crate::async_lib::File::open(&cpath)
+                .await
+                .with_context(|| format!("Failed to open reader to {}", cpath.display()))?,
+        )
+    };
     Ok(AsyncReader {
-        fd: crate::async_lib::File::open(cpath).await.with_context(|| {
+        fd,
+        verifier: Verifier::new(sri),
+        #[cfg(feature = "tokio")]
+        seeking: false,
+    })
+}
+
+fn read_unchecked(cache: &Path, sri: &Integrity, key: Option<&EncryptionKey>) -> Result<Vec<u8>> {
+    let (cpath, encoding) = match locate(cache, sri)? {
+        Stored::File(cpath, encoding) => (cpath, encoding),
+        Stored::Packed(data) => return Ok(data),
+    };
+    let mut ret = Vec::new();
+    File::open(&cpath)
+        .and_then(|fd| decoder(cache, fd, encoding, key))
+        .and_then(|mut fd| fd.read_to_end(&mut ret))
+        .with_context(|| format!("Failed to read contents for file at {}", cpath.display()))?;
+    Ok(ret)
+}
+
+/// Reads the bytes from `start` up to `end` (or the end of the data) without
+/// checking them against the content's integrity, which would mean reading
+/// all of it. Chunked content still has every chunk in the range checked.
+pub fn read_range(
+    cache: &Path,
+    sri: &Integrity,
+    start: u64,
+    end: Option<u64>,
+    key: Option<&EncryptionKey>,
+) -> Result<Vec<u8>> {
+    let (cpath, encoding) = match locate(cache, sri)? {
+        Stored::File(cpath, encoding) => (cpath, encoding),
+        Stored::Packed(data) => return Ok(slice_range(data, start, end)),
+    };
+    let mut ret = Vec::new();
+    File::open(&cpath)
+        .and_then(|mut fd| {
+            if encoding.chunked {
+                ret = chunk::read_range(cache, fd, start, end)?;
+                return Ok(ret.len());
+            }
+            let mut fd = if encoding.is_plain() {
+                fd.seek(SeekFrom::Start(start))?;
+                Box::new(fd)
+            } else {
+                let mut fd = decoder(cache, fd, encoding, key)?;
+                io::copy(&mut (&mut fd).take(start), &mut io::sink())?;
+                fd
+            };
+            match end {
+                Some(end) => fd.take(end.saturating_sub(start)).read_to_end(&mut ret),
+                None => fd.read_to_end(&mut ret),
+            }
+        })
+        .with_context(|| {
             format!(
-                "Failed to open reader to {}",
-                path::content_path(cache, &sri).display()
+                "Failed to read range of contents for file at {}",
+                cpath.display()
             )
-        })?,
-        checker: IntegrityChecker::new(sri),
-    })
+        })?;
+    Ok(ret)
 }
 
-pub fn read(cache: &Path, sri: &Integrity) -> Result<Vec<u8>> {
-    let cpath = path::content_path(cache, sri);
-    let ret = fs::read(cpath).with_context(|| {
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub async fn read_range_async(
+    cache: &Path,
+    sri: &Integrity,
+    start: u64,
+    end: Option<u64>,
+    key: Option<&EncryptionKey>,
+) -> Result<Vec<u8>> {
+    let cpath = match locate_async(cache, sri).await? {
+        Stored::File(cpath, encoding) if encoding.is_plain() => cpath,
+        Stored::Packed(data) => return Ok(slice_range(data, start, end)),
+        _ => {
+            let cache = cache.to_path_buf();
+            let sri = sri.clone();
+            let key = key.cloned();
+            return crate::async_lib::unwrap_joinhandle_value(
+                crate::async_lib::spawn_blocking(move || {
+                    read_range(&cache, &sri, start, end, key.as_ref())
+                })
+                .await,
+            );
+        }
+    };
+    let mut ret = Vec::new();
+    async {
+        let mut fd = crate::async_lib::File::open(&cpath).await?;
+        AsyncSeekExt::seek(&mut fd, SeekFrom::Start(start)).await?;
+        match end {
+            Some(end) => {
+                AsyncReadExt::read_to_end(
+                    &mut AsyncReadExt::take(fd, end.saturating_sub(start)),
+                    &mut ret,
+                )
+                .await
+            }
+            None => AsyncReadExt::read_to_end(&mut fd, &mut ret).await,
+        }
+    }
+    .await
+    .with_context(|| {
         format!(
-            "Failed to read contents for file at {}",
-            path::content_path(cache, sri).display()
+            "Failed to read range of contents for file at {}",
+            cpath.display()
         )
     })?;
+    Ok(ret)
+}
+
+pub fn read(cache: &Path, sri: &Integrity, key: Option<&EncryptionKey>) -> Result<Vec<u8>> {
+    let ret = read_unchecked(cache, sri, key)?;
     sri.check(&ret)?;
     Ok(ret)
 }
 
 #[cfg(any(feature = "async-std", feature = "tokio"))]
-pub async fn read_async<'a>(cache: &'a Path, sri: &'a Integrity) -> Result<Vec<u8>> {
-    let cpath = path::content_path(cache, sri);
-    let ret = crate::async_lib::read(&cpath).await.with_context(|| {
-        format!(
-            "Failed to read contents for file at {}",
-            path::content_path(cache, sri).display()
-        )
-    })?;
+pub async fn read_async<'a>(
+    cache: &'a Path,
+    sri: &'a Integrity,
+    key: Option<&'a EncryptionKey>,
+) -> Result<Vec<u8>> {
+    let ret = match locate_async(cache, sri).await? {
+        Stored::File(cpath, encoding) if encoding.is_plain() => crate::async_lib::read(&cpath)
+            .await
+            .with_context(|| format!("Failed to read contents for file at {}", cpath.display()))?,
+        Stored::Packed(data) => data,
+        _ => {
+            let cache = cache.to_path_buf();
+            let sri = sri.clone();
+            let key = key.cloned();
+            crate::async_lib::unwrap_joinhandle_value(
+                crate::async_lib::spawn_blocking(move || {
+                    read_unchecked(&cache, &sri, key.as_ref())
+                })
+                .await,
+            )?
+        }
+    };
     sri.check(&ret)?;
     Ok(ret)
 }
 
+/// Content returned by [`read_mmap`]: a view straight into the content file
+/// when it's stored as-is, or the decoded bytes otherwise.
+#[cfg(feature = "mmap")]
+pub enum Mapped {
+    Map(memmap2::Mmap),
+    Owned(Vec<u8>),
+}
+
+#[cfg(feature = "mmap")]
+impl std::ops::Deref for Mapped {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Mapped::Map(map) => map,
+            Mapped::Owned(data) => data,
+        }
+    }
+}
+
+#[cfg(feature = "mmap")]
+pub fn read_mmap(cache: &Path, sri: &Integrity, key: Option<&EncryptionKey>) -> Result<Mapped> {
+    let (cpath, encoding) = match locate(cache, sri)? {
+        Stored::File(cpath, encoding) => (cpath, encoding),
+        Stored::Packed(data) => {
+            sri.check(&data)?;
+            return Ok(Mapped::Owned(data));
+        }
+    };
+    let ret = if !encoding.is_plain() {
+        Mapped::Owned(read_unchecked(cache, sri, key)?)
+    } else {
+        let fd = File::open(&cpath)
+            .with_context(|| format!("Failed to open reader to {}", cpath.display()))?;
+        let len = fd
+            .metadata()
+            .with_context(|| format!("Failed to read metadata for {}", cpath.display()))?
+            .len();
+        if len == 0 {
+            // Empty files can't be mapped.
+            Mapped::Owned(Vec::new())
+        } else {
+            // Safety: content files are never modified in place once they've
+            // been written. They're only ever removed, which leaves existing
+            // maps intact.
+            Mapped::Map(
+                unsafe { memmap2::Mmap::map(&fd) }
+                    .with_context(|| format!("Failed to map contents of {}", cpath.display()))?,
+            )
+        }
+    };
+    sri.check(&*ret)?;
+    Ok(ret)
+}
+
 pub fn reflink_unchecked(cache: &Path, sri: &Integrity, to: &Path) -> Result<()> {
-    let cpath = path::content_path(cache, sri);
+    let cpath = match locate(cache, sri)? {
+        Stored::File(cpath, encoding) if encoding.is_plain() => cpath,
+        Stored::File(cpath, _) => return unlinkable(&cpath),
+        Stored::Packed(_) => return unlinkable(&pack::pack_dir(cache)),
+    };
     reflink_copy::reflink(cpath, to).with_context(|| {
         format!(
             "Failed to reflink cache contents from {} to {}",
@@ -143,7 +666,7 @@ pub fn reflink_unchecked(cache: &Path, sri: &Integrity, to: &Path) -> Result<()>
 }
 
 pub fn reflink(cache: &Path, sri: &Integrity, to: &Path) -> Result<()> {
-    let mut reader = open(cache, sri.clone())?;
+    let mut reader = open(cache, sri.clone(), None)?;
     let mut buf: [u8; 1024] = [0; 1024];
     loop {
         let read = reader.read(&mut buf).with_context(|| {
@@ -162,7 +685,7 @@ pub fn reflink(cache: &Path, sri: &Integrity, to: &Path) -> Result<()> {
 
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 pub async fn reflink_async(cache: &Path, sri: &Integrity, to: &Path) -> Result<()> {
-    let mut reader = open_async(cache, sri.clone()).await?;
+    let mut reader = open_async(cache, sri.clone(), None).await?;
     let mut buf = [0u8; 1024 * 8];
     loop {
         let read = AsyncReadExt::read(&mut reader, &mut buf)
@@ -181,8 +704,32 @@ pub async fn reflink_async(cache: &Path, sri: &Integrity, to: &Path) -> Result<(
     reflink_unchecked(cache, sri, to)
 }
 
-pub fn copy_unchecked(cache: &Path, sri: &Integrity, to: &Path) -> Result<u64> {
-    let cpath = path::content_path(cache, sri);
+pub fn copy_unchecked(
+    cache: &Path,
+    sri: &Integrity,
+    to: &Path,
+    key: Option<&EncryptionKey>,
+) -> Result<u64> {
+    let (cpath, encoding) = match locate(cache, sri)? {
+        Stored::File(cpath, encoding) => (cpath, encoding),
+        Stored::Packed(data) => {
+            return std::fs::write(to, &data)
+                .map(|_| data.len() as u64)
+                .with_context(|| format!("Failed to copy cache contents to {}", to.display()))
+        }
+    };
+    if !encoding.is_plain() {
+        return File::open(&cpath)
+            .and_then(|fd| decoder(cache, fd, encoding, key))
+            .and_then(|mut fd| io::copy(&mut fd, &mut File::create(to)?))
+            .with_context(|| {
+                format!(
+                    "Failed to copy cache contents from {} to {}",
+                    cpath.display(),
+                    to.display()
+                )
+            });
+    }
     std::fs::copy(cpath, to).with_context(|| {
         format!(
             "Failed to copy cache contents from {} to {}",
@@ -192,8 +739,8 @@ pub fn copy_unchecked(cache: &Path, sri: &Integrity, to: &Path) -> Result<u64> {
     })
 }
 
-pub fn copy(cache: &Path, sri: &Integrity, to: &Path) -> Result<u64> {
-    let mut reader = open(cache, sri.clone())?;
+pub fn copy(cache: &Path, sri: &Integrity, to: &Path, key: Option<&EncryptionKey>) -> Result<u64> {
+    let mut reader = open(cache, sri.clone(), key)?;
     let mut buf: [u8; 1024] = [0; 1024];
     let mut size = 0;
     loop {
@@ -209,7 +756,7 @@ pub fn copy(cache: &Path, sri: &Integrity, to: &Path) -> Result<u64> {
         }
     }
     reader.check()?;
-    copy_unchecked(cache, sri, to)?;
+    copy_unchecked(cache, sri, to, key)?;
 
     Ok(size as u64)
 }
@@ -219,8 +766,23 @@ pub async fn copy_unchecked_async<'a>(
     cache: &'a Path,
     sri: &'a Integrity,
     to: &'a Path,
+    key: Option<&'a EncryptionKey>,
 ) -> Result<u64> {
-    let cpath = path::content_path(cache, sri);
+    let cpath = match locate_async(cache, sri).await? {
+        Stored::File(cpath, encoding) if encoding.is_plain() => cpath,
+        _ => {
+            let cache = cache.to_path_buf();
+            let sri = sri.clone();
+            let to = to.to_path_buf();
+            let key = key.cloned();
+            return crate::async_lib::unwrap_joinhandle_value(
+                crate::async_lib::spawn_blocking(move || {
+                    copy_unchecked(&cache, &sri, &to, key.as_ref())
+                })
+                .await,
+            );
+        }
+    };
     crate::async_lib::copy(&cpath, to).await.with_context(|| {
         format!(
             "Failed to copy cache contents from {} to {}",
@@ -231,8 +793,13 @@ pub async fn copy_unchecked_async<'a>(
 }
 
 #[cfg(any(feature = "async-std", feature = "tokio"))]
-pub async fn copy_async<'a>(cache: &'a Path, sri: &'a Integrity, to: &'a Path) -> Result<u64> {
-    let mut reader = open_async(cache, sri.clone()).await?;
+pub async fn copy_async<'a>(
+    cache: &'a Path,
+    sri: &'a Integrity,
+    to: &'a Path,
+    key: Option<&'a EncryptionKey>,
+) -> Result<u64> {
+    let mut reader = open_async(cache, sri.clone(), key).await?;
     let mut buf: [u8; 1024] = [0; 1024];
     let mut size = 0;
     loop {
@@ -250,12 +817,16 @@ pub async fn copy_async<'a>(cache: &'a Path, sri: &'a Integrity, to: &'a Path) -
         }
     }
     reader.check()?;
-    copy_unchecked_async(cache, sri, to).await?;
+    copy_unchecked_async(cache, sri, to, key).await?;
     Ok(size as u64)
 }
 
 pub fn hard_link_unchecked(cache: &Path, sri: &Integrity, to: &Path) -> Result<()> {
-    let cpath = path::content_path(cache, sri);
+    let cpath = match locate(cache, sri)? {
+        Stored::File(cpath, encoding) if encoding.is_plain() => cpath,
+        Stored::File(cpath, _) => return unlinkable(&cpath),
+        Stored::Packed(_) => return unlinkable(&pack::pack_dir(cache)),
+    };
     std::fs::hard_link(cpath, to).with_context(|| {
         format!(
             "Failed to link cache contents from {} to {}",
@@ -268,7 +839,7 @@ pub fn hard_link_unchecked(cache: &Path, sri: &Integrity, to: &Path) -> Result<(
 
 pub fn hard_link(cache: &Path, sri: &Integrity, to: &Path) -> Result<()> {
     hard_link_unchecked(cache, sri, to)?;
-    let mut reader = open(cache, sri.clone())?;
+    let mut reader = open(cache, sri.clone(), None)?;
     let mut buf = [0u8; 1024 * 8];
     loop {
         let read = reader.read(&mut buf).with_context(|| {
@@ -287,7 +858,7 @@ pub fn hard_link(cache: &Path, sri: &Integrity, to: &Path) -> Result<()> {
 
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 pub async fn hard_link_async(cache: &Path, sri: &Integrity, to: &Path) -> Result<()> {
-    let mut reader = open_async(cache, sri.clone()).await?;
+    let mut reader = open_async(cache, sri.clone(), None).await?;
     let mut buf = [0u8; 1024 * 8];
     loop {
         let read = AsyncReadExt::read(&mut reader, &mut buf)
@@ -308,21 +879,21 @@ pub async fn hard_link_async(cache: &Path, sri: &Integrity, to: &Path) -> Result
 }
 
 pub fn has_content(cache: &Path, sri: &Integrity) -> Option<Integrity> {
-    if path::content_path(cache, sri).exists() {
-        Some(sri.clone())
-    } else {
-        None
+    if path::find_content(cache, sri).is_some() || matches!(pack::find(cache, sri), Ok(Some(_))) {
+        return Some(sri.clone());
     }
+    None
 }
 
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 pub async fn has_content_async(cache: &Path, sri: &Integrity) -> Option<Integrity> {
-    if crate::async_lib::metadata(path::content_path(cache, sri))
-        .await
-        .is_ok()
-    {
-        Some(sri.clone())
-    } else {
-        None
+    for (cpath, _) in path::stored_paths(cache, sri) {
+        if crate::async_lib::metadata(cpath).await.is_ok() {
+            return Some(sri.clone());
+        }
+    }
+    if matches!(pack::find_async(cache, sri).await, Ok(Some(_))) {
+        return Some(sri.clone());
     }
+    None
 }
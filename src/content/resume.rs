@@ -0,0 +1,283 @@
+//! State kept alongside the temp files of resumable writes, so that a writer
+//! picking one up doesn't have to hash everything that's already in it.
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+use digest::generic_array::GenericArray;
+use ssri::{Algorithm, Integrity, IntegrityOpts};
+
+/// How much data gets hashed between saves of a hasher's state.
+const SAVE_EVERY: u64 = 1024 * 1024;
+
+const BLOCK_SIZE: usize = 64;
+
+/// Saved states hold the number of bytes hashed, then the eight state words.
+const STATE_SIZE: usize = 8 + 8 * 4;
+
+const SHA256_INIT: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// Hashes what's written to the cache.
+pub enum Hasher {
+    Plain(IntegrityOpts),
+    Resumable(Box<Sha256State>),
+}
+
+impl Hasher {
+    /// Returns a hasher for the resumable write whose temp file is at
+    /// `tmp`, along with how much of it is already accounted for. SHA-256
+    /// hashers pick up from their last saved state, if it still matches the
+    /// `len` bytes in the file. Anything else has to be hashed again from
+    /// the start.
+    pub fn resume(algorithm: Algorithm, tmp: &Path, file: &File, len: u64) -> (Hasher, u64) {
+        if algorithm != Algorithm::Sha256 {
+            return (Hasher::Plain(IntegrityOpts::new().algorithm(algorithm)), 0);
+        }
+        let Ok(file) = file.try_clone() else {
+            return (Hasher::Plain(IntegrityOpts::new().algorithm(algorithm)), 0);
+        };
+        let state = Sha256State::load(state_path(tmp), file, len);
+        let hashed = state.hashed;
+        (Hasher::Resumable(Box::new(state)), hashed)
+    }
+
+    pub fn input(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Plain(builder) => builder.input(data),
+            Hasher::Resumable(state) => state.input(data),
+        }
+    }
+
+    pub fn result(self) -> Integrity {
+        match self {
+            Hasher::Plain(builder) => builder.result(),
+            Hasher::Resumable(state) => state.result(),
+        }
+    }
+}
+
+/// A SHA-256 hasher that saves its state next to a resumable write's temp
+/// file every so often.
+pub struct Sha256State {
+    state: [u32; 8],
+    /// Bytes already compressed into `state`, in whole blocks.
+    hashed: u64,
+    /// The beginning of the next block.
+    pending: Vec<u8>,
+    /// How much had been hashed the last time the state was saved.
+    saved: u64,
+    /// The temp file being hashed, which is synced before every save so a
+    /// saved state never covers data that didn't make it to disk.
+    file: File,
+    path: PathBuf,
+}
+
+impl Sha256State {
+    /// Loads the state saved at `path`, or starts over if it's missing, or
+    /// covers more than the `len` bytes the temp file holds.
+    fn load(path: PathBuf, file: File, len: u64) -> Sha256State {
+        let mut state = Sha256State {
+            state: SHA256_INIT,
+            hashed: 0,
+            pending: Vec::with_capacity(BLOCK_SIZE),
+            saved: 0,
+            file,
+            path,
+        };
+        if len == 0 {
+            // Whatever's saved belonged to an earlier write under this
+            // token, so it has to go before anything is written again.
+            let _ = fs::remove_file(&state.path);
+            return state;
+        }
+        let Ok(saved) = fs::read(&state.path) else {
+            return state;
+        };
+        if saved.len() != STATE_SIZE {
+            return state;
+        }
+        let hashed = u64::from_le_bytes(saved[..8].try_into().unwrap());
+        if hashed > len || hashed % BLOCK_SIZE as u64 != 0 {
+            return state;
+        }
+        for (word, bytes) in state.state.iter_mut().zip(saved[8..].chunks_exact(4)) {
+            *word = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+        state.hashed = hashed;
+        state.saved = hashed;
+        state
+    }
+
+    fn input(&mut self, mut data: &[u8]) {
+        if !self.pending.is_empty() {
+            let take = (BLOCK_SIZE - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.pending.len() < BLOCK_SIZE {
+                return;
+            }
+            let block = std::mem::take(&mut self.pending);
+            self.compress(&block);
+        }
+        let whole = data.len() - data.len() % BLOCK_SIZE;
+        self.compress(&data[..whole]);
+        self.pending.extend_from_slice(&data[whole..]);
+        if self.hashed - self.saved >= SAVE_EVERY {
+            self.save();
+        }
+    }
+
+    fn compress(&mut self, blocks: &[u8]) {
+        for block in blocks.chunks_exact(BLOCK_SIZE) {
+            sha2::compress256(
+                &mut self.state,
+                std::slice::from_ref(GenericArray::from_slice(block)),
+            );
+        }
+        self.hashed += blocks.len() as u64;
+    }
+
+    /// Saves the state for the next writer to use. Failing to is fine, since
+    /// it'll just have more to hash.
+    fn save(&mut self) {
+        if self.file.sync_data().is_err() {
+            return;
+        }
+        let mut saved = Vec::with_capacity(STATE_SIZE);
+        saved.extend_from_slice(&self.hashed.to_le_bytes());
+        for word in self.state {
+            saved.extend_from_slice(&word.to_le_bytes());
+        }
+        let new = self.path.with_extension("new");
+        if fs::write(&new, saved)
+            .and_then(|_| fs::rename(&new, &self.path))
+            .is_ok()
+        {
+            self.saved = self.hashed;
+        }
+    }
+
+    fn result(mut self) -> Integrity {
+        let len = self.hashed + self.pending.len() as u64;
+        let mut tail = std::mem::take(&mut self.pending);
+        tail.push(0x80);
+        while tail.len() % BLOCK_SIZE != BLOCK_SIZE - 8 {
+            tail.push(0);
+        }
+        tail.extend_from_slice(&(len * 8).to_be_bytes());
+        self.compress(&tail);
+        let digest = self
+            .state
+            .iter()
+            .flat_map(|word| word.to_be_bytes())
+            .collect::<Vec<_>>();
+        Integrity::from_hex(hex::encode(digest), Algorithm::Sha256)
+            .expect("SHA-256 digests are valid hex")
+    }
+}
+
+/// Where the hasher state for the resumable temp file at `tmp` is saved.
+pub fn state_path(tmp: &Path) -> PathBuf {
+    tmp.with_extension("sha256")
+}
+
+/// Removes the state saved for the resumable temp file at `tmp`, once it's
+/// been committed.
+pub fn forget(tmp: &Path) {
+    let _ = fs::remove_file(state_path(tmp));
+}
+
+/// Marks a resumable temp file as in use by a writer in this process until
+/// it's dropped. File locks, where there are any, keep out writers in other
+/// processes.
+pub struct Claim(PathBuf);
+
+impl Claim {
+    /// Claims the temp file at `tmp`, unless another writer already has.
+    pub fn new(tmp: &Path) -> Option<Claim> {
+        claimed()
+            .insert(tmp.to_path_buf())
+            .then(|| Claim(tmp.to_path_buf()))
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        claimed().remove(&self.0);
+    }
+}
+
+fn claimed() -> MutexGuard<'static, HashSet<PathBuf>> {
+    static CLAIMED: OnceLock<Mutex<HashSet<PathBuf>>> = OnceLock::new();
+    CLAIMED
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn hasher(dir: &Path, data: &[u8]) -> (PathBuf, File) {
+        let tmp = dir.join("download");
+        let mut file = File::create(&tmp).unwrap();
+        file.write_all(data).unwrap();
+        (tmp, file)
+    }
+
+    #[test]
+    fn matches_sha256() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tmp, file) = hasher(dir.path(), b"");
+        for len in [0, 1, 55, 56, 63, 64, 65, 200] {
+            let data = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+            let (mut hasher, hashed) = Hasher::resume(Algorithm::Sha256, &tmp, &file, 0);
+            assert_eq!(hashed, 0);
+            // Uneven pieces, to cross block boundaries in odd places.
+            for piece in data.chunks(7) {
+                hasher.input(piece);
+            }
+            assert_eq!(hasher.result(), Integrity::from(&data));
+        }
+    }
+
+    #[test]
+    fn picks_up_saved_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = (0..SAVE_EVERY + 100)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let (tmp, file) = hasher(dir.path(), &data);
+        let (mut hasher, _) = Hasher::resume(Algorithm::Sha256, &tmp, &file, 0);
+        hasher.input(&data);
+        drop(hasher);
+        assert!(state_path(&tmp).exists());
+
+        let len = data.len() as u64;
+        let (mut hasher, hashed) = Hasher::resume(Algorithm::Sha256, &tmp, &file, len);
+        assert!(hashed >= SAVE_EVERY && hashed <= len);
+        hasher.input(&data[hashed as usize..]);
+        assert_eq!(hasher.result(), Integrity::from(&data));
+
+        // State that covers more than the file holds is useless.
+        let (_, hashed) = Hasher::resume(Algorithm::Sha256, &tmp, &file, 100);
+        assert_eq!(hashed, 0);
+        // And so is any state for a file that's starting over.
+        Hasher::resume(Algorithm::Sha256, &tmp, &file, 0);
+        assert!(!state_path(&tmp).exists());
+    }
+
+    #[test]
+    fn claims_are_exclusive() {
+        let path = Path::new("claims-are-exclusive");
+        let claim = Claim::new(path).unwrap();
+        assert!(Claim::new(path).is_none());
+        drop(claim);
+        assert!(Claim::new(path).is_some());
+    }
+}
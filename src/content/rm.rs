@@ -1,30 +1,68 @@
 use std::fs;
+use std::io::ErrorKind;
 use std::path::Path;
 
 use ssri::Integrity;
 
-use crate::content::path;
+use crate::content::{pack, path};
 use crate::errors::{IoErrorExt, Result};
 
 pub fn rm(cache: &Path, sri: &Integrity) -> Result<()> {
-    fs::remove_file(path::content_path(cache, sri)).with_context(|| {
-        format!(
-            "Failed to remove cache file {}",
-            path::content_path(cache, sri).display()
-        )
-    })?;
+    crate::memo::forget_content(cache, sri);
+    let mut removed = false;
+    // Content may have been written more than once with different
+    // compression settings, so clear out every copy.
+    for (cpath, _) in path::stored_paths(cache, sri) {
+        match fs::remove_file(&cpath) {
+            Ok(()) => removed = true,
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to remove cache file {}", cpath.display()))
+            }
+        }
+    }
+    if !removed && !pack::remove(cache, sri)? {
+        return Err(std::io::Error::from(ErrorKind::NotFound)).with_context(|| {
+            format!(
+                "Failed to remove cache file {}",
+                path::content_path(cache, sri).display()
+            )
+        });
+    }
     Ok(())
 }
 
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 pub async fn rm_async(cache: &Path, sri: &Integrity) -> Result<()> {
-    crate::async_lib::remove_file(path::content_path(cache, sri))
-        .await
-        .with_context(|| {
+    crate::memo::forget_content(cache, sri);
+    let mut removed = false;
+    for (cpath, _) in path::stored_paths(cache, sri) {
+        match crate::async_lib::remove_file(&cpath).await {
+            Ok(()) => removed = true,
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to remove cache file {}", cpath.display()))
+            }
+        }
+    }
+    if !removed && !pack_remove_async(cache, sri).await? {
+        return Err(std::io::Error::from(ErrorKind::NotFound)).with_context(|| {
             format!(
                 "Failed to remove cache file {}",
                 path::content_path(cache, sri).display()
             )
-        })?;
+        });
+    }
     Ok(())
 }
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+async fn pack_remove_async(cache: &Path, sri: &Integrity) -> Result<bool> {
+    let cache = cache.to_path_buf();
+    let sri = sri.clone();
+    crate::async_lib::unwrap_joinhandle_value(
+        crate::async_lib::spawn_blocking(move || pack::remove(&cache, &sri)).await,
+    )
+}
@@ -1,4 +1,4 @@
-use std::fs::DirBuilder;
+use std::fs::{DirBuilder, File, OpenOptions};
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 #[cfg(any(feature = "async-std", feature = "tokio"))]
@@ -17,8 +17,15 @@ use tempfile::NamedTempFile;
 
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 use crate::async_lib::{AsyncWrite, JoinHandle};
-use crate::content::path;
+use crate::content::chunk::ChunkWriter;
+use crate::content::compress::Encoder;
+#[cfg(feature = "encryption")]
+use crate::content::encrypt::EncryptWriter;
+use crate::content::path::{self, Encoding};
+use crate::content::resume::{self, Claim, Hasher};
+use crate::durability::{self, Durability};
 use crate::errors::{IoErrorExt, Result};
+use crate::put::WriteOpts;
 
 #[cfg(feature = "mmap")]
 pub const MAX_MMAP_SIZE: usize = 1024 * 1024;
@@ -33,6 +40,10 @@ impl MmapMut {
         panic!()
     }
 
+    fn flush(&self) -> std::io::Result<()> {
+        panic!()
+    }
+
     fn copy_from_slice(&self, _: &[u8]) {
         panic!()
     }
@@ -40,16 +51,18 @@ impl MmapMut {
 
 pub struct Writer {
     cache: PathBuf,
-    builder: IntegrityOpts,
+    builder: Hasher,
     mmap: Option<MmapMut>,
-    tmpfile: NamedTempFile,
+    tmpfile: Encoder<Sink>,
+    encoding: Encoding,
+    durability: Durability,
+    resumed: usize,
 }
 
 impl Writer {
-    pub fn new(cache: &Path, algo: Algorithm, size: Option<usize>) -> Result<Writer> {
+    pub fn new(cache: &Path, opts: &WriteOpts, size: Option<usize>) -> Result<Writer> {
         let cache_path = cache.to_path_buf();
-        let mut tmp_path = cache_path.clone();
-        tmp_path.push("tmp");
+        let tmp_path = tmp_dir(cache, opts);
         DirBuilder::new()
             .recursive(true)
             .create(&tmp_path)
@@ -59,6 +72,19 @@ impl Writer {
                     tmp_path.display()
                 )
             })?;
+        let encoding = make_encoding(opts)?;
+        if let Some(token) = &opts.resume {
+            let (sink, builder, resumed) = open_resumable(&tmp_path, token, algorithm(opts))?;
+            return Ok(Writer {
+                cache: cache_path,
+                builder,
+                tmpfile: Encoder::Plain(sink),
+                encoding,
+                durability: opts.durability,
+                mmap: None,
+                resumed,
+            });
+        }
         let tmp_path_clone = tmp_path.clone();
         let mut tmpfile = NamedTempFile::new_in(tmp_path).with_context(|| {
             format!(
@@ -66,23 +92,41 @@ impl Writer {
                 tmp_path_clone.display()
             )
         })?;
-        let mmap = make_mmap(&mut tmpfile, size)?;
+        crate::flock::lock(tmpfile.as_file())
+            .with_context(|| format!("Failed to lock temp file at {}", tmpfile.path().display()))?;
+        let mmap = make_mmap(&mut tmpfile, size, opts, encoding)?;
         Ok(Writer {
             cache: cache_path,
-            builder: IntegrityOpts::new().algorithm(algo),
-            tmpfile,
+            builder: Hasher::Plain(IntegrityOpts::new().algorithm(algorithm(opts))),
+            tmpfile: make_encoder(cache, tmpfile, opts)?,
+            encoding,
+            durability: opts.durability,
             mmap,
+            resumed: 0,
         })
     }
 
-    pub fn close(self) -> Result<Integrity> {
+    /// How many bytes earlier attempts at a resumable write had already
+    /// written when this one picked it up.
+    pub fn resumed(&self) -> usize {
+        self.resumed
+    }
+
+    /// Finishes writing the content and moves it into place, returning its
+    /// integrity, and where it was put if nothing was there yet.
+    pub fn close(self) -> Result<(Integrity, Option<PathBuf>)> {
         let sri = self.builder.result();
-        let cpath = path::content_path(&self.cache, &sri);
-        DirBuilder::new()
-            .recursive(true)
-            // Safe unwrap. cpath always has multiple segments
-            .create(cpath.parent().unwrap())
-            .with_context(|| {
+        let cpath = path::encoded_path(&self.cache, &sri, self.encoding);
+        let tmpfile = self
+            .tmpfile
+            .finish()
+            .and_then(Sink::finish)
+            .with_context(|| "Failed to finish encoding cache contents".to_string())?;
+        sync_tmpfile(&tmpfile, self.mmap.as_ref(), self.durability)
+            .with_context(|| "Failed to sync cache contents to disk".to_string())?;
+        // Safe unwrap. cpath always has multiple segments
+        durability::create_dir_all(cpath.parent().unwrap(), self.durability).with_context(
+            || {
                 format!(
                     "Failed to create destination directory for cache contents, at {}",
                     path::content_path(&self.cache, &sri)
@@ -90,8 +134,10 @@ impl Writer {
                         .unwrap()
                         .display()
                 )
-            })?;
-        let res = self.tmpfile.persist(&cpath);
+            },
+        )?;
+        let existed = cpath.exists();
+        let res = tmpfile.persist(&cpath);
         match res {
             Ok(_) => {}
             Err(e) => {
@@ -99,28 +145,33 @@ impl Writer {
                 // This is ok. We can deal. Let's just make sure the destination
                 // file actually exists, and we can move on.
                 if !cpath.exists() {
-                    return Err(e.error).with_context(|| {
+                    return Err(e).with_context(|| {
                         format!(
                             "Failed to persist cache contents while closing writer, at {}",
-                            path::content_path(&self.cache, &sri).display()
+                            cpath.display()
                         )
                     })?;
                 }
             }
         }
-        Ok(sri)
+        durability::step("content persisted")
+            .and_then(|_| durability::sync_parent(&cpath, self.durability, "content dir synced"))
+            .with_context(|| format!("Failed to sync cache contents at {}", cpath.display()))?;
+        Ok((sri, (!existed).then_some(cpath)))
     }
 }
 
 impl Write for Writer {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.builder.input(buf);
-        if let Some(mmap) = &mut self.mmap {
+        let written = if let Some(mmap) = &mut self.mmap {
             mmap.copy_from_slice(buf);
-            Ok(buf.len())
+            buf.len()
         } else {
-            self.tmpfile.write(buf)
-        }
+            // Encoders can take less than all of it.
+            self.tmpfile.write(buf)?
+        };
+        self.builder.input(&buf[..written]);
+        Ok(written)
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
@@ -129,7 +180,7 @@ impl Write for Writer {
 }
 
 #[cfg(any(feature = "async-std", feature = "tokio"))]
-pub struct AsyncWriter(Mutex<State>);
+pub struct AsyncWriter(Mutex<State>, usize);
 
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 enum State {
@@ -140,8 +191,10 @@ enum State {
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 struct Inner {
     cache: PathBuf,
-    builder: IntegrityOpts,
-    tmpfile: NamedTempFile,
+    builder: Hasher,
+    tmpfile: Encoder<Sink>,
+    encoding: Encoding,
+    durability: Durability,
     mmap: Option<MmapMut>,
     buf: Vec<u8>,
     last_op: Option<Operation>,
@@ -157,10 +210,9 @@ enum Operation {
 impl AsyncWriter {
     #[allow(clippy::new_ret_no_self)]
     #[allow(clippy::needless_lifetimes)]
-    pub async fn new(cache: &Path, algo: Algorithm, size: Option<usize>) -> Result<AsyncWriter> {
+    pub async fn new(cache: &Path, opts: &WriteOpts, size: Option<usize>) -> Result<AsyncWriter> {
         let cache_path = cache.to_path_buf();
-        let mut tmp_path = cache_path.clone();
-        tmp_path.push("tmp");
+        let tmp_path = tmp_dir(cache, opts);
         crate::async_lib::DirBuilder::new()
             .recursive(true)
             .create(&tmp_path)
@@ -171,19 +223,53 @@ impl AsyncWriter {
                     tmp_path.display()
                 )
             })?;
+        let encoding = make_encoding(opts)?;
+        if let Some(token) = opts.resume.clone() {
+            let algorithm = algorithm(opts);
+            let (sink, builder, resumed) = crate::async_lib::unwrap_joinhandle_value(
+                crate::async_lib::spawn_blocking(move || {
+                    open_resumable(&tmp_path, &token, algorithm)
+                })
+                .await,
+            )?;
+            let inner = Inner {
+                cache: cache_path,
+                builder,
+                mmap: None,
+                tmpfile: Encoder::Plain(sink),
+                encoding,
+                durability: opts.durability,
+                buf: vec![],
+                last_op: None,
+            };
+            return Ok(AsyncWriter(Mutex::new(State::Idle(Some(inner))), resumed));
+        }
         let mut tmpfile = crate::async_lib::create_named_tempfile(tmp_path).await?;
-        let mmap = make_mmap(&mut tmpfile, size)?;
-        Ok(AsyncWriter(Mutex::new(State::Idle(Some(Inner {
+        crate::flock::lock(tmpfile.as_file())
+            .with_context(|| format!("Failed to lock temp file at {}", tmpfile.path().display()))?;
+        let mmap = make_mmap(&mut tmpfile, size, opts, encoding)?;
+        let inner = Inner {
             cache: cache_path,
-            builder: IntegrityOpts::new().algorithm(algo),
+            builder: Hasher::Plain(IntegrityOpts::new().algorithm(algorithm(opts))),
             mmap,
-            tmpfile,
+            tmpfile: make_encoder(cache, tmpfile, opts)?,
+            encoding,
+            durability: opts.durability,
             buf: vec![],
             last_op: None,
-        })))))
+        };
+        Ok(AsyncWriter(Mutex::new(State::Idle(Some(inner))), 0))
+    }
+
+    /// How many bytes earlier attempts at a resumable write had already
+    /// written when this one picked it up.
+    pub fn resumed(&self) -> usize {
+        self.1
     }
 
-    pub async fn close(self) -> Result<Integrity> {
+    /// Finishes writing the content and moves it into place. See
+    /// [`Writer::close`].
+    pub async fn close(self) -> Result<(Integrity, Option<PathBuf>)> {
         // NOTE: How do I even get access to `inner` safely???
         // let inner = ???;
         // Blocking, but should be a very fast op.
@@ -197,46 +283,72 @@ impl AsyncWriter {
                         Some(inner) => {
                             let (s, r) = futures::channel::oneshot::channel();
                             let tmpfile = inner.tmpfile;
+                            let mmap = inner.mmap;
+                            let level = inner.durability;
                             let sri = inner.builder.result();
-                            let cpath = path::content_path(&inner.cache, &sri);
+                            let cpath = path::encoded_path(&inner.cache, &sri, inner.encoding);
 
                             // Start the operation asynchronously.
-                            *state = State::Busy(crate::async_lib::spawn_blocking(|| {
-                                let res = std::fs::DirBuilder::new()
-                                    .recursive(true)
-                                    // Safe unwrap. cpath always has multiple segments
-                                    .create(cpath.parent().unwrap())
-                                    .with_context(|| {
-                                        format!(
-                                            "building directory {} failed",
-                                            cpath.parent().unwrap().display()
-                                        )
-                                    });
-                                if res.is_err() {
-                                    let _ = s.send(res.map(|_| sri));
-                                } else {
-                                    let res = tmpfile
-                                        .persist(&cpath)
-                                        .map_err(|e| e.error)
+                            *state = State::Busy(crate::async_lib::spawn_blocking(move || {
+                                // Safe unwrap. cpath always has multiple segments
+                                let res =
+                                    durability::create_dir_all(cpath.parent().unwrap(), level)
                                         .with_context(|| {
+                                            format!(
+                                                "building directory {} failed",
+                                                cpath.parent().unwrap().display()
+                                            )
+                                        });
+                                let tmpfile = res.and_then(|_| {
+                                    tmpfile.finish().and_then(Sink::finish).with_context(|| {
+                                        String::from("Failed to finish encoding cache contents")
+                                    })
+                                });
+                                let tmpfile = tmpfile.and_then(|tmpfile| {
+                                    sync_tmpfile(&tmpfile, mmap.as_ref(), level).with_context(
+                                        || String::from("Failed to sync cache contents to disk"),
+                                    )?;
+                                    Ok(tmpfile)
+                                });
+                                match tmpfile {
+                                    Err(e) => {
+                                        let _ = s.send(Err(e));
+                                    }
+                                    Ok(tmpfile) => {
+                                        let existed = cpath.exists();
+                                        let res = tmpfile.persist(&cpath).with_context(|| {
                                             format!("persisting file {} failed", cpath.display())
                                         });
-                                    if res.is_err() {
-                                        // We might run into conflicts
-                                        // sometimes when persisting files.
-                                        // This is ok. We can deal. Let's just
-                                        // make sure the destination file
-                                        // actually exists, and we can move
-                                        // on.
-                                        let _ = s.send(
-                                            std::fs::metadata(cpath)
+                                        let res = if res.is_err() {
+                                            // We might run into conflicts
+                                            // sometimes when persisting files.
+                                            // This is ok. We can deal. Let's just
+                                            // make sure the destination file
+                                            // actually exists, and we can move
+                                            // on.
+                                            std::fs::metadata(&cpath)
                                                 .with_context(|| {
                                                     String::from("File still doesn't exist")
                                                 })
-                                                .map(|_| sri),
-                                        );
-                                    } else {
-                                        let _ = s.send(res.map(|_| sri));
+                                                .map(|_| ())
+                                        } else {
+                                            res.map(|_| ())
+                                        };
+                                        let res = res.and_then(|_| {
+                                            durability::step("content persisted")
+                                                .and_then(|_| {
+                                                    durability::sync_parent(
+                                                        &cpath,
+                                                        level,
+                                                        "content dir synced",
+                                                    )
+                                                })
+                                                .with_context(|| {
+                                                    format!("syncing {} failed", cpath.display())
+                                                })
+                                        });
+                                        let created = (!existed).then_some(cpath);
+                                        let _ = s.send(res.map(|_| (sri, created)));
                                     }
                                 }
                                 State::Idle(None)
@@ -306,16 +418,18 @@ impl AsyncWrite for AsyncWriter {
 
                         // Start the operation asynchronously.
                         *state = State::Busy(crate::async_lib::spawn_blocking(|| {
-                            inner.builder.input(&inner.buf);
-                            if let Some(mmap) = &mut inner.mmap {
+                            let res = if let Some(mmap) = &mut inner.mmap {
                                 mmap.copy_from_slice(&inner.buf);
-                                inner.last_op = Some(Operation::Write(Ok(inner.buf.len())));
-                                State::Idle(Some(inner))
+                                Ok(inner.buf.len())
                             } else {
-                                let res = inner.tmpfile.write(&inner.buf);
-                                inner.last_op = Some(Operation::Write(res));
-                                State::Idle(Some(inner))
+                                inner.tmpfile.write(&inner.buf)
+                            };
+                            // Only what was actually written, like in `Writer::write`.
+                            if let Ok(written) = res {
+                                inner.builder.input(&inner.buf[..written]);
                             }
+                            inner.last_op = Some(Operation::Write(res));
+                            State::Idle(Some(inner))
                         }));
                     }
                 }
@@ -423,9 +537,263 @@ impl AsyncWriter {
     }
 }
 
+/// Temporary file that content gets written into, encrypting it along the
+/// way if requested.
+enum Sink {
+    Plain(NamedTempFile),
+    #[cfg(feature = "encryption")]
+    Encrypted(Box<EncryptWriter<NamedTempFile>>),
+    Chunked(Box<ChunkWriter>),
+    /// A resumable write's temp file, which outlives the writer unless it's
+    /// committed.
+    Resumable(File, PathBuf, Claim),
+}
+
+impl Sink {
+    fn finish(self) -> std::io::Result<Finished> {
+        match self {
+            Sink::Plain(tmpfile) => Ok(Finished::Temp(tmpfile)),
+            Sink::Chunked(writer) => writer.finish().map(Finished::Temp),
+            Sink::Resumable(file, path, claim) => Ok(Finished::Resumable(file, path, claim)),
+            #[cfg(feature = "encryption")]
+            Sink::Encrypted(writer) => writer.finish().map(Finished::Temp),
+        }
+    }
+}
+
+/// A temp file that's been written in full, ready to be moved into place.
+enum Finished {
+    Temp(NamedTempFile),
+    Resumable(File, PathBuf, Claim),
+}
+
+impl Finished {
+    fn as_file(&self) -> &File {
+        match self {
+            Finished::Temp(tmpfile) => tmpfile.as_file(),
+            Finished::Resumable(file, _, _) => file,
+        }
+    }
+
+    /// Moves the temp file to `cpath`. If that fails, the temp file is
+    /// removed anyway, like a writer's temp files always are.
+    fn persist(self, cpath: &Path) -> std::io::Result<()> {
+        match self {
+            Finished::Temp(tmpfile) => tmpfile.persist(cpath).map(|_| ()).map_err(|e| e.error),
+            Finished::Resumable(_file, path, _claim) => {
+                resume::forget(&path);
+                let res = std::fs::rename(&path, cpath);
+                if res.is_err() {
+                    let _ = std::fs::remove_file(&path);
+                }
+                res
+            }
+        }
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Sink::Plain(tmpfile) => tmpfile.write(buf),
+            #[cfg(feature = "encryption")]
+            Sink::Encrypted(writer) => writer.write(buf),
+            Sink::Chunked(writer) => writer.write(buf),
+            Sink::Resumable(file, _, _) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Sink::Plain(tmpfile) => tmpfile.flush(),
+            #[cfg(feature = "encryption")]
+            Sink::Encrypted(writer) => writer.flush(),
+            Sink::Chunked(writer) => writer.flush(),
+            Sink::Resumable(file, _, _) => file.flush(),
+        }
+    }
+}
+
+/// Makes sure everything written to `tmpfile` is on disk before it's moved
+/// into place, if `durability` asks for it.
+fn sync_tmpfile(
+    tmpfile: &Finished,
+    mmap: Option<&MmapMut>,
+    durability: Durability,
+) -> std::io::Result<()> {
+    if durability >= Durability::Data {
+        if let Some(mmap) = mmap {
+            mmap.flush()?;
+        }
+    }
+    durability::sync_file(tmpfile.as_file(), durability, "content synced")
+}
+
+fn tmp_dir(cache: &Path, opts: &WriteOpts) -> PathBuf {
+    opts.tmp_dir.clone().unwrap_or_else(|| cache.join("tmp"))
+}
+
+fn algorithm(opts: &WriteOpts) -> Algorithm {
+    opts.algorithm.unwrap_or(Algorithm::Sha256)
+}
+
+/// Checks that `opts` make sense together, for writes that turn out not to
+/// need a writer of their own.
+pub fn check_opts(opts: &WriteOpts) -> Result<()> {
+    make_encoding(opts).map(|_| ())
+}
+
+fn make_encoding(opts: &WriteOpts) -> Result<Encoding> {
+    let encoding = Encoding {
+        compression: opts.compression,
+        encrypted: opts.encryption_key.is_some(),
+        chunked: opts.chunking.is_some(),
+    };
+    if encoding.chunked && (encoding.compression.is_some() || encoding.encrypted) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "chunked content can't also be compressed or encrypted",
+        ))
+        .with_context(|| "Failed to initialize a writer".to_string());
+    }
+    if opts.encrypt_metadata && !encoding.encrypted {
+        return Err(crate::content::encrypt::missing_key())
+            .with_context(|| "Failed to initialize a writer".to_string());
+    }
+    if opts.resume.is_some() && !encoding.is_plain() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "resumable writes can't be compressed, encrypted or chunked",
+        ))
+        .with_context(|| "Failed to initialize a writer".to_string());
+    }
+    Ok(encoding)
+}
+
+/// Opens the temp file a resumable write keeps under `token`, creating it if
+/// this is the first attempt. Whatever earlier attempts left in it, beyond
+/// what the hasher's saved state covers, is hashed again.
+///
+/// Only one writer at a time can have the file. Other processes are kept
+/// out by a file lock, or on Windows by opening the file without sharing it.
+fn open_resumable(
+    tmp_path: &Path,
+    token: &str,
+    algorithm: Algorithm,
+) -> Result<(Sink, Hasher, usize)> {
+    let dir = tmp_path.join("resume");
+    DirBuilder::new()
+        .recursive(true)
+        .create(&dir)
+        .with_context(|| {
+            format!(
+                "Failed to create directory for resumable writes, at {}",
+                dir.display()
+            )
+        })?;
+    let path = dir.join(crate::index::hash_key(token));
+    let in_progress = || {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::WouldBlock,
+            format!("resumable write {token:?} is already in progress"),
+        ))
+        .with_context(|| format!("Failed to lock temp file at {}", path.display()))
+    };
+    let Some(claim) = Claim::new(&path) else {
+        return in_progress();
+    };
+    let mut options = OpenOptions::new();
+    options.read(true).append(true).create(true);
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        // Deleting stays shared, so that the file can be moved into place.
+        const FILE_SHARE_DELETE: u32 = 0x4;
+        options.share_mode(FILE_SHARE_DELETE);
+    }
+    let mut file = match options.open(&path) {
+        #[cfg(windows)]
+        Err(err) if err.raw_os_error() == Some(ERROR_SHARING_VIOLATION) => return in_progress(),
+        res => res
+            .with_context(|| format!("Failed to open resumable temp file at {}", path.display()))?,
+    };
+    if !crate::flock::try_lock(&file)
+        .with_context(|| format!("Failed to lock temp file at {}", path.display()))?
+    {
+        return in_progress();
+    }
+    let len = file
+        .metadata()
+        .with_context(|| format!("Failed to read resumable temp file at {}", path.display()))?
+        .len();
+    let (mut hasher, hashed) = Hasher::resume(algorithm, &path, &file, len);
+    file.seek(std::io::SeekFrom::Start(hashed))
+        .with_context(|| format!("Failed to read resumable temp file at {}", path.display()))?;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file
+            .read(&mut buf)
+            .with_context(|| format!("Failed to read resumable temp file at {}", path.display()))?;
+        if n == 0 {
+            break;
+        }
+        hasher.input(&buf[..n]);
+    }
+    Ok((Sink::Resumable(file, path, claim), hasher, len as usize))
+}
+
+/// Windows' error for opening a file someone else has open without sharing
+/// it.
+#[cfg(windows)]
+const ERROR_SHARING_VIOLATION: i32 = 32;
+
+fn make_encoder(cache: &Path, tmpfile: NamedTempFile, opts: &WriteOpts) -> Result<Encoder<Sink>> {
+    let path = tmpfile.path().to_path_buf();
+    if let Some(chunking) = opts.chunking {
+        return Ok(Encoder::Plain(Sink::Chunked(Box::new(ChunkWriter::new(
+            cache,
+            &tmp_dir(cache, opts),
+            chunking,
+            opts.durability,
+            tmpfile,
+        )))));
+    }
+    let sink = match &opts.encryption_key {
+        None => Sink::Plain(tmpfile),
+        #[cfg(feature = "encryption")]
+        Some(key) => Sink::Encrypted(Box::new(EncryptWriter::new(tmpfile, key).with_context(
+            || {
+                format!(
+                    "Failed to set up encryption for temp file at {}",
+                    path.display()
+                )
+            },
+        )?)),
+        #[cfg(not(feature = "encryption"))]
+        Some(_) => unreachable!("encryption keys only exist with the `encryption` feature"),
+    };
+    Encoder::new(sink, opts.compression).with_context(|| {
+        format!(
+            "Failed to set up compression for temp file at {}",
+            path.display()
+        )
+    })
+}
+
 #[cfg(feature = "mmap")]
-fn make_mmap(tmpfile: &mut NamedTempFile, size: Option<usize>) -> Result<Option<MmapMut>> {
-    if let Some(size @ 0..=MAX_MMAP_SIZE) = size {
+fn make_mmap(
+    tmpfile: &mut NamedTempFile,
+    size: Option<usize>,
+    opts: &WriteOpts,
+    encoding: Encoding,
+) -> Result<Option<MmapMut>> {
+    // Encoded data doesn't have a size we can know up front.
+    if !encoding.is_plain() {
+        return Ok(None);
+    }
+    let threshold = opts.mmap_threshold.unwrap_or(MAX_MMAP_SIZE);
+    // Empty files can't be mapped.
+    if let Some(size) = size.filter(|size| *size > 0 && *size <= threshold) {
         allocate_file(tmpfile.as_file(), size).with_context(|| {
             format!(
                 "Failed to configure file length for temp file at {}",
@@ -441,20 +809,19 @@ fn make_mmap(tmpfile: &mut NamedTempFile, size: Option<usize>) -> Result<Option<
 #[cfg(feature = "mmap")]
 #[cfg(target_os = "linux")]
 fn allocate_file(file: &std::fs::File, size: usize) -> std::io::Result<()> {
-    use std::io::{Error, ErrorKind};
+    use std::io::Error;
     use std::os::fd::AsRawFd;
 
     let fd = file.as_raw_fd();
     match unsafe { libc::posix_fallocate64(fd, 0, size as i64) } {
         0 => Ok(()),
-        libc::ENOSPC => Err(Error::new(
-            ErrorKind::Other, // ErrorKind::StorageFull is unstable
+        libc::ENOSPC => Err(Error::other(
+            // ErrorKind::StorageFull is unstable
             "cannot allocate file: no space left on device",
         )),
-        err => Err(Error::new(
-            ErrorKind::Other,
-            format!("posix_fallocate64 failed with code {err}"),
-        )),
+        err => Err(Error::other(format!(
+            "posix_fallocate64 failed with code {err}"
+        ))),
     }
 }
 
@@ -465,7 +832,12 @@ fn allocate_file(file: &std::fs::File, size: usize) -> std::io::Result<()> {
 }
 
 #[cfg(not(feature = "mmap"))]
-fn make_mmap(_: &mut NamedTempFile, _: Option<usize>) -> Result<Option<MmapMut>> {
+fn make_mmap(
+    _: &mut NamedTempFile,
+    _: Option<usize>,
+    _: &WriteOpts,
+    _: Encoding,
+) -> Result<Option<MmapMut>> {
     Ok(None)
 }
 
@@ -485,14 +857,15 @@ mod tests {
     fn basic_write() {
         let tmp = tempfile::tempdir().unwrap();
         let dir = tmp.path().to_owned();
-        let mut writer = Writer::new(&dir, Algorithm::Sha256, None).unwrap();
+        let mut writer = Writer::new(&dir, &WriteOpts::new(), None).unwrap();
         writer.write_all(b"hello world").unwrap();
-        let sri = writer.close().unwrap();
+        let (sri, created) = writer.close().unwrap();
         assert_eq!(sri.to_string(), Integrity::from(b"hello world").to_string());
         assert_eq!(
             std::fs::read(path::content_path(&dir, &sri)).unwrap(),
             b"hello world"
         );
+        assert_eq!(created, Some(path::content_path(&dir, &sri)));
     }
 
     #[cfg(any(feature = "async-std", feature = "tokio"))]
@@ -500,15 +873,16 @@ mod tests {
     async fn basic_async_write() {
         let tmp = tempfile::tempdir().unwrap();
         let dir = tmp.path().to_owned();
-        let mut writer = AsyncWriter::new(&dir, Algorithm::Sha256, None)
+        let mut writer = AsyncWriter::new(&dir, &WriteOpts::new(), None)
             .await
             .unwrap();
         writer.write_all(b"hello world").await.unwrap();
-        let sri = writer.close().await.unwrap();
+        let (sri, created) = writer.close().await.unwrap();
         assert_eq!(sri.to_string(), Integrity::from(b"hello world").to_string());
         assert_eq!(
             std::fs::read(path::content_path(&dir, &sri)).unwrap(),
             b"hello world"
         );
+        assert_eq!(created, Some(path::content_path(&dir, &sri)));
     }
 }
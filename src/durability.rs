@@ -0,0 +1,218 @@
+//! How hard writes try to survive a crash or power loss.
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+/// How much effort a write makes to ensure its data survives a crash or
+/// power loss, by `fsync`ing files and directories along the way.
+///
+/// Each level also covers everything the levels before it do.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Durability {
+    /// Leave flushing to the operating system. This is the fastest, but a
+    /// crash can leave index entries pointing at truncated or missing
+    /// content.
+    #[default]
+    None,
+    /// Sync content to disk before moving it into place, and index buckets
+    /// after appending to them, so an index entry never points at content
+    /// that was only partially written.
+    Data,
+    /// Also sync the directories content and index buckets are moved or
+    /// created in, so a write that returned can't be undone by a crash.
+    Full,
+}
+
+/// Syncs the contents of `file` to disk if `durability` asks for it,
+/// marking `name` as done once it has.
+pub(crate) fn sync_file(file: &File, durability: Durability, name: &'static str) -> io::Result<()> {
+    if durability >= Durability::Data {
+        file.sync_all()?;
+        step(name)?;
+    }
+    Ok(())
+}
+
+/// Syncs the entries of the directory `file` lives in, so renames and newly
+/// created files in it survive a crash, if `durability` asks for it.
+pub(crate) fn sync_parent(
+    file: &Path,
+    durability: Durability,
+    name: &'static str,
+) -> io::Result<()> {
+    if durability >= Durability::Full {
+        // Safe unwrap. Everything synced this way lives inside the cache.
+        sync_dir(file.parent().unwrap())?;
+        step(name)?;
+    }
+    Ok(())
+}
+
+/// Creates `dir` and any of its ancestors that are missing. If `durability`
+/// asks for it, also syncs the directory each new one was created in, since
+/// syncing the parent of a file later on doesn't make the directories above
+/// it survive a crash.
+pub(crate) fn create_dir_all(dir: &Path, durability: Durability) -> io::Result<()> {
+    if durability < Durability::Full {
+        return fs::create_dir_all(dir);
+    }
+    let missing = dir.ancestors().take_while(|dir| !dir.exists()).count();
+    fs::create_dir_all(dir)?;
+    for created in dir.ancestors().take(missing) {
+        match created.parent() {
+            Some(parent) if parent.as_os_str().is_empty() => sync_dir(Path::new("."))?,
+            Some(parent) => sync_dir(parent)?,
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+// Directory entries can't be synced separately on other platforms, where
+// syncing the files themselves is as much as we can do.
+#[cfg(not(unix))]
+fn sync_dir(_: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+thread_local! {
+    static STEPS: std::cell::RefCell<Vec<&'static str>> = const { std::cell::RefCell::new(Vec::new()) };
+    static CRASH_AFTER: std::cell::Cell<Option<&'static str>> = const { std::cell::Cell::new(None) };
+}
+
+/// Marks that a step of a write has completed. Tests use this to check the
+/// order steps happen in, and to simulate a crash right after one of them.
+pub(crate) fn step(name: &'static str) -> io::Result<()> {
+    #[cfg(test)]
+    {
+        STEPS.with(|steps| steps.borrow_mut().push(name));
+        if CRASH_AFTER.with(|crash| crash.get()) == Some(name) {
+            return Err(io::Error::other(format!("simulated crash after {name}")));
+        }
+    }
+    #[cfg(not(test))]
+    let _ = name;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::WriteOpts;
+
+    const STEPS_FULL: &[&str] = &[
+        "content synced",
+        "content persisted",
+        "content dir synced",
+        "index written",
+        "index synced",
+        "index dir synced",
+        "reverse index synced",
+        "reverse index dir synced",
+    ];
+
+    fn write(dir: &Path, durability: Durability, crash_after: Option<&'static str>) -> bool {
+        STEPS.with(|steps| steps.borrow_mut().clear());
+        CRASH_AFTER.with(|crash| crash.set(crash_after));
+        let res = WriteOpts::new()
+            .durability(durability)
+            .open_sync(dir, "my-key")
+            .and_then(|mut writer| {
+                io::Write::write_all(&mut writer, b"hello world").unwrap();
+                writer.commit()
+            });
+        CRASH_AFTER.with(|crash| crash.set(None));
+        res.is_ok()
+    }
+
+    fn steps() -> Vec<&'static str> {
+        STEPS.with(|steps| steps.borrow().clone())
+    }
+
+    #[test]
+    fn step_order() {
+        // Each in a fresh cache, since rewriting an unchanged entry skips the
+        // reverse index.
+        let tmp = tempfile::tempdir().unwrap();
+        assert!(write(tmp.path(), Durability::None, None));
+        assert_eq!(steps(), vec!["content persisted", "index written"]);
+        let tmp = tempfile::tempdir().unwrap();
+        assert!(write(tmp.path(), Durability::Data, None));
+        assert_eq!(
+            steps(),
+            vec![
+                "content synced",
+                "content persisted",
+                "index written",
+                "index synced",
+                "reverse index synced"
+            ]
+        );
+        let tmp = tempfile::tempdir().unwrap();
+        assert!(write(tmp.path(), Durability::Full, None));
+        assert_eq!(steps(), STEPS_FULL);
+    }
+
+    #[test]
+    fn crash_between_steps() {
+        for &crash_after in STEPS_FULL {
+            let tmp = tempfile::tempdir().unwrap();
+            let dir = tmp.path();
+            assert!(!write(dir, Durability::Full, Some(crash_after)));
+            // Whatever made it into the index must point at complete content.
+            match crate::index::find(dir, "my-key").unwrap() {
+                Some(entry) => {
+                    assert!(crash_after.contains("index "));
+                    assert_eq!(
+                        crate::read_hash_sync(dir, &entry.integrity).unwrap(),
+                        b"hello world"
+                    );
+                }
+                None => assert!(!crash_after.contains("index ")),
+            }
+            // And retrying the write afterwards works as usual.
+            assert!(write(dir, Durability::Full, None));
+            assert_eq!(crate::read_sync(dir, "my-key").unwrap(), b"hello world");
+        }
+    }
+
+    #[test]
+    fn creates_missing_ancestors() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("a/b/c");
+        create_dir_all(&dir, Durability::Full).unwrap();
+        assert!(dir.is_dir());
+        // Creating it again is fine too.
+        create_dir_all(&dir, Durability::Full).unwrap();
+    }
+
+    #[test]
+    fn removals_honour_durability() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        assert!(write(dir, Durability::None, None));
+        STEPS.with(|steps| steps.borrow_mut().clear());
+        crate::RemoveOpts::new()
+            .durability(Durability::Full)
+            .remove_sync(dir, "my-key")
+            .unwrap();
+        assert_eq!(
+            steps(),
+            vec!["index written", "index synced", "index dir synced"]
+        );
+        assert!(write(dir, Durability::None, None));
+        STEPS.with(|steps| steps.borrow_mut().clear());
+        crate::RemoveOpts::new()
+            .remove_fully(true)
+            .durability(Durability::Full)
+            .remove_sync(dir, "my-key")
+            .unwrap();
+        assert_eq!(steps(), vec!["index dir synced"]);
+    }
+}
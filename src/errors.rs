@@ -27,6 +27,33 @@ pub enum Error {
     #[diagnostic(code(cacache::serde_error), url(docsrs))]
     SerdeError(#[source] serde_json::Error, String),
 
+    /// Returned when trying to modify a cache that was opened read-only.
+    #[error("Cache at {0:?} was opened read-only")]
+    #[diagnostic(code(cacache::read_only), url(docsrs))]
+    ReadOnly(PathBuf),
+
+    /// Returned when a conditional write finds that the entry for its key
+    /// isn't what it was told to expect. A conditional write expecting an
+    /// entry that's gone fails with [`Error::EntryNotFound`] instead. See
+    /// [`crate::WriteOpts::if_integrity`] and [`crate::WriteOpts::if_absent`].
+    #[error("Entry for key {1:?} in cache {0:?} changed before it could be written")]
+    #[diagnostic(code(cacache::conflict), url(docsrs))]
+    Conflict(PathBuf, String),
+
+    /// Returned when an entry's metadata is encrypted, and it can't be read
+    /// or changed without the key it was encrypted with. See
+    /// [`crate::WriteOpts::encrypt_metadata`].
+    #[error("Metadata for key {1:?} in cache {0:?} is encrypted")]
+    #[diagnostic(code(cacache::metadata_sealed), url(docsrs))]
+    MetadataSealed(PathBuf, String),
+
+    /// Returned when [`crate::get_or_insert_with_sync`] is called for a key
+    /// by the same thread that's already producing its data, which would
+    /// otherwise wait on itself forever.
+    #[error("Data for key {1:?} in cache {0:?} was requested while it was being produced")]
+    #[diagnostic(code(cacache::reentrant), url(docsrs))]
+    Reentrant(PathBuf, String),
+
     /// Returned when an integrity check has failed.
     #[error(transparent)]
     #[diagnostic(code(cacache::integrity_error), url(docsrs))]
@@ -59,5 +86,5 @@ impl<T> IoErrorExt<T> for std::result::Result<T, serde_json::Error> {
 }
 
 pub fn io_error(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> std::io::Error {
-    std::io::Error::new(std::io::ErrorKind::Other, err)
+    std::io::Error::other(err)
 }
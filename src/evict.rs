@@ -0,0 +1,661 @@
+//! Functions for keeping the cache under a maximum size.
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_derive::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::content::{chunk, path};
+use crate::durability::Durability;
+use crate::errors::{IoErrorExt, Result};
+use crate::index::{self, Metadata};
+
+pub(crate) const POLICY_FILE: &str = "policy-v1.json";
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+struct Policy {
+    max_size: Option<u64>,
+}
+
+/// Summary of the work done by [`evict_to`] and [`evict_to_sync`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EvictStats {
+    /// Number of index entries that were evicted.
+    pub evicted_entries: usize,
+    /// Number of content files removed because no remaining entry referenced
+    /// them.
+    pub reclaimed_count: usize,
+    /// Total size, in bytes, of all removed content files.
+    pub reclaimed_size: u64,
+    /// Total size, in bytes, of the content left in the cache.
+    pub kept_size: u64,
+}
+
+/// Sets the maximum number of bytes of content the cache may hold, or removes
+/// the limit if `max_size` is `None`.
+///
+/// The limit is stored in the cache itself, so it applies to every writer
+/// using it. Whenever a write pushes the cache over the limit, the
+/// least-recently-used keys are evicted until it fits again. See [`evict_to`]
+/// for details.
+///
+/// Writers don't measure the whole cache after every write. Each process
+/// keeps an estimate of how much content the cache holds, and only measures
+/// it again once the estimate goes over the limit, or once about an eighth of
+/// the limit has been written since it last did. Writes by other processes
+/// can take the cache over the limit by that much until they're noticed.
+///
+/// ## Example
+/// ```no_run
+/// use async_attributes;
+///
+/// #[async_attributes::main]
+/// async fn main() -> cacache::Result<()> {
+///     cacache::set_max_size("./my-cache", Some(1024 * 1024 * 1024)).await?;
+///     Ok(())
+/// }
+/// ```
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub async fn set_max_size<P: AsRef<Path>>(cache: P, max_size: Option<u64>) -> Result<()> {
+    let cache = cache.as_ref().to_path_buf();
+    crate::async_lib::unwrap_joinhandle_value(
+        crate::async_lib::spawn_blocking(move || set_max_size_sync(cache, max_size)).await,
+    )
+}
+
+/// Returns the maximum number of bytes of content the cache may hold, if one
+/// was set with [`set_max_size`].
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub async fn max_size<P: AsRef<Path>>(cache: P) -> Result<Option<u64>> {
+    Ok(read_policy_async(cache.as_ref()).await?.max_size)
+}
+
+/// Evicts the least-recently-used keys from the cache until the content it
+/// holds takes up no more than `max_size` bytes.
+///
+/// Keys are ordered by the last time they were written or read by key.
+/// Content is only removed once no remaining key points to it, and content
+/// written without a key (with [`crate::write_hash`] and friends) is never
+/// evicted, since there's no way to tell when it was last used.
+///
+/// ## Example
+/// ```no_run
+/// use async_attributes;
+///
+/// #[async_attributes::main]
+/// async fn main() -> cacache::Result<()> {
+///     let stats = cacache::evict_to("./my-cache", 1024 * 1024).await?;
+///     println!("evicted {} entries", stats.evicted_entries);
+///     Ok(())
+/// }
+/// ```
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub async fn evict_to<P: AsRef<Path>>(cache: P, max_size: u64) -> Result<EvictStats> {
+    let cache = cache.as_ref().to_path_buf();
+    crate::async_lib::unwrap_joinhandle_value(
+        crate::async_lib::spawn_blocking(move || evict_to_sync(cache, max_size)).await,
+    )
+}
+
+/// Synchronously sets the maximum number of bytes of content the cache may
+/// hold, or removes the limit if `max_size` is `None`. See [`set_max_size`]
+/// for details.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache::Result<()> {
+///     cacache::set_max_size_sync("./my-cache", Some(1024 * 1024 * 1024))?;
+///     Ok(())
+/// }
+/// ```
+pub fn set_max_size_sync<P: AsRef<Path>>(cache: P, max_size: Option<u64>) -> Result<()> {
+    fn inner(cache: &Path, max_size: Option<u64>) -> Result<()> {
+        let policy = Policy { max_size };
+        let policy_path = cache.join(POLICY_FILE);
+        let tmp_path = cache.join("tmp");
+        fs::create_dir_all(&tmp_path).with_context(|| {
+            format!(
+                "Failed to create cache directory for temporary files, at {}",
+                tmp_path.display()
+            )
+        })?;
+        let mut tmp = tempfile::NamedTempFile::new_in(&tmp_path).with_context(|| {
+            format!(
+                "Failed to create temp file for cache policy, inside {}",
+                tmp_path.display()
+            )
+        })?;
+        serde_json::to_writer(&mut tmp, &policy)
+            .with_context(|| String::from("Failed to serialize cache policy"))?;
+        tmp.persist(&policy_path)
+            .map_err(|e| e.error)
+            .with_context(|| {
+                format!("Failed to write cache policy to {}", policy_path.display())
+            })?;
+        policies().remove(&policy_path);
+        Ok(())
+    }
+    inner(cache.as_ref(), max_size)
+}
+
+/// Synchronously returns the maximum number of bytes of content the cache
+/// may hold, if one was set with [`set_max_size_sync`].
+pub fn max_size_sync<P: AsRef<Path>>(cache: P) -> Result<Option<u64>> {
+    Ok(read_policy(cache.as_ref())?.max_size)
+}
+
+/// Synchronously evicts the least-recently-used keys from the cache until
+/// the content it holds takes up no more than `max_size` bytes. See
+/// [`evict_to`] for details.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache::Result<()> {
+///     let stats = cacache::evict_to_sync("./my-cache", 1024 * 1024)?;
+///     println!("evicted {} entries", stats.evicted_entries);
+///     Ok(())
+/// }
+/// ```
+pub fn evict_to_sync<P: AsRef<Path>>(cache: P, max_size: u64) -> Result<EvictStats> {
+    evict(cache.as_ref(), max_size, &HashSet::new())
+}
+
+/// Evicts least-recently-used entries, other than those for `spared` keys,
+/// until the cache holds at most `max_size` bytes of content, or there's
+/// nothing left to evict.
+fn evict(cache: &Path, max_size: u64, spared: &HashSet<String>) -> Result<EvictStats> {
+    let stats = evict_entries(cache, max_size, spared)?;
+    measured(cache, stats.kept_size);
+    Ok(stats)
+}
+
+fn evict_entries(cache: &Path, max_size: u64, spared: &HashSet<String>) -> Result<EvictStats> {
+    let sizes = content_sizes(cache)?;
+    let mut stats = EvictStats {
+        kept_size: sizes.values().sum(),
+        ..Default::default()
+    };
+    if stats.kept_size <= max_size || !index::index_dir(cache).exists() {
+        return Ok(stats);
+    }
+
+    let mut entries = Vec::new();
+    let mut refs = HashMap::new();
+    for entry in index::ls(cache) {
+        let entry = entry?;
+        let cpath = path::content_path(cache, &entry.integrity);
+        *refs.entry(cpath.clone()).or_insert(0usize) += 1;
+        let accessed = index::last_access(cache, &entry.key).unwrap_or(UNIX_EPOCH);
+        entries.push((accessed, entry.time, cpath, entry));
+    }
+    entries.sort_by_key(|e| (e.0, e.1));
+
+    // Chunks are shared between chunked content, so they're only freed once
+    // no remaining manifest lists them.
+    let mut manifests = HashMap::new();
+    let mut chunk_refs = HashMap::new();
+    for (_, _, cpath, entry) in &entries {
+        if manifests.contains_key(cpath) {
+            continue;
+        }
+        let chunks = match path::find_content(cache, &entry.integrity) {
+            Some((mpath, encoding)) if encoding.chunked => {
+                chunk::chunk_integrities(&mpath).unwrap_or_default()
+            }
+            _ => Vec::new(),
+        };
+        let chunks = chunks
+            .iter()
+            .map(|chunk| path::chunk_path(cache, chunk))
+            .collect::<Vec<_>>();
+        for chunk in &chunks {
+            *chunk_refs.entry(chunk.clone()).or_insert(0usize) += 1;
+        }
+        manifests.insert(cpath.clone(), chunks);
+    }
+
+    for (_, _, cpath, entry) in entries {
+        if stats.kept_size <= max_size {
+            break;
+        }
+        if spared.contains(&entry.key) {
+            continue;
+        }
+        // Skip keys that were rewritten since we listed them.
+        let listed =
+            |current: &Metadata| current.integrity == entry.integrity && current.time == entry.time;
+        if !index::delete_if(cache, &entry.key, Durability::None, listed)? {
+            continue;
+        }
+        stats.evicted_entries += 1;
+        let count = refs.get_mut(&cpath).expect("counted above");
+        *count -= 1;
+        if *count > 0 {
+            continue;
+        }
+        crate::memo::forget_content(cache, &entry.integrity);
+        for (stored, _) in path::stored_paths(cache, &entry.integrity) {
+            reclaim(&stored, &sizes, &mut stats)?;
+        }
+        for chunk in manifests.remove(&cpath).unwrap_or_default() {
+            let count = chunk_refs.get_mut(&chunk).expect("counted above");
+            *count -= 1;
+            if *count == 0 {
+                reclaim(&chunk, &sizes, &mut stats)?;
+            }
+        }
+    }
+    Ok(stats)
+}
+
+/// Removes the file at `stored`, if it was there when the cache's size was
+/// taken, and accounts for the space it took up.
+fn reclaim(stored: &Path, sizes: &HashMap<PathBuf, u64>, stats: &mut EvictStats) -> Result<()> {
+    let Some(size) = sizes.get(stored) else {
+        return Ok(());
+    };
+    match fs::remove_file(stored) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(e)
+                .with_context(|| format!("Failed to remove content at {}", stored.display()))
+        }
+        _ => {}
+    }
+    stats.reclaimed_count += 1;
+    stats.reclaimed_size += size;
+    stats.kept_size -= size;
+    Ok(())
+}
+
+/// Evicts entries from the cache if `added` more bytes may have pushed it
+/// over its limit. That's `limit`, if the write was made through a
+/// [`crate::Cache`] opened with one, or the limit set with
+/// [`set_max_size_sync`] otherwise. Called after every write.
+pub(crate) fn enforce_max_size(cache: &Path, limit: Option<u64>, added: u64) -> Result<()> {
+    enforce_max_size_sparing(cache, limit, added, &HashSet::new())
+}
+
+/// Like [`enforce_max_size`], but never evicts `spared` keys, so that a
+/// batch isn't partly evicted right after it was committed.
+pub(crate) fn enforce_max_size_sparing(
+    cache: &Path,
+    limit: Option<u64>,
+    added: u64,
+    spared: &HashSet<String>,
+) -> Result<()> {
+    let max_size = match limit {
+        Some(limit) => Some(limit),
+        None => read_policy(cache)?.max_size,
+    };
+    if let Some(max_size) = max_size {
+        if due(cache, max_size, added) {
+            evict(cache, max_size, spared)?;
+        }
+    }
+    Ok(())
+}
+
+/// Evicts entries from the cache if `added` more bytes may have pushed it
+/// over its limit. See [`enforce_max_size`].
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub(crate) async fn enforce_max_size_async(
+    cache: &Path,
+    limit: Option<u64>,
+    added: u64,
+) -> Result<()> {
+    let max_size = match limit {
+        Some(limit) => Some(limit),
+        None => read_policy_async(cache).await?.max_size,
+    };
+    if let Some(max_size) = max_size {
+        if due(cache, max_size, added) {
+            evict_to(cache, max_size).await?;
+        }
+    }
+    Ok(())
+}
+
+/// What this process knows about how much content a cache holds: its size
+/// when it was last measured, and how much has been written to it since.
+#[derive(Default)]
+struct Usage {
+    measured: u64,
+    added: u64,
+}
+
+/// The fraction of the limit that can be written between measurements, even
+/// if the estimate stays under it, so that writes by other processes are
+/// noticed too.
+const REMEASURE_EVERY: u64 = 8;
+
+fn usage() -> MutexGuard<'static, HashMap<PathBuf, Usage>> {
+    static USAGE: OnceLock<Mutex<HashMap<PathBuf, Usage>>> = OnceLock::new();
+    USAGE
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+}
+
+/// Accounts for `added` bytes written to `cache`, and returns whether it's
+/// time to measure it again, because it may have gone over `max_size`.
+///
+/// Measuring means walking all of the cache's content, so writes only do it
+/// the first time, once the estimate goes over the limit, or once a good
+/// share of the limit has been written since. The estimate counts bytes as
+/// they were handed to the writer, before any compression, so it errs on the
+/// side of measuring too often.
+fn due(cache: &Path, max_size: u64, added: u64) -> bool {
+    match usage().get_mut(cache) {
+        Some(usage) => {
+            usage.added = usage.added.saturating_add(added);
+            usage.measured.saturating_add(usage.added) > max_size
+                || usage.added >= max_size / REMEASURE_EVERY
+        }
+        None => true,
+    }
+}
+
+/// Records that `cache` was just measured to hold `size` bytes of content.
+fn measured(cache: &Path, size: u64) {
+    usage().insert(
+        cache.to_path_buf(),
+        Usage {
+            measured: size,
+            added: 0,
+        },
+    );
+}
+
+/// A policy this process has already read, along with the modification time
+/// and length of the file it was read from. It's only read again once the
+/// file changes.
+struct ReadPolicy {
+    modified: Option<SystemTime>,
+    len: u64,
+    policy: Policy,
+}
+
+fn policies() -> MutexGuard<'static, HashMap<PathBuf, ReadPolicy>> {
+    static POLICIES: OnceLock<Mutex<HashMap<PathBuf, ReadPolicy>>> = OnceLock::new();
+    POLICIES
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+}
+
+/// The policy read from `policy_path` before, if the file hasn't changed
+/// since.
+fn cached_policy(policy_path: &Path, stat: &fs::Metadata) -> Option<Policy> {
+    policies()
+        .get(policy_path)
+        .filter(|read| read.modified == stat.modified().ok() && read.len == stat.len())
+        .map(|read| read.policy.clone())
+}
+
+fn remember_policy(policy_path: &Path, stat: &fs::Metadata, policy: &Policy) {
+    let read = ReadPolicy {
+        modified: stat.modified().ok(),
+        len: stat.len(),
+        policy: policy.clone(),
+    };
+    policies().insert(policy_path.to_path_buf(), read);
+}
+
+fn read_policy(cache: &Path) -> Result<Policy> {
+    let policy_path = cache.join(POLICY_FILE);
+    let stat = match fs::metadata(&policy_path) {
+        Ok(stat) => stat,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Policy::default()),
+        Err(e) => {
+            return Err(e).with_context(|| {
+                format!("Failed to read cache policy at {}", policy_path.display())
+            })
+        }
+    };
+    if let Some(policy) = cached_policy(&policy_path, &stat) {
+        return Ok(policy);
+    }
+    match fs::read(&policy_path) {
+        Ok(data) => {
+            let policy = parse_policy(&policy_path, &data)?;
+            remember_policy(&policy_path, &stat, &policy);
+            Ok(policy)
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Policy::default()),
+        Err(e) => Err(e)
+            .with_context(|| format!("Failed to read cache policy at {}", policy_path.display())),
+    }
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+async fn read_policy_async(cache: &Path) -> Result<Policy> {
+    let policy_path = cache.join(POLICY_FILE);
+    let stat = match crate::async_lib::metadata(&policy_path).await {
+        Ok(stat) => stat,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Policy::default()),
+        Err(e) => {
+            return Err(e).with_context(|| {
+                format!("Failed to read cache policy at {}", policy_path.display())
+            })
+        }
+    };
+    if let Some(policy) = cached_policy(&policy_path, &stat) {
+        return Ok(policy);
+    }
+    match crate::async_lib::read(&policy_path).await {
+        Ok(data) => {
+            let policy = parse_policy(&policy_path, &data)?;
+            remember_policy(&policy_path, &stat, &policy);
+            Ok(policy)
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Policy::default()),
+        Err(e) => Err(e)
+            .with_context(|| format!("Failed to read cache policy at {}", policy_path.display())),
+    }
+}
+
+fn parse_policy(policy_path: &Path, data: &[u8]) -> Result<Policy> {
+    serde_json::from_slice(data)
+        .with_context(|| format!("Failed to parse cache policy at {}", policy_path.display()))
+}
+
+/// Sizes of every content file and chunk in the cache, by path.
+fn content_sizes(cache: &Path) -> Result<HashMap<PathBuf, u64>> {
+    let mut sizes = HashMap::new();
+    for dir in [path::content_dir(cache), path::chunk_dir(cache)] {
+        dir_sizes(&dir, &mut sizes)?;
+    }
+    Ok(sizes)
+}
+
+fn dir_sizes(dir: &Path, sizes: &mut HashMap<PathBuf, u64>) -> Result<()> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    for entry in WalkDir::new(dir) {
+        let entry = entry
+            .map_err(|e| crate::errors::io_error(e.to_string()))
+            .with_context(|| {
+                format!(
+                    "Error while walking cache content directory at {}",
+                    dir.display()
+                )
+            })?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let size = entry
+            .metadata()
+            .map_err(|e| crate::errors::io_error(e.to_string()))
+            .with_context(|| format!("Failed to stat content file at {}", entry.path().display()))?
+            .len();
+        sizes.insert(entry.into_path(), size);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    #[cfg(feature = "async-std")]
+    use async_attributes::test as async_test;
+    #[cfg(feature = "tokio")]
+    use tokio::test as async_test;
+
+    fn pause() {
+        // Access times come from file mtimes, which can be fairly coarse.
+        sleep(Duration::from_millis(20));
+    }
+
+    #[test]
+    fn max_size_round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        assert_eq!(max_size_sync(&dir).unwrap(), None);
+        set_max_size_sync(&dir, Some(1024)).unwrap();
+        assert_eq!(max_size_sync(&dir).unwrap(), Some(1024));
+        set_max_size_sync(&dir, None).unwrap();
+        assert_eq!(max_size_sync(&dir).unwrap(), None);
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::write_sync(&dir, "a", b"aaaaa").unwrap();
+        pause();
+        let b = crate::write_sync(&dir, "b", b"bbbbb").unwrap();
+        pause();
+        crate::write_sync(&dir, "c", b"ccccc").unwrap();
+        pause();
+        crate::read_sync(&dir, "a").unwrap();
+
+        let stats = evict_to_sync(&dir, 10).unwrap();
+        assert_eq!(
+            stats,
+            EvictStats {
+                evicted_entries: 1,
+                reclaimed_count: 1,
+                reclaimed_size: 5,
+                kept_size: 10,
+            }
+        );
+        assert_eq!(crate::metadata_sync(&dir, "b").unwrap(), None);
+        assert!(!crate::exists_sync(&dir, &b));
+        assert_eq!(crate::read_sync(&dir, "a").unwrap(), b"aaaaa");
+        assert_eq!(crate::read_sync(&dir, "c").unwrap(), b"ccccc");
+    }
+
+    #[test]
+    fn evict_keeps_shared_content() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri = crate::write_sync(&dir, "a", b"shared").unwrap();
+        pause();
+        crate::write_sync(&dir, "b", b"shared").unwrap();
+        pause();
+        crate::write_sync(&dir, "c", b"other").unwrap();
+
+        let stats = evict_to_sync(&dir, 6).unwrap();
+        // Evicting "a" alone frees nothing, since "b" still points at it.
+        assert_eq!(stats.evicted_entries, 2);
+        assert_eq!(stats.reclaimed_count, 1);
+        assert_eq!(stats.kept_size, 5);
+        assert!(!crate::exists_sync(&dir, &sri));
+        assert_eq!(crate::read_sync(&dir, "c").unwrap(), b"other");
+    }
+
+    #[test]
+    fn evict_frees_chunks_no_manifest_needs() {
+        use std::io::Write;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let write = |key: &str, data: &[u8]| {
+            let mut writer = crate::WriteOpts::new()
+                .chunking(crate::Chunking::Fixed(4096))
+                .open_sync(&dir, key)
+                .unwrap();
+            writer.write_all(data).unwrap();
+            writer.commit().unwrap();
+        };
+        let shared = (0..8192).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let a = [&shared[..], &[b'a'; 1808]].concat();
+        let b = [&shared[..], &[b'b'; 1808]].concat();
+        write("a", &a);
+        pause();
+        write("b", &b);
+
+        let chunks = || {
+            WalkDir::new(path::chunk_dir(&dir))
+                .into_iter()
+                .filter(|entry| entry.as_ref().unwrap().file_type().is_file())
+                .count()
+        };
+        assert_eq!(chunks(), 4);
+        let stats = evict_to_sync(&dir, 11_000).unwrap();
+        // Evicting "a" frees its manifest and the one chunk only it uses.
+        assert_eq!(stats.evicted_entries, 1);
+        assert_eq!(stats.reclaimed_count, 2);
+        assert!(stats.kept_size <= 11_000);
+        assert_eq!(chunks(), 3);
+        assert_eq!(crate::metadata_sync(&dir, "a").unwrap(), None);
+        assert_eq!(crate::read_sync(&dir, "b").unwrap(), b);
+    }
+
+    #[test]
+    fn write_enforces_max_size() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        set_max_size_sync(&dir, Some(10)).unwrap();
+        crate::write_sync(&dir, "a", b"aaaaa").unwrap();
+        pause();
+        crate::write_sync(&dir, "b", b"bbbbb").unwrap();
+        pause();
+        crate::write_sync(&dir, "c", b"ccccc").unwrap();
+
+        assert_eq!(crate::metadata_sync(&dir, "a").unwrap(), None);
+        assert!(crate::metadata_sync(&dir, "b").unwrap().is_some());
+        assert!(crate::metadata_sync(&dir, "c").unwrap().is_some());
+    }
+
+    #[test]
+    fn writes_measure_only_when_they_could_matter() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        assert!(due(dir, 800, 10));
+        measured(dir, 100);
+        assert!(!due(dir, 800, 50));
+        assert!(!due(dir, 800, 49));
+        // A share of the limit was written since the last measurement.
+        assert!(due(dir, 800, 1));
+        measured(dir, 100);
+        // This one could take the cache over the limit.
+        assert!(due(dir, 800, 701));
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    #[async_test]
+    async fn write_enforces_max_size_async() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        set_max_size(&dir, Some(10)).await.unwrap();
+        assert_eq!(max_size(&dir).await.unwrap(), Some(10));
+        crate::write(&dir, "a", b"aaaaa").await.unwrap();
+        pause();
+        crate::write(&dir, "b", b"bbbbb").await.unwrap();
+        pause();
+        crate::write(&dir, "c", b"ccccc").await.unwrap();
+
+        assert_eq!(crate::metadata(&dir, "a").await.unwrap(), None);
+        assert!(crate::metadata(&dir, "c").await.unwrap().is_some());
+        assert_eq!(evict_to(&dir, 0).await.unwrap().kept_size, 0);
+    }
+}
@@ -0,0 +1,194 @@
+//! Advisory file locks, used to coordinate writers, readers and cleanups
+//! across threads and processes. On platforms without `flock`, taking a lock
+//! always succeeds right away, and nothing is coordinated.
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+use walkdir::WalkDir;
+
+/// Blocks until `file` is locked exclusively.
+#[cfg(unix)]
+pub(crate) fn lock(file: &File) -> io::Result<()> {
+    rustix::fs::flock(file, rustix::fs::FlockOperation::LockExclusive)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn lock(_: &File) -> io::Result<()> {
+    Ok(())
+}
+
+/// Blocks until `file` is locked, shared with other shared holders.
+#[cfg(unix)]
+pub(crate) fn lock_shared(file: &File) -> io::Result<()> {
+    rustix::fs::flock(file, rustix::fs::FlockOperation::LockShared)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn lock_shared(_: &File) -> io::Result<()> {
+    Ok(())
+}
+
+/// Locks `file` exclusively if nobody else holds it, returning whether it
+/// did.
+#[cfg(unix)]
+pub(crate) fn try_lock(file: &File) -> io::Result<bool> {
+    match rustix::fs::flock(file, rustix::fs::FlockOperation::NonBlockingLockExclusive) {
+        Ok(()) => Ok(true),
+        Err(err) if err == rustix::io::Errno::WOULDBLOCK => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn try_lock(_: &File) -> io::Result<bool> {
+    Ok(true)
+}
+
+/// Returns whether `path` still refers to the file `file` was opened from.
+#[cfg(unix)]
+pub(crate) fn same_file(file: &File, path: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let (locked, current) = match fs::symlink_metadata(path) {
+        Ok(current) => (file.metadata()?, current),
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    Ok(locked.dev() == current.dev() && locked.ino() == current.ino())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn same_file(_: &File, _: &Path) -> io::Result<bool> {
+    Ok(true)
+}
+
+/// Opens the lock file at `path`, creating it and its directory if needed,
+/// and blocks until it's locked. The lock is held until the returned file is
+/// dropped.
+///
+/// Lock files nobody holds can be removed by [`remove_unused`] at any time,
+/// so if the file was removed or replaced while we waited for it, this
+/// starts over with whatever is at `path` now.
+pub(crate) fn open_locked(path: &Path, exclusive: bool) -> io::Result<File> {
+    loop {
+        let file = open(path)?;
+        if exclusive {
+            lock(&file)?;
+        } else {
+            lock_shared(&file)?;
+        }
+        if same_file(&file, path)? {
+            return Ok(file);
+        }
+    }
+}
+
+/// Like [`open_locked`], taking an exclusive lock, but returns `None`
+/// instead of waiting if someone else holds it.
+#[cfg(any(test, feature = "async-std", feature = "tokio"))]
+pub(crate) fn try_open_locked(path: &Path) -> io::Result<Option<File>> {
+    loop {
+        let file = open(path)?;
+        if !try_lock(&file)? {
+            return Ok(None);
+        }
+        if same_file(&file, path)? {
+            return Ok(Some(file));
+        }
+    }
+}
+
+/// Like [`open_locked`], taking a shared lock, but never creates anything.
+/// Returns `None` if there's no lock file at `path`, in which case nobody
+/// holds it, so readers can go ahead without one. That keeps lookups working
+/// on caches they can't write to.
+pub(crate) fn open_shared_if_exists(path: &Path) -> io::Result<Option<File>> {
+    loop {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        lock_shared(&file)?;
+        if same_file(&file, path)? {
+            return Ok(Some(file));
+        }
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    // Safe unwrap. Lock files always live in a directory.
+    DirBuilder::new()
+        .recursive(true)
+        .create(path.parent().unwrap())?;
+    OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+}
+
+/// Removes the lock files under `dir` that nobody currently holds,
+/// returning how many were removed.
+pub(crate) fn remove_unused(dir: &Path) -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in WalkDir::new(dir) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) if err.io_error().map(io::Error::kind) == Some(ErrorKind::NotFound) => {
+                continue
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let file = match File::open(entry.path()) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        // Whoever opens it next notices it's gone once they have the lock,
+        // and starts over with a new file.
+        if try_lock(&file)? && same_file(&file, entry.path())? {
+            match fs::remove_file(entry.path()) {
+                Ok(()) => removed += 1,
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn removes_only_unused_locks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let held = open_locked(&dir.join("a/held"), true).unwrap();
+        drop(open_locked(&dir.join("b/unused"), false).unwrap());
+        assert_eq!(remove_unused(dir).unwrap(), 1);
+        assert!(dir.join("a/held").exists());
+        assert!(!dir.join("b/unused").exists());
+        assert!(try_open_locked(&dir.join("a/held")).unwrap().is_none());
+        drop(held);
+        assert!(try_open_locked(&dir.join("a/held")).unwrap().is_some());
+    }
+
+    #[test]
+    fn shared_locks_dont_create_missing_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("a/missing");
+        assert!(open_shared_if_exists(&path).unwrap().is_none());
+        assert!(!tmp.path().join("a").exists());
+        drop(open_locked(&path, true).unwrap());
+        assert!(open_shared_if_exists(&path).unwrap().is_some());
+    }
+}
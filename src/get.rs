@@ -1,4 +1,5 @@
 //! Functions for reading from cache.
+use std::ops::{Bound, RangeBounds};
 use std::path::Path;
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 use std::pin::Pin;
@@ -8,10 +9,12 @@ use std::task::{Context as TaskContext, Poll};
 use ssri::{Algorithm, Integrity};
 
 #[cfg(any(feature = "async-std", feature = "tokio"))]
-use crate::async_lib::AsyncRead;
+use crate::async_lib::{AsyncRead, AsyncSeek, AsyncWriteExt};
+use crate::content::encrypt::EncryptionKey;
 use crate::content::read;
-use crate::errors::{Error, Result};
+use crate::errors::{Error, IoErrorExt, Result};
 use crate::index::{self, Metadata};
+use crate::memo;
 
 // ---------
 // Async API
@@ -21,6 +24,10 @@ use crate::index::{self, Metadata};
 ///
 /// Make sure to call `.check()` when done reading to verify that the
 /// extracted data passes integrity verification.
+///
+/// Seeking anywhere but back to the start gives up on verification, and
+/// makes `check()` fail. Content stored compressed, encrypted or chunked
+/// can't be seeked at all.
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 pub struct Reader {
     reader: read::AsyncReader,
@@ -47,6 +54,31 @@ impl AsyncRead for Reader {
     }
 }
 
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+impl AsyncSeek for Reader {
+    #[cfg(feature = "async-std")]
+    fn poll_seek(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        pos: std::io::SeekFrom,
+    ) -> Poll<std::io::Result<u64>> {
+        Pin::new(&mut self.reader).poll_seek(cx, pos)
+    }
+
+    #[cfg(feature = "tokio")]
+    fn start_seek(mut self: Pin<&mut Self>, pos: std::io::SeekFrom) -> std::io::Result<()> {
+        Pin::new(&mut self.reader).start_seek(pos)
+    }
+
+    #[cfg(feature = "tokio")]
+    fn poll_complete(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<u64>> {
+        Pin::new(&mut self.reader).poll_complete(cx)
+    }
+}
+
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 impl Reader {
     /// Checks that data read from disk passes integrity checks. Returns the
@@ -99,14 +131,7 @@ impl Reader {
         P: AsRef<Path>,
         K: AsRef<str>,
     {
-        async fn inner(cache: &Path, key: &str) -> Result<Reader> {
-            if let Some(entry) = index::find_async(cache, key).await? {
-                Reader::open_hash(cache, entry.integrity).await
-            } else {
-                Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
-            }
-        }
-        inner(cache.as_ref(), key.as_ref()).await
+        ReadOpts::new().open(cache, key).await
     }
 
     /// Opens a new file handle into the cache, based on its integrity address.
@@ -131,14 +156,13 @@ impl Reader {
     where
         P: AsRef<Path>,
     {
-        Ok(Reader {
-            reader: read::open_async(cache.as_ref(), sri).await?,
-        })
+        ReadOpts::new().open_hash(cache, sri).await
     }
 }
 
 /// Reads the entire contents of a cache file into a bytes vector, looking the
-/// data up by key.
+/// data up by key. Expired entries are treated as
+/// missing; use [`ReadOpts::allow_stale`] to read them anyway.
 ///
 /// ## Example
 /// ```no_run
@@ -157,14 +181,7 @@ where
     P: AsRef<Path>,
     K: AsRef<str>,
 {
-    async fn inner(cache: &Path, key: &str) -> Result<Vec<u8>> {
-        if let Some(entry) = index::find_async(cache, key).await? {
-            read_hash(cache, &entry.integrity).await
-        } else {
-            Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
-        }
-    }
-    inner(cache.as_ref(), key.as_ref()).await
+    ReadOpts::new().read(cache, key).await
 }
 
 /// Reads the entire contents of a cache file into a bytes vector, looking the
@@ -187,7 +204,111 @@ pub async fn read_hash<P>(cache: P, sri: &Integrity) -> Result<Vec<u8>>
 where
     P: AsRef<Path>,
 {
-    read::read_async(cache.as_ref(), sri).await
+    ReadOpts::new().read_hash(cache, sri).await
+}
+
+/// Memory-maps the contents of a cache file, looking the data up by key,
+/// instead of copying it onto the heap. The whole file is checked against
+/// its integrity before it's returned.
+///
+/// Content stored compressed or encrypted can't be mapped, and is decoded
+/// into memory instead.
+///
+/// ## Example
+/// ```no_run
+/// use async_attributes;
+///
+/// #[async_attributes::main]
+/// async fn main() -> cacache::Result<()> {
+///     let data = cacache::read_mmap("./my-cache", "my-key").await?;
+///     println!("{} bytes", data.len());
+///     Ok(())
+/// }
+/// ```
+#[cfg(all(feature = "mmap", any(feature = "async-std", feature = "tokio")))]
+pub async fn read_mmap<P, K>(cache: P, key: K) -> Result<MmapContent>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+{
+    ReadOpts::new().read_mmap(cache, key).await
+}
+
+/// Memory-maps the contents of a cache file, looking the data up by its
+/// content address. See [`read_mmap`] for details.
+///
+/// ## Example
+/// ```no_run
+/// use async_attributes;
+///
+/// #[async_attributes::main]
+/// async fn main() -> cacache::Result<()> {
+///     let sri = cacache::write("./my-cache", "my-key", b"hello").await?;
+///     let data = cacache::read_hash_mmap("./my-cache", &sri).await?;
+///     assert_eq!(&data[..], b"hello");
+///     Ok(())
+/// }
+/// ```
+#[cfg(all(feature = "mmap", any(feature = "async-std", feature = "tokio")))]
+pub async fn read_hash_mmap<P>(cache: P, sri: &Integrity) -> Result<MmapContent>
+where
+    P: AsRef<Path>,
+{
+    ReadOpts::new().read_hash_mmap(cache, sri).await
+}
+
+/// Reads a range of bytes from a cache file, looking the data up by key.
+/// Ranges that run past the end of the data are cut short.
+///
+/// Only the requested bytes are read, so they **aren't** checked against the
+/// content's integrity. Use [`read`] when that matters, or write the content
+/// with [`crate::WriteOpts::chunking`], in which case every chunk the range
+/// spans is checked.
+///
+/// ## Example
+/// ```no_run
+/// use async_attributes;
+///
+/// #[async_attributes::main]
+/// async fn main() -> cacache::Result<()> {
+///     cacache::write("./my-cache", "my-key", b"hello world").await?;
+///     let data = cacache::read_range("./my-cache", "my-key", 6..).await?;
+///     assert_eq!(data, b"world");
+///     Ok(())
+/// }
+/// ```
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub async fn read_range<P, K, R>(cache: P, key: K, range: R) -> Result<Vec<u8>>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+    R: RangeBounds<u64>,
+{
+    ReadOpts::new().read_range(cache, key, range).await
+}
+
+/// Reads a range of bytes from a cache file, looking the data up by its
+/// content address. See [`read_range`] for details.
+///
+/// ## Example
+/// ```no_run
+/// use async_attributes;
+///
+/// #[async_attributes::main]
+/// async fn main() -> cacache::Result<()> {
+///     let sri = cacache::write("./my-cache", "my-key", b"hello world").await?;
+///     let data = cacache::read_hash_range("./my-cache", &sri, 0..5).await?;
+///     assert_eq!(data, b"hello");
+///     Ok(())
+/// }
+/// ```
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub async fn read_hash_range<P, R>(cache: P, sri: &Integrity, range: R) -> Result<Vec<u8>>
+where
+    P: AsRef<Path>,
+    R: RangeBounds<u64>,
+{
+    ReadOpts::new().read_hash_range(cache, sri, range).await
 }
 
 /// Copies cache data to a specified location. Returns the number of bytes
@@ -211,14 +332,7 @@ where
     K: AsRef<str>,
     Q: AsRef<Path>,
 {
-    async fn inner(cache: &Path, key: &str, to: &Path) -> Result<u64> {
-        if let Some(entry) = index::find_async(cache, key).await? {
-            copy_hash(cache, &entry.integrity, to).await
-        } else {
-            Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
-        }
-    }
-    inner(cache.as_ref(), key.as_ref(), to.as_ref()).await
+    ReadOpts::new().copy(cache, key, to).await
 }
 
 /// Copies cache data to a specified location. Cache data will not be checked
@@ -242,14 +356,7 @@ where
     K: AsRef<str>,
     Q: AsRef<Path>,
 {
-    async fn inner(cache: &Path, key: &str, to: &Path) -> Result<u64> {
-        if let Some(entry) = index::find_async(cache, key).await? {
-            copy_hash_unchecked(cache, &entry.integrity, to).await
-        } else {
-            Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
-        }
-    }
-    inner(cache.as_ref(), key.as_ref(), to.as_ref()).await
+    ReadOpts::new().copy_unchecked(cache, key, to).await
 }
 
 /// Copies a cache data by hash to a specified location. Returns the number of
@@ -273,7 +380,7 @@ where
     P: AsRef<Path>,
     Q: AsRef<Path>,
 {
-    read::copy_async(cache.as_ref(), sri, to.as_ref()).await
+    ReadOpts::new().copy_hash(cache, sri, to).await
 }
 
 /// Copies a cache data by hash to a specified location. Copied data will not
@@ -297,7 +404,7 @@ where
     P: AsRef<Path>,
     Q: AsRef<Path>,
 {
-    read::copy_unchecked_async(cache.as_ref(), sri, to.as_ref()).await
+    ReadOpts::new().copy_hash_unchecked(cache, sri, to).await
 }
 
 /// Creates a reflink/clonefile from a cache entry to a destination path.
@@ -327,7 +434,8 @@ where
     Q: AsRef<Path>,
 {
     async fn inner(cache: &Path, key: &str, to: &Path) -> Result<()> {
-        if let Some(entry) = index::find_async(cache, key).await? {
+        if let Some(entry) = find_fresh_async(cache, key, &ReadOpts::new()).await? {
+            index::touch_async(cache, key).await;
             reflink_hash(cache, &entry.integrity, to).await
         } else {
             Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
@@ -364,7 +472,8 @@ where
     Q: AsRef<Path>,
 {
     async fn inner(cache: &Path, key: &str, to: &Path) -> Result<()> {
-        if let Some(entry) = index::find_async(cache, key).await? {
+        if let Some(entry) = find_fresh_async(cache, key, &ReadOpts::new()).await? {
+            index::touch_async(cache, key).await;
             reflink_hash_unchecked_sync(cache, &entry.integrity, to)
         } else {
             Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
@@ -411,7 +520,8 @@ where
     Q: AsRef<Path>,
 {
     async fn inner(cache: &Path, key: &str, to: &Path) -> Result<()> {
-        if let Some(entry) = index::find(cache, key)? {
+        if let Some(entry) = find_fresh(cache, key, &ReadOpts::new())? {
+            index::touch_async(cache, key).await;
             read::hard_link_async(cache, &entry.integrity, to).await
         } else {
             Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
@@ -431,13 +541,14 @@ where
     P: AsRef<Path>,
     K: AsRef<str>,
 {
-    index::find_async(cache.as_ref(), key.as_ref()).await
+    ReadOpts::new().metadata(cache, key).await
 }
 
 /// Returns true if the given hash exists in the cache.
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 pub async fn exists<P: AsRef<Path>>(cache: P, sri: &Integrity) -> bool {
     read::has_content_async(cache.as_ref(), sri).await.is_some()
+        || matches!(find_inline_async(cache.as_ref(), sri).await, Ok(Some(_)))
 }
 
 // ---------------
@@ -449,6 +560,10 @@ pub async fn exists<P: AsRef<Path>>(cache: P, sri: &Integrity) -> bool {
 /// Make sure to call `get.check()` when done reading
 /// to verify that the extracted data passes integrity
 /// verification.
+///
+/// Seeking anywhere but back to the start gives up on verification, and
+/// makes `check()` fail. Content stored compressed, encrypted or chunked
+/// can't be seeked at all.
 pub struct SyncReader {
     reader: read::Reader,
 }
@@ -459,6 +574,12 @@ impl std::io::Read for SyncReader {
     }
 }
 
+impl std::io::Seek for SyncReader {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.reader.seek(pos)
+    }
+}
+
 impl SyncReader {
     /// Checks that data read from disk passes integrity checks. Returns the
     /// algorithm that was used verified the data. Should be called only after
@@ -502,14 +623,7 @@ impl SyncReader {
         P: AsRef<Path>,
         K: AsRef<str>,
     {
-        fn inner(cache: &Path, key: &str) -> Result<SyncReader> {
-            if let Some(entry) = index::find(cache, key)? {
-                SyncReader::open_hash(cache, entry.integrity)
-            } else {
-                Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
-            }
-        }
-        inner(cache.as_ref(), key.as_ref())
+        ReadOpts::new().open_sync(cache, key)
     }
 
     /// Opens a new synchronous file handle into the cache, based on its integrity address.
@@ -532,14 +646,13 @@ impl SyncReader {
     where
         P: AsRef<Path>,
     {
-        Ok(SyncReader {
-            reader: read::open(cache.as_ref(), sri)?,
-        })
+        ReadOpts::new().open_hash_sync(cache, sri)
     }
 }
 
 /// Reads the entire contents of a cache file synchronously into a bytes
-/// vector, looking the data up by key.
+/// vector, looking the data up by key. Expired entries are treated as
+/// missing; use [`ReadOpts::allow_stale`] to read them anyway.
 ///
 /// ## Example
 /// ```no_run
@@ -555,14 +668,7 @@ where
     P: AsRef<Path>,
     K: AsRef<str>,
 {
-    fn inner(cache: &Path, key: &str) -> Result<Vec<u8>> {
-        if let Some(entry) = index::find(cache, key)? {
-            read_hash_sync(cache, &entry.integrity)
-        } else {
-            Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
-        }
-    }
-    inner(cache.as_ref(), key.as_ref())
+    ReadOpts::new().read_sync(cache, key)
 }
 
 /// Reads the entire contents of a cache file synchronously into a bytes
@@ -582,7 +688,88 @@ pub fn read_hash_sync<P>(cache: P, sri: &Integrity) -> Result<Vec<u8>>
 where
     P: AsRef<Path>,
 {
-    read::read(cache.as_ref(), sri)
+    ReadOpts::new().read_hash_sync(cache, sri)
+}
+
+/// Synchronously reads a range of bytes from a cache file, looking the data
+/// up by key. See [`read_range`] for details.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache::Result<()> {
+///     cacache::write_sync("./my-cache", "my-key", b"hello world")?;
+///     let data = cacache::read_range_sync("./my-cache", "my-key", 6..)?;
+///     assert_eq!(data, b"world");
+///     Ok(())
+/// }
+/// ```
+pub fn read_range_sync<P, K, R>(cache: P, key: K, range: R) -> Result<Vec<u8>>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+    R: RangeBounds<u64>,
+{
+    ReadOpts::new().read_range_sync(cache, key, range)
+}
+
+/// Synchronously reads a range of bytes from a cache file, looking the data
+/// up by its content address. See [`read_range`] for details.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache::Result<()> {
+///     let sri = cacache::write_sync("./my-cache", "my-key", b"hello world")?;
+///     let data = cacache::read_hash_range_sync("./my-cache", &sri, 0..5)?;
+///     assert_eq!(data, b"hello");
+///     Ok(())
+/// }
+/// ```
+pub fn read_hash_range_sync<P, R>(cache: P, sri: &Integrity, range: R) -> Result<Vec<u8>>
+where
+    P: AsRef<Path>,
+    R: RangeBounds<u64>,
+{
+    ReadOpts::new().read_hash_range_sync(cache, sri, range)
+}
+
+/// Memory-maps the contents of a cache file synchronously, looking the data
+/// up by key. See [`read_mmap`] for details.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache::Result<()> {
+///     let data = cacache::read_mmap_sync("./my-cache", "my-key")?;
+///     println!("{} bytes", data.len());
+///     Ok(())
+/// }
+/// ```
+#[cfg(feature = "mmap")]
+pub fn read_mmap_sync<P, K>(cache: P, key: K) -> Result<MmapContent>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+{
+    ReadOpts::new().read_mmap_sync(cache, key)
+}
+
+/// Memory-maps the contents of a cache file synchronously, looking the data
+/// up by its content address. See [`read_mmap`] for details.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache::Result<()> {
+///     let sri = cacache::write_sync("./my-cache", "my-key", b"hello")?;
+///     let data = cacache::read_hash_mmap_sync("./my-cache", &sri)?;
+///     assert_eq!(&data[..], b"hello");
+///     Ok(())
+/// }
+/// ```
+#[cfg(feature = "mmap")]
+pub fn read_hash_mmap_sync<P>(cache: P, sri: &Integrity) -> Result<MmapContent>
+where
+    P: AsRef<Path>,
+{
+    ReadOpts::new().read_hash_mmap_sync(cache, sri)
 }
 
 /// Copies a cache entry by key to a specified location. Returns the number of
@@ -606,14 +793,7 @@ where
     K: AsRef<str>,
     Q: AsRef<Path>,
 {
-    fn inner(cache: &Path, key: &str, to: &Path) -> Result<u64> {
-        if let Some(entry) = index::find(cache, key)? {
-            copy_hash_sync(cache, &entry.integrity, to)
-        } else {
-            Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
-        }
-    }
-    inner(cache.as_ref(), key.as_ref(), to.as_ref())
+    ReadOpts::new().copy_sync(cache, key, to)
 }
 
 /// Copies a cache entry by key to a specified location. Does not verify cache
@@ -637,14 +817,7 @@ where
     K: AsRef<str>,
     Q: AsRef<Path>,
 {
-    fn inner(cache: &Path, key: &str, to: &Path) -> Result<u64> {
-        if let Some(entry) = index::find(cache, key)? {
-            copy_hash_unchecked_sync(cache, &entry.integrity, to)
-        } else {
-            Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
-        }
-    }
-    inner(cache.as_ref(), key.as_ref(), to.as_ref())
+    ReadOpts::new().copy_unchecked_sync(cache, key, to)
 }
 
 /// Copies a cache entry by integrity address to a specified location. Returns
@@ -668,7 +841,7 @@ where
     P: AsRef<Path>,
     Q: AsRef<Path>,
 {
-    read::copy(cache.as_ref(), sri, to.as_ref())
+    ReadOpts::new().copy_hash_sync(cache, sri, to)
 }
 
 /// Copies a cache entry by integrity address to a specified location. Does
@@ -692,7 +865,7 @@ where
     P: AsRef<Path>,
     Q: AsRef<Path>,
 {
-    read::copy_unchecked(cache.as_ref(), sri, to.as_ref())
+    ReadOpts::new().copy_hash_unchecked_sync(cache, sri, to)
 }
 
 /// Creates a reflink/clonefile from a cache entry to a destination path.
@@ -721,7 +894,8 @@ where
     Q: AsRef<Path>,
 {
     fn inner(cache: &Path, key: &str, to: &Path) -> Result<()> {
-        if let Some(entry) = index::find(cache, key)? {
+        if let Some(entry) = find_fresh(cache, key, &ReadOpts::new())? {
+            index::touch(cache, key);
             reflink_hash_sync(cache, &entry.integrity, to)
         } else {
             Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
@@ -814,7 +988,8 @@ where
     Q: AsRef<Path>,
 {
     fn inner(cache: &Path, key: &str, to: &Path) -> Result<()> {
-        if let Some(entry) = index::find(cache, key)? {
+        if let Some(entry) = find_fresh(cache, key, &ReadOpts::new())? {
+            index::touch(cache, key);
             reflink_hash_unchecked_sync(cache, &entry.integrity, to)
         } else {
             Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
@@ -834,7 +1009,8 @@ where
     Q: AsRef<Path>,
 {
     fn inner(cache: &Path, key: &str, to: &Path) -> Result<()> {
-        if let Some(entry) = index::find(cache, key)? {
+        if let Some(entry) = find_fresh(cache, key, &ReadOpts::new())? {
+            index::touch(cache, key);
             hard_link_hash_unchecked_sync(cache, &entry.integrity, to)
         } else {
             Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
@@ -851,7 +1027,8 @@ where
     Q: AsRef<Path>,
 {
     fn inner(cache: &Path, key: &str, to: &Path) -> Result<()> {
-        if let Some(entry) = index::find(cache, key)? {
+        if let Some(entry) = find_fresh(cache, key, &ReadOpts::new())? {
+            index::touch(cache, key);
             read::hard_link(cache, &entry.integrity, to)
         } else {
             Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
@@ -892,96 +1069,813 @@ where
     P: AsRef<Path>,
     K: AsRef<str>,
 {
-    index::find(cache.as_ref(), key.as_ref())
+    ReadOpts::new().metadata_sync(cache, key)
 }
 
 /// Returns true if the given hash exists in the cache.
 pub fn exists_sync<P: AsRef<Path>>(cache: P, sri: &Integrity) -> bool {
     read::has_content(cache.as_ref(), sri).is_some()
+        || matches!(find_inline(cache.as_ref(), sri), Ok(Some(_)))
 }
 
-#[cfg(test)]
-mod tests {
-    #[cfg(any(feature = "async-std", feature = "tokio"))]
-    use crate::async_lib::AsyncReadExt;
-    use std::fs;
+// ------------
+// Mapped reads
+// ------------
 
-    #[cfg(feature = "async-std")]
-    use async_attributes::test as async_test;
-    #[cfg(feature = "tokio")]
-    use tokio::test as async_test;
+/// Integrity-checked contents of a cache file, mapped into memory. Derefs to
+/// the content's bytes.
+///
+/// The map stays valid even if the content is removed from the cache while
+/// it's alive.
+#[cfg(feature = "mmap")]
+pub struct MmapContent {
+    inner: read::Mapped,
+}
 
-    #[cfg(any(feature = "async-std", feature = "tokio"))]
-    #[async_test]
-    async fn test_open() {
-        let tmp = tempfile::tempdir().unwrap();
-        let dir = tmp.path().to_owned();
-        crate::write(&dir, "my-key", b"hello world").await.unwrap();
+#[cfg(feature = "mmap")]
+impl std::ops::Deref for MmapContent {
+    type Target = [u8];
 
-        let mut handle = crate::Reader::open(&dir, "my-key").await.unwrap();
-        let mut str = String::new();
-        handle.read_to_string(&mut str).await.unwrap();
-        handle.check().unwrap();
-        assert_eq!(str, String::from("hello world"));
+    fn deref(&self) -> &[u8] {
+        &self.inner
     }
+}
 
-    #[cfg(any(feature = "async-std", feature = "tokio"))]
-    #[async_test]
-    async fn test_open_hash() {
-        let tmp = tempfile::tempdir().unwrap();
-        let dir = tmp.path().to_owned();
-        let sri = crate::write(&dir, "my-key", b"hello world").await.unwrap();
-
-        let mut handle = crate::Reader::open_hash(&dir, sri).await.unwrap();
-        let mut str = String::new();
-        handle.read_to_string(&mut str).await.unwrap();
-        handle.check().unwrap();
-        assert_eq!(str, String::from("hello world"));
+#[cfg(feature = "mmap")]
+impl AsRef<[u8]> for MmapContent {
+    fn as_ref(&self) -> &[u8] {
+        &self.inner
     }
+}
 
-    #[test]
-    fn test_open_sync() {
-        use std::io::prelude::*;
-        let tmp = tempfile::tempdir().unwrap();
-        let dir = tmp.path().to_owned();
-        crate::write_sync(&dir, "my-key", b"hello world").unwrap();
-
-        let mut handle = crate::SyncReader::open(&dir, "my-key").unwrap();
-        let mut str = String::new();
-        handle.read_to_string(&mut str).unwrap();
-        handle.check().unwrap();
-        assert_eq!(str, String::from("hello world"));
+#[cfg(feature = "mmap")]
+impl std::fmt::Debug for MmapContent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MmapContent")
+            .field("len", &self.len())
+            .finish()
     }
+}
 
-    #[test]
-    fn test_open_hash_sync() {
-        use std::io::prelude::*;
-        let tmp = tempfile::tempdir().unwrap();
-        let dir = tmp.path().to_owned();
-        let sri = crate::write_sync(&dir, "my-key", b"hello world").unwrap();
+// ------------
+// Read options
+// ------------
 
-        let mut handle = crate::SyncReader::open_hash(&dir, sri).unwrap();
-        let mut str = String::new();
-        handle.read_to_string(&mut str).unwrap();
-        handle.check().unwrap();
-        assert_eq!(str, String::from("hello world"));
+/// Options for reading entries from the cache by key.
+///
+/// By default, entries written with [`crate::WriteOpts::ttl`] or
+/// [`crate::WriteOpts::expires_at`] are treated as missing once they expire.
+#[derive(Clone, Default)]
+pub struct ReadOpts {
+    pub(crate) allow_stale: bool,
+    pub(crate) encryption_key: Option<EncryptionKey>,
+    pub(crate) read_only: bool,
+}
+
+impl ReadOpts {
+    /// Creates a default set of cache reading options.
+    pub fn new() -> Self {
+        Default::default()
     }
 
-    #[cfg(any(feature = "async-std", feature = "tokio"))]
-    #[async_test]
-    async fn test_read() {
-        let tmp = tempfile::tempdir().unwrap();
-        let dir = tmp.path().to_owned();
-        crate::write(&dir, "my-key", b"hello world").await.unwrap();
+    /// Set whether to return entries even after they've expired.
+    pub fn allow_stale(mut self, allow_stale: bool) -> Self {
+        self.allow_stale = allow_stale;
+        self
+    }
 
-        let data = crate::read(&dir, "my-key").await.unwrap();
-        assert_eq!(data, b"hello world");
+    /// Sets the key used to decrypt data and metadata written with
+    /// [`crate::WriteOpts::encrypt`]. Reading encrypted data without it
+    /// fails.
+    #[cfg(feature = "encryption")]
+    pub fn encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.encryption_key = Some(key);
+        self
     }
 
-    #[cfg(any(feature = "async-std", feature = "tokio"))]
-    #[async_test]
-    async fn test_read_hash() {
-        let tmp = tempfile::tempdir().unwrap();
+    /// Reads the entire contents of a cache file into a bytes vector, looking
+    /// the data up by key.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use async_attributes;
+    ///
+    /// #[async_attributes::main]
+    /// async fn main() -> cacache::Result<()> {
+    ///     let data = cacache::ReadOpts::new()
+    ///         .allow_stale(true)
+    ///         .read("./my-cache", "my-key")
+    ///         .await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn read<P, K>(self, cache: P, key: K) -> Result<Vec<u8>>
+    where
+        P: AsRef<Path>,
+        K: AsRef<str>,
+    {
+        async fn inner(me: ReadOpts, cache: &Path, key: &str) -> Result<Vec<u8>> {
+            if let Some(entry) = find_fresh_async(cache, key, &me).await? {
+                me.touch_async(cache, key).await;
+                if let Some(data) = entry.inline {
+                    entry.integrity.check(&data)?;
+                    return Ok(data);
+                }
+                me.read_hash(cache, &entry.integrity).await
+            } else {
+                Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
+            }
+        }
+        inner(self, cache.as_ref(), key.as_ref()).await
+    }
+
+    /// Opens a new file handle into the cache, looking it up in the index
+    /// using `key`.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn open<P, K>(self, cache: P, key: K) -> Result<Reader>
+    where
+        P: AsRef<Path>,
+        K: AsRef<str>,
+    {
+        async fn inner(me: ReadOpts, cache: &Path, key: &str) -> Result<Reader> {
+            if let Some(entry) = find_fresh_async(cache, key, &me).await? {
+                me.touch_async(cache, key).await;
+                me.open_hash(cache, entry.integrity).await
+            } else {
+                Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
+            }
+        }
+        inner(self, cache.as_ref(), key.as_ref()).await
+    }
+
+    /// Gets the metadata entry for a certain key.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn metadata<P, K>(self, cache: P, key: K) -> Result<Option<Metadata>>
+    where
+        P: AsRef<Path>,
+        K: AsRef<str>,
+    {
+        find_fresh_async(cache.as_ref(), key.as_ref(), &self).await
+    }
+
+    /// Reads the entire contents of a cache file into a bytes vector, looking
+    /// the data up by its content address.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn read_hash<P>(&self, cache: P, sri: &Integrity) -> Result<Vec<u8>>
+    where
+        P: AsRef<Path>,
+    {
+        let cache = cache.as_ref();
+        let memoize = self.encryption_key.is_none();
+        if let Some(data) = memoize.then(|| memo::content(cache, sri)).flatten() {
+            return Ok(data.to_vec());
+        }
+        let data = match find_inline_async(cache, sri).await? {
+            Some(data) => {
+                sri.check(&data)?;
+                data
+            }
+            None => read::read_async(cache, sri, self.encryption_key.as_ref()).await?,
+        };
+        if memoize {
+            memo::put_content(cache, sri, &data);
+        }
+        Ok(data)
+    }
+
+    /// Opens a new file handle into the cache, based on its integrity address.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn open_hash<P>(&self, cache: P, sri: Integrity) -> Result<Reader>
+    where
+        P: AsRef<Path>,
+    {
+        let cache = cache.as_ref();
+        if let Some(data) = find_inline_async(cache, &sri).await? {
+            return Ok(Reader {
+                reader: read::AsyncReader::in_memory(data, sri),
+            });
+        }
+        Ok(Reader {
+            reader: read::open_async(cache, sri, self.encryption_key.as_ref()).await?,
+        })
+    }
+
+    /// Reads a range of bytes from a cache file, looking the data up by key.
+    /// See [`read_range`] for details.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn read_range<P, K, R>(self, cache: P, key: K, range: R) -> Result<Vec<u8>>
+    where
+        P: AsRef<Path>,
+        K: AsRef<str>,
+        R: RangeBounds<u64>,
+    {
+        async fn inner(
+            me: ReadOpts,
+            cache: &Path,
+            key: &str,
+            range: (Bound<u64>, Bound<u64>),
+        ) -> Result<Vec<u8>> {
+            if let Some(entry) = find_fresh_async(cache, key, &me).await? {
+                me.touch_async(cache, key).await;
+                me.read_hash_range(cache, &entry.integrity, range).await
+            } else {
+                Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
+            }
+        }
+        inner(
+            self,
+            cache.as_ref(),
+            key.as_ref(),
+            (range.start_bound().cloned(), range.end_bound().cloned()),
+        )
+        .await
+    }
+
+    /// Reads a range of bytes from a cache file, looking the data up by its
+    /// content address.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn read_hash_range<P, R>(
+        &self,
+        cache: P,
+        sri: &Integrity,
+        range: R,
+    ) -> Result<Vec<u8>>
+    where
+        P: AsRef<Path>,
+        R: RangeBounds<u64>,
+    {
+        let cache = cache.as_ref();
+        let (start, end) = bounds(range);
+        if let Some(data) = find_inline_async(cache, sri).await? {
+            sri.check(&data)?;
+            return Ok(read::slice_range(data, start, end));
+        }
+        read::read_range_async(cache, sri, start, end, self.encryption_key.as_ref()).await
+    }
+
+    /// Copies cache data to a specified location, looking it up by key.
+    /// Returns the number of bytes copied. See [`copy`] for details.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn copy<P, K, Q>(self, cache: P, key: K, to: Q) -> Result<u64>
+    where
+        P: AsRef<Path>,
+        K: AsRef<str>,
+        Q: AsRef<Path>,
+    {
+        async fn inner(me: ReadOpts, cache: &Path, key: &str, to: &Path) -> Result<u64> {
+            if let Some(entry) = find_fresh_async(cache, key, &me).await? {
+                me.touch_async(cache, key).await;
+                me.copy_hash(cache, &entry.integrity, to).await
+            } else {
+                Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
+            }
+        }
+        inner(self, cache.as_ref(), key.as_ref(), to.as_ref()).await
+    }
+
+    /// Copies cache data to a specified location, looking it up by key,
+    /// without checking it. See [`copy_unchecked`] for details.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn copy_unchecked<P, K, Q>(self, cache: P, key: K, to: Q) -> Result<u64>
+    where
+        P: AsRef<Path>,
+        K: AsRef<str>,
+        Q: AsRef<Path>,
+    {
+        async fn inner(me: ReadOpts, cache: &Path, key: &str, to: &Path) -> Result<u64> {
+            if let Some(entry) = find_fresh_async(cache, key, &me).await? {
+                me.touch_async(cache, key).await;
+                me.copy_hash_unchecked(cache, &entry.integrity, to).await
+            } else {
+                Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
+            }
+        }
+        inner(self, cache.as_ref(), key.as_ref(), to.as_ref()).await
+    }
+
+    /// Copies cache data to a specified location, looking it up by its
+    /// content address. Returns the number of bytes copied.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn copy_hash<P, Q>(&self, cache: P, sri: &Integrity, to: Q) -> Result<u64>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let (cache, to) = (cache.as_ref(), to.as_ref());
+        if let Some(data) = find_inline_async(cache, sri).await? {
+            sri.check(&data)?;
+            return write_inline_async(&data, to).await;
+        }
+        read::copy_async(cache, sri, to, self.encryption_key.as_ref()).await
+    }
+
+    /// Copies cache data to a specified location, looking it up by its
+    /// content address, without checking it.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn copy_hash_unchecked<P, Q>(&self, cache: P, sri: &Integrity, to: Q) -> Result<u64>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let (cache, to) = (cache.as_ref(), to.as_ref());
+        if let Some(data) = find_inline_async(cache, sri).await? {
+            return write_inline_async(&data, to).await;
+        }
+        read::copy_unchecked_async(cache, sri, to, self.encryption_key.as_ref()).await
+    }
+
+    /// Memory-maps the contents of a cache file, looking the data up by key.
+    /// See [`read_mmap`] for details.
+    #[cfg(all(feature = "mmap", any(feature = "async-std", feature = "tokio")))]
+    pub async fn read_mmap<P, K>(self, cache: P, key: K) -> Result<MmapContent>
+    where
+        P: AsRef<Path>,
+        K: AsRef<str>,
+    {
+        async fn inner(me: ReadOpts, cache: &Path, key: &str) -> Result<MmapContent> {
+            if let Some(entry) = find_fresh_async(cache, key, &me).await? {
+                me.touch_async(cache, key).await;
+                me.read_hash_mmap(cache, &entry.integrity).await
+            } else {
+                Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
+            }
+        }
+        inner(self, cache.as_ref(), key.as_ref()).await
+    }
+
+    /// Memory-maps the contents of a cache file, looking the data up by its
+    /// content address.
+    #[cfg(all(feature = "mmap", any(feature = "async-std", feature = "tokio")))]
+    pub async fn read_hash_mmap<P>(&self, cache: P, sri: &Integrity) -> Result<MmapContent>
+    where
+        P: AsRef<Path>,
+    {
+        if let Some(data) = find_inline_async(cache.as_ref(), sri).await? {
+            sri.check(&data)?;
+            return Ok(MmapContent {
+                inner: read::Mapped::Owned(data),
+            });
+        }
+        let cache = cache.as_ref().to_path_buf();
+        let sri = sri.clone();
+        let key = self.encryption_key.clone();
+        let inner = crate::async_lib::unwrap_joinhandle_value(
+            crate::async_lib::spawn_blocking(move || read::read_mmap(&cache, &sri, key.as_ref()))
+                .await,
+        )?;
+        Ok(MmapContent { inner })
+    }
+
+    /// Reads the entire contents of a cache file synchronously into a bytes
+    /// vector, looking the data up by key.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// fn main() -> cacache::Result<()> {
+    ///     let data = cacache::ReadOpts::new()
+    ///         .allow_stale(true)
+    ///         .read_sync("./my-cache", "my-key")?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn read_sync<P, K>(self, cache: P, key: K) -> Result<Vec<u8>>
+    where
+        P: AsRef<Path>,
+        K: AsRef<str>,
+    {
+        fn inner(me: ReadOpts, cache: &Path, key: &str) -> Result<Vec<u8>> {
+            if let Some(entry) = find_fresh(cache, key, &me)? {
+                me.touch(cache, key);
+                if let Some(data) = entry.inline {
+                    entry.integrity.check(&data)?;
+                    return Ok(data);
+                }
+                me.read_hash_sync(cache, &entry.integrity)
+            } else {
+                Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
+            }
+        }
+        inner(self, cache.as_ref(), key.as_ref())
+    }
+
+    /// Opens a new synchronous file handle into the cache, looking it up in
+    /// the index using `key`.
+    pub fn open_sync<P, K>(self, cache: P, key: K) -> Result<SyncReader>
+    where
+        P: AsRef<Path>,
+        K: AsRef<str>,
+    {
+        fn inner(me: ReadOpts, cache: &Path, key: &str) -> Result<SyncReader> {
+            if let Some(entry) = find_fresh(cache, key, &me)? {
+                me.touch(cache, key);
+                me.open_hash_sync(cache, entry.integrity)
+            } else {
+                Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
+            }
+        }
+        inner(self, cache.as_ref(), key.as_ref())
+    }
+
+    /// Gets metadata for a certain key.
+    pub fn metadata_sync<P, K>(self, cache: P, key: K) -> Result<Option<Metadata>>
+    where
+        P: AsRef<Path>,
+        K: AsRef<str>,
+    {
+        find_fresh(cache.as_ref(), key.as_ref(), &self)
+    }
+
+    /// Reads the entire contents of a cache file synchronously into a bytes
+    /// vector, looking the data up by its content address.
+    pub fn read_hash_sync<P>(&self, cache: P, sri: &Integrity) -> Result<Vec<u8>>
+    where
+        P: AsRef<Path>,
+    {
+        let cache = cache.as_ref();
+        let memoize = self.encryption_key.is_none();
+        if let Some(data) = memoize.then(|| memo::content(cache, sri)).flatten() {
+            return Ok(data.to_vec());
+        }
+        let data = match find_inline(cache, sri)? {
+            Some(data) => {
+                sri.check(&data)?;
+                data
+            }
+            None => read::read(cache, sri, self.encryption_key.as_ref())?,
+        };
+        if memoize {
+            memo::put_content(cache, sri, &data);
+        }
+        Ok(data)
+    }
+
+    /// Synchronously reads a range of bytes from a cache file, looking the
+    /// data up by key.
+    pub fn read_range_sync<P, K, R>(self, cache: P, key: K, range: R) -> Result<Vec<u8>>
+    where
+        P: AsRef<Path>,
+        K: AsRef<str>,
+        R: RangeBounds<u64>,
+    {
+        fn inner(
+            me: ReadOpts,
+            cache: &Path,
+            key: &str,
+            range: (Bound<u64>, Bound<u64>),
+        ) -> Result<Vec<u8>> {
+            if let Some(entry) = find_fresh(cache, key, &me)? {
+                me.touch(cache, key);
+                me.read_hash_range_sync(cache, &entry.integrity, range)
+            } else {
+                Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
+            }
+        }
+        inner(
+            self,
+            cache.as_ref(),
+            key.as_ref(),
+            (range.start_bound().cloned(), range.end_bound().cloned()),
+        )
+    }
+
+    /// Synchronously reads a range of bytes from a cache file, looking the
+    /// data up by its content address.
+    pub fn read_hash_range_sync<P, R>(&self, cache: P, sri: &Integrity, range: R) -> Result<Vec<u8>>
+    where
+        P: AsRef<Path>,
+        R: RangeBounds<u64>,
+    {
+        let cache = cache.as_ref();
+        let (start, end) = bounds(range);
+        if let Some(data) = find_inline(cache, sri)? {
+            sri.check(&data)?;
+            return Ok(read::slice_range(data, start, end));
+        }
+        read::read_range(cache, sri, start, end, self.encryption_key.as_ref())
+    }
+
+    /// Synchronously copies cache data to a specified location, looking it
+    /// up by key. Returns the number of bytes copied.
+    pub fn copy_sync<P, K, Q>(self, cache: P, key: K, to: Q) -> Result<u64>
+    where
+        P: AsRef<Path>,
+        K: AsRef<str>,
+        Q: AsRef<Path>,
+    {
+        fn inner(me: ReadOpts, cache: &Path, key: &str, to: &Path) -> Result<u64> {
+            if let Some(entry) = find_fresh(cache, key, &me)? {
+                me.touch(cache, key);
+                me.copy_hash_sync(cache, &entry.integrity, to)
+            } else {
+                Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
+            }
+        }
+        inner(self, cache.as_ref(), key.as_ref(), to.as_ref())
+    }
+
+    /// Synchronously copies cache data to a specified location, looking it
+    /// up by key, without checking it.
+    pub fn copy_unchecked_sync<P, K, Q>(self, cache: P, key: K, to: Q) -> Result<u64>
+    where
+        P: AsRef<Path>,
+        K: AsRef<str>,
+        Q: AsRef<Path>,
+    {
+        fn inner(me: ReadOpts, cache: &Path, key: &str, to: &Path) -> Result<u64> {
+            if let Some(entry) = find_fresh(cache, key, &me)? {
+                me.touch(cache, key);
+                me.copy_hash_unchecked_sync(cache, &entry.integrity, to)
+            } else {
+                Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
+            }
+        }
+        inner(self, cache.as_ref(), key.as_ref(), to.as_ref())
+    }
+
+    /// Synchronously copies cache data to a specified location, looking it
+    /// up by its content address. Returns the number of bytes copied.
+    pub fn copy_hash_sync<P, Q>(&self, cache: P, sri: &Integrity, to: Q) -> Result<u64>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let (cache, to) = (cache.as_ref(), to.as_ref());
+        if let Some(data) = find_inline(cache, sri)? {
+            sri.check(&data)?;
+            return write_inline(&data, to);
+        }
+        read::copy(cache, sri, to, self.encryption_key.as_ref())
+    }
+
+    /// Synchronously copies cache data to a specified location, looking it
+    /// up by its content address, without checking it.
+    pub fn copy_hash_unchecked_sync<P, Q>(&self, cache: P, sri: &Integrity, to: Q) -> Result<u64>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let (cache, to) = (cache.as_ref(), to.as_ref());
+        if let Some(data) = find_inline(cache, sri)? {
+            return write_inline(&data, to);
+        }
+        read::copy_unchecked(cache, sri, to, self.encryption_key.as_ref())
+    }
+
+    /// Memory-maps the contents of a cache file synchronously, looking the
+    /// data up by key.
+    #[cfg(feature = "mmap")]
+    pub fn read_mmap_sync<P, K>(self, cache: P, key: K) -> Result<MmapContent>
+    where
+        P: AsRef<Path>,
+        K: AsRef<str>,
+    {
+        fn inner(me: ReadOpts, cache: &Path, key: &str) -> Result<MmapContent> {
+            if let Some(entry) = find_fresh(cache, key, &me)? {
+                me.touch(cache, key);
+                me.read_hash_mmap_sync(cache, &entry.integrity)
+            } else {
+                Err(Error::EntryNotFound(cache.to_path_buf(), key.into()))
+            }
+        }
+        inner(self, cache.as_ref(), key.as_ref())
+    }
+
+    /// Memory-maps the contents of a cache file synchronously, looking the
+    /// data up by its content address.
+    #[cfg(feature = "mmap")]
+    pub fn read_hash_mmap_sync<P>(&self, cache: P, sri: &Integrity) -> Result<MmapContent>
+    where
+        P: AsRef<Path>,
+    {
+        let cache = cache.as_ref();
+        if let Some(data) = find_inline(cache, sri)? {
+            sri.check(&data)?;
+            return Ok(MmapContent {
+                inner: read::Mapped::Owned(data),
+            });
+        }
+        Ok(MmapContent {
+            inner: read::read_mmap(cache, sri, self.encryption_key.as_ref())?,
+        })
+    }
+
+    /// Opens a new synchronous file handle into the cache, based on its
+    /// integrity address.
+    pub fn open_hash_sync<P>(&self, cache: P, sri: Integrity) -> Result<SyncReader>
+    where
+        P: AsRef<Path>,
+    {
+        let cache = cache.as_ref();
+        if let Some(data) = find_inline(cache, &sri)? {
+            return Ok(SyncReader {
+                reader: read::Reader::in_memory(data, sri),
+            });
+        }
+        Ok(SyncReader {
+            reader: read::open(cache, sri, self.encryption_key.as_ref())?,
+        })
+    }
+    /// Records an access to `key`, unless these options come from a
+    /// read-only [`crate::Cache`].
+    pub(crate) fn touch(&self, cache: &Path, key: &str) {
+        if !self.read_only {
+            index::touch(cache, key);
+        }
+    }
+
+    /// Like [`ReadOpts::touch`], without blocking the async runtime.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub(crate) async fn touch_async(&self, cache: &Path, key: &str) {
+        if !self.read_only {
+            index::touch_async(cache, key).await;
+        }
+    }
+}
+
+/// The first byte in `range`, and the one just past it, if any.
+fn bounds(range: impl RangeBounds<u64>) -> (u64, Option<u64>) {
+    let start = match range.start_bound() {
+        Bound::Included(start) => *start,
+        Bound::Excluded(start) => start.saturating_add(1),
+        Bound::Unbounded => 0,
+    };
+    let end = match range.end_bound() {
+        Bound::Included(end) => Some(end.saturating_add(1)),
+        Bound::Excluded(end) => Some(*end),
+        Bound::Unbounded => None,
+    };
+    (start, end)
+}
+
+/// Looks up the index entry for `key`, treating it as missing if it has
+/// expired, unless `opts` allows stale entries.
+fn find_fresh(cache: &Path, key: &str, opts: &ReadOpts) -> Result<Option<Metadata>> {
+    let memoize = opts.encryption_key.is_none();
+    let entry = match memoize.then(|| memo::entry(cache, key)).flatten() {
+        Some(entry) => Some(entry),
+        None => {
+            let entry = index::find_with_key(cache, key, opts.encryption_key.as_ref())?;
+            if let Some(entry) = entry.as_ref().filter(|_| memoize) {
+                memo::put_entry(cache, entry);
+            }
+            entry
+        }
+    };
+    Ok(entry.filter(|entry| opts.allow_stale || !entry.is_expired()))
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+async fn find_fresh_async(cache: &Path, key: &str, opts: &ReadOpts) -> Result<Option<Metadata>> {
+    let memoize = opts.encryption_key.is_none();
+    let entry = match memoize.then(|| memo::entry(cache, key)).flatten() {
+        Some(entry) => Some(entry),
+        None => {
+            let entry =
+                index::find_with_key_async(cache, key, opts.encryption_key.as_ref()).await?;
+            if let Some(entry) = entry.as_ref().filter(|_| memoize) {
+                memo::put_entry(cache, entry);
+            }
+            entry
+        }
+    };
+    Ok(entry.filter(|entry| opts.allow_stale || !entry.is_expired()))
+}
+
+/// Data for `sri` that was stored inline in an index entry, when there's no
+/// content file for it. See [`crate::WriteOpts::inline_threshold`].
+fn find_inline(cache: &Path, sri: &Integrity) -> Result<Option<Vec<u8>>> {
+    if !index::may_have_inline(cache) || read::has_content(cache, sri).is_some() {
+        return Ok(None);
+    }
+    for key in crate::keys_for_hash_sync(cache, sri)? {
+        if let Some(data) = index::find(cache, &key)?.and_then(|entry| entry.inline) {
+            return Ok(Some(data));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+async fn find_inline_async(cache: &Path, sri: &Integrity) -> Result<Option<Vec<u8>>> {
+    if !index::may_have_inline_async(cache).await
+        || read::has_content_async(cache, sri).await.is_some()
+    {
+        return Ok(None);
+    }
+    for key in crate::keys_for_hash(cache, sri).await? {
+        if let Some(data) = index::find_async(cache, &key)
+            .await?
+            .and_then(|entry| entry.inline)
+        {
+            return Ok(Some(data));
+        }
+    }
+    Ok(None)
+}
+
+fn write_inline(data: &[u8], to: &Path) -> Result<u64> {
+    std::fs::write(to, data)
+        .with_context(|| format!("Failed to copy cache contents to {}", to.display()))?;
+    Ok(data.len() as u64)
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+async fn write_inline_async(data: &[u8], to: &Path) -> Result<u64> {
+    let mut file = crate::async_lib::File::create(to)
+        .await
+        .with_context(|| format!("Failed to copy cache contents to {}", to.display()))?;
+    file.write_all(data)
+        .await
+        .with_context(|| format!("Failed to copy cache contents to {}", to.display()))?;
+    file.flush()
+        .await
+        .with_context(|| format!("Failed to copy cache contents to {}", to.display()))?;
+    Ok(data.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    use crate::async_lib::AsyncReadExt;
+    use std::fs;
+
+    #[cfg(feature = "async-std")]
+    use async_attributes::test as async_test;
+    #[cfg(feature = "tokio")]
+    use tokio::test as async_test;
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    #[async_test]
+    async fn test_open() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::write(&dir, "my-key", b"hello world").await.unwrap();
+
+        let mut handle = crate::Reader::open(&dir, "my-key").await.unwrap();
+        let mut str = String::new();
+        handle.read_to_string(&mut str).await.unwrap();
+        handle.check().unwrap();
+        assert_eq!(str, String::from("hello world"));
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    #[async_test]
+    async fn test_open_hash() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri = crate::write(&dir, "my-key", b"hello world").await.unwrap();
+
+        let mut handle = crate::Reader::open_hash(&dir, sri).await.unwrap();
+        let mut str = String::new();
+        handle.read_to_string(&mut str).await.unwrap();
+        handle.check().unwrap();
+        assert_eq!(str, String::from("hello world"));
+    }
+
+    #[test]
+    fn test_open_sync() {
+        use std::io::prelude::*;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::write_sync(&dir, "my-key", b"hello world").unwrap();
+
+        let mut handle = crate::SyncReader::open(&dir, "my-key").unwrap();
+        let mut str = String::new();
+        handle.read_to_string(&mut str).unwrap();
+        handle.check().unwrap();
+        assert_eq!(str, String::from("hello world"));
+    }
+
+    #[test]
+    fn test_open_hash_sync() {
+        use std::io::prelude::*;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri = crate::write_sync(&dir, "my-key", b"hello world").unwrap();
+
+        let mut handle = crate::SyncReader::open_hash(&dir, sri).unwrap();
+        let mut str = String::new();
+        handle.read_to_string(&mut str).unwrap();
+        handle.check().unwrap();
+        assert_eq!(str, String::from("hello world"));
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    #[async_test]
+    async fn test_read() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::write(&dir, "my-key", b"hello world").await.unwrap();
+
+        let data = crate::read(&dir, "my-key").await.unwrap();
+        assert_eq!(data, b"hello world");
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    #[async_test]
+    async fn test_read_hash() {
+        let tmp = tempfile::tempdir().unwrap();
         let dir = tmp.path().to_owned();
         let sri = crate::write(&dir, "my-key", b"hello world").await.unwrap();
 
@@ -1009,6 +1903,147 @@ mod tests {
         assert_eq!(data, b"hello world");
     }
 
+    #[test]
+    fn test_read_range_sync() {
+        let compressions = crate::Compression::ALL.iter().copied().map(Some);
+        for compression in std::iter::once(None).chain(compressions) {
+            let tmp = tempfile::tempdir().unwrap();
+            let dir = tmp.path().to_owned();
+            let mut opts = crate::WriteOpts::new();
+            if let Some(compression) = compression {
+                opts = opts.compression(compression);
+            }
+            let mut writer = opts.open_sync(&dir, "my-key").unwrap();
+            std::io::Write::write_all(&mut writer, b"hello world").unwrap();
+            let sri = writer.commit().unwrap();
+
+            assert_eq!(
+                crate::read_range_sync(&dir, "my-key", 6..).unwrap(),
+                b"world"
+            );
+            assert_eq!(
+                crate::read_hash_range_sync(&dir, &sri, ..=4).unwrap(),
+                b"hello"
+            );
+            assert_eq!(
+                crate::read_hash_range_sync(&dir, &sri, 3..100).unwrap(),
+                b"lo world"
+            );
+            assert!(crate::read_hash_range_sync(&dir, &sri, 20..)
+                .unwrap()
+                .is_empty());
+        }
+    }
+
+    #[test]
+    fn test_sync_reader_seek() {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::write_sync(&dir, "my-key", b"hello world").unwrap();
+
+        let mut handle = crate::SyncReader::open(&dir, "my-key").unwrap();
+        let mut buf = [0u8; 5];
+        handle.read_exact(&mut buf).unwrap();
+        // Asking where we are doesn't count as seeking.
+        assert_eq!(handle.stream_position().unwrap(), 5);
+        handle.seek(SeekFrom::Start(6)).unwrap();
+        handle.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"world");
+
+        // Going back to the start verifies the data again.
+        handle.seek(SeekFrom::Start(0)).unwrap();
+        let mut str = String::new();
+        handle.read_to_string(&mut str).unwrap();
+        assert_eq!(str, "hello world");
+        handle.check().unwrap();
+
+        let mut handle = crate::SyncReader::open(&dir, "my-key").unwrap();
+        handle.seek(SeekFrom::End(-5)).unwrap();
+        handle.read_to_string(&mut String::new()).unwrap();
+        assert!(handle.check().is_err());
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    #[async_test]
+    async fn test_reader_seek() {
+        use crate::async_lib::AsyncSeekExt;
+        use std::io::SeekFrom;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri = crate::write(&dir, "my-key", b"hello world").await.unwrap();
+
+        let mut handle = crate::Reader::open(&dir, "my-key").await.unwrap();
+        handle.seek(SeekFrom::Start(6)).await.unwrap();
+        let mut str = String::new();
+        handle.read_to_string(&mut str).await.unwrap();
+        assert_eq!(str, "world");
+
+        handle.seek(SeekFrom::Start(0)).await.unwrap();
+        let mut str = String::new();
+        handle.read_to_string(&mut str).await.unwrap();
+        assert_eq!(str, "hello world");
+        handle.check().unwrap();
+
+        let mut handle = crate::Reader::open(&dir, "my-key").await.unwrap();
+        handle.seek(SeekFrom::Current(3)).await.unwrap();
+        handle.read_to_string(&mut String::new()).await.unwrap();
+        assert!(handle.check().is_err());
+
+        assert_eq!(
+            crate::read_range(&dir, "my-key", 6..).await.unwrap(),
+            b"world"
+        );
+        assert_eq!(
+            crate::read_hash_range(&dir, &sri, 0..5).await.unwrap(),
+            b"hello"
+        );
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_read_mmap_sync() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri = crate::write_sync(&dir, "my-key", b"hello world").unwrap();
+        crate::write_sync(&dir, "empty", b"").unwrap();
+
+        let data = crate::read_mmap_sync(&dir, "my-key").unwrap();
+        assert_eq!(&data[..], b"hello world");
+        assert!(crate::read_mmap_sync(&dir, "empty").unwrap().is_empty());
+
+        // Removing the content doesn't invalidate existing maps.
+        crate::remove_hash_sync(&dir, &sri).unwrap();
+        assert_eq!(&data[..], b"hello world");
+
+        fs::create_dir_all(
+            crate::content::path::content_path(&dir, &sri)
+                .parent()
+                .unwrap(),
+        )
+        .unwrap();
+        fs::write(crate::content::path::content_path(&dir, &sri), b"corrupted").unwrap();
+        assert!(matches!(
+            crate::read_hash_mmap_sync(&dir, &sri),
+            Err(crate::Error::IntegrityError(_))
+        ));
+    }
+
+    #[cfg(all(feature = "mmap", any(feature = "async-std", feature = "tokio")))]
+    #[async_test]
+    async fn test_read_mmap() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri = crate::write(&dir, "my-key", b"hello world").await.unwrap();
+
+        let data = crate::read_mmap(&dir, "my-key").await.unwrap();
+        assert_eq!(&data[..], b"hello world");
+        let data = crate::read_hash_mmap(&dir, &sri).await.unwrap();
+        assert_eq!(data.as_ref(), b"hello world");
+    }
+
     #[cfg(any(feature = "async-std", feature = "tokio"))]
     #[async_test]
     async fn test_copy() {
@@ -1058,4 +2093,388 @@ mod tests {
         let data = fs::read(&dest).unwrap();
         assert_eq!(data, b"hello world");
     }
+
+    #[test]
+    fn test_read_sync_expired() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut writer = crate::WriteOpts::new()
+            .expires_at(1)
+            .open_sync(dir, "my-key")
+            .unwrap();
+        std::io::Write::write_all(&mut writer, b"hello world").unwrap();
+        writer.commit().unwrap();
+
+        assert!(matches!(
+            crate::read_sync(dir, "my-key"),
+            Err(crate::Error::EntryNotFound(_, _))
+        ));
+        assert!(crate::SyncReader::open(dir, "my-key").is_err());
+        assert_eq!(crate::metadata_sync(dir, "my-key").unwrap(), None);
+
+        let stale = crate::ReadOpts::new().allow_stale(true);
+        assert_eq!(
+            stale.clone().read_sync(dir, "my-key").unwrap(),
+            b"hello world"
+        );
+        assert!(stale
+            .metadata_sync(dir, "my-key")
+            .unwrap()
+            .unwrap()
+            .is_expired());
+    }
+
+    #[test]
+    fn test_read_sync_ttl() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut writer = crate::WriteOpts::new()
+            .time(1_000)
+            .ttl(std::time::Duration::from_secs(1))
+            .open_sync(dir, "old")
+            .unwrap();
+        std::io::Write::write_all(&mut writer, b"hello").unwrap();
+        writer.commit().unwrap();
+        let mut writer = crate::WriteOpts::new()
+            .ttl(std::time::Duration::from_secs(3600))
+            .open_sync(dir, "new")
+            .unwrap();
+        std::io::Write::write_all(&mut writer, b"world").unwrap();
+        writer.commit().unwrap();
+
+        let stale = crate::ReadOpts::new().allow_stale(true);
+        assert_eq!(
+            stale.metadata_sync(dir, "old").unwrap().unwrap().expires(),
+            Some(2_000)
+        );
+        assert!(crate::read_sync(dir, "old").is_err());
+        assert_eq!(crate::read_sync(dir, "new").unwrap(), b"world");
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    #[async_test]
+    async fn test_read_expired() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut writer = crate::WriteOpts::new()
+            .expires_at(1)
+            .open(dir, "my-key")
+            .await
+            .unwrap();
+        crate::async_lib::AsyncWriteExt::write_all(&mut writer, b"hello world")
+            .await
+            .unwrap();
+        writer.commit().await.unwrap();
+
+        assert!(crate::read(dir, "my-key").await.is_err());
+        assert!(crate::Reader::open(dir, "my-key").await.is_err());
+        assert_eq!(crate::metadata(dir, "my-key").await.unwrap(), None);
+        let data = crate::ReadOpts::new()
+            .allow_stale(true)
+            .read(dir, "my-key")
+            .await
+            .unwrap();
+        assert_eq!(data, b"hello world");
+    }
+
+    #[cfg(any(feature = "zstd", feature = "gzip"))]
+    #[test]
+    fn test_read_sync_compressed() {
+        use std::io::{Read, Write};
+
+        for &compression in crate::Compression::ALL {
+            let tmp = tempfile::tempdir().unwrap();
+            let dir = tmp.path();
+            let data = b"hello world ".repeat(100);
+            let mut writer = crate::WriteOpts::new()
+                .compression(compression)
+                .open_sync(dir, "my-key")
+                .unwrap();
+            writer.write_all(&data).unwrap();
+            let sri = writer.commit().unwrap();
+            assert_eq!(sri, ssri::Integrity::from(&data));
+
+            let encoding = crate::content::path::Encoding {
+                compression: Some(compression),
+                encrypted: false,
+                chunked: false,
+            };
+            let cpath = crate::content::path::encoded_path(dir, &sri, encoding);
+            assert!(fs::metadata(cpath).unwrap().len() < data.len() as u64);
+            assert!(crate::exists_sync(dir, &sri));
+            assert_eq!(crate::read_sync(dir, "my-key").unwrap(), data);
+            assert_eq!(crate::read_hash_sync(dir, &sri).unwrap(), data);
+
+            let mut reader = crate::SyncReader::open(dir, "my-key").unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            reader.check().unwrap();
+            assert_eq!(buf, data);
+
+            let dest = dir.join("data");
+            crate::copy_sync(dir, "my-key", &dest).unwrap();
+            assert_eq!(fs::read(&dest).unwrap(), data);
+            assert!(crate::hard_link_sync(dir, "my-key", dir.join("link")).is_err());
+
+            crate::remove_hash_sync(dir, &sri).unwrap();
+            assert!(!crate::exists_sync(dir, &sri));
+
+            // Encoders don't always take everything they're given at once.
+            let data = (0..200_000u32)
+                .flat_map(|i| i.to_le_bytes())
+                .collect::<Vec<u8>>();
+            let mut writer = crate::WriteOpts::new()
+                .compression(compression)
+                .open_sync(dir, "big")
+                .unwrap();
+            writer.write_all(&data).unwrap();
+            assert_eq!(writer.commit().unwrap(), ssri::Integrity::from(&data));
+            assert!(crate::read_sync(dir, "big").unwrap() == data);
+        }
+    }
+
+    #[cfg(all(
+        any(feature = "zstd", feature = "gzip"),
+        any(feature = "async-std", feature = "tokio")
+    ))]
+    #[async_test]
+    async fn test_read_compressed() {
+        use crate::async_lib::AsyncSeekExt;
+        use std::io::SeekFrom;
+
+        for &compression in crate::Compression::ALL {
+            let tmp = tempfile::tempdir().unwrap();
+            let dir = tmp.path();
+            let mut writer = crate::WriteOpts::new()
+                .compression(compression)
+                .open(dir, "my-key")
+                .await
+                .unwrap();
+            crate::async_lib::AsyncWriteExt::write_all(&mut writer, b"hello world")
+                .await
+                .unwrap();
+            let sri = writer.commit().await.unwrap();
+
+            assert!(crate::exists(dir, &sri).await);
+            assert_eq!(crate::read(dir, "my-key").await.unwrap(), b"hello world");
+
+            let mut reader = crate::Reader::open(dir, "my-key").await.unwrap();
+            let mut buf = String::new();
+            reader.read_to_string(&mut buf).await.unwrap();
+            reader.check().unwrap();
+            assert_eq!(buf, "hello world");
+
+            // Like the sync reader, this can't be seeked.
+            let mut reader = crate::Reader::open(dir, "my-key").await.unwrap();
+            assert!(reader.seek(SeekFrom::Start(6)).await.is_err());
+
+            // Data bigger than what's decoded ahead of the reader still comes
+            // through whole, and in order.
+            let data = (0..200_000u32)
+                .flat_map(|i| i.to_le_bytes())
+                .collect::<Vec<u8>>();
+            let mut writer = crate::WriteOpts::new()
+                .compression(compression)
+                .open(dir, "big")
+                .await
+                .unwrap();
+            crate::async_lib::AsyncWriteExt::write_all(&mut writer, &data)
+                .await
+                .unwrap();
+            writer.commit().await.unwrap();
+            let mut reader = crate::Reader::open(dir, "big").await.unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).await.unwrap();
+            reader.check().unwrap();
+            assert!(buf == data);
+
+            let dest = dir.join("data");
+            crate::copy(dir, "my-key", &dest).await.unwrap();
+            assert_eq!(fs::read(&dest).unwrap(), b"hello world");
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_read_sync_encrypted() {
+        use std::io::{Read, Write};
+
+        let compressions = crate::Compression::ALL.iter().copied().map(Some);
+        for compression in std::iter::once(None).chain(compressions) {
+            let tmp = tempfile::tempdir().unwrap();
+            let dir = tmp.path();
+            let key = crate::EncryptionKey::generate();
+            let data = b"hello world ".repeat(100);
+            let mut opts = crate::WriteOpts::new()
+                .encrypt(key.clone())
+                .encrypt_metadata(true)
+                .metadata(serde_json::json!({"secret": "shh"}));
+            if let Some(compression) = compression {
+                opts = opts.compression(compression);
+            }
+            let mut writer = opts.open_sync(dir, "my-key").unwrap();
+            writer.write_all(&data).unwrap();
+            let sri = writer.commit().unwrap();
+            assert_eq!(sri, ssri::Integrity::from(&data));
+
+            let encoding = crate::content::path::Encoding {
+                compression,
+                encrypted: true,
+                chunked: false,
+            };
+            let cpath = crate::content::path::encoded_path(dir, &sri, encoding);
+            let stored = fs::read(cpath).unwrap();
+            assert!(!stored.windows(11).any(|w| w == b"hello world"));
+            assert!(crate::exists_sync(dir, &sri));
+
+            assert!(crate::read_sync(dir, "my-key").is_err());
+            assert!(crate::read_hash_sync(dir, &sri).is_err());
+            let wrong = crate::ReadOpts::new().encryption_key(crate::EncryptionKey::generate());
+            assert!(wrong.read_sync(dir, "my-key").is_err());
+            assert!(matches!(
+                crate::metadata_sync(dir, "my-key"),
+                Err(crate::Error::MetadataSealed(_, _))
+            ));
+            assert!(crate::copy_sync(dir, "my-key", dir.join("copy")).is_err());
+
+            let opts = crate::ReadOpts::new().encryption_key(key);
+            assert_eq!(opts.clone().read_sync(dir, "my-key").unwrap(), data);
+            assert_eq!(opts.read_hash_sync(dir, &sri).unwrap(), data);
+            let meta = opts.clone().metadata_sync(dir, "my-key").unwrap().unwrap();
+            assert_eq!(meta.metadata, serde_json::json!({"secret": "shh"}));
+            let copy = dir.join("copy");
+            let copied = opts.clone().copy_sync(dir, "my-key", &copy).unwrap();
+            assert_eq!(copied, data.len() as u64);
+            assert_eq!(fs::read(&copy).unwrap(), data);
+
+            let mut reader = opts.open_sync(dir, "my-key").unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            reader.check().unwrap();
+            assert_eq!(buf, data);
+
+            assert!(crate::hard_link_sync(dir, "my-key", dir.join("link")).is_err());
+            crate::remove_hash_sync(dir, &sri).unwrap();
+            assert!(!crate::exists_sync(dir, &sri));
+        }
+    }
+
+    #[cfg(all(feature = "encryption", any(feature = "async-std", feature = "tokio")))]
+    #[async_test]
+    async fn test_read_encrypted() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let key = crate::EncryptionKey::generate();
+        let mut writer = crate::WriteOpts::new()
+            .encrypt(key.clone())
+            .open(dir, "my-key")
+            .await
+            .unwrap();
+        crate::async_lib::AsyncWriteExt::write_all(&mut writer, b"hello world")
+            .await
+            .unwrap();
+        let sri = writer.commit().await.unwrap();
+
+        assert!(crate::read(dir, "my-key").await.is_err());
+        let opts = crate::ReadOpts::new().encryption_key(key);
+        assert_eq!(
+            opts.clone().read(dir, "my-key").await.unwrap(),
+            b"hello world"
+        );
+        assert_eq!(opts.read_hash(dir, &sri).await.unwrap(), b"hello world");
+        let copy = dir.join("copy");
+        opts.copy_hash(dir, &sri, &copy).await.unwrap();
+        assert_eq!(fs::read(&copy).unwrap(), b"hello world");
+
+        let mut reader = opts.open(dir, "my-key").await.unwrap();
+        let mut buf = String::new();
+        reader.read_to_string(&mut buf).await.unwrap();
+        reader.check().unwrap();
+        assert_eq!(buf, "hello world");
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encrypt_metadata_needs_a_key() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let opts = crate::WriteOpts::new().encrypt_metadata(true);
+        assert!(opts.clone().open_sync(dir, "my-key").is_err());
+        let sri = ssri::Integrity::from(b"hello");
+        assert!(crate::index::insert(dir, "my-key", opts.integrity(sri)).is_err());
+        assert!(crate::metadata_sync(dir, "my-key").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_read_inline_sync() {
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let dest = dir.join("data");
+        let opts = crate::WriteOpts::new().inline_threshold(16);
+        let mut writer = opts.clone().open_sync(&dir, "small").unwrap();
+        writer.write_all(b"hello world").unwrap();
+        let sri = writer.commit().unwrap();
+        let mut writer = opts.open_sync(&dir, "big").unwrap();
+        writer.write_all(b"hello world, and then some").unwrap();
+        let big = writer.commit().unwrap();
+
+        assert!(crate::content::path::find_content(&dir, &sri).is_none());
+        assert!(crate::content::path::find_content(&dir, &big).is_some());
+        let entry = crate::metadata_sync(&dir, "small").unwrap().unwrap();
+        assert_eq!(entry.inline(), Some(&b"hello world"[..]));
+
+        assert_eq!(crate::read_sync(&dir, "small").unwrap(), b"hello world");
+        assert_eq!(crate::read_hash_sync(&dir, &sri).unwrap(), b"hello world");
+        assert_eq!(
+            crate::read_range_sync(&dir, "small", 6..).unwrap(),
+            b"world"
+        );
+        assert!(crate::exists_sync(&dir, &sri));
+        assert_eq!(crate::copy_hash_sync(&dir, &sri, &dest).unwrap(), 11);
+        assert_eq!(fs::read(&dest).unwrap(), b"hello world");
+
+        let mut reader = crate::SyncReader::open_hash(&dir, sri.clone()).unwrap();
+        reader.seek(SeekFrom::Start(6)).unwrap();
+        let mut buf = String::new();
+        reader.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "world");
+
+        crate::RemoveOpts::new()
+            .remove_fully(true)
+            .remove_sync(&dir, "small")
+            .unwrap();
+        assert!(!crate::exists_sync(&dir, &sri));
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    #[async_test]
+    async fn test_read_inline() {
+        use crate::async_lib::AsyncWriteExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let dest = dir.join("data");
+        let mut writer = crate::WriteOpts::new()
+            .inline_threshold(16)
+            .open(&dir, "small")
+            .await
+            .unwrap();
+        writer.write_all(b"hello world").await.unwrap();
+        let sri = writer.commit().await.unwrap();
+
+        assert!(crate::content::path::find_content(&dir, &sri).is_none());
+        assert_eq!(crate::read(&dir, "small").await.unwrap(), b"hello world");
+        assert_eq!(crate::read_hash(&dir, &sri).await.unwrap(), b"hello world");
+        assert!(crate::exists(&dir, &sri).await);
+        assert_eq!(crate::copy(&dir, "small", &dest).await.unwrap(), 11);
+        assert_eq!(fs::read(&dest).unwrap(), b"hello world");
+
+        let mut reader = crate::Reader::open(&dir, "small").await.unwrap();
+        let mut buf = String::new();
+        reader.read_to_string(&mut buf).await.unwrap();
+        reader.check().unwrap();
+        assert_eq!(buf, "hello world");
+    }
 }
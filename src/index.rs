@@ -1,6 +1,6 @@
 //! Raw access to the cache index. Use with caution!
 
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fs::{self, OpenOptions};
 use std::hash::{Hash, Hasher};
 use std::io::{ErrorKind, Write};
@@ -10,7 +10,9 @@ use std::time::{SystemTime, UNIX_EPOCH};
 use digest::Digest;
 use either::{Left, Right};
 #[cfg(any(feature = "async-std", feature = "tokio"))]
-use futures::stream::StreamExt;
+use futures::future;
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+use futures::stream::{self, BoxStream, Stream, StreamExt};
 use serde_derive::{Deserialize, Serialize};
 use serde_json::Value;
 use sha1::Sha1;
@@ -20,14 +22,16 @@ use walkdir::WalkDir;
 
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 use crate::async_lib::{AsyncBufReadExt, AsyncWriteExt};
-use crate::content::path::content_path;
-use crate::errors::{IoErrorExt, Result};
-use crate::put::WriteOpts;
+use crate::content::encrypt::{self, EncryptionKey};
+use crate::durability::{self, Durability};
+use crate::errors::{Error, IoErrorExt, Result};
+use crate::put::{Condition, WriteOpts};
 
 const INDEX_VERSION: &str = "5";
+const JOURNAL_VERSION: &str = "1";
 
 /// Represents a cache index entry, which points to content.
-#[derive(PartialEq, Debug)]
+#[derive(Clone, PartialEq, Debug)]
 pub struct Metadata {
     /// Key this entry is stored under.
     pub key: String,
@@ -41,6 +45,67 @@ pub struct Metadata {
     pub metadata: Value,
     /// Raw metadata in binary form. Can be different from JSON metadata.
     pub raw_metadata: Option<Vec<u8>>,
+    pub(crate) expires: Option<u128>,
+    pub(crate) inline: Option<Vec<u8>>,
+}
+
+impl Metadata {
+    /// Timestamp in unix milliseconds after which this entry is considered
+    /// expired, if any. See [`WriteOpts::ttl`].
+    pub fn expires(&self) -> Option<u128> {
+        self.expires
+    }
+
+    /// Sets when this entry expires, in unix milliseconds, or makes it
+    /// never expire. Takes effect when the entry is handed back from
+    /// [`crate::update_metadata_sync`]'s closure.
+    pub fn set_expires(&mut self, expires: Option<u128>) {
+        self.expires = expires;
+    }
+
+    /// The entry's data itself, when it was small enough to be stored in the
+    /// index rather than in `{cache}/content`. See
+    /// [`WriteOpts::inline_threshold`].
+    pub fn inline(&self) -> Option<&[u8]> {
+        self.inline.as_deref()
+    }
+
+    /// Returns true if this entry has an expiry time and it has passed.
+    pub fn is_expired(&self) -> bool {
+        matches!(self.expires, Some(expires) if expires <= now())
+    }
+}
+
+/// A single historical index entry for a key, as returned by [`history`].
+#[derive(PartialEq, Debug)]
+pub enum HistoryEntry {
+    /// The key was pointed at some content.
+    Write(Metadata),
+    /// The key was deleted.
+    Delete {
+        /// Key that was deleted.
+        key: String,
+        /// Timestamp in unix milliseconds when the key was deleted.
+        time: u128,
+    },
+}
+
+impl HistoryEntry {
+    /// Key this entry is stored under.
+    pub fn key(&self) -> &str {
+        match self {
+            HistoryEntry::Write(meta) => &meta.key,
+            HistoryEntry::Delete { key, .. } => key,
+        }
+    }
+
+    /// Timestamp in unix milliseconds when this entry was written.
+    pub fn time(&self) -> u128 {
+        match self {
+            HistoryEntry::Write(meta) => meta.time,
+            HistoryEntry::Delete { time, .. } => *time,
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -51,6 +116,15 @@ struct SerializableMetadata {
     size: usize,
     metadata: Value,
     raw_metadata: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires: Option<u128>,
+    /// `metadata` and `raw_metadata`, encrypted, when the entry was written
+    /// with [`WriteOpts::encrypt_metadata`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sealed: Option<String>,
+    /// Hex-encoded data, when it was stored inline.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    inline: Option<String>,
 }
 
 impl PartialEq for SerializableMetadata {
@@ -61,42 +135,177 @@ impl PartialEq for SerializableMetadata {
 
 impl Eq for SerializableMetadata {}
 
+impl SerializableMetadata {
+    /// Entries whose integrity doesn't parse are skipped, as they are
+    /// everywhere else, but corrupt inline data is an error.
+    fn into_history(self) -> Option<Result<HistoryEntry>> {
+        Some(match self.integrity {
+            Some(integrity) => {
+                let integrity = integrity.parse().ok()?;
+                decode_inline(&self.key, self.inline).map(|inline| {
+                    HistoryEntry::Write(Metadata {
+                        key: self.key,
+                        integrity,
+                        time: self.time,
+                        size: self.size,
+                        metadata: self.metadata,
+                        raw_metadata: self.raw_metadata,
+                        expires: self.expires,
+                        inline,
+                    })
+                })
+            }
+            None => Ok(HistoryEntry::Delete {
+                key: self.key,
+                time: self.time,
+            }),
+        })
+    }
+}
+
+/// Decodes an entry's inline data. Data that isn't valid hex has been
+/// corrupted, and fails like any other content that doesn't check out.
+fn decode_inline(key: &str, inline: Option<String>) -> Result<Option<Vec<u8>>> {
+    inline
+        .map(|data| {
+            hex::decode(data).map_err(|err| {
+                Error::IntegrityError(ssri::Error::HexDecodeError(format!(
+                    "inline data for key `{key}` is corrupt: {err}"
+                )))
+            })
+        })
+        .transpose()
+}
+
 impl Hash for SerializableMetadata {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.key.hash(state);
     }
 }
 
+fn serialize_entry(key: &str, opts: &mut WriteOpts) -> Result<String> {
+    let time = opts.time.unwrap_or_else(now);
+    let metadata = opts.metadata.take().unwrap_or(serde_json::Value::Null);
+    let raw_metadata = opts.raw_metadata.take();
+    #[cfg(feature = "encryption")]
+    let (metadata, raw_metadata, sealed) = match (opts.encrypt_metadata, &opts.encryption_key) {
+        (true, Some(enc_key)) => {
+            let sealed = encrypt::seal_metadata(enc_key, metadata, raw_metadata)
+                .with_context(|| format!("Failed to encrypt metadata for key `{key}`"))?;
+            (serde_json::Value::Null, None, Some(sealed))
+        }
+        (true, None) => {
+            return Err(encrypt::missing_key())
+                .with_context(|| format!("Failed to encrypt metadata for key `{key}`"))
+        }
+        _ => (metadata, raw_metadata, None),
+    };
+    #[cfg(not(feature = "encryption"))]
+    let sealed = None;
+    serde_json::to_string(&SerializableMetadata {
+        key: key.to_owned(),
+        integrity: opts.sri.clone().map(|x| x.to_string()),
+        time,
+        size: opts.size.unwrap_or(0),
+        metadata,
+        raw_metadata,
+        expires: opts
+            .expires
+            .or_else(|| opts.ttl.map(|ttl| time + ttl.as_millis())),
+        sealed,
+        inline: opts.inline_data.as_deref().map(hex::encode),
+    })
+    .with_context(|| format!("Failed to serialize entry with key `{key}`"))
+}
+
+/// Fails unless the entry currently in effect for `key` is what `condition`
+/// expects. Expired entries count as absent, like they do for reads: a key
+/// with no live entry fails [`Condition::Integrity`] with
+/// [`Error::EntryNotFound`], and anything else that doesn't match fails with
+/// [`Error::Conflict`].
+fn check_condition(cache: &Path, key: &str, condition: &Condition) -> Result<()> {
+    let current = find(cache, key)?.filter(|entry| !entry.is_expired());
+    match (condition, &current) {
+        (Condition::Absent, None) => Ok(()),
+        (Condition::Integrity(expected), Some(current))
+            if expected.matches(&current.integrity).is_some() =>
+        {
+            Ok(())
+        }
+        (Condition::Integrity(_), None) => {
+            Err(Error::EntryNotFound(cache.to_path_buf(), key.to_string()))
+        }
+        _ => Err(Error::Conflict(cache.to_path_buf(), key.to_string())),
+    }
+}
+
+/// Locks `bucket` through a lock file of its own in `locks-v1`, rather than
+/// the bucket itself, which compaction replaces and removal unlinks. Held
+/// until the returned file is dropped.
+pub(crate) fn lock_bucket(cache: &Path, bucket: &Path, exclusive: bool) -> Result<fs::File> {
+    // Safe unwrap. Buckets are always named after their key's hash.
+    let lpath = crate::lock::locks_dir(cache)
+        .join("buckets")
+        .join(bucket.file_name().unwrap());
+    crate::flock::open_locked(&lpath, exclusive)
+        .with_context(|| format!("Failed to lock index bucket at {bucket:?}"))
+}
+
+/// Exclusively locks every bucket in `buckets`, in order, so that callers
+/// locking overlapping sets can't deadlock.
+fn lock_buckets(cache: &Path, buckets: &BTreeSet<PathBuf>) -> Result<Vec<fs::File>> {
+    buckets
+        .iter()
+        .map(|bucket| lock_bucket(cache, bucket, true))
+        .collect()
+}
+
 /// Raw insertion into the cache index.
 pub fn insert(cache: &Path, key: &str, opts: WriteOpts) -> Result<Integrity> {
     let bucket = bucket_path(cache, key);
-    fs::create_dir_all(bucket.parent().unwrap()).with_context(|| {
+    // Held until we return. Conditional writes take it exclusively, so
+    // nobody else's write can sneak in between the check and the write.
+    // Everyone else shares it, which keeps compaction from replacing the
+    // bucket out from under them.
+    let _lock = lock_bucket(cache, &bucket, opts.condition.is_some())?;
+    if let Some(condition) = &opts.condition {
+        check_condition(cache, key, condition)?;
+    }
+    append(cache, key, opts)
+}
+
+/// Appends an entry for `key` to its bucket. Must be called with the bucket
+/// locked.
+fn append(cache: &Path, key: &str, mut opts: WriteOpts) -> Result<Integrity> {
+    let bucket = bucket_path(cache, key);
+    durability::create_dir_all(bucket.parent().unwrap(), opts.durability).with_context(|| {
         format!(
             "Failed to create index bucket directory: {:?}",
             bucket.parent().unwrap()
         )
     })?;
-    let stringified = serde_json::to_string(&SerializableMetadata {
-        key: key.to_owned(),
-        integrity: opts.sri.clone().map(|x| x.to_string()),
-        time: opts.time.unwrap_or_else(now),
-        size: opts.size.unwrap_or(0),
-        metadata: opts.metadata.unwrap_or(serde_json::Value::Null),
-        raw_metadata: opts.raw_metadata,
-    })
-    .with_context(|| format!("Failed to serialize entry with key `{key}`"))?;
-
     let mut buck = OpenOptions::new()
         .create(true)
         .append(true)
         .open(&bucket)
         .with_context(|| format!("Failed to create or open index bucket at {bucket:?}"))?;
-
+    if opts.inline_data.is_some() {
+        mark_inline(cache, opts.durability)?;
+    }
+    let stringified = serialize_entry(key, &mut opts)?;
     let out = format!("\n{}\t{}", hash_entry(&stringified), stringified);
     buck.write_all(out.as_bytes())
         .with_context(|| format!("Failed to write to index bucket at {bucket:?}"))?;
+    crate::memo::forget_entry(cache, key);
     buck.flush()
         .with_context(|| format!("Failed to flush bucket at {bucket:?}"))?;
+    durability::step("index written")
+        .and_then(|_| durability::sync_file(&buck, opts.durability, "index synced"))
+        .and_then(|_| durability::sync_parent(&bucket, opts.durability, "index dir synced"))
+        .with_context(|| format!("Failed to sync bucket at {bucket:?}"))?;
+    if let Some(sri) = &opts.sri {
+        crate::reverse::add(cache, key, sri, opts.durability)?;
+    }
     Ok(opts
         .sri
         .or_else(|| "sha1-deadbeef".parse::<Integrity>().ok())
@@ -105,9 +314,28 @@ pub fn insert(cache: &Path, key: &str, opts: WriteOpts) -> Result<Integrity> {
 
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 /// Asynchronous raw insertion into the cache index.
-pub async fn insert_async<'a>(cache: &'a Path, key: &'a str, opts: WriteOpts) -> Result<Integrity> {
+pub async fn insert_async<'a>(
+    cache: &'a Path,
+    key: &'a str,
+    mut opts: WriteOpts,
+) -> Result<Integrity> {
+    if opts.condition.is_some() {
+        // Checking and writing have to happen under a blocking file lock.
+        let cache = cache.to_path_buf();
+        let key = key.to_string();
+        return crate::async_lib::unwrap_joinhandle_value(
+            crate::async_lib::spawn_blocking(move || insert(&cache, &key, opts)).await,
+        );
+    }
     let bucket = bucket_path(cache, key);
-    crate::async_lib::create_dir_all(bucket.parent().unwrap())
+    // Shared, like in `insert`. Taking it can mean waiting for a compaction.
+    let _lock = {
+        let (cache, bucket) = (cache.to_path_buf(), bucket.clone());
+        crate::async_lib::unwrap_joinhandle_value(
+            crate::async_lib::spawn_blocking(move || lock_bucket(&cache, &bucket, false)).await,
+        )?
+    };
+    create_dir_all_async(bucket.parent().unwrap(), opts.durability)
         .await
         .with_context(|| {
             format!(
@@ -115,16 +343,14 @@ pub async fn insert_async<'a>(cache: &'a Path, key: &'a str, opts: WriteOpts) ->
                 bucket.parent().unwrap()
             )
         })?;
-    let stringified = serde_json::to_string(&SerializableMetadata {
-        key: key.to_owned(),
-        integrity: opts.sri.clone().map(|x| x.to_string()),
-        time: opts.time.unwrap_or_else(now),
-        size: opts.size.unwrap_or(0),
-        metadata: opts.metadata.unwrap_or(serde_json::Value::Null),
-        raw_metadata: opts.raw_metadata,
-    })
-    .with_context(|| format!("Failed to serialize entry with key `{key}`"))?;
-
+    if opts.inline_data.is_some() {
+        let cache = cache.to_path_buf();
+        let durability = opts.durability;
+        crate::async_lib::unwrap_joinhandle_value(
+            crate::async_lib::spawn_blocking(move || mark_inline(&cache, durability)).await,
+        )?;
+    }
+    let stringified = serialize_entry(key, &mut opts)?;
     let mut buck = crate::async_lib::OpenOptions::new()
         .create(true)
         .append(true)
@@ -136,121 +362,778 @@ pub async fn insert_async<'a>(cache: &'a Path, key: &'a str, opts: WriteOpts) ->
     buck.write_all(out.as_bytes())
         .await
         .with_context(|| format!("Failed to write to index bucket at {bucket:?}"))?;
+    crate::memo::forget_entry(cache, key);
     buck.flush()
         .await
         .with_context(|| format!("Failed to flush bucket at {bucket:?}"))?;
+    durability::step("index written")
+        .with_context(|| format!("Failed to write to index bucket at {bucket:?}"))?;
+    if opts.durability >= Durability::Data {
+        buck.sync_all()
+            .await
+            .with_context(|| format!("Failed to sync bucket at {bucket:?}"))?;
+        durability::step("index synced")
+            .with_context(|| format!("Failed to sync bucket at {bucket:?}"))?;
+    }
+    sync_parent_async(&bucket, opts.durability, "index dir synced").await?;
+    if let Some(sri) = &opts.sri {
+        crate::reverse::add_async(cache, key, sri, opts.durability).await?;
+    }
     Ok(opts
         .sri
         .or_else(|| "sha1-deadbeef".parse::<Integrity>().ok())
         .unwrap())
 }
 
+/// Replaces the entry for `key` with one built out of it by `f`, which
+/// returns the options to index the new entry with, along with whatever the
+/// caller wants back. The bucket is locked exclusively the whole time, so
+/// nothing else can be written to the key in between.
+///
+/// Fails with [`Error::EntryNotFound`] if there's no entry for `key`, and
+/// with [`Error::MetadataSealed`] if its metadata is encrypted, since it
+/// can't be handed to `f` or carried over as it is.
+pub(crate) fn update<F, T>(cache: &Path, key: &str, f: F) -> Result<T>
+where
+    F: FnOnce(Metadata) -> (WriteOpts, T),
+{
+    let (_lock, current) = lock_current(cache, key)?;
+    let (opts, out) = f(current);
+    append(cache, key, opts)?;
+    Ok(out)
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+/// Asynchronously replaces the entry for `key` with one built out of it by
+/// `f`. See [`update`] for details.
+pub(crate) async fn update_async<F, T>(cache: &Path, key: &str, f: F) -> Result<T>
+where
+    F: FnOnce(Metadata) -> (WriteOpts, T),
+{
+    let (cache, key) = (cache.to_path_buf(), key.to_string());
+    let (lock, current) = {
+        let (cache, key) = (cache.clone(), key.clone());
+        crate::async_lib::unwrap_joinhandle_value(
+            crate::async_lib::spawn_blocking(move || lock_current(&cache, &key)).await,
+        )?
+    };
+    let (opts, out) = f(current);
+    crate::async_lib::unwrap_joinhandle_value(
+        crate::async_lib::spawn_blocking(move || {
+            let _lock = lock;
+            append(&cache, &key, opts)
+        })
+        .await,
+    )?;
+    Ok(out)
+}
+
+/// Locks the bucket for `key` exclusively, and reads the entry currently in
+/// effect for it. See [`update`].
+fn lock_current(cache: &Path, key: &str) -> Result<(fs::File, Metadata)> {
+    let lock = lock_bucket(cache, &bucket_path(cache, key), true)?;
+    let Some((entry, integrity)) = latest_serialized(key_entries(cache, key)?, key) else {
+        return Err(Error::EntryNotFound(cache.to_path_buf(), key.to_string()));
+    };
+    if entry.sealed.is_some() {
+        return Err(Error::MetadataSealed(cache.to_path_buf(), key.to_string()));
+    }
+    Ok((lock, into_metadata(entry, integrity, None)?))
+}
+
+/// Creates `dir` and its missing ancestors, syncing them like
+/// [`durability::create_dir_all`] does if `durability` asks for it.
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+async fn create_dir_all_async(dir: &Path, durability: Durability) -> std::io::Result<()> {
+    if durability < Durability::Full {
+        return crate::async_lib::create_dir_all(dir).await;
+    }
+    let dir = dir.to_path_buf();
+    crate::async_lib::unwrap_joinhandle_value(
+        crate::async_lib::spawn_blocking(move || durability::create_dir_all(&dir, durability))
+            .await,
+    )
+}
+
+/// Syncs the directory `file` lives in without blocking the executor, if
+/// `durability` asks for it.
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub(crate) async fn sync_parent_async(
+    file: &Path,
+    durability: Durability,
+    name: &'static str,
+) -> Result<()> {
+    if durability < Durability::Full {
+        return Ok(());
+    }
+    let file = file.to_path_buf();
+    crate::async_lib::unwrap_joinhandle_value(
+        crate::async_lib::spawn_blocking(move || {
+            durability::sync_parent(&file, durability, name)
+                .with_context(|| format!("Failed to sync directory containing {file:?}"))
+        })
+        .await,
+    )
+}
+
 /// Raw index Metadata access.
+///
+/// Metadata written with [`WriteOpts::encrypt_metadata`] is returned as
+/// `Null`, since there's no key to decrypt it with.
 pub fn find(cache: &Path, key: &str) -> Result<Option<Metadata>> {
+    latest_entry(key_entries(cache, key)?, key, None)
+}
+
+/// Like [`find`], decrypting sealed metadata with `enc_key`. Fails with
+/// [`Error::MetadataSealed`] if the metadata is sealed and there's no key.
+pub(crate) fn find_with_key(
+    cache: &Path,
+    key: &str,
+    enc_key: Option<&EncryptionKey>,
+) -> Result<Option<Metadata>> {
+    unsealed_entry(cache, key_entries(cache, key)?, key, enc_key)
+}
+
+/// Reads the entries in `key`'s bucket, along with any for `key` in journals
+/// that haven't been applied to it yet.
+fn key_entries(cache: &Path, key: &str) -> Result<Vec<SerializableMetadata>> {
     let bucket = bucket_path(cache, key);
-    Ok(bucket_entries(&bucket)
-        .with_context(|| format!("Failed to read index bucket entries from {bucket:?}"))?
-        .into_iter()
-        .fold(None, |acc, entry| {
-            if entry.key == key {
-                if let Some(integrity) = entry.integrity {
-                    let integrity: Integrity = match integrity.parse() {
-                        Ok(sri) => sri,
-                        _ => return acc,
-                    };
-                    Some(Metadata {
-                        key: entry.key,
-                        integrity,
-                        size: entry.size,
-                        time: entry.time,
-                        metadata: entry.metadata,
-                        raw_metadata: entry.raw_metadata,
-                    })
-                } else {
-                    None
-                }
-            } else {
-                acc
-            }
-        }))
+    // Journals have to be looked at before the bucket: a batch that's been
+    // partly applied to it by then is in a journal that's already visible.
+    let journaled = journaled_lines(cache, key)?;
+    if journaled.is_empty() {
+        bucket_entries(&bucket)
+    } else {
+        bucket_entries_with(&bucket, journaled)
+    }
+    .with_context(|| format!("Failed to read index bucket entries from {bucket:?}"))
 }
 
 #[cfg(any(feature = "async-std", feature = "tokio"))]
-/// Asynchronous raw index Metadata access.
+/// Asynchronous raw index Metadata access. See [`find`] for details.
 pub async fn find_async(cache: &Path, key: &str) -> Result<Option<Metadata>> {
+    latest_entry(key_entries_async(cache, key).await?, key, None)
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+/// Like [`find_async`], decrypting sealed metadata with `enc_key`. See
+/// [`find_with_key`].
+pub(crate) async fn find_with_key_async(
+    cache: &Path,
+    key: &str,
+    enc_key: Option<&EncryptionKey>,
+) -> Result<Option<Metadata>> {
+    unsealed_entry(cache, key_entries_async(cache, key).await?, key, enc_key)
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+/// Asynchronous version of [`key_entries`].
+async fn key_entries_async(cache: &Path, key: &str) -> Result<Vec<SerializableMetadata>> {
+    if has_journals(cache) {
+        let cache = cache.to_path_buf();
+        let key = key.to_string();
+        return crate::async_lib::unwrap_joinhandle_value(
+            crate::async_lib::spawn_blocking(move || key_entries(&cache, &key)).await,
+        );
+    }
     let bucket = bucket_path(cache, key);
-    Ok(bucket_entries_async(&bucket)
+    bucket_entries_async(&bucket)
         .await
+        .with_context(|| format!("Failed to read index bucket entries from {bucket:?}"))
+}
+
+/// Makes every entry in `entries` visible at once: readers going through
+/// [`find`] either see all of them or none of them. Entries without an
+/// integrity are deletions. Conditional entries are checked against the
+/// index as it was before the batch, and nothing is written if any of them
+/// fails.
+///
+/// The entries are first written to a journal in `{cache}/journal-v1`, and
+/// moving it into place is what commits them. They're then appended to their
+/// buckets, and the journal removed. Until then, [`find`] merges in whatever
+/// hasn't made it to the buckets yet. Journals left behind by a crash are
+/// replayed by the next batch, or by [`crate::verify`].
+pub(crate) fn insert_all(cache: &Path, entries: Vec<(String, WriteOpts)>) -> Result<()> {
+    replay_journals(cache)?;
+    if entries.is_empty() {
+        return Ok(());
+    }
+    let durability = entries
+        .iter()
+        .map(|(_, opts)| opts.durability)
+        .max()
+        .unwrap_or_default();
+    // Keep conditional writes to these keys out until the batch is applied.
+    let buckets = entries
+        .iter()
+        .map(|(key, _)| bucket_path(cache, key))
+        .collect::<BTreeSet<_>>();
+    let _locks = lock_buckets(cache, &buckets)?;
+    for (key, opts) in &entries {
+        if let Some(condition) = &opts.condition {
+            check_condition(cache, key, condition)?;
+        }
+    }
+    if entries.iter().any(|(_, opts)| opts.inline_data.is_some()) {
+        mark_inline(cache, durability)?;
+    }
+    let mut lines = String::new();
+    let mut keys = Vec::with_capacity(entries.len());
+    for (key, mut opts) in entries {
+        let stringified = serialize_entry(&key, &mut opts)?;
+        lines.push_str(&format!("\n{}\t{}", hash_entry(&stringified), stringified));
+        keys.push(key);
+    }
+    let (journal, _lock) = write_journal(cache, &lines, durability)?;
+    for key in &keys {
+        crate::memo::forget_entry(cache, key);
+    }
+    apply_journal(cache, &journal, durability)
+}
+
+/// Applies journals that were committed but never fully applied, usually
+/// because whoever committed them crashed. Journals that are still being
+/// applied are left alone.
+pub(crate) fn replay_journals(cache: &Path) -> Result<()> {
+    for journal in journal_paths(cache)? {
+        let file = match fs::File::open(&journal) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to open index journal at {journal:?}"))
+            }
+        };
+        let locked = crate::flock::try_lock(&file)
+            .with_context(|| format!("Failed to lock index journal at {journal:?}"))?;
+        // If it's gone by the time we have the lock, it's been applied.
+        if locked && journal.exists() {
+            let buckets = bucket_lines(&journal)
+                .with_context(|| format!("Failed to read index journal at {journal:?}"))?
+                .iter()
+                .filter_map(|line| parse_entry(line))
+                .map(|entry| bucket_path(cache, &entry.key))
+                .collect::<BTreeSet<_>>();
+            let _locks = lock_buckets(cache, &buckets)?;
+            apply_journal(cache, &journal, Durability::default())?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes `lines` to a new journal, returning its path and an open handle
+/// that keeps others from replaying it while it's being applied.
+fn write_journal(cache: &Path, lines: &str, durability: Durability) -> Result<(PathBuf, fs::File)> {
+    let dir = journal_dir(cache);
+    let tmp_path = cache.join("tmp");
+    fs::create_dir_all(&tmp_path)
+        .with_context(|| format!("Failed to create directory at {}", tmp_path.display()))?;
+    let mut tmp = tempfile::NamedTempFile::new_in(&tmp_path).with_context(|| {
+        format!(
+            "Failed to create temp file for index journal, inside {}",
+            tmp_path.display()
+        )
+    })?;
+    tmp.write_all(lines.as_bytes())
+        .with_context(|| format!("Failed to write to temp file at {:?}", tmp.path()))?;
+    crate::flock::lock(tmp.as_file())
+        .with_context(|| format!("Failed to lock temp file at {:?}", tmp.path()))?;
+    durability::sync_file(tmp.as_file(), durability, "journal synced")
+        .with_context(|| format!("Failed to sync temp file at {:?}", tmp.path()))?;
+    // Journals are numbered in the order they're committed, which is the
+    // order they're replayed in. Numbers only have to be ordered among
+    // journals that haven't been applied yet, so they start over once
+    // they've all been removed.
+    let _lock = lock_journals(cache)?;
+    durability::create_dir_all(&dir, durability)
+        .with_context(|| format!("Failed to create directory at {}", dir.display()))?;
+    let next = journal_paths(cache)?
+        .iter()
+        .filter_map(|path| path.file_name()?.to_str()?.parse::<u64>().ok())
+        .max()
+        .map_or(0, |last| last + 1);
+    let journal = dir.join(format!("{next:020}"));
+    let file = tmp
+        .persist(&journal)
+        .map_err(|e| e.error)
+        .with_context(|| format!("Failed to commit index journal at {journal:?}"))?;
+    durability::step("journal committed")
+        .and_then(|_| durability::sync_parent(&journal, durability, "journal dir synced"))
+        .with_context(|| format!("Failed to sync index journal at {journal:?}"))?;
+    Ok((journal, file))
+}
+
+/// Appends whatever entries in `journal` aren't in their buckets yet, then
+/// removes it. Must be called with the journal and the buckets it touches
+/// locked.
+fn apply_journal(cache: &Path, journal: &Path, durability: Durability) -> Result<()> {
+    let lines = bucket_lines(journal)
+        .with_context(|| format!("Failed to read index journal at {journal:?}"))?;
+    let mut buckets: Vec<(PathBuf, Vec<String>, Vec<String>)> = Vec::new();
+    let mut referrers = Vec::new();
+    for line in lines {
+        let Some(entry) = parse_entry(&line) else {
+            continue;
+        };
+        if let Some(Ok(sri)) = entry.integrity.as_deref().map(str::parse::<Integrity>) {
+            referrers.push((entry.key.clone(), sri));
+        }
+        let bucket = bucket_path(cache, &entry.key);
+        match buckets.iter_mut().find(|(path, _, _)| *path == bucket) {
+            Some((_, keys, pending)) => {
+                keys.push(entry.key);
+                pending.push(line);
+            }
+            None => buckets.push((bucket, vec![entry.key], vec![line])),
+        }
+    }
+    for (bucket, keys, pending) in buckets {
+        let applied = bucket_lines(&bucket)
+            .with_context(|| format!("Failed to read index bucket at {bucket:?}"))?
+            .into_iter()
+            .collect::<HashSet<_>>();
+        durability::create_dir_all(bucket.parent().unwrap(), durability).with_context(|| {
+            format!(
+                "Failed to create index bucket directory: {:?}",
+                bucket.parent().unwrap()
+            )
+        })?;
+        let mut buck = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&bucket)
+            .with_context(|| format!("Failed to create or open index bucket at {bucket:?}"))?;
+        let out = pending
+            .iter()
+            .filter(|line| !applied.contains(*line))
+            .map(|line| format!("\n{line}"))
+            .collect::<String>();
+        buck.write_all(out.as_bytes())
+            .with_context(|| format!("Failed to write to index bucket at {bucket:?}"))?;
+        for key in &keys {
+            crate::memo::forget_entry(cache, key);
+        }
+        durability::sync_file(&buck, durability, "index synced")
+            .and_then(|_| durability::sync_parent(&bucket, durability, "index dir synced"))
+            .with_context(|| format!("Failed to sync bucket at {bucket:?}"))?;
+    }
+    for (key, sri) in &referrers {
+        crate::reverse::add(cache, key, sri, durability)?;
+    }
+    match fs::remove_file(journal) {
+        Err(err) if err.kind() != ErrorKind::NotFound => {
+            return Err(err)
+                .with_context(|| format!("Failed to remove index journal at {journal:?}"))
+        }
+        _ => {}
+    }
+    // Lookups read the journal directory every time, which is cheapest when
+    // it isn't there. It's only removed once it's empty, and nobody adds
+    // journals to it without the journal lock.
+    let _lock = lock_journals(cache)?;
+    let _ = fs::remove_dir(journal_dir(cache));
+    Ok(())
+}
+
+/// Locks the journal directory, so journals can be numbered and the
+/// directory removed without racing with each other.
+fn lock_journals(cache: &Path) -> Result<fs::File> {
+    let lpath = crate::lock::locks_dir(cache).join("journals");
+    crate::flock::open_locked(&lpath, true)
+        .with_context(|| format!("Failed to lock index journals at {lpath:?}"))
+}
+
+/// Lists committed journals, oldest first.
+fn journal_paths(cache: &Path) -> Result<Vec<PathBuf>> {
+    let dir = journal_dir(cache);
+    let mut paths = match fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<Vec<_>>>(),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err),
+    }
+    .with_context(|| format!("Failed to read index journals at {}", dir.display()))?;
+    paths.sort();
+    Ok(paths)
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+/// Quickly checks whether there are any journals at all, so async reads only
+/// go to a blocking thread to look at them when they have to.
+fn has_journals(cache: &Path) -> bool {
+    fs::read_dir(journal_dir(cache)).is_ok_and(|mut entries| entries.next().is_some())
+}
+
+/// Returns the lines for `key` from journals that haven't been removed yet.
+fn journaled_lines(cache: &Path, key: &str) -> Result<Vec<String>> {
+    let mut found = Vec::new();
+    for journal in journal_paths(cache)? {
+        let lines = match bucket_lines(&journal) {
+            Ok(lines) => lines,
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to read index journal at {journal:?}"))
+            }
+        };
+        found.extend(
+            lines
+                .into_iter()
+                .filter(|line| parse_entry(line).is_some_and(|entry| entry.key == key)),
+        );
+    }
+    Ok(found)
+}
+
+fn journal_dir(cache: &Path) -> PathBuf {
+    cache.join(format!("journal-v{JOURNAL_VERSION}"))
+}
+
+/// Picks the entry currently in effect for `key` out of its bucket's
+/// entries, if it hasn't been deleted.
+fn latest_entry(
+    entries: Vec<SerializableMetadata>,
+    key: &str,
+    enc_key: Option<&EncryptionKey>,
+) -> Result<Option<Metadata>> {
+    latest_serialized(entries, key)
+        .map(|(entry, integrity)| into_metadata(entry, integrity, enc_key))
+        .transpose()
+}
+
+/// Like [`latest_entry`], but refuses to hand out sealed metadata as `Null`
+/// when there's no key to decrypt it with.
+fn unsealed_entry(
+    cache: &Path,
+    entries: Vec<SerializableMetadata>,
+    key: &str,
+    enc_key: Option<&EncryptionKey>,
+) -> Result<Option<Metadata>> {
+    match latest_serialized(entries, key) {
+        Some((entry, _)) if entry.sealed.is_some() && enc_key.is_none() => {
+            Err(Error::MetadataSealed(cache.to_path_buf(), key.to_string()))
+        }
+        latest => latest
+            .map(|(entry, integrity)| into_metadata(entry, integrity, enc_key))
+            .transpose(),
+    }
+}
+
+/// Like [`latest_entry`], but returns the entry as it was stored.
+fn latest_serialized(
+    entries: Vec<SerializableMetadata>,
+    key: &str,
+) -> Option<(SerializableMetadata, Integrity)> {
+    entries.into_iter().fold(None, |acc, entry| {
+        if entry.key == key {
+            if let Some(integrity) = &entry.integrity {
+                let integrity: Integrity = match integrity.parse() {
+                    Ok(sri) => sri,
+                    _ => return acc,
+                };
+                Some((entry, integrity))
+            } else {
+                None
+            }
+        } else {
+            acc
+        }
+    })
+}
+
+/// Turns a stored entry into [`Metadata`], decrypting sealed metadata with
+/// `enc_key`.
+fn into_metadata(
+    entry: SerializableMetadata,
+    integrity: Integrity,
+    enc_key: Option<&EncryptionKey>,
+) -> Result<Metadata> {
+    let (metadata, raw_metadata) = match (&entry.sealed, enc_key) {
+        (Some(sealed), Some(enc_key)) => encrypt::unseal_metadata(enc_key, sealed)
+            .with_context(|| format!("Failed to decrypt metadata for key `{}`", entry.key))?,
+        _ => (entry.metadata, entry.raw_metadata),
+    };
+    let inline = decode_inline(&entry.key, entry.inline)?;
+    Ok(Metadata {
+        key: entry.key,
+        integrity,
+        size: entry.size,
+        time: entry.time,
+        metadata,
+        raw_metadata,
+        expires: entry.expires,
+        inline,
+    })
+}
+
+/// Lists every index entry ever written for `key`, including deletions, in
+/// the order they were written.
+///
+/// Unlike [`find`], which only looks at the latest entry, this can be used to
+/// audit when the content for a key changed, or to roll back to an earlier
+/// integrity. Note that [`compact`] discards older entries.
+pub fn history(cache: &Path, key: &str) -> Result<Vec<HistoryEntry>> {
+    let bucket = bucket_path(cache, key);
+    bucket_entries(&bucket)
         .with_context(|| format!("Failed to read index bucket entries from {bucket:?}"))?
         .into_iter()
-        .fold(None, |acc, entry| {
-            if entry.key == key {
-                if let Some(integrity) = entry.integrity {
-                    let integrity: Integrity = match integrity.parse() {
-                        Ok(sri) => sri,
-                        _ => return acc,
-                    };
-                    Some(Metadata {
-                        key: entry.key,
-                        integrity,
-                        size: entry.size,
-                        time: entry.time,
-                        metadata: entry.metadata,
-                        raw_metadata: entry.raw_metadata,
+        .filter(|entry| entry.key == key)
+        .filter_map(SerializableMetadata::into_history)
+        .collect()
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+/// Asynchronously streams every index entry ever written for `key`, including
+/// deletions, in the order they were written. See [`history`] for details.
+pub fn history_async(
+    cache: &Path,
+    key: &str,
+) -> impl Stream<Item = Result<HistoryEntry>> + Send + Unpin {
+    let bucket = bucket_path(cache, key);
+    let key = key.to_owned();
+    stream::once(async move {
+        bucket_stream(bucket.clone())
+            .await
+            .with_context(|| format!("Failed to read index bucket entries from {bucket:?}"))
+    })
+    .flat_map(move |res| match res {
+        Ok(entries) => {
+            let key = key.clone();
+            entries
+                .filter_map(move |entry| {
+                    future::ready(if entry.key == key {
+                        entry.into_history()
+                    } else {
+                        None
                     })
-                } else {
-                    None
-                }
-            } else {
-                acc
-            }
-        }))
+                })
+                .left_stream()
+        }
+        Err(err) => stream::once(future::ready(Err(err))).right_stream(),
+    })
+    .boxed()
 }
 
 /// Deletes an index entry, without deleting the actual cache data entry.
 pub fn delete(cache: &Path, key: &str) -> Result<()> {
-    insert(
-        cache,
-        key,
-        WriteOpts {
-            algorithm: None,
-            size: None,
-            sri: None,
-            time: None,
-            metadata: None,
-            raw_metadata: None,
-        },
-    )
-    .map(|_| ())
+    delete_with(cache, key, Durability::None)
+}
+
+/// Like [`delete`], syncing the deletion as hard as `durability` asks for.
+pub(crate) fn delete_with(cache: &Path, key: &str, durability: Durability) -> Result<()> {
+    insert(cache, key, removal(durability)).map(|_| ())
 }
 
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 /// Asynchronously deletes an index entry, without deleting the actual cache
 /// data entry.
 pub async fn delete_async(cache: &Path, key: &str) -> Result<()> {
-    insert(
-        cache,
-        key,
-        WriteOpts {
-            algorithm: None,
-            size: None,
-            sri: None,
-            time: None,
-            metadata: None,
-            raw_metadata: None,
-        },
-    )
-    .map(|_| ())
+    delete_with_async(cache, key, Durability::None).await
+}
+
+/// Like [`delete_async`], syncing the deletion as hard as `durability` asks
+/// for.
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub(crate) async fn delete_with_async(
+    cache: &Path,
+    key: &str,
+    durability: Durability,
+) -> Result<()> {
+    insert_async(cache, key, removal(durability))
+        .await
+        .map(|_| ())
+}
+
+/// Deletes the entry for `key` if `f` says the one currently in effect
+/// should go, returning whether it did. The bucket stays locked from the
+/// check until the deletion is written, so an entry written in between is
+/// never deleted in place of the one that was checked.
+pub(crate) fn delete_if<F>(cache: &Path, key: &str, durability: Durability, f: F) -> Result<bool>
+where
+    F: FnOnce(&Metadata) -> bool,
+{
+    let _lock = lock_bucket(cache, &bucket_path(cache, key), true)?;
+    match find(cache, key)? {
+        Some(entry) if f(&entry) => {}
+        _ => return Ok(false),
+    }
+    append(cache, key, removal(durability))?;
+    Ok(true)
+}
+
+/// Options for the entry that marks a key as deleted.
+fn removal(durability: Durability) -> WriteOpts {
+    WriteOpts::new().durability(durability)
 }
 
 /// Lists raw index Metadata entries.
 pub fn ls(cache: &Path) -> impl Iterator<Item = Result<Metadata>> {
-    let cache_path = cache.join(format!("index-v{INDEX_VERSION}"));
+    bucket_paths(cache)
+        .map(|bucket| {
+            let bucket = bucket?;
+            live_entries(bucket_entries(&bucket).with_context(|| {
+                format!("Error getting bucket entries from {}", bucket.display())
+            })?)
+        })
+        .flat_map(|res: Result<Vec<Metadata>>| match res {
+            Ok(it) => Left(it.into_iter().map(Ok)),
+            Err(err) => Right(std::iter::once(Err(err))),
+        })
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+/// Asynchronously lists raw index Metadata entries.
+pub fn ls_async(cache: &Path) -> impl Stream<Item = Result<Metadata>> + Send + Unpin {
+    struct State {
+        dirs: Vec<PathBuf>,
+        buckets: Vec<PathBuf>,
+        entries: std::vec::IntoIter<Metadata>,
+    }
+    let state = State {
+        dirs: vec![index_dir(cache)],
+        buckets: Vec::new(),
+        entries: Vec::new().into_iter(),
+    };
+    stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(entry) = state.entries.next() {
+                return Some((Ok(entry), state));
+            }
+            if let Some(bucket) = state.buckets.pop() {
+                match bucket_entries_async(&bucket).await.with_context(|| {
+                    format!("Error getting bucket entries from {}", bucket.display())
+                }) {
+                    Ok(entries) => match live_entries(entries) {
+                        Ok(entries) => state.entries = entries.into_iter(),
+                        Err(err) => return Some((Err(err), state)),
+                    },
+                    Err(err) => return Some((Err(err), state)),
+                }
+                continue;
+            }
+            let dir = state.dirs.pop()?;
+            match read_dir_async(dir).await {
+                Ok(children) => {
+                    for (path, is_dir) in children {
+                        if is_dir {
+                            state.dirs.push(path);
+                        } else {
+                            state.buckets.push(path);
+                        }
+                    }
+                }
+                Err(err) => return Some((Err(err), state)),
+            }
+        }
+    })
+    .boxed()
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+/// Lists the children of `dir`, along with whether each is a directory. The
+/// listing itself happens on a blocking thread.
+async fn read_dir_async(dir: PathBuf) -> Result<Vec<(PathBuf, bool)>> {
+    crate::async_lib::unwrap_joinhandle_value(
+        crate::async_lib::spawn_blocking(move || {
+            fs::read_dir(&dir)
+                .and_then(|entries| {
+                    entries
+                        .map(|entry| {
+                            let entry = entry?;
+                            Ok((entry.path(), entry.file_type()?.is_dir()))
+                        })
+                        .collect::<std::io::Result<Vec<_>>>()
+                })
+                .with_context(|| {
+                    format!(
+                        "Error while walking cache index directory at {}",
+                        dir.display()
+                    )
+                })
+        })
+        .await,
+    )
+}
+
+/// Reduces the raw entries in a bucket down to the latest live entry for each
+/// key.
+fn live_entries(entries: Vec<SerializableMetadata>) -> Result<Vec<Metadata>> {
+    entries
+        .into_iter()
+        .rev()
+        .collect::<HashSet<SerializableMetadata>>()
+        .into_iter()
+        .filter_map(|se| {
+            let integrity = se.integrity?.parse().ok()?;
+            Some(decode_inline(&se.key, se.inline).map(|inline| Metadata {
+                key: se.key,
+                integrity,
+                time: se.time,
+                size: se.size,
+                metadata: se.metadata,
+                raw_metadata: se.raw_metadata,
+                expires: se.expires,
+                inline,
+            }))
+        })
+        .collect()
+}
+
+/// Compacts every bucket in the index, keeping only the latest entry for each
+/// key and dropping deleted keys altogether. Returns the number of entries
+/// that were removed.
+///
+/// Buckets are rewritten atomically, while holding the same lock writers
+/// take to append to them, so entries written during compaction aren't lost.
+/// The reverse index used by [`crate::keys_for_hash`] is rebuilt afterwards,
+/// dropping keys that no longer point at their content.
+pub fn compact(cache: &Path) -> Result<usize> {
+    compact_with_history(cache, 1)
+}
+
+/// Compacts every bucket in the index, keeping up to `history` of the most
+/// recent entries for each key. Keys whose latest entry is a deletion are
+/// dropped altogether. Returns the number of entries that were removed.
+pub fn compact_with_history(cache: &Path, history: usize) -> Result<usize> {
+    if !index_dir(cache).exists() {
+        return Ok(0);
+    }
+    let mut removed = 0;
+    for bucket in bucket_paths(cache) {
+        let rebuilt = rebuild_bucket(cache, &bucket?, history.max(1), |_, _| true)?;
+        removed += rebuilt.total - rebuilt.kept;
+    }
+    // The reverse index only ever grows otherwise.
+    crate::reverse::rebuild(cache)?;
+    Ok(removed)
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+/// Asynchronously compacts every bucket in the index, keeping only the latest
+/// entry for each key. See [`compact`] for details.
+pub async fn compact_async(cache: &Path) -> Result<usize> {
+    compact_with_history_async(cache, 1).await
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+/// Asynchronously compacts every bucket in the index, keeping up to `history`
+/// of the most recent entries for each key. See [`compact_with_history`] for
+/// details.
+pub async fn compact_with_history_async(cache: &Path, history: usize) -> Result<usize> {
+    let cache = cache.to_path_buf();
+    crate::async_lib::unwrap_joinhandle_value(
+        crate::async_lib::spawn_blocking(move || compact_with_history(&cache, history)).await,
+    )
+}
+
+/// Lists the paths of all bucket files in the index.
+pub(crate) fn bucket_paths(cache: &Path) -> impl Iterator<Item = Result<PathBuf>> {
+    let cache_path = index_dir(cache);
     let cloned = cache_path.clone();
     WalkDir::new(&cache_path)
         .into_iter()
-        .map(move |bucket| {
-            let bucket = bucket
+        .filter_map(move |bucket| {
+            let bucket = match bucket
                 .map_err(|e| match e.io_error() {
                     Some(io_err) => std::io::Error::new(io_err.kind(), io_err.kind().to_string()),
                     None => crate::errors::io_error("Unexpected error"),
@@ -260,71 +1143,265 @@ pub fn ls(cache: &Path) -> impl Iterator<Item = Result<Metadata>> {
                         "Error while walking cache index directory at {}",
                         cloned.display()
                     )
-                })?;
-
+                }) {
+                Ok(bucket) => bucket,
+                Err(err) => return Some(Err(err)),
+            };
             if bucket.file_type().is_dir() {
-                return Ok(Vec::new());
+                None
+            } else {
+                Some(Ok(bucket.into_path()))
             }
-
-            let owned_path = bucket.path().to_owned();
-            Ok(bucket_entries(bucket.path())
-                .with_context(|| {
-                    format!("Error getting bucket entries from {}", owned_path.display())
-                })?
-                .into_iter()
-                .rev()
-                .collect::<HashSet<SerializableMetadata>>()
-                .into_iter()
-                .filter_map(|se| {
-                    if let Some(i) = se.integrity {
-                        Some(Metadata {
-                            key: se.key,
-                            integrity: i.parse().unwrap(),
-                            time: se.time,
-                            size: se.size,
-                            metadata: se.metadata,
-                            raw_metadata: se.raw_metadata,
-                        })
-                    } else {
-                        None
-                    }
-                })
-                .collect())
-        })
-        .flat_map(|res| match res {
-            Ok(it) => Left(it.into_iter().map(Ok)),
-            Err(err) => Right(std::iter::once(Err(err))),
         })
 }
 
-fn bucket_path(cache: &Path, key: &str) -> PathBuf {
+/// Entry counts for a bucket rewritten by [`rebuild_bucket`].
+pub(crate) struct RebuiltBucket {
+    /// Entries in the bucket before it was rewritten.
+    pub(crate) total: usize,
+    /// Entries left in it afterwards.
+    pub(crate) kept: usize,
+    /// Entries dropped because `keep` rejected them.
+    pub(crate) rejected: usize,
+}
+
+/// Rewrites a bucket so that it only holds the `history` most recent entries
+/// for each of its keys. Keys whose latest entry is a deletion are dropped
+/// altogether, as are keys whose latest entry `keep` rejects, so that an
+/// older entry doesn't take its place. Older entries `keep` rejects are
+/// dropped on their own.
+///
+/// The bucket is locked exclusively while it's rewritten, so this must not
+/// be called while holding its lock.
+pub(crate) fn rebuild_bucket<F>(
+    cache: &Path,
+    bucket: &Path,
+    history: usize,
+    mut keep: F,
+) -> Result<RebuiltBucket>
+where
+    F: FnMut(&str, &Integrity) -> bool,
+{
+    let _lock = lock_bucket(cache, bucket, true)?;
+    let entries = bucket_entries(bucket)
+        .with_context(|| format!("Failed to read index bucket entries from {bucket:?}"))?;
+    let total = entries.len();
+    // Walk backwards so we see the latest entry for each key first.
+    let mut seen = HashMap::new();
+    let mut kept = Vec::new();
+    let mut rejected = 0;
+    for entry in entries.into_iter().rev() {
+        let count = seen.entry(entry.key.clone()).or_insert(0usize);
+        *count = count.saturating_add(1);
+        if *count > history {
+            continue;
+        }
+        let latest = *count == 1;
+        let sri = match entry.integrity.as_deref().map(str::parse::<Integrity>) {
+            Some(Ok(sri)) => Some(sri),
+            Some(Err(_)) => {
+                if latest {
+                    *count = usize::MAX;
+                }
+                continue;
+            }
+            None => None,
+        };
+        match sri {
+            // Nothing worth keeping for deleted keys.
+            None if latest => *count = usize::MAX,
+            None => kept.push(entry),
+            // Inline entries carry their own content.
+            Some(_) if entry.inline.is_some() => kept.push(entry),
+            Some(sri) if keep(&entry.key, &sri) => kept.push(entry),
+            Some(_) => {
+                rejected += 1;
+                if latest {
+                    *count = usize::MAX;
+                }
+            }
+        }
+    }
+    kept.reverse();
+    if kept.len() != total {
+        write_bucket(cache, bucket, &kept)?;
+    }
+    Ok(RebuiltBucket {
+        total,
+        kept: kept.len(),
+        rejected,
+    })
+}
+
+/// Atomically replaces the contents of `bucket` with `entries`, removing the
+/// bucket altogether if there's nothing left in it. Must be called with the
+/// bucket locked exclusively.
+///
+/// The new contents are always synced before they replace the old ones, and
+/// the bucket's directory after, whatever durability the entries were
+/// written with. A crash in between could otherwise leave the bucket empty,
+/// losing entries nobody asked to remove.
+fn write_bucket(cache: &Path, bucket: &Path, entries: &[SerializableMetadata]) -> Result<()> {
+    // There's no telling which keys were in here, so forget about all of them.
+    crate::memo::forget_all(cache);
+    if entries.is_empty() {
+        return match fs::remove_file(bucket) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Failed to remove bucket at {bucket:?}"))
+            }
+            _ => Ok(()),
+        };
+    }
+    let tmp_path = cache.join("tmp");
+    fs::create_dir_all(&tmp_path).with_context(|| {
+        format!(
+            "Failed to create cache directory for temporary files, at {}",
+            tmp_path.display()
+        )
+    })?;
+    let mut tmp = tempfile::NamedTempFile::new_in(&tmp_path).with_context(|| {
+        format!(
+            "Failed to create temp file while rewriting bucket, inside {}",
+            tmp_path.display()
+        )
+    })?;
+    for entry in entries {
+        let stringified = serde_json::to_string(entry)
+            .with_context(|| format!("Failed to serialize entry with key `{}`", entry.key))?;
+        let out = format!("\n{}\t{}", hash_entry(&stringified), stringified);
+        tmp.write_all(out.as_bytes())
+            .with_context(|| format!("Failed to write to temp file at {:?}", tmp.path()))?;
+    }
+    durability::sync_file(tmp.as_file(), Durability::Full, "rewritten index synced")
+        .with_context(|| format!("Failed to sync temp file at {:?}", tmp.path()))?;
+    // Carry the bucket's access time over, so rewriting it doesn't affect
+    // eviction order.
+    let accessed = fs::metadata(bucket)
+        .map(|meta| filetime::FileTime::from_last_modification_time(&meta))
+        .ok();
+    tmp.persist(bucket)
+        .map_err(|e| e.error)
+        .with_context(|| format!("Failed to replace index bucket at {bucket:?}"))?;
+    durability::sync_parent(bucket, Durability::Full, "rewritten index dir synced")
+        .with_context(|| format!("Failed to sync directory containing {bucket:?}"))?;
+    if let Some(accessed) = accessed {
+        let _ = filetime::set_file_mtime(bucket, accessed);
+    }
+    Ok(())
+}
+
+/// Records an access to `key` by bumping its bucket's modification time.
+/// This is what [`crate::evict_to`] uses to find least-recently-used keys.
+/// Failures are ignored, since they shouldn't prevent reads from succeeding.
+pub(crate) fn touch(cache: &Path, key: &str) {
+    let _ = filetime::set_file_mtime(bucket_path(cache, key), filetime::FileTime::now());
+}
+
+/// Like [`touch`], without blocking the async runtime.
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub(crate) async fn touch_async(cache: &Path, key: &str) {
+    let bucket = bucket_path(cache, key);
+    let _ = crate::async_lib::spawn_blocking(move || {
+        filetime::set_file_mtime(bucket, filetime::FileTime::now())
+    })
+    .await;
+}
+
+/// Returns the last time `key` was written or read.
+pub(crate) fn last_access(cache: &Path, key: &str) -> Option<SystemTime> {
+    fs::metadata(bucket_path(cache, key))
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+pub(crate) fn index_dir(cache: &Path) -> PathBuf {
+    cache.join(format!("index-v{INDEX_VERSION}"))
+}
+
+/// A directory whose existence means some entry in the cache was written
+/// with inline data, so reads by hash that find no content file have to look
+/// for it in the index. Caches that have never held any skip that lookup.
+fn inline_marker(cache: &Path) -> PathBuf {
+    cache.join("inline-v1")
+}
+
+/// Whether `cache` might hold inline data. See [`inline_marker`].
+pub(crate) fn may_have_inline(cache: &Path) -> bool {
+    inline_marker(cache).exists()
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub(crate) async fn may_have_inline_async(cache: &Path) -> bool {
+    crate::async_lib::metadata(inline_marker(cache))
+        .await
+        .is_ok()
+}
+
+/// Marks `cache` as holding inline data. Has to happen before the first
+/// inline entry is written, so it's never missed by reads.
+fn mark_inline(cache: &Path, durability: Durability) -> Result<()> {
+    let marker = inline_marker(cache);
+    if marker.exists() {
+        return Ok(());
+    }
+    durability::create_dir_all(&marker, durability)
+        .with_context(|| format!("Failed to create inline data marker at {marker:?}"))
+}
+
+pub(crate) fn bucket_path(cache: &Path, key: &str) -> PathBuf {
     let hashed = hash_key(key);
-    cache
-        .join(format!("index-v{INDEX_VERSION}"))
+    index_dir(cache)
         .join(&hashed[0..2])
         .join(&hashed[2..4])
         .join(&hashed[4..])
 }
 
-fn hash_key(key: &str) -> String {
+pub(crate) fn hash_key(key: &str) -> String {
     let mut hasher = Sha1::new();
     hasher.update(key);
     hex::encode(hasher.finalize())
 }
 
-fn hash_entry(key: &str) -> String {
+pub(crate) fn hash_entry(key: &str) -> String {
     let mut hasher = Sha256::new();
     hasher.update(key);
     hex::encode(hasher.finalize())
 }
 
-fn now() -> u128 {
+pub(crate) fn now() -> u128 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
         .as_millis()
 }
 
+/// Reads the raw lines of a bucket, or a journal, which holds the same kind
+/// of lines. Missing files are empty.
+fn bucket_lines(bucket: &Path) -> std::io::Result<Vec<String>> {
+    use std::io::{BufRead, BufReader};
+    match fs::File::open(bucket) {
+        Ok(file) => Ok(BufReader::new(file)
+            .lines()
+            .map_while(std::result::Result::ok)
+            .filter(|line| !line.is_empty())
+            .collect()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Reads a bucket's entries along with the `journaled` ones that haven't
+/// been appended to it yet, as if they had been.
+fn bucket_entries_with(
+    bucket: &Path,
+    journaled: Vec<String>,
+) -> std::io::Result<Vec<SerializableMetadata>> {
+    let mut lines = bucket_lines(bucket)?;
+    let applied = lines.iter().cloned().collect::<HashSet<_>>();
+    lines.extend(journaled.into_iter().filter(|line| !applied.contains(line)));
+    Ok(lines.iter().filter_map(|line| parse_entry(line)).collect())
+}
+
 fn bucket_entries(bucket: &Path) -> std::io::Result<Vec<SerializableMetadata>> {
     use std::io::{BufRead, BufReader};
     fs::File::open(bucket)
@@ -332,14 +1409,7 @@ fn bucket_entries(bucket: &Path) -> std::io::Result<Vec<SerializableMetadata>> {
             BufReader::new(file)
                 .lines()
                 .map_while(std::result::Result::ok)
-                .filter_map(|entry| {
-                    let entry_str = match entry.split('\t').collect::<Vec<&str>>()[..] {
-                        [hash, entry_str] if hash_entry(entry_str) == hash => entry_str,
-                        // Something's wrong with the entry. Abort.
-                        _ => return None,
-                    };
-                    serde_json::from_str::<SerializableMetadata>(entry_str).ok()
-                })
+                .filter_map(|entry| parse_entry(&entry))
                 .collect()
         })
         .or_else(|err| {
@@ -366,24 +1436,42 @@ async fn bucket_entries_async(bucket: &Path) -> std::io::Result<Vec<Serializable
     let mut lines =
         crate::async_lib::lines_to_stream(crate::async_lib::BufReader::new(file).lines());
     while let Some(line) = lines.next().await {
-        if let Ok(entry) = line {
-            let entry_str = match entry.split('\t').collect::<Vec<&str>>()[..] {
-                [hash, entry_str] if hash_entry(entry_str) == hash => entry_str,
-                // Something's wrong with the entry. Abort.
-                _ => continue,
-            };
-            if let Ok(serialized) = serde_json::from_str::<SerializableMetadata>(entry_str) {
-                vec.push(serialized);
-            }
+        if let Some(serialized) = line.ok().and_then(|entry| parse_entry(&entry)) {
+            vec.push(serialized);
         }
     }
     Ok(vec)
 }
 
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+async fn bucket_stream(
+    bucket: PathBuf,
+) -> std::io::Result<BoxStream<'static, SerializableMetadata>> {
+    let file = match crate::async_lib::File::open(&bucket).await {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(stream::empty().boxed()),
+        Err(err) => return Err(err),
+    };
+    let lines = crate::async_lib::lines_to_stream(crate::async_lib::BufReader::new(file).lines());
+    Ok(lines
+        .filter_map(|line| future::ready(line.ok().and_then(|entry| parse_entry(&entry))))
+        .boxed())
+}
+
+fn parse_entry(entry: &str) -> Option<SerializableMetadata> {
+    let entry_str = match entry.split('\t').collect::<Vec<&str>>()[..] {
+        [hash, entry_str] if hash_entry(entry_str) == hash => entry_str,
+        // Something's wrong with the entry. Abort.
+        _ => return None,
+    };
+    serde_json::from_str::<SerializableMetadata>(entry_str).ok()
+}
+
 /// Builder for options and flags for remove cache entry.
 #[derive(Clone, Default)]
 pub struct RemoveOpts {
     pub(crate) remove_fully: bool,
+    pub(crate) durability: Durability,
 }
 
 impl RemoveOpts {
@@ -394,58 +1482,128 @@ impl RemoveOpts {
 
     /// Set the remove fully option
     /// If remove_fully is set to true then the index file itself will be physically deleted rather than appending a null.
+    /// The content it points to is deleted too, unless other keys still refer to it.
+    /// Those are looked up with [`crate::keys_for_hash_sync`], which can miss
+    /// keys whose writer crashed partway through until the next [`compact`]
+    /// or [`crate::verify_sync`].
     pub fn remove_fully(mut self, remove_fully: bool) -> Self {
         self.remove_fully = remove_fully;
         self
     }
 
-    /// Removes an individual index metadata entry. The associated content will be left in the cache.
+    /// Sets how hard the removal tries to survive a crash or power loss. See
+    /// [`WriteOpts::durability`]. Defaults to [`Durability::None`].
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Removes an individual index metadata entry. Unless `remove_fully` is
+    /// set, the associated content will be left in the cache. When it is,
+    /// the content is only deleted if no other key still points at it.
     pub fn remove_sync<P, K>(self, cache: P, key: K) -> Result<()>
     where
         P: AsRef<Path>,
         K: AsRef<str>,
     {
+        self.remove_with_report_sync(cache, key).map(|_| ())
+    }
+
+    /// Like [`RemoveOpts::remove_sync`], but also reports what happened to
+    /// the key's content.
+    pub fn remove_with_report_sync<P, K>(self, cache: P, key: K) -> Result<RemoveReport>
+    where
+        P: AsRef<Path>,
+        K: AsRef<str>,
+    {
+        let (cache, key) = (cache.as_ref(), key.as_ref());
         if !self.remove_fully {
-            delete(cache.as_ref(), key.as_ref())
-        } else {
-            if let Some(meta) = crate::metadata_sync(cache.as_ref(), key.as_ref())? {
-                let content = content_path(cache.as_ref(), &meta.integrity);
-                fs::remove_file(&content)
-                    .with_context(|| format!("Failed to remove content at {content:?}"))?;
+            delete_with(cache, key, self.durability)?;
+            return Ok(RemoveReport::default());
+        }
+        // Nothing else can be written to the key until its bucket is gone.
+        let bucket = bucket_path(cache, key);
+        let _lock = lock_bucket(cache, &bucket, true)?;
+        let mut report = RemoveReport::default();
+        if let Some(meta) = find(cache, key)? {
+            // Keys are added to the reverse index under a shared lock, so
+            // none can start pointing at the content while this decides
+            // whether to delete it.
+            let _reverse = crate::reverse::lock_reverse(cache, true)?;
+            let referrers =
+                other_referrers(key, crate::reverse::keys_locked(cache, &meta.integrity)?);
+            if referrers.is_empty() {
+                // Inline data goes away with the bucket itself.
+                if meta.inline.is_none() {
+                    crate::content::rm::rm(cache, &meta.integrity)?;
+                }
+                report.removed = Some(meta.integrity);
+            } else {
+                report.kept = Some(meta.integrity);
+                report.kept_for = referrers;
             }
-            let bucket = bucket_path(cache.as_ref(), key.as_ref());
-            fs::remove_file(&bucket)
-                .with_context(|| format!("Failed to remove bucket at {bucket:?}"))
         }
+        fs::remove_file(&bucket)
+            .and_then(|_| durability::sync_parent(&bucket, self.durability, "index dir synced"))
+            .with_context(|| format!("Failed to remove bucket at {bucket:?}"))?;
+        crate::memo::forget_entry(cache, key);
+        Ok(report)
     }
 
-    /// Removes an individual index metadata entry. The associated content will be left in the cache.
+    /// Removes an individual index metadata entry. Unless `remove_fully` is
+    /// set, the associated content will be left in the cache. When it is,
+    /// the content is only deleted if no other key still points at it.
     #[cfg(any(feature = "async-std", feature = "tokio"))]
     pub async fn remove<P, K>(self, cache: P, key: K) -> Result<()>
     where
         P: AsRef<Path>,
         K: AsRef<str>,
     {
+        self.remove_with_report(cache, key).await.map(|_| ())
+    }
+
+    /// Like [`RemoveOpts::remove`], but also reports what happened to the
+    /// key's content.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn remove_with_report<P, K>(self, cache: P, key: K) -> Result<RemoveReport>
+    where
+        P: AsRef<Path>,
+        K: AsRef<str>,
+    {
+        let (cache, key) = (cache.as_ref(), key.as_ref());
         if !self.remove_fully {
-            delete_async(cache.as_ref(), key.as_ref()).await
-        } else {
-            if let Some(meta) = crate::metadata(cache.as_ref(), key.as_ref()).await? {
-                let content = content_path(cache.as_ref(), &meta.integrity);
-                crate::async_lib::remove_file(&content)
-                    .await
-                    .with_context(|| format!("Failed to remove content at {content:?}"))?;
-            }
-            let bucket = bucket_path(cache.as_ref(), key.as_ref());
-            crate::async_lib::remove_file(&bucket)
-                .await
-                .with_context(|| format!("Failed to remove bucket at {bucket:?}"))
+            delete_with_async(cache, key, self.durability).await?;
+            return Ok(RemoveReport::default());
         }
+        // Removing fully means holding blocking file locks throughout.
+        let (cache, key) = (cache.to_path_buf(), key.to_string());
+        crate::async_lib::unwrap_joinhandle_value(
+            crate::async_lib::spawn_blocking(move || self.remove_with_report_sync(cache, key))
+                .await,
+        )
     }
 }
 
+/// What happened to a key's content when it was removed with
+/// [`RemoveOpts::remove_fully`]. See [`RemoveOpts::remove_with_report_sync`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RemoveReport {
+    /// Content that was deleted, since nothing else referred to it.
+    pub removed: Option<Integrity>,
+    /// Content that was left in place because other keys still point at it.
+    pub kept: Option<Integrity>,
+    /// The keys still pointing at the `kept` content.
+    pub kept_for: Vec<String>,
+}
+
+fn other_referrers(key: &str, keys: Vec<String>) -> Vec<String> {
+    keys.into_iter().filter(|other| other != key).collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::content::path::content_path;
     use serde_json::json;
 
     #[cfg(feature = "async-std")]
@@ -510,10 +1668,95 @@ mod tests {
                 size: 0,
                 metadata: json!(null),
                 raw_metadata: None,
+                expires: None,
+                inline: None,
             }
         );
     }
 
+    #[test]
+    fn find_merges_unapplied_journals() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::write_sync(&dir, "a", b"old").unwrap();
+        let new = crate::write_hash_sync(&dir, b"new").unwrap();
+        let mut lines = String::new();
+        for key in ["a", "b"] {
+            let stringified =
+                serialize_entry(key, &mut WriteOpts::new().integrity(new.clone())).unwrap();
+            lines.push_str(&format!("\n{}\t{}", hash_entry(&stringified), stringified));
+        }
+        // As if whoever committed the journal crashed before applying it.
+        let (journal, lock) = write_journal(&dir, &lines, Durability::None).unwrap();
+        drop(lock);
+        assert_eq!(find(&dir, "a").unwrap().unwrap().integrity, new);
+        assert_eq!(find(&dir, "b").unwrap().unwrap().integrity, new);
+        assert_eq!(history(&dir, "a").unwrap().len(), 1);
+
+        replay_journals(&dir).unwrap();
+        assert!(!journal.exists());
+        assert_eq!(history(&dir, "a").unwrap().len(), 2);
+        assert_eq!(find(&dir, "a").unwrap().unwrap().integrity, new);
+    }
+
+    #[test]
+    fn journals_replay_in_commit_order() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let journals = ["first", "second"].map(|data| {
+            let sri = Integrity::from(data);
+            let stringified = serialize_entry("a", &mut WriteOpts::new().integrity(sri)).unwrap();
+            let line = format!("\n{}\t{}", hash_entry(&stringified), stringified);
+            write_journal(&dir, &line, Durability::None).unwrap().0
+        });
+        assert!(journals[0] < journals[1]);
+        replay_journals(&dir).unwrap();
+        assert_eq!(
+            find(&dir, "a").unwrap().unwrap().integrity,
+            Integrity::from("second")
+        );
+        assert!(!journal_dir(&dir).exists());
+    }
+
+    #[test]
+    fn corrupt_inline_data_is_an_integrity_error() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let mut opts = WriteOpts::new().integrity(Integrity::from(b"hello"));
+        opts.inline_data = Some(b"hello".to_vec());
+        let stringified = serialize_entry("a", &mut opts)
+            .unwrap()
+            .replace(&hex::encode(b"hello"), "not hex");
+        let bucket = bucket_path(&dir, "a");
+        fs::create_dir_all(bucket.parent().unwrap()).unwrap();
+        fs::write(
+            &bucket,
+            format!("\n{}\t{}", hash_entry(&stringified), stringified),
+        )
+        .unwrap();
+
+        assert!(matches!(find(&dir, "a"), Err(Error::IntegrityError(_))));
+        assert!(matches!(history(&dir, "a"), Err(Error::IntegrityError(_))));
+        assert!(matches!(
+            ls(&dir).next(),
+            Some(Err(Error::IntegrityError(_)))
+        ));
+    }
+
+    #[test]
+    fn delete_if_checks_the_current_entry() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let old = crate::write_sync(&dir, "a", b"old").unwrap();
+        crate::write_sync(&dir, "a", b"new").unwrap();
+        let is_old = |entry: &Metadata| entry.integrity == old;
+        assert!(!delete_if(&dir, "a", Durability::None, is_old).unwrap());
+        assert!(find(&dir, "a").unwrap().is_some());
+        assert!(delete_if(&dir, "a", Durability::None, |_| true).unwrap());
+        assert_eq!(find(&dir, "a").unwrap(), None);
+        assert!(!delete_if(&dir, "a", Durability::None, |_| true).unwrap());
+    }
+
     #[test]
     fn find_none() {
         let tmp = tempfile::tempdir().unwrap();
@@ -566,6 +1809,32 @@ mod tests {
         assert!(!content.exists());
     }
 
+    #[test]
+    fn delete_fully_keeps_shared_content() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri = crate::write_sync(&dir, "hello", b"hello").unwrap();
+        crate::write_sync(&dir, "world", b"hello").unwrap();
+
+        let report = RemoveOpts::new()
+            .remove_fully(true)
+            .remove_with_report_sync(&dir, "hello")
+            .unwrap();
+        assert_eq!(report.removed, None);
+        assert_eq!(report.kept, Some(sri.clone()));
+        assert_eq!(report.kept_for, vec![String::from("world")]);
+        assert_eq!(find(&dir, "hello").unwrap(), None);
+        assert_eq!(crate::read_sync(&dir, "world").unwrap(), b"hello");
+
+        let report = RemoveOpts::new()
+            .remove_fully(true)
+            .remove_with_report_sync(&dir, "world")
+            .unwrap();
+        assert_eq!(report.removed, Some(sri.clone()));
+        assert!(report.kept_for.is_empty());
+        assert!(!crate::exists_sync(&dir, &sri));
+    }
+
     #[cfg(any(feature = "async-std", feature = "tokio"))]
     #[async_test]
     async fn delete_fully_async() {
@@ -604,6 +1873,8 @@ mod tests {
                 size: 0,
                 metadata: json!(null),
                 raw_metadata: None,
+                expires: None,
+                inline: None,
             }
         );
     }
@@ -631,10 +1902,182 @@ mod tests {
                 size: 0,
                 metadata: json!(null),
                 raw_metadata: None,
+                expires: None,
+                inline: None,
             }
         );
     }
 
+    #[test]
+    fn history_basic() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let old: Integrity = "sha1-deadbeef".parse().unwrap();
+        let new = Integrity::from(b"hello");
+        insert(
+            &dir,
+            "hello",
+            WriteOpts::new().integrity(old.clone()).time(1),
+        )
+        .unwrap();
+        insert(
+            &dir,
+            "hello",
+            WriteOpts::new().integrity(new.clone()).time(2),
+        )
+        .unwrap();
+        delete(&dir, "hello").unwrap();
+
+        let history = history(&dir, "hello").unwrap();
+        assert_eq!(history.len(), 3);
+        match &history[..] {
+            [HistoryEntry::Write(first), HistoryEntry::Write(second), HistoryEntry::Delete { key, .. }] =>
+            {
+                assert_eq!(first.integrity, old);
+                assert_eq!(second.integrity, new);
+                assert_eq!(key, "hello");
+            }
+            other => panic!("unexpected history: {other:?}"),
+        }
+        assert_eq!(history[1].time(), 2);
+    }
+
+    #[test]
+    fn history_none() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        assert!(history(&dir, "hello").unwrap().is_empty());
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    #[async_test]
+    async fn history_async_basic() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri: Integrity = "sha1-deadbeef".parse().unwrap();
+        insert(
+            &dir,
+            "hello",
+            WriteOpts::new().integrity(sri.clone()).time(1),
+        )
+        .unwrap();
+        delete(&dir, "hello").unwrap();
+
+        let entries = history_async(&dir, "hello")
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(entries, history(&dir, "hello").unwrap());
+        assert!(history_async(&dir, "missing").next().await.is_none());
+    }
+
+    #[test]
+    fn compact_basic() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let old: Integrity = "sha1-deadbeef".parse().unwrap();
+        let new: Integrity = "sha1-badc0ffee".parse().unwrap();
+        insert(&dir, "hello", WriteOpts::new().integrity(old).time(1)).unwrap();
+        insert(
+            &dir,
+            "hello",
+            WriteOpts::new().integrity(new.clone()).time(2),
+        )
+        .unwrap();
+        insert(
+            &dir,
+            "world",
+            WriteOpts::new().integrity(new.clone()).time(3),
+        )
+        .unwrap();
+        delete(&dir, "world").unwrap();
+
+        assert_eq!(compact(&dir).unwrap(), 3);
+        assert_eq!(
+            bucket_entries(&bucket_path(&dir, "hello")).unwrap().len(),
+            1
+        );
+        assert!(!bucket_path(&dir, "world").exists());
+        assert_eq!(find(&dir, "hello").unwrap().unwrap().integrity, new);
+        assert_eq!(find(&dir, "world").unwrap(), None);
+        assert_eq!(compact(&dir).unwrap(), 0);
+    }
+
+    #[test]
+    fn compact_with_history_basic() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        for time in 0..5 {
+            let sri = Integrity::from(time.to_string());
+            insert(&dir, "hello", WriteOpts::new().integrity(sri).time(time)).unwrap();
+        }
+
+        assert_eq!(compact_with_history(&dir, 2).unwrap(), 3);
+        let entries = bucket_entries(&bucket_path(&dir, "hello")).unwrap();
+        assert_eq!(
+            entries.iter().map(|e| e.time).collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+        assert_eq!(find(&dir, "hello").unwrap().unwrap().time, 4);
+    }
+
+    #[test]
+    fn rebuild_bucket_drops_keys_with_rejected_latest_entry() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        for time in 0..3 {
+            let sri = Integrity::from(time.to_string());
+            insert(&dir, "hello", WriteOpts::new().integrity(sri).time(time)).unwrap();
+        }
+        let latest = Integrity::from("2");
+        let bucket = bucket_path(&dir, "hello");
+        let rebuilt = rebuild_bucket(&dir, &bucket, 2, |_, sri| *sri != latest).unwrap();
+        assert_eq!((rebuilt.total, rebuilt.kept, rebuilt.rejected), (3, 0, 1));
+        assert_eq!(find(&dir, "hello").unwrap(), None);
+    }
+
+    #[test]
+    fn compact_keeps_concurrent_writes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let writers = (0..4)
+            .map(|n| {
+                let dir = dir.clone();
+                std::thread::spawn(move || {
+                    for i in 0..50 {
+                        let sri = Integrity::from(format!("{n}-{i}"));
+                        insert(&dir, &format!("key-{n}"), WriteOpts::new().integrity(sri)).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        while !writers.iter().all(|writer| writer.is_finished()) {
+            compact(&dir).unwrap();
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        for n in 0..4 {
+            let entry = find(&dir, &format!("key-{n}")).unwrap().unwrap();
+            assert_eq!(entry.integrity, Integrity::from(format!("{n}-49")));
+        }
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    #[async_test]
+    async fn compact_async_basic() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri: Integrity = "sha1-deadbeef".parse().unwrap();
+        insert(&dir, "hello", WriteOpts::new().integrity(sri.clone())).unwrap();
+        insert(&dir, "hello", WriteOpts::new().integrity(sri.clone())).unwrap();
+
+        assert_eq!(compact_async(&dir).await.unwrap(), 1);
+        assert_eq!(find(&dir, "hello").unwrap().unwrap().integrity, sri);
+    }
+
     #[test]
     fn ls_basic() {
         let tmp = tempfile::tempdir().unwrap();
@@ -650,6 +2093,29 @@ mod tests {
         assert_eq!(entries, vec![String::from("hello"), String::from("world")])
     }
 
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    #[async_test]
+    async fn ls_async_basic() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri: Integrity = "sha1-deadbeef".parse().unwrap();
+        let time = 1_234_567;
+        let opts = WriteOpts::new().integrity(sri.clone()).time(time);
+        insert(&dir, "hello", opts).unwrap();
+        let opts = WriteOpts::new().integrity(sri).time(time);
+        insert(&dir, "world", opts).unwrap();
+        delete(&dir, "world").unwrap();
+
+        let entries = ls_async(&dir)
+            .map(|x| Ok(x?.key))
+            .collect::<Vec<Result<_>>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(entries, vec![String::from("hello")]);
+    }
+
     #[test]
     fn ls_basic_with_delete() {
         let tmp = tempfile::tempdir().unwrap();
@@ -10,6 +10,10 @@
 //! returned objects, as well as `WriteOpts`, which is analogous to
 //! `OpenOpts`, but is only able to write.
 //!
+//! To configure things like the hash algorithm or a read-only mode once for
+//! every operation, open a [`Cache`] with [`CacheOpts`] and call the same
+//! operations as methods on it.
+//!
 //! One major difference is that the default APIs are all async functions, as
 //! opposed to `std::fs`, where they're all synchronous. Synchronous APIs in
 //! cacache are accessible through the `_sync` suffix.
@@ -161,24 +165,49 @@ pub use ssri::{Algorithm, Integrity};
 
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 mod async_lib;
+mod batch;
 
 mod content;
+mod durability;
 mod errors;
+mod flock;
 pub mod index;
 
+mod cache;
+mod evict;
 mod get;
 #[cfg(feature = "link_to")]
 mod linkto;
+mod lock;
 mod ls;
+mod memo;
 mod put;
+mod repack;
+mod reverse;
 mod rm;
+mod tmp;
+mod verify;
 
+pub use content::chunk::Chunking;
+pub use content::compress::Compression;
+#[cfg(feature = "encryption")]
+pub use content::encrypt::EncryptionKey;
+pub use durability::Durability;
 pub use errors::{Error, Result};
-pub use index::{Metadata, RemoveOpts};
+pub use index::{Metadata, RemoveOpts, RemoveReport};
 
+pub use batch::Batch;
+pub use cache::{Cache, CacheOpts};
+pub use evict::*;
 pub use get::*;
 #[cfg(feature = "link_to")]
 pub use linkto::*;
+pub use lock::*;
 pub use ls::*;
+pub use memo::*;
 pub use put::*;
+pub use repack::*;
+pub use reverse::*;
 pub use rm::*;
+pub use tmp::*;
+pub use verify::*;
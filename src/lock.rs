@@ -0,0 +1,697 @@
+//! Functions for coordinating work on a key, within a process and across
+//! processes.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock};
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+use std::task::{Poll, Waker};
+use std::thread::{self, ThreadId};
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+use std::time::Duration;
+
+use ssri::Integrity;
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+use crate::async_lib::AsyncWriteExt;
+use crate::errors::{Error, IoErrorExt, Result};
+use crate::flock;
+use crate::get::ReadOpts;
+use crate::index;
+use crate::put::WriteOpts;
+
+const LOCKS_VERSION: &str = "1";
+
+/// The longest [`lock_key`] waits between checks on a held lock.
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+const MAX_LOCK_WAIT: Duration = Duration::from_millis(50);
+
+/// An advisory lock on a single key in a cache, held until it's dropped.
+/// Returned by [`lock_key`] and [`lock_key_sync`].
+pub struct KeyLock {
+    _file: File,
+}
+
+/// Takes an advisory lock on `key`, waiting for whoever holds it to let go
+/// first. The lock is shared between processes and threads alike, and is
+/// released when the returned [`KeyLock`] is dropped, or when its process
+/// exits.
+///
+/// Locks are purely advisory: they don't stop anyone from reading or writing
+/// the key, only from locking it at the same time. On platforms without file
+/// locks, this never waits. While the lock is held by someone else, this
+/// checks back on it every so often, rather than tying up a blocking thread
+/// for the whole wait.
+///
+/// ## Example
+/// ```no_run
+/// use async_attributes;
+///
+/// #[async_attributes::main]
+/// async fn main() -> cacache::Result<()> {
+///     let lock = cacache::lock_key("./my-cache", "my-key").await?;
+///     cacache::write("./my-cache", "my-key", b"hello").await?;
+///     drop(lock);
+///     Ok(())
+/// }
+/// ```
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub async fn lock_key<P, K>(cache: P, key: K) -> Result<KeyLock>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+{
+    let (cache, key) = (cache.as_ref(), key.as_ref());
+    let lpath = lock_path(cache, key);
+    let mut wait = Duration::from_millis(1);
+    loop {
+        let file = flock::try_open_locked(&lpath)
+            .with_context(|| format!("Failed to lock key {key:?} at {}", lpath.display()))?;
+        if let Some(file) = file {
+            return Ok(KeyLock { _file: file });
+        }
+        crate::async_lib::sleep(wait).await;
+        wait = (wait * 2).min(MAX_LOCK_WAIT);
+    }
+}
+
+/// Synchronously takes an advisory lock on `key`, blocking until whoever
+/// holds it lets go first. See [`lock_key`] for details.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache::Result<()> {
+///     let lock = cacache::lock_key_sync("./my-cache", "my-key")?;
+///     cacache::write_sync("./my-cache", "my-key", b"hello")?;
+///     drop(lock);
+///     Ok(())
+/// }
+/// ```
+pub fn lock_key_sync<P, K>(cache: P, key: K) -> Result<KeyLock>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+{
+    fn inner(cache: &Path, key: &str) -> Result<KeyLock> {
+        let lpath = lock_path(cache, key);
+        let file = flock::open_locked(&lpath, true)
+            .with_context(|| format!("Failed to lock key {key:?} at {}", lpath.display()))?;
+        Ok(KeyLock { _file: file })
+    }
+    inner(cache.as_ref(), key.as_ref())
+}
+
+/// Reads the data indexed under `key` along with its integrity, calling `f`
+/// to produce the data and writing it to the cache if it's missing.
+///
+/// Only one caller at a time gets to run `f` for a given key. Concurrent
+/// misses within this process are coalesced: everyone else waits for the
+/// first caller's `f` and gets a copy of its result, without touching the
+/// cache at all. Other processes wait on a lock on the key (see
+/// [`lock_key`]), and then read what was written instead of producing the
+/// data again.
+///
+/// If `f` fails, or the caller running it goes away before the data is
+/// written, the next caller in line runs its own `f`. Data that's in the
+/// cache but fails its integrity check is treated like a miss, so `f` runs
+/// and its result is written over it. Errors from `f` are returned as they
+/// are, and errors from the cache are converted into `E`.
+/// Use [`crate::WriteOpts::get_or_insert_with`] to control how the data is
+/// written.
+///
+/// `f` can't wait for its own result either: calling this again for the same
+/// key from inside `f` fails with [`Error::Reentrant`]. That's only caught
+/// while the nested call is polled as part of `f`'s future, though. If `f`
+/// hands it off to a task of its own and waits for that, it waits forever.
+///
+/// ## Example
+/// ```no_run
+/// use async_attributes;
+///
+/// #[async_attributes::main]
+/// async fn main() -> cacache::Result<()> {
+///     let (data, sri) = cacache::get_or_insert_with("./my-cache", "my-key", || async {
+///         // Expensive fetch goes here.
+///         Ok::<_, cacache::Error>(b"hello".to_vec())
+///     })
+///     .await?;
+///     Ok(())
+/// }
+/// ```
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub async fn get_or_insert_with<P, K, F, Fut, E>(
+    cache: P,
+    key: K,
+    f: F,
+) -> std::result::Result<(Vec<u8>, Integrity), E>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = std::result::Result<Vec<u8>, E>>,
+    E: From<Error>,
+{
+    get_or_insert_with_opts(
+        cache.as_ref(),
+        key.as_ref(),
+        ReadOpts::new(),
+        WriteOpts::new(),
+        f,
+    )
+    .await
+}
+
+/// Synchronously reads the data indexed under `key` along with its
+/// integrity, calling `f` to produce the data if it's missing. See
+/// [`get_or_insert_with`] for details.
+///
+/// Since `f` runs on the calling thread, it can't wait for its own result:
+/// calling this again for the same key from inside `f` fails with
+/// [`Error::Reentrant`] instead.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache::Result<()> {
+///     let (data, sri) = cacache::get_or_insert_with_sync("./my-cache", "my-key", || {
+///         // Expensive fetch goes here.
+///         Ok::<_, cacache::Error>(b"hello".to_vec())
+///     })?;
+///     Ok(())
+/// }
+/// ```
+pub fn get_or_insert_with_sync<P, K, F, E>(
+    cache: P,
+    key: K,
+    f: F,
+) -> std::result::Result<(Vec<u8>, Integrity), E>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+    F: FnOnce() -> std::result::Result<Vec<u8>, E>,
+    E: From<Error>,
+{
+    get_or_insert_with_opts_sync(
+        cache.as_ref(),
+        key.as_ref(),
+        ReadOpts::new(),
+        WriteOpts::new(),
+        f,
+    )
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub(crate) async fn get_or_insert_with_opts<F, Fut, E>(
+    cache: &Path,
+    key: &str,
+    read_opts: ReadOpts,
+    write_opts: WriteOpts,
+    f: F,
+) -> std::result::Result<(Vec<u8>, Integrity), E>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = std::result::Result<Vec<u8>, E>>,
+    E: From<Error>,
+{
+    let leader = loop {
+        if let Some(found) = lookup_async(cache, key, &read_opts).await? {
+            return Ok(found);
+        }
+        match join(cache, key) {
+            Role::Leader(leader) => break leader,
+            Role::Follower(flight) => {
+                if flight.is_producing() {
+                    return Err(Error::Reentrant(cache.to_path_buf(), key.to_string()).into());
+                }
+                if let Outcome::Landed(data, sri) = flight.wait_async().await {
+                    return Ok((data, sri));
+                }
+                // Whoever was producing the data gave up, so look again.
+            }
+        }
+    };
+    let _lock = lock_key(cache, key).await?;
+    // Another process might've written it while we were waiting.
+    if let Some((data, sri)) = lookup_async(cache, key, &read_opts).await? {
+        leader.land(&data, &sri);
+        return Ok((data, sri));
+    }
+    let mut produce = std::pin::pin!(f());
+    let data =
+        std::future::poll_fn(|cx| leader.flight.produce(|| produce.as_mut().poll(cx))).await?;
+    let mut writer = write_opts.size(data.len()).open(cache, key).await?;
+    writer.write_all(&data).await.with_context(|| {
+        format!("Failed to write to cache data for key {key} for cache at {cache:?}")
+    })?;
+    let sri = writer.commit().await?;
+    leader.land(&data, &sri);
+    Ok((data, sri))
+}
+
+pub(crate) fn get_or_insert_with_opts_sync<F, E>(
+    cache: &Path,
+    key: &str,
+    read_opts: ReadOpts,
+    write_opts: WriteOpts,
+    f: F,
+) -> std::result::Result<(Vec<u8>, Integrity), E>
+where
+    F: FnOnce() -> std::result::Result<Vec<u8>, E>,
+    E: From<Error>,
+{
+    let leader = loop {
+        if let Some(found) = lookup(cache, key, &read_opts)? {
+            return Ok(found);
+        }
+        match join(cache, key) {
+            Role::Leader(leader) => break leader,
+            Role::Follower(flight) => {
+                if flight.leader == thread::current().id() || flight.is_producing() {
+                    return Err(Error::Reentrant(cache.to_path_buf(), key.to_string()).into());
+                }
+                if let Outcome::Landed(data, sri) = flight.wait() {
+                    return Ok((data, sri));
+                }
+                // Whoever was producing the data gave up, so look again.
+            }
+        }
+    };
+    let _lock = lock_key_sync(cache, key)?;
+    // Another process might've written it while we were waiting.
+    if let Some((data, sri)) = lookup(cache, key, &read_opts)? {
+        leader.land(&data, &sri);
+        return Ok((data, sri));
+    }
+    let data = f()?;
+    let mut writer = write_opts.size(data.len()).open_sync(cache, key)?;
+    io::Write::write_all(&mut writer, &data).with_context(|| {
+        format!("Failed to write to cache data for key {key} for cache at {cache:?}")
+    })?;
+    let sri = writer.commit()?;
+    leader.land(&data, &sri);
+    Ok((data, sri))
+}
+
+/// Reads the data for `key` and its integrity, if it's in the cache. Data
+/// that fails its integrity check counts as missing, so that it's produced
+/// and written again.
+fn lookup(cache: &Path, key: &str, opts: &ReadOpts) -> Result<Option<(Vec<u8>, Integrity)>> {
+    let Some(entry) = opts.clone().metadata_sync(cache, key)? else {
+        return Ok(None);
+    };
+    opts.touch(cache, key);
+    let data = match entry.inline {
+        Some(data) => check(data, &entry.integrity),
+        None => opts.read_hash_sync(cache, &entry.integrity),
+    };
+    found(data, entry.integrity)
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+async fn lookup_async(
+    cache: &Path,
+    key: &str,
+    opts: &ReadOpts,
+) -> Result<Option<(Vec<u8>, Integrity)>> {
+    let Some(entry) = opts.clone().metadata(cache, key).await? else {
+        return Ok(None);
+    };
+    opts.touch_async(cache, key).await;
+    let data = match entry.inline {
+        Some(data) => check(data, &entry.integrity),
+        None => opts.read_hash(cache, &entry.integrity).await,
+    };
+    found(data, entry.integrity)
+}
+
+fn check(data: Vec<u8>, sri: &Integrity) -> Result<Vec<u8>> {
+    sri.check(&data)?;
+    Ok(data)
+}
+
+/// What a lookup found, given how reading the data for its entry went.
+fn found(data: Result<Vec<u8>>, sri: Integrity) -> Result<Option<(Vec<u8>, Integrity)>> {
+    match data {
+        Ok(data) => Ok(Some((data, sri))),
+        Err(Error::IntegrityError(_)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// How a computation of some key's data that others were waiting on ended.
+#[derive(Clone)]
+enum Outcome {
+    Landed(Vec<u8>, Integrity),
+    Aborted,
+}
+
+/// A computation of some key's data that other callers in this process can
+/// wait on, instead of starting their own.
+struct Flight {
+    state: Mutex<FlightState>,
+    finished: Condvar,
+    /// The thread that started the computation. Only meaningful for
+    /// synchronous callers, which can't wait on their own thread.
+    leader: ThreadId,
+}
+
+thread_local! {
+    /// The flights whose data is being produced by a future that's being
+    /// polled on this thread right now. Anything joining one of them from
+    /// there would be waiting on itself.
+    static PRODUCING: RefCell<Vec<Arc<Flight>>> = const { RefCell::new(Vec::new()) };
+}
+
+#[derive(Default)]
+struct FlightState {
+    outcome: Option<Outcome>,
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    wakers: Vec<Waker>,
+}
+
+impl Flight {
+    fn state(&self) -> MutexGuard<'_, FlightState> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Records how the computation ended, unless it already has been.
+    fn finish(&self, outcome: Outcome) {
+        let mut state = self.state();
+        if state.outcome.is_some() {
+            return;
+        }
+        state.outcome = Some(outcome);
+        #[cfg(any(feature = "async-std", feature = "tokio"))]
+        for waker in state.wakers.drain(..) {
+            waker.wake();
+        }
+        self.finished.notify_all();
+    }
+
+    /// Whether this flight's data is being produced further up the stack.
+    fn is_producing(self: &Arc<Self>) -> bool {
+        PRODUCING.with(|producing| {
+            producing
+                .borrow()
+                .iter()
+                .any(|flight| Arc::ptr_eq(flight, self))
+        })
+    }
+
+    /// Runs `poll`, a step in producing this flight's data, marked as such
+    /// for [`Flight::is_producing`].
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    fn produce<T>(self: &Arc<Self>, poll: impl FnOnce() -> T) -> T {
+        struct Unmark;
+        impl Drop for Unmark {
+            fn drop(&mut self) {
+                PRODUCING.with(|producing| producing.borrow_mut().pop());
+            }
+        }
+        PRODUCING.with(|producing| producing.borrow_mut().push(self.clone()));
+        let _unmark = Unmark;
+        poll()
+    }
+
+    fn wait(&self) -> Outcome {
+        let mut state = self.state();
+        loop {
+            if let Some(outcome) = &state.outcome {
+                return outcome.clone();
+            }
+            state = self
+                .finished
+                .wait(state)
+                .unwrap_or_else(|err| err.into_inner());
+        }
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    async fn wait_async(&self) -> Outcome {
+        std::future::poll_fn(|cx| {
+            let mut state = self.state();
+            match &state.outcome {
+                Some(outcome) => Poll::Ready(outcome.clone()),
+                None => {
+                    if !state.wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                        state.wakers.push(cx.waker().clone());
+                    }
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+}
+
+enum Role {
+    Leader(Leader),
+    Follower(Arc<Flight>),
+}
+
+/// The caller producing some key's data. Anyone waiting on it is told it
+/// gave up if this is dropped before the data lands.
+struct Leader {
+    id: (PathBuf, String),
+    flight: Arc<Flight>,
+}
+
+impl Leader {
+    fn land(self, data: &[u8], sri: &Integrity) {
+        self.flight
+            .finish(Outcome::Landed(data.to_vec(), sri.clone()));
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        flights().remove(&self.id);
+        self.flight.finish(Outcome::Aborted);
+    }
+}
+
+/// Starts producing the data for `key`, or joins whoever already is, however
+/// they spelled the path to `cache`.
+fn join(cache: &Path, key: &str) -> Role {
+    let id = (crate::memo::memo_key(cache), key.to_string());
+    let mut flights = flights();
+    if let Some(flight) = flights.get(&id) {
+        return Role::Follower(flight.clone());
+    }
+    let flight = Arc::new(Flight {
+        state: Default::default(),
+        finished: Condvar::new(),
+        leader: thread::current().id(),
+    });
+    flights.insert(id.clone(), flight.clone());
+    Role::Leader(Leader { id, flight })
+}
+
+type Flights = HashMap<(PathBuf, String), Arc<Flight>>;
+
+fn flights() -> MutexGuard<'static, Flights> {
+    static FLIGHTS: OnceLock<Mutex<Flights>> = OnceLock::new();
+    FLIGHTS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+}
+
+/// Where lock files for this cache live. Ones nobody holds are removed by
+/// [`crate::verify`].
+pub(crate) fn locks_dir(cache: &Path) -> PathBuf {
+    cache.join(format!("locks-v{LOCKS_VERSION}"))
+}
+
+fn lock_path(cache: &Path, key: &str) -> PathBuf {
+    locks_dir(cache).join(index::hash_key(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    use futures::FutureExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[cfg(feature = "async-std")]
+    use async_attributes::test as async_test;
+    #[cfg(feature = "tokio")]
+    use tokio::test as async_test;
+
+    #[cfg(all(unix, any(feature = "async-std", feature = "tokio")))]
+    #[async_test]
+    async fn lock_key_waits_without_blocking() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let held = lock_key_sync(dir, "my-key").unwrap();
+        let waiting = lock_key(dir, "my-key").fuse();
+        let release = async {
+            crate::async_lib::sleep(std::time::Duration::from_millis(20)).await;
+            drop(held);
+        };
+        let (lock, _) = futures::join!(waiting, release);
+        let _lock = lock.unwrap();
+        assert!(flock::try_open_locked(&lock_path(dir, "my-key"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn verify_removes_unused_locks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let held = lock_key_sync(dir, "held").unwrap();
+        drop(lock_key_sync(dir, "unused").unwrap());
+        crate::verify_sync(dir).unwrap();
+        assert!(lock_path(dir, "held").exists());
+        assert!(!lock_path(dir, "unused").exists());
+        drop(held);
+        // Locking a key again after its file was removed just makes a new one.
+        lock_key_sync(dir, "unused").unwrap();
+    }
+
+    #[test]
+    fn get_or_insert_with_sync_runs_once() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let threads = (0..8)
+            .map(|_| {
+                let dir = dir.clone();
+                let calls = calls.clone();
+                std::thread::spawn(move || {
+                    get_or_insert_with_sync(&dir, "my-key", || {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        std::thread::sleep(std::time::Duration::from_millis(50));
+                        Ok::<_, Error>(b"hello".to_vec())
+                    })
+                    .unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            let (data, sri) = thread.join().unwrap();
+            assert_eq!(data, b"hello");
+            assert_eq!(sri, Integrity::from(b"hello"));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(crate::read_sync(&dir, "my-key").unwrap(), b"hello");
+    }
+
+    #[test]
+    fn get_or_insert_with_sync_passes_errors_through() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let res = get_or_insert_with_sync(dir, "my-key", || {
+            Err::<Vec<u8>, _>(Error::SizeMismatch(1, 2))
+        });
+        assert!(matches!(res, Err(Error::SizeMismatch(1, 2))));
+        let (data, _) =
+            get_or_insert_with_sync(dir, "my-key", || Ok::<_, Error>(b"hello".to_vec())).unwrap();
+        assert_eq!(data, b"hello");
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    #[async_test]
+    async fn get_or_insert_with_coalesces() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let calls = AtomicUsize::new(0);
+        let (gate, opened) = futures::channel::oneshot::channel::<()>();
+        let opened = opened.shared();
+        let lookups = (0..8).map(|_| {
+            get_or_insert_with(dir, "my-key", || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                opened.clone().await.unwrap();
+                Ok::<_, Error>(b"hello".to_vec())
+            })
+        });
+        let all = futures::future::join_all(lookups);
+        let open = async {
+            // Give everyone else a chance to join the first lookup.
+            let _ = crate::async_lib::spawn_blocking(|| {
+                std::thread::sleep(std::time::Duration::from_millis(50))
+            })
+            .await;
+            gate.send(()).unwrap();
+        };
+        let (results, _) = futures::join!(all, open);
+        for res in results {
+            assert_eq!(res.unwrap().0, b"hello");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    #[async_test]
+    async fn get_or_insert_with_reads_existing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let sri = crate::write(dir, "my-key", b"hello").await.unwrap();
+        let found = get_or_insert_with(dir, "my-key", || async {
+            Err::<Vec<u8>, _>(Error::SizeMismatch(1, 2))
+        })
+        .await
+        .unwrap();
+        assert_eq!(found, (b"hello".to_vec(), sri));
+        let lock = lock_key(dir, "my-key").await.unwrap();
+        drop(lock);
+    }
+
+    #[test]
+    fn get_or_insert_with_sync_refuses_reentry() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let res = get_or_insert_with_sync(dir, "my-key", || {
+            let inner = get_or_insert_with_sync(dir, "my-key", || Ok::<_, Error>(b"a".to_vec()));
+            assert!(matches!(inner, Err(Error::Reentrant(_, _))));
+            Ok::<_, Error>(b"hello".to_vec())
+        });
+        assert_eq!(res.unwrap().0, b"hello");
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    #[async_test]
+    async fn get_or_insert_with_refuses_reentry() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let res = get_or_insert_with(dir, "my-key", || async {
+            let inner =
+                get_or_insert_with(dir, "my-key", || async { Ok::<_, Error>(b"a".to_vec()) }).await;
+            assert!(matches!(inner, Err(Error::Reentrant(_, _))));
+            Ok::<_, Error>(b"hello".to_vec())
+        })
+        .await;
+        assert_eq!(res.unwrap().0, b"hello");
+    }
+
+    #[test]
+    fn spellings_of_a_path_share_flights() {
+        let tmp = tempfile::tempdir().unwrap();
+        let Role::Leader(leader) = join(tmp.path(), "my-key") else {
+            panic!("nobody else is producing this key");
+        };
+        assert!(matches!(
+            join(&tmp.path().join("."), "my-key"),
+            Role::Follower(flight) if Arc::ptr_eq(&flight, &leader.flight)
+        ));
+    }
+
+    #[test]
+    fn get_or_insert_with_sync_replaces_corrupt_data() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let sri = crate::write_sync(dir, "my-key", b"hello").unwrap();
+        let cpath = crate::content::path::content_path(dir, &sri);
+        std::fs::write(&cpath, b"jello").unwrap();
+        let (data, got) =
+            get_or_insert_with_sync(dir, "my-key", || Ok::<_, Error>(b"hello".to_vec())).unwrap();
+        assert_eq!(data, b"hello");
+        assert_eq!(got, sri);
+        assert_eq!(crate::read_sync(dir, "my-key").unwrap(), b"hello");
+    }
+}
@@ -1,18 +1,69 @@
 //! Functions for iterating over the cache.
 use std::path::Path;
 
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+use futures::future;
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+use futures::stream::{Stream, StreamExt};
+
 use crate::errors::Result;
 use crate::index;
 
+/// Returns a stream that asynchronously lists all cache index entries.
+/// Expired entries are skipped.
+///
+/// ## Example
+/// ```no_run
+/// use async_attributes;
+/// use futures::stream::StreamExt;
+///
+/// #[async_attributes::main]
+/// async fn main() -> cacache::Result<()> {
+///     let mut entries = cacache::list("./my-cache");
+///     while let Some(entry) = entries.next().await {
+///         println!("{}", entry?.key);
+///     }
+///     Ok(())
+/// }
+/// ```
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub fn list<P: AsRef<Path>>(
+    cache: P,
+) -> impl Stream<Item = Result<index::Metadata>> + Send + Unpin {
+    index::ls_async(cache.as_ref())
+        .filter(|entry| future::ready(!matches!(entry, Ok(entry) if entry.is_expired())))
+}
+
 /// Returns a synchronous iterator that lists all cache index entries.
+/// Expired entries are skipped.
 pub fn list_sync<P: AsRef<Path>>(cache: P) -> impl Iterator<Item = Result<index::Metadata>> {
-    index::ls(cache.as_ref())
+    index::ls(cache.as_ref()).filter(|entry| !matches!(entry, Ok(entry) if entry.is_expired()))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[cfg(feature = "async-std")]
+    use async_attributes::test as async_test;
+    #[cfg(feature = "tokio")]
+    use tokio::test as async_test;
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    #[async_test]
+    async fn test_list() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+
+        assert!(list(&dir).next().await.unwrap().is_err());
+
+        crate::write(&dir, "hello", b"hello").await.unwrap();
+        crate::write(&dir, "world", b"world").await.unwrap();
+        let mut keys = list(&dir).map(|x| x.unwrap().key).collect::<Vec<_>>().await;
+        keys.sort();
+        assert_eq!(keys, vec![String::from("hello"), String::from("world")]);
+    }
+
     #[test]
     fn test_list_sync() {
         // check that the public interface to list elements can actually use the
@@ -25,4 +76,23 @@ mod tests {
             .collect::<Result<Vec<_>>>()
             .is_err())
     }
+
+    #[test]
+    fn test_list_sync_skips_expired() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::write_sync(&dir, "fresh", b"hello").unwrap();
+        let mut writer = crate::WriteOpts::new()
+            .expires_at(1)
+            .open_sync(&dir, "stale")
+            .unwrap();
+        std::io::Write::write_all(&mut writer, b"hello").unwrap();
+        writer.commit().unwrap();
+
+        let keys = list_sync(&dir)
+            .map(|x| Ok(x?.key))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(keys, vec![String::from("fresh")]);
+    }
 }
@@ -0,0 +1,344 @@
+//! An opt-in, in-process memory cache of recently used index entries and
+//! content, in front of the cache on disk.
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+
+use ssri::Integrity;
+
+use crate::index::Metadata;
+
+/// Keeps up to `max_size` bytes of recently used entries and content from
+/// `cache` in memory, for the rest of this process's life, or stops doing so
+/// if `max_size` is `None`.
+///
+/// Once enabled, [`crate::read`], [`crate::metadata`], [`crate::read_hash`]
+/// and the rest of the reads by key or by hash are answered from memory
+/// whenever they can be, skipping the filesystem and integrity checks
+/// altogether. Content is remembered when it's read, and when it's written
+/// by a [`crate::Writer`] or [`crate::SyncWriter`] whose declared
+/// [`crate::WriteOpts::size`] is no more than `max_size` bytes. The least
+/// recently used items are forgotten first once there's no more room.
+///
+/// Removing keys or content through this crate, clearing the cache, or
+/// otherwise cleaning it up, forgets whatever it affects. Changes made by
+/// other processes aren't noticed, though, so only turn this on for caches
+/// this process is the only writer of, or where a stale read now and then
+/// is fine. Reads and writes that use an encryption key always skip the
+/// memory cache. Different spellings of the cache's path, like `./my-cache`
+/// and `my-cache`, share the same one.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache::Result<()> {
+///     cacache::set_memo_size("./my-cache", Some(64 * 1024 * 1024));
+///     cacache::write_sync("./my-cache", "my-key", b"hello")?;
+///     // Doesn't touch the disk.
+///     let data = cacache::read_sync("./my-cache", "my-key")?;
+///     Ok(())
+/// }
+/// ```
+pub fn set_memo_size<P: AsRef<Path>>(cache: P, max_size: Option<usize>) {
+    let cache = memo_key(cache.as_ref());
+    let mut memos = memos();
+    match max_size {
+        Some(max_size) => {
+            let memo = memos.entry(cache).or_insert_with(|| Memo::new(max_size));
+            memo.max_size = max_size;
+            memo.shrink();
+        }
+        None => {
+            memos.remove(&cache);
+        }
+    }
+}
+
+/// Returns the most memory the memory cache for `cache` may use, if one was
+/// set up with [`set_memo_size`].
+pub fn memo_size<P: AsRef<Path>>(cache: P) -> Option<usize> {
+    with_memo(cache.as_ref(), |memo| memo.max_size)
+}
+
+/// Returns the remembered index entry for `key`, if there is one.
+pub(crate) fn entry(cache: &Path, key: &str) -> Option<Metadata> {
+    with_memo(cache, |memo| {
+        match memo.get(&Slot::Entry(key.to_string()))? {
+            Item::Entry(entry) => Some(entry.clone()),
+            Item::Content(_) => None,
+        }
+    })
+    .flatten()
+}
+
+/// Remembers `entry` as the current index entry for its key.
+pub(crate) fn put_entry(cache: &Path, entry: &Metadata) {
+    with_memo(cache, |memo| {
+        let slot = Slot::Entry(entry.key.clone());
+        memo.put(slot, Item::Entry(entry.clone()));
+    });
+}
+
+/// Returns the remembered content for `sri`, if there is any. It's shared
+/// with the memory cache, so callers only pay for a copy if they need one.
+pub(crate) fn content(cache: &Path, sri: &Integrity) -> Option<Arc<[u8]>> {
+    with_memo(cache, |memo| {
+        match memo.get(&Slot::Content(sri.to_string()))? {
+            Item::Content(data) => Some(Arc::clone(data)),
+            Item::Entry(_) => None,
+        }
+    })
+    .flatten()
+}
+
+/// Remembers `data` as the content for `sri`.
+pub(crate) fn put_content(cache: &Path, sri: &Integrity, data: &[u8]) {
+    with_memo(cache, |memo| {
+        if data.len() <= memo.max_size {
+            memo.put(Slot::Content(sri.to_string()), Item::Content(data.into()));
+        }
+    });
+}
+
+/// Forgets the index entry for `key`, after it's been changed on disk.
+pub(crate) fn forget_entry(cache: &Path, key: &str) {
+    with_memo(cache, |memo| memo.remove(&Slot::Entry(key.to_string())));
+}
+
+/// Forgets the content for `sri`, after it's been removed from disk.
+pub(crate) fn forget_content(cache: &Path, sri: &Integrity) {
+    with_memo(cache, |memo| memo.remove(&Slot::Content(sri.to_string())));
+}
+
+/// Forgets everything about `cache`, after changes too broad to keep track
+/// of individually.
+pub(crate) fn forget_all(cache: &Path) {
+    with_memo(cache, |memo| *memo = Memo::new(memo.max_size));
+}
+
+/// Calls `f` with the memory cache for `cache`, if it has one. Processes
+/// that never set one up don't pay for looking up the path.
+fn with_memo<T>(cache: &Path, f: impl FnOnce(&mut Memo) -> T) -> Option<T> {
+    if memos().is_empty() {
+        return None;
+    }
+    let cache = memo_key(cache);
+    memos().get_mut(&cache).map(f)
+}
+
+/// The path per-process state for `cache`, like its memory cache, is kept
+/// under, which is the same for every spelling of it. Only the part of the
+/// path that exists can be canonicalized, so the rest is tacked back on as
+/// is, which keeps the key the same once the cache is created.
+pub(crate) fn memo_key(cache: &Path) -> PathBuf {
+    let mut existing = cache;
+    let mut missing = Vec::new();
+    loop {
+        let dir = if existing.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            existing
+        };
+        if let Ok(canonical) = dir.canonicalize() {
+            return missing
+                .into_iter()
+                .rev()
+                .fold(canonical, |path, name| path.join(name));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name);
+                existing = parent;
+            }
+            _ => return cache.to_path_buf(),
+        }
+    }
+}
+
+fn memos() -> MutexGuard<'static, HashMap<PathBuf, Memo>> {
+    static MEMOS: OnceLock<Mutex<HashMap<PathBuf, Memo>>> = OnceLock::new();
+    MEMOS
+        .get_or_init(Default::default)
+        .lock()
+        // Nothing in here panics halfway through an update.
+        .unwrap_or_else(|err| err.into_inner())
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Slot {
+    Entry(String),
+    Content(String),
+}
+
+enum Item {
+    Entry(Metadata),
+    Content(Arc<[u8]>),
+}
+
+impl Item {
+    /// Roughly how much memory this takes up.
+    fn size(&self) -> usize {
+        match self {
+            Item::Entry(entry) => {
+                std::mem::size_of::<Metadata>()
+                    + entry.key.len()
+                    + entry.metadata.to_string().len()
+                    + entry.raw_metadata.as_ref().map_or(0, Vec::len)
+                    + entry.inline().map_or(0, <[u8]>::len)
+            }
+            Item::Content(data) => data.len(),
+        }
+    }
+}
+
+/// A least-recently-used map, bounded by the total size of what's in it.
+struct Memo {
+    max_size: usize,
+    size: usize,
+    clock: u64,
+    items: HashMap<Slot, (Item, usize, u64)>,
+    by_use: BTreeMap<u64, Slot>,
+}
+
+impl Memo {
+    fn new(max_size: usize) -> Self {
+        Memo {
+            max_size,
+            size: 0,
+            clock: 0,
+            items: HashMap::new(),
+            by_use: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, slot: &Slot) -> Option<&Item> {
+        self.clock += 1;
+        let (item, _, used) = self.items.get_mut(slot)?;
+        self.by_use.remove(used);
+        self.by_use.insert(self.clock, slot.clone());
+        *used = self.clock;
+        Some(item)
+    }
+
+    fn put(&mut self, slot: Slot, item: Item) {
+        self.remove(&slot);
+        let size = item.size();
+        if size > self.max_size {
+            return;
+        }
+        self.clock += 1;
+        self.size += size;
+        self.by_use.insert(self.clock, slot.clone());
+        self.items.insert(slot, (item, size, self.clock));
+        self.shrink();
+    }
+
+    fn remove(&mut self, slot: &Slot) {
+        if let Some((_, size, used)) = self.items.remove(slot) {
+            self.by_use.remove(&used);
+            self.size -= size;
+        }
+    }
+
+    /// Forgets the least recently used items until everything fits.
+    fn shrink(&mut self) {
+        while self.size > self.max_size {
+            let Some((_, slot)) = self.by_use.pop_first() else {
+                break;
+            };
+            if let Some((_, size, _)) = self.items.remove(&slot) {
+                self.size -= size;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forgets_least_recently_used_content() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        set_memo_size(dir, Some(10));
+        let (a, b, c) = (
+            Integrity::from(b"aaaa"),
+            Integrity::from(b"bbbb"),
+            Integrity::from(b"cccc"),
+        );
+        put_content(dir, &a, b"aaaa");
+        put_content(dir, &b, b"bbbb");
+        assert_eq!(content(dir, &a).as_deref(), Some(&b"aaaa"[..]));
+        put_content(dir, &c, b"cccc");
+        assert_eq!(content(dir, &a).as_deref(), Some(&b"aaaa"[..]));
+        assert_eq!(content(dir, &b), None);
+        assert_eq!(content(dir, &c).as_deref(), Some(&b"cccc"[..]));
+        put_content(dir, &b, b"this is too big to remember");
+        assert_eq!(content(dir, &b), None);
+        set_memo_size(dir, None);
+        assert_eq!(content(dir, &a), None);
+    }
+
+    #[test]
+    fn serves_reads_from_memory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        set_memo_size(dir, Some(1024));
+        let sri = crate::write_sync(dir, "my-key", b"hello").unwrap();
+        // Pull the rug out from under the cache on disk.
+        std::fs::remove_dir_all(
+            crate::content::path::content_path(dir, &sri)
+                .parent()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(crate::read_hash_sync(dir, &sri).unwrap(), b"hello");
+        assert_eq!(crate::read_sync(dir, "my-key").unwrap(), b"hello");
+        assert!(crate::metadata_sync(dir, "my-key").unwrap().is_some());
+
+        crate::remove_sync(dir, "my-key").unwrap();
+        assert!(crate::read_sync(dir, "my-key").is_err());
+        assert_eq!(crate::read_hash_sync(dir, &sri).unwrap(), b"hello");
+        crate::clear_sync(dir).unwrap();
+        assert!(crate::read_hash_sync(dir, &sri).is_err());
+        set_memo_size(dir, None);
+    }
+
+    #[test]
+    fn spellings_of_a_path_share_a_memo() {
+        let tmp = tempfile::tempdir().unwrap();
+        // Doesn't exist yet when the memory cache is set up.
+        let dir = tmp.path().join("cache");
+        set_memo_size(&dir, Some(1024));
+        let other = tmp.path().join(".").join("cache");
+        assert_eq!(memo_size(&other), Some(1024));
+        let sri = crate::write_sync(&other, "my-key", b"hello").unwrap();
+        assert_eq!(content(&dir, &sri).as_deref(), Some(&b"hello"[..]));
+        crate::remove_sync(&dir, "my-key").unwrap();
+        assert!(crate::read_sync(&other, "my-key").is_err());
+        set_memo_size(&other, None);
+        assert_eq!(memo_size(&dir), None);
+    }
+
+    #[test]
+    fn only_declared_sizes_are_held_onto() {
+        use std::io::Write;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        set_memo_size(dir, Some(1024));
+        let mut writer = crate::SyncWriter::create(dir, "my-key").unwrap();
+        writer.write_all(b"hello").unwrap();
+        let sri = writer.commit().unwrap();
+        assert_eq!(content(dir, &sri), None);
+
+        let mut writer = crate::WriteOpts::new()
+            .size(5)
+            .open_sync(dir, "my-key")
+            .unwrap();
+        writer.write_all(b"hello").unwrap();
+        let sri = writer.commit().unwrap();
+        assert_eq!(content(dir, &sri).as_deref(), Some(&b"hello"[..]));
+        set_memo_size(dir, None);
+    }
+}
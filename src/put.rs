@@ -1,17 +1,28 @@
 //! Functions for writing to cache.
+use std::fs::File;
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+use std::future::Future;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 use std::pin::Pin;
+use std::time::Duration;
 
 use serde_json::Value;
-use ssri::{Algorithm, Integrity};
+use ssri::{Algorithm, Integrity, IntegrityOpts};
 
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 use crate::async_lib::{AsyncWrite, AsyncWriteExt};
+use crate::content::chunk::Chunking;
+use crate::content::compress::Compression;
+use crate::content::encrypt::EncryptionKey;
+use crate::content::pack;
 use crate::content::write;
+use crate::durability::Durability;
 use crate::errors::{Error, IoErrorExt, Result};
-use crate::index;
+use crate::get::ReadOpts;
+use crate::index::{self, Metadata};
+use crate::memo;
 
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 use std::task::{Context as TaskContext, Poll};
@@ -131,14 +142,84 @@ where
     }
     inner(algo, cache.as_ref(), data.as_ref()).await
 }
+
+/// Changes the metadata for `key` without touching its content, by calling
+/// `f` with the current entry and indexing what it returns.
+///
+/// Only the `metadata`, `raw_metadata` and `expires` that `f` returns are
+/// used. The new entry keeps pointing at the same content, with the same
+/// size, and gets the current time as its `time`. Returns the new entry, or
+/// [`Error::EntryNotFound`] if there's no entry for `key`.
+///
+/// The key's index bucket is locked from reading the current entry until the
+/// new one is written, so updates and writes racing with this one can't be
+/// lost. `f` is called while it's locked, and shouldn't write to the cache
+/// itself. Entries whose metadata was written with
+/// [`WriteOpts::encrypt_metadata`] can't be updated, and fail with
+/// [`Error::MetadataSealed`].
+///
+/// ## Example
+/// ```no_run
+/// use async_attributes;
+///
+/// #[async_attributes::main]
+/// async fn main() -> cacache::Result<()> {
+///     let entry = cacache::update_metadata("./my-cache", "my-key", |mut entry| {
+///         entry.metadata = serde_json::json!({ "etag": "abc123" });
+///         entry
+///     })
+///     .await?;
+///     Ok(())
+/// }
+/// ```
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub async fn update_metadata<P, K, F>(cache: P, key: K, f: F) -> Result<Metadata>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+    F: FnOnce(Metadata) -> Metadata,
+{
+    index::update_async(cache.as_ref(), key.as_ref(), |old| metadata_update(old, f)).await
+}
+
+/// Builds the entry [`update_metadata`] replaces `old` with, along with the
+/// options to index it with.
+fn metadata_update<F>(old: Metadata, f: F) -> (WriteOpts, Metadata)
+where
+    F: FnOnce(Metadata) -> Metadata,
+{
+    let new = f(old.clone());
+    let new = Metadata {
+        time: index::now(),
+        metadata: new.metadata,
+        raw_metadata: new.raw_metadata,
+        expires: new.expires,
+        ..old
+    };
+    let mut opts = WriteOpts::new()
+        .integrity(new.integrity.clone())
+        .size(new.size)
+        .time(new.time)
+        .metadata(new.metadata.clone());
+    opts.raw_metadata = new.raw_metadata.clone();
+    opts.expires = new.expires;
+    opts.inline_data = new.inline.clone();
+    (opts, new)
+}
+
 /// A reference to an open file writing to the cache.
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 pub struct Writer {
     cache: PathBuf,
     key: Option<String>,
     written: usize,
-    pub(crate) writer: write::AsyncWriter,
+    /// Left out when the write was declared small enough not to need a
+    /// content file.
+    pub(crate) writer: Option<write::AsyncWriter>,
     opts: WriteOpts,
+    small: Option<Vec<u8>>,
+    memo_limit: Option<usize>,
+    memo: Option<Vec<u8>>,
 }
 
 #[cfg(any(feature = "async-std", feature = "tokio"))]
@@ -148,18 +229,31 @@ impl AsyncWrite for Writer {
         cx: &mut TaskContext<'_>,
         buf: &[u8],
     ) -> Poll<std::io::Result<usize>> {
-        let amt = futures::ready!(Pin::new(&mut self.writer).poll_write(cx, buf))?;
+        let amt = match &mut self.writer {
+            Some(writer) => futures::ready!(Pin::new(writer).poll_write(cx, buf))?,
+            None => check_declared(&self.opts, self.written, buf.len())?,
+        };
         self.written += amt;
+        let limit = self.opts.small_limit(self.key.is_some());
+        buffer_small(&mut self.small, limit, &buf[..amt]);
+        let memo_limit = self.memo_limit;
+        buffer_small(&mut self.memo, memo_limit, &buf[..amt]);
         Poll::Ready(Ok(amt))
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
-        Pin::new(&mut self.writer).poll_flush(cx)
+        match &mut self.writer {
+            Some(writer) => Pin::new(writer).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
     }
 
     #[cfg(feature = "async-std")]
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
-        Pin::new(&mut self.writer).poll_close(cx)
+        match &mut self.writer {
+            Some(writer) => Pin::new(writer).poll_close(cx),
+            None => Poll::Ready(Ok(())),
+        }
     }
 
     #[cfg(feature = "tokio")]
@@ -167,7 +261,10 @@ impl AsyncWrite for Writer {
         mut self: Pin<&mut Self>,
         cx: &mut TaskContext<'_>,
     ) -> Poll<std::io::Result<()>> {
-        Pin::new(&mut self.writer).poll_shutdown(cx)
+        match &mut self.writer {
+            Some(writer) => Pin::new(writer).poll_shutdown(cx),
+            None => Poll::Ready(Ok(())),
+        }
     }
 }
 
@@ -225,13 +322,52 @@ impl Writer {
         inner(algo, cache.as_ref(), key.as_ref()).await
     }
 
+    /// How many bytes have been written so far, including any written before
+    /// a [`WriteOpts::resume`]d write was picked back up.
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
     /// Closes the Writer handle and writes content and index entries. Also
     /// verifies data against `size` and `integrity` options, if provided.
     /// Must be called manually in order to complete the writing process,
     /// otherwise everything will be thrown out.
-    pub async fn commit(mut self) -> Result<Integrity> {
+    pub async fn commit(self) -> Result<Integrity> {
+        let cache = self.cache.clone();
+        let key = self.key.clone();
+        let added = self.written as u64;
+        let (opts, writer_sri, _pack_lock, _) = self.finish().await?;
+        let limit = opts.max_size;
+        let sri = if let Some(key) = key {
+            index::insert_async(&cache, &key, opts).await?
+        } else {
+            writer_sri
+        };
+        crate::evict::enforce_max_size_async(&cache, limit, added).await?;
+        Ok(sri)
+    }
+
+    /// Writes and checks the content, returning the options to index it
+    /// with, with their integrity filled in, and the integrity the content
+    /// was actually written with. If the content was packed, also returns
+    /// the lock that keeps [`crate::repack`] from dropping it, which has to
+    /// be held until it's indexed. Content written to a file of its own that
+    /// wasn't there before comes with that file's path.
+    pub(crate) async fn finish(
+        mut self,
+    ) -> Result<(WriteOpts, Integrity, Option<File>, Option<PathBuf>)> {
         let cache = self.cache;
-        let writer_sri = self.writer.close().await?;
+        let (writer_sri, packed, created) = match (self.small.take(), self.writer) {
+            (Some(data), _) => {
+                let (sri, packed) = self.opts.take_small(self.key.is_some(), data);
+                (sri, packed, None)
+            }
+            (None, Some(writer)) => {
+                let (sri, created) = writer.close().await?;
+                (sri, None, created)
+            }
+            (None, None) => unreachable!("writes without a content file keep their data"),
+        };
         if let Some(sri) = &self.opts.sri {
             if sri.matches(&writer_sri).is_none() {
                 return Err(ssri::Error::IntegrityCheckError(sri.clone(), writer_sri).into());
@@ -244,11 +380,17 @@ impl Writer {
                 return Err(Error::SizeMismatch(size, self.written));
             }
         }
-        if let Some(key) = self.key {
-            index::insert_async(&cache, &key, self.opts).await
-        } else {
-            Ok(writer_sri)
+        // Only packed once it's known to be what was expected.
+        let pack_lock = match packed {
+            Some(data) => {
+                Some(pack::insert_async(&cache, &writer_sri, data, self.opts.durability).await?)
+            }
+            None => None,
+        };
+        if let Some(data) = self.memo {
+            memo::put_content(&cache, &writer_sri, &data);
         }
+        Ok((self.opts, writer_sri, pack_lock, created))
     }
 }
 
@@ -296,7 +438,10 @@ where
     K: AsRef<str>,
 {
     fn inner(algo: Algorithm, cache: &Path, key: &str, data: &[u8]) -> Result<Integrity> {
-        let mut writer = SyncWriter::create_with_algo(algo, cache, key)?;
+        let mut writer = WriteOpts::new()
+            .algorithm(algo)
+            .size(data.len())
+            .open_sync(cache, key)?;
         writer.write_all(data).with_context(|| {
             format!("Failed to write to cache data for key {key} for cache at {cache:?}")
         })?;
@@ -354,6 +499,29 @@ where
     }
     inner(algo, cache.as_ref(), data.as_ref())
 }
+
+/// Synchronously changes the metadata for `key` without touching its
+/// content. See [`update_metadata`] for details.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache::Result<()> {
+///     let entry = cacache::update_metadata_sync("./my-cache", "my-key", |mut entry| {
+///         entry.metadata = serde_json::json!({ "etag": "abc123" });
+///         entry
+///     })?;
+///     Ok(())
+/// }
+/// ```
+pub fn update_metadata_sync<P, K, F>(cache: P, key: K, f: F) -> Result<Metadata>
+where
+    P: AsRef<Path>,
+    K: AsRef<str>,
+    F: FnOnce(Metadata) -> Metadata,
+{
+    index::update(cache.as_ref(), key.as_ref(), |old| metadata_update(old, f))
+}
+
 /// Builder for options and flags for opening a new cache file to write data into.
 #[derive(Clone, Default)]
 pub struct WriteOpts {
@@ -363,6 +531,29 @@ pub struct WriteOpts {
     pub(crate) time: Option<u128>,
     pub(crate) metadata: Option<Value>,
     pub(crate) raw_metadata: Option<Vec<u8>>,
+    pub(crate) ttl: Option<Duration>,
+    pub(crate) expires: Option<u128>,
+    pub(crate) compression: Option<Compression>,
+    pub(crate) encryption_key: Option<EncryptionKey>,
+    pub(crate) encrypt_metadata: bool,
+    pub(crate) tmp_dir: Option<PathBuf>,
+    pub(crate) mmap_threshold: Option<usize>,
+    pub(crate) read_only: bool,
+    pub(crate) durability: Durability,
+    pub(crate) max_size: Option<u64>,
+    pub(crate) chunking: Option<Chunking>,
+    pub(crate) inline_threshold: Option<usize>,
+    pub(crate) inline_data: Option<Vec<u8>>,
+    pub(crate) pack_threshold: Option<usize>,
+    pub(crate) resume: Option<String>,
+    pub(crate) condition: Option<Condition>,
+}
+
+/// What a conditional write expects to find in the index for its key.
+#[derive(Clone)]
+pub(crate) enum Condition {
+    Absent,
+    Integrity(Integrity),
 }
 
 impl WriteOpts {
@@ -379,16 +570,23 @@ impl WriteOpts {
         K: AsRef<str>,
     {
         async fn inner(me: WriteOpts, cache: &Path, key: &str) -> Result<Writer> {
+            if me.read_only {
+                return Err(Error::ReadOnly(cache.to_path_buf()));
+            }
+            let writer = if me.fits_small(true) {
+                write::check_opts(&me)?;
+                None
+            } else {
+                Some(write::AsyncWriter::new(cache, &me, None).await?)
+            };
             Ok(Writer {
                 cache: cache.to_path_buf(),
                 key: Some(String::from(key)),
-                written: 0,
-                writer: write::AsyncWriter::new(
-                    cache,
-                    me.algorithm.unwrap_or(Algorithm::Sha256),
-                    None,
-                )
-                .await?,
+                written: writer.as_ref().map_or(0, |writer| writer.resumed()),
+                writer,
+                small: me.small_limit(true).map(|_| Vec::new()),
+                memo_limit: me.memo_limit(cache),
+                memo: me.memo_limit(cache).map(|_| Vec::new()),
                 opts: me,
             })
         }
@@ -402,16 +600,23 @@ impl WriteOpts {
         P: AsRef<Path>,
     {
         async fn inner(me: WriteOpts, cache: &Path) -> Result<Writer> {
+            if me.read_only {
+                return Err(Error::ReadOnly(cache.to_path_buf()));
+            }
+            let writer = if me.fits_small(false) {
+                write::check_opts(&me)?;
+                None
+            } else {
+                Some(write::AsyncWriter::new(cache, &me, me.size).await?)
+            };
             Ok(Writer {
                 cache: cache.to_path_buf(),
                 key: None,
-                written: 0,
-                writer: write::AsyncWriter::new(
-                    cache,
-                    me.algorithm.unwrap_or(Algorithm::Sha256),
-                    me.size,
-                )
-                .await?,
+                written: writer.as_ref().map_or(0, |writer| writer.resumed()),
+                writer,
+                small: me.small_limit(false).map(|_| Vec::new()),
+                memo_limit: me.memo_limit(cache),
+                memo: me.memo_limit(cache).map(|_| Vec::new()),
                 opts: me,
             })
         }
@@ -425,15 +630,23 @@ impl WriteOpts {
         K: AsRef<str>,
     {
         fn inner(me: WriteOpts, cache: &Path, key: &str) -> Result<SyncWriter> {
+            if me.read_only {
+                return Err(Error::ReadOnly(cache.to_path_buf()));
+            }
+            let writer = if me.fits_small(true) {
+                write::check_opts(&me)?;
+                None
+            } else {
+                Some(write::Writer::new(cache, &me, me.size)?)
+            };
             Ok(SyncWriter {
                 cache: cache.to_path_buf(),
                 key: Some(String::from(key)),
-                written: 0,
-                writer: write::Writer::new(
-                    cache,
-                    me.algorithm.unwrap_or(Algorithm::Sha256),
-                    me.size,
-                )?,
+                written: writer.as_ref().map_or(0, |writer| writer.resumed()),
+                writer,
+                small: me.small_limit(true).map(|_| Vec::new()),
+                memo_limit: me.memo_limit(cache),
+                memo: me.memo_limit(cache).map(|_| Vec::new()),
                 opts: me,
             })
         }
@@ -446,21 +659,89 @@ impl WriteOpts {
         P: AsRef<Path>,
     {
         fn inner(me: WriteOpts, cache: &Path) -> Result<SyncWriter> {
+            if me.read_only {
+                return Err(Error::ReadOnly(cache.to_path_buf()));
+            }
+            let writer = if me.fits_small(false) {
+                write::check_opts(&me)?;
+                None
+            } else {
+                Some(write::Writer::new(cache, &me, me.size)?)
+            };
             Ok(SyncWriter {
                 cache: cache.to_path_buf(),
                 key: None,
-                written: 0,
-                writer: write::Writer::new(
-                    cache,
-                    me.algorithm.unwrap_or(Algorithm::Sha256),
-                    me.size,
-                )?,
+                written: writer.as_ref().map_or(0, |writer| writer.resumed()),
+                writer,
+                small: me.small_limit(false).map(|_| Vec::new()),
+                memo_limit: me.memo_limit(cache),
+                memo: me.memo_limit(cache).map(|_| Vec::new()),
                 opts: me,
             })
         }
         inner(self, cache.as_ref())
     }
 
+    /// Reads the data indexed under `key` along with its integrity, calling
+    /// `f` to produce the data and writing it with these options if it's
+    /// missing. Concurrent misses for the same key only run `f` once. See
+    /// [`crate::get_or_insert_with`] for details.
+    ///
+    /// The data is read back with the key passed to [`WriteOpts::encrypt`],
+    /// if there is one.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use async_attributes;
+    ///
+    /// #[async_attributes::main]
+    /// async fn main() -> cacache::Result<()> {
+    ///     let (data, sri) = cacache::WriteOpts::new()
+    ///         .algorithm(cacache::Algorithm::Xxh3)
+    ///         .get_or_insert_with("./my-cache", "my-key", || async {
+    ///             Ok::<_, cacache::Error>(b"hello".to_vec())
+    ///         })
+    ///         .await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn get_or_insert_with<P, K, F, Fut, E>(
+        self,
+        cache: P,
+        key: K,
+        f: F,
+    ) -> std::result::Result<(Vec<u8>, Integrity), E>
+    where
+        P: AsRef<Path>,
+        K: AsRef<str>,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = std::result::Result<Vec<u8>, E>>,
+        E: From<Error>,
+    {
+        let read_opts = self.read_opts();
+        crate::lock::get_or_insert_with_opts(cache.as_ref(), key.as_ref(), read_opts, self, f).await
+    }
+
+    /// Synchronously reads the data indexed under `key` along with its
+    /// integrity, calling `f` to produce the data and writing it with these
+    /// options if it's missing. See [`WriteOpts::get_or_insert_with`].
+    pub fn get_or_insert_with_sync<P, K, F, E>(
+        self,
+        cache: P,
+        key: K,
+        f: F,
+    ) -> std::result::Result<(Vec<u8>, Integrity), E>
+    where
+        P: AsRef<Path>,
+        K: AsRef<str>,
+        F: FnOnce() -> std::result::Result<Vec<u8>, E>,
+        E: From<Error>,
+    {
+        let read_opts = self.read_opts();
+        crate::lock::get_or_insert_with_opts_sync(cache.as_ref(), key.as_ref(), read_opts, self, f)
+    }
+
     /// Configures the algorithm to write data under.
     pub fn algorithm(mut self, algo: Algorithm) -> Self {
         self.algorithm = Some(algo);
@@ -494,6 +775,159 @@ impl WriteOpts {
         self
     }
 
+    /// Sets the directory temporary files are written to before being moved
+    /// into the cache. Defaults to `{cache}/tmp`. It must be on the same
+    /// filesystem as the cache.
+    pub fn tmp_dir(mut self, tmp_dir: impl AsRef<Path>) -> Self {
+        self.tmp_dir = Some(tmp_dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets the largest expected [`WriteOpts::size`], in bytes, for which
+    /// data is written through a memory map when the "mmap" feature is on.
+    /// Defaults to 1MiB.
+    pub fn mmap_threshold(mut self, mmap_threshold: usize) -> Self {
+        self.mmap_threshold = Some(mmap_threshold);
+        self
+    }
+
+    /// Stores data of at most `inline_threshold` bytes directly in its index
+    /// entry instead of in a content file, saving a file (and its inode) per
+    /// tiny entry. Reads, copies and existence checks find inline data
+    /// transparently, by key or by hash. Doesn't apply to
+    /// [`WriteOpts::open_hash`], or to encrypted or chunked writes.
+    pub fn inline_threshold(mut self, inline_threshold: usize) -> Self {
+        self.inline_threshold = Some(inline_threshold);
+        self
+    }
+
+    /// Appends data of at most `pack_threshold` bytes to a shared pack file
+    /// instead of giving it a content file of its own, so caches with lots of
+    /// small entries don't run out of inodes. Packed data is read back
+    /// transparently, but can't be hard linked or reflinked out of the
+    /// cache, and doesn't count towards [`crate::set_max_size`]. The space it
+    /// takes up is only reclaimed by [`crate::repack`].
+    ///
+    /// Doesn't apply to encrypted or chunked writes. Data small enough for
+    /// [`WriteOpts::inline_threshold`] is stored inline instead.
+    pub fn pack_threshold(mut self, pack_threshold: usize) -> Self {
+        self.pack_threshold = Some(pack_threshold);
+        self
+    }
+
+    /// Makes the write resumable under `token`: its temp file is kept when the
+    /// writer is dropped without being committed, and the next writer opened
+    /// with the same `token` appends to whatever it already holds. Check
+    /// `written()` on the new writer to find out where to carry on from.
+    ///
+    /// Only one writer can use a `token` at a time, and opening another one
+    /// fails until it's dropped. SHA-256 writes save their hasher's state
+    /// every so often, so that resuming only hashes what was written since.
+    /// With other algorithms, the data that's already there is hashed again.
+    /// Resumable writes can't be compressed, encrypted or chunked, and
+    /// [`crate::clean_tmp`] removes ones that were left alone for too long.
+    pub fn resume(mut self, token: impl Into<String>) -> Self {
+        self.resume = Some(token.into());
+        self
+    }
+
+    /// Sets how hard the write tries to survive a crash or power loss.
+    /// Defaults to [`Durability::None`].
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Compresses the data on disk. The resulting [`Integrity`] still
+    /// describes the uncompressed data, and reads decompress it transparently.
+    /// Compressed content can't be hard linked or reflinked out of the cache.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Splits the data into chunks stored separately, each with its own
+    /// integrity, so chunks shared with other chunked content are only stored
+    /// once and [`crate::read_range`] only checks the chunks it reads. The
+    /// resulting [`Integrity`] still describes the whole of the data.
+    ///
+    /// Chunked content can't also be compressed or encrypted, and can't be
+    /// hard linked or reflinked out of the cache. Chunks are removed by
+    /// [`crate::verify`] and by eviction once no content uses them anymore,
+    /// and count towards [`crate::set_max_size`].
+    pub fn chunking(mut self, chunking: Chunking) -> Self {
+        self.chunking = Some(chunking);
+        self
+    }
+
+    /// Encrypts the data on disk with `key`. The resulting [`Integrity`]
+    /// still describes the plaintext, and reading the data back requires
+    /// passing the same key to [`crate::ReadOpts::encryption_key`].
+    /// Encrypted content can't be hard linked or reflinked out of the cache.
+    ///
+    /// Content is still stored under the hash of its plaintext, so anyone who
+    /// can list the cache directory can tell whether it holds some data they
+    /// already know, even without the key.
+    #[cfg(feature = "encryption")]
+    pub fn encrypt(mut self, key: EncryptionKey) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Also encrypts this entry's metadata and raw metadata in the index,
+    /// using the key passed to [`WriteOpts::encrypt`]. The key, integrity,
+    /// size and timestamps are still stored in the clear so lookups keep
+    /// working. Writing fails if there's no key to encrypt with, and reading
+    /// the entry's metadata fails with [`crate::Error::MetadataSealed`]
+    /// without the key.
+    #[cfg(feature = "encryption")]
+    pub fn encrypt_metadata(mut self, encrypt: bool) -> Self {
+        self.encrypt_metadata = encrypt;
+        self
+    }
+
+    /// Sets how long after its write time this entry stays fresh. Once it
+    /// expires, reads by key treat the entry as missing unless
+    /// [`crate::ReadOpts::allow_stale`] is set.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Sets the specific time in unix milliseconds at which this entry
+    /// expires. Takes precedence over [`WriteOpts::ttl`].
+    pub fn expires_at(mut self, expires: u128) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+
+    /// Only commits the entry if the key currently points at content matching
+    /// `expected`, failing with [`Error::Conflict`] otherwise. Paired with
+    /// the integrity from an earlier read, this lets writers sharing a cache
+    /// notice when someone else changed the key in the meantime, instead of
+    /// silently overwriting it. If the key has been removed or has expired
+    /// since, this fails with [`Error::EntryNotFound`], same as reading it
+    /// would.
+    ///
+    /// The check and the write happen under an exclusive lock on the key's
+    /// index bucket, which every other write to it also takes, so nothing
+    /// can be written to the key in between. On platforms without file locks
+    /// nothing is locked. Content is still written before the check,
+    /// and is left for [`crate::verify`] to clean up if it fails. Doesn't
+    /// apply to [`WriteOpts::open_hash`].
+    pub fn if_integrity(mut self, expected: Integrity) -> Self {
+        self.condition = Some(Condition::Integrity(expected));
+        self
+    }
+
+    /// Only commits the entry if there isn't one for the key yet, or only a
+    /// deleted or expired one, failing with [`Error::Conflict`] otherwise.
+    /// See [`WriteOpts::if_integrity`] for details.
+    pub fn if_absent(mut self) -> Self {
+        self.condition = Some(Condition::Absent);
+        self
+    }
+
     /// Sets the expected integrity hash of the written data. If there's a
     /// mismatch between this Integrity and the one calculated by the write,
     /// `put.commit()` will error.
@@ -501,6 +935,92 @@ impl WriteOpts {
         self.sri = Some(sri);
         self
     }
+
+    /// Options for reading back what these options write.
+    fn read_opts(&self) -> ReadOpts {
+        ReadOpts {
+            encryption_key: self.encryption_key.clone(),
+            ..ReadOpts::new()
+        }
+    }
+
+    /// The most data that gets stored somewhere other than a content file of
+    /// its own: inline in the index, for `keyed` writes, or packed.
+    fn small_limit(&self, keyed: bool) -> Option<usize> {
+        if self.encryption_key.is_some() || self.chunking.is_some() || self.resume.is_some() {
+            return None;
+        }
+        self.inline_threshold
+            .filter(|_| keyed)
+            .max(self.pack_threshold)
+    }
+
+    /// Whether the write's declared size is small enough that it'll never
+    /// need a content file, so one doesn't have to be created for it.
+    fn fits_small(&self, keyed: bool) -> bool {
+        matches!(
+            (self.size, self.small_limit(keyed)),
+            (Some(size), Some(limit)) if size <= limit
+        )
+    }
+
+    /// The most data to hold onto so it can be remembered by `cache`'s memory
+    /// cache once it's committed, if it has one. Only writes whose declared
+    /// size fits are held onto, since anything else might not be remembered
+    /// anyway. See [`crate::set_memo_size`].
+    fn memo_limit(&self, cache: &Path) -> Option<usize> {
+        if self.encryption_key.is_some() || self.resume.is_some() {
+            return None;
+        }
+        let size = self.size?;
+        crate::memo_size(cache)
+            .filter(|limit| size <= *limit)
+            .map(|_| size)
+    }
+
+    /// Hashes data that turned out small enough not to need a content file.
+    /// It's held onto for the index entry if it fits inline, and otherwise
+    /// handed back to be packed.
+    fn take_small(&mut self, keyed: bool, data: Vec<u8>) -> (Integrity, Option<Vec<u8>>) {
+        let sri = IntegrityOpts::new()
+            .algorithm(self.algorithm.unwrap_or(Algorithm::Sha256))
+            .chain(&data)
+            .result();
+        if keyed
+            && self
+                .inline_threshold
+                .is_some_and(|limit| data.len() <= limit)
+        {
+            self.inline_data = Some(data);
+            (sri, None)
+        } else {
+            (sri, Some(data))
+        }
+    }
+}
+
+/// Refuses to write more than the declared size, for writes that only have
+/// their small buffer to put it in. Otherwise returns how much was written.
+fn check_declared(opts: &WriteOpts, written: usize, len: usize) -> std::io::Result<usize> {
+    match opts.size {
+        Some(size) if written + len > size => Err(crate::errors::io_error(Error::SizeMismatch(
+            size,
+            written + len,
+        ))),
+        _ => Ok(len),
+    }
+}
+
+/// Adds freshly written `data` to a small write's buffer, giving up on it
+/// once there's more than `limit` bytes.
+fn buffer_small(small: &mut Option<Vec<u8>>, limit: Option<usize>, data: &[u8]) {
+    if let Some(buf) = small {
+        if buf.len() + data.len() > limit.unwrap_or(0) {
+            *small = None;
+        } else {
+            buf.extend_from_slice(data);
+        }
+    }
 }
 
 /// A reference to an open file writing to the cache.
@@ -508,18 +1028,32 @@ pub struct SyncWriter {
     cache: PathBuf,
     key: Option<String>,
     written: usize,
-    pub(crate) writer: write::Writer,
+    /// Left out when the write was declared small enough not to need a
+    /// content file.
+    pub(crate) writer: Option<write::Writer>,
     opts: WriteOpts,
+    small: Option<Vec<u8>>,
+    memo_limit: Option<usize>,
+    memo: Option<Vec<u8>>,
 }
 
 impl Write for SyncWriter {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let written = self.writer.write(buf)?;
+        let written = match &mut self.writer {
+            Some(writer) => writer.write(buf)?,
+            None => check_declared(&self.opts, self.written, buf.len())?,
+        };
         self.written += written;
+        let limit = self.opts.small_limit(self.key.is_some());
+        buffer_small(&mut self.small, limit, &buf[..written]);
+        buffer_small(&mut self.memo, self.memo_limit, &buf[..written]);
         Ok(written)
     }
     fn flush(&mut self) -> std::io::Result<()> {
-        self.writer.flush()
+        match &mut self.writer {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
     }
 }
 
@@ -576,13 +1110,53 @@ impl SyncWriter {
         }
         inner(algo, cache.as_ref(), key.as_ref())
     }
+
+    /// How many bytes have been written so far, including any written before
+    /// a [`WriteOpts::resume`]d write was picked back up.
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
     /// Closes the Writer handle and writes content and index entries. Also
     /// verifies data against `size` and `integrity` options, if provided.
     /// Must be called manually in order to complete the writing process,
     /// otherwise everything will be thrown out.
-    pub fn commit(mut self) -> Result<Integrity> {
+    pub fn commit(self) -> Result<Integrity> {
+        let cache = self.cache.clone();
+        let key = self.key.clone();
+        let added = self.written as u64;
+        let (opts, writer_sri, _pack_lock, _) = self.finish()?;
+        let limit = opts.max_size;
+        let sri = if let Some(key) = key {
+            index::insert(&cache, &key, opts)?
+        } else {
+            writer_sri
+        };
+        crate::evict::enforce_max_size(&cache, limit, added)?;
+        Ok(sri)
+    }
+
+    /// Writes and checks the content, returning the options to index it
+    /// with, with their integrity filled in, and the integrity the content
+    /// was actually written with. If the content was packed, also returns
+    /// the lock that keeps [`crate::repack`] from dropping it, which has to
+    /// be held until it's indexed. Content written to a file of its own that
+    /// wasn't there before comes with that file's path.
+    pub(crate) fn finish(
+        mut self,
+    ) -> Result<(WriteOpts, Integrity, Option<File>, Option<PathBuf>)> {
         let cache = self.cache;
-        let writer_sri = self.writer.close()?;
+        let (writer_sri, packed, created) = match (self.small.take(), self.writer) {
+            (Some(data), _) => {
+                let (sri, packed) = self.opts.take_small(self.key.is_some(), data);
+                (sri, packed, None)
+            }
+            (None, Some(writer)) => {
+                let (sri, created) = writer.close()?;
+                (sri, None, created)
+            }
+            (None, None) => unreachable!("writes without a content file keep their data"),
+        };
         if let Some(sri) = &self.opts.sri {
             if sri.matches(&writer_sri).is_none() {
                 return Err(ssri::Error::IntegrityCheckError(sri.clone(), writer_sri).into());
@@ -595,11 +1169,20 @@ impl SyncWriter {
                 return Err(Error::SizeMismatch(size, self.written));
             }
         }
-        if let Some(key) = self.key {
-            index::insert(&cache, &key, self.opts)
-        } else {
-            Ok(writer_sri)
+        // Only packed once it's known to be what was expected.
+        let pack_lock = match packed {
+            Some(data) => Some(pack::insert(
+                &cache,
+                &writer_sri,
+                &data,
+                self.opts.durability,
+            )?),
+            None => None,
+        };
+        if let Some(data) = self.memo {
+            memo::put_content(&cache, &writer_sri, &data);
         }
+        Ok((self.opts, writer_sri, pack_lock, created))
     }
 }
 
@@ -629,6 +1212,303 @@ mod tests {
         assert_eq!(data, b"hello");
     }
 
+    #[test]
+    fn declared_small_writes_skip_the_temp_file() {
+        use std::io::Write;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let opts = crate::WriteOpts::new().inline_threshold(16).size(5);
+        let mut writer = opts.clone().open_sync(&dir, "hello").unwrap();
+        writer.write_all(b"hello").unwrap();
+        assert!(!dir.join("tmp").exists());
+        writer.commit().unwrap();
+        assert_eq!(crate::read_sync(&dir, "hello").unwrap(), b"hello");
+
+        // Going over the declared size would lose data, so it's refused.
+        let mut writer = opts.open_sync(&dir, "hello").unwrap();
+        assert!(writer.write_all(b"hello world").is_err());
+    }
+
+    #[test]
+    fn resume_sync() {
+        use std::io::Write;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let opts = crate::WriteOpts::new().resume("download");
+        let mut writer = opts.clone().open_sync(&dir, "hello").unwrap();
+        assert_eq!(writer.written(), 0);
+        writer.write_all(b"hello ").unwrap();
+        drop(writer);
+
+        let mut writer = opts.clone().open_sync(&dir, "hello").unwrap();
+        assert_eq!(writer.written(), 6);
+        assert!(opts.clone().open_sync(&dir, "hello").is_err());
+        writer.write_all(b"world").unwrap();
+        let sri = writer.commit().unwrap();
+        assert_eq!(sri, ssri::Integrity::from(b"hello world"));
+        let data = crate::read_sync(&dir, "hello").unwrap();
+        assert_eq!(data, b"hello world");
+
+        let writer = opts.open_sync(&dir, "hello").unwrap();
+        assert_eq!(writer.written(), 0);
+    }
+
+    #[test]
+    fn resume_picks_up_hasher_state() {
+        use std::io::Write;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let data = (0..3_000_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let opts = crate::WriteOpts::new().resume("download");
+        let mut writer = opts.clone().open_hash_sync(&dir).unwrap();
+        writer.write_all(&data[..2_000_000]).unwrap();
+        drop(writer);
+
+        let mut writer = opts.open_hash_sync(&dir).unwrap();
+        assert_eq!(writer.written(), 2_000_000);
+        writer.write_all(&data[2_000_000..]).unwrap();
+        let sri = writer.commit().unwrap();
+        assert_eq!(sri, ssri::Integrity::from(&data));
+        assert_eq!(crate::read_hash_sync(&dir, &sri).unwrap(), data);
+        // Nothing's left behind for the token once it's committed.
+        assert_eq!(
+            std::fs::read_dir(dir.join("tmp/resume")).unwrap().count(),
+            0
+        );
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    #[async_test]
+    async fn resume_async() {
+        use crate::async_lib::AsyncWriteExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let opts = crate::WriteOpts::new().resume("download").size(11);
+        let mut writer = opts.clone().open_hash(&dir).await.unwrap();
+        writer.write_all(b"hello ").await.unwrap();
+        drop(writer);
+
+        let mut writer = opts.open_hash(&dir).await.unwrap();
+        assert_eq!(writer.written(), 6);
+        writer.write_all(b"world").await.unwrap();
+        let sri = writer.commit().await.unwrap();
+        let data = crate::read_hash(&dir, &sri).await.unwrap();
+        assert_eq!(data, b"hello world");
+    }
+
+    #[test]
+    fn conditional_write_sync() {
+        use std::io::Write;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let write = |opts: crate::WriteOpts, data: &[u8]| {
+            let mut writer = opts.open_sync(&dir, "key")?;
+            writer.write_all(data).unwrap();
+            writer.commit()
+        };
+        let first = write(crate::WriteOpts::new().if_absent(), b"one").unwrap();
+        assert!(matches!(
+            write(crate::WriteOpts::new().if_absent(), b"two"),
+            Err(crate::Error::Conflict(_, _))
+        ));
+
+        let second = write(crate::WriteOpts::new().if_integrity(first.clone()), b"two").unwrap();
+        assert!(matches!(
+            write(crate::WriteOpts::new().if_integrity(first), b"three"),
+            Err(crate::Error::Conflict(_, _))
+        ));
+        assert_eq!(crate::read_sync(&dir, "key").unwrap(), b"two");
+
+        crate::remove_sync(&dir, "key").unwrap();
+        assert!(matches!(
+            write(crate::WriteOpts::new().if_integrity(second), b"three"),
+            Err(crate::Error::EntryNotFound(_, _))
+        ));
+        write(crate::WriteOpts::new().if_absent(), b"three").unwrap();
+        assert_eq!(crate::read_sync(&dir, "key").unwrap(), b"three");
+    }
+
+    #[test]
+    fn conditional_write_treats_expired_as_absent() {
+        use std::io::Write;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let sri = crate::WriteOpts::new()
+            .expires_at(1)
+            .open_sync(dir, "key")
+            .and_then(|mut writer| {
+                writer.write_all(b"one").unwrap();
+                writer.commit()
+            })
+            .unwrap();
+        let res = crate::WriteOpts::new()
+            .if_integrity(sri)
+            .open_sync(dir, "key")
+            .and_then(|mut writer| {
+                writer.write_all(b"two").unwrap();
+                writer.commit()
+            });
+        assert!(matches!(res, Err(crate::Error::EntryNotFound(_, _))));
+        crate::WriteOpts::new()
+            .if_absent()
+            .open_sync(dir, "key")
+            .and_then(|mut writer| {
+                writer.write_all(b"two").unwrap();
+                writer.commit()
+            })
+            .unwrap();
+        assert_eq!(crate::read_sync(dir, "key").unwrap(), b"two");
+    }
+
+    #[test]
+    fn conditional_write_after_compact() {
+        use std::io::Write;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let sri = crate::write_sync(dir, "key", b"one").unwrap();
+        crate::write_sync(dir, "key", b"one").unwrap();
+        crate::index::compact(dir).unwrap();
+        let mut writer = crate::WriteOpts::new()
+            .if_integrity(sri)
+            .open_sync(dir, "key")
+            .unwrap();
+        writer.write_all(b"two").unwrap();
+        writer.commit().unwrap();
+        assert_eq!(crate::read_sync(dir, "key").unwrap(), b"two");
+        assert!(dir.join("locks-v1").join("buckets").is_dir());
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    #[async_test]
+    async fn conditional_write_async() {
+        use crate::async_lib::AsyncWriteExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri = crate::write(&dir, "key", b"one").await.unwrap();
+        let mut writer = crate::WriteOpts::new()
+            .if_absent()
+            .open(&dir, "key")
+            .await
+            .unwrap();
+        writer.write_all(b"two").await.unwrap();
+        assert!(matches!(
+            writer.commit().await,
+            Err(crate::Error::Conflict(_, _))
+        ));
+
+        let mut writer = crate::WriteOpts::new()
+            .if_integrity(sri)
+            .open(&dir, "key")
+            .await
+            .unwrap();
+        writer.write_all(b"two").await.unwrap();
+        writer.commit().await.unwrap();
+        assert_eq!(crate::read(&dir, "key").await.unwrap(), b"two");
+    }
+
+    #[test]
+    fn update_metadata_sync() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::write_sync(&dir, "key", b"hello").unwrap();
+        let old = crate::metadata_sync(&dir, "key").unwrap().unwrap();
+        let updated = crate::update_metadata_sync(&dir, "key", |mut entry| {
+            entry.metadata = serde_json::json!({ "etag": "abc" });
+            entry.raw_metadata = Some(b"raw".to_vec());
+            entry.size = 100;
+            entry.set_expires(Some(u128::MAX));
+            entry
+        })
+        .unwrap();
+        let entry = crate::metadata_sync(&dir, "key").unwrap().unwrap();
+        assert_eq!(entry, updated);
+        assert_eq!(entry.integrity, old.integrity);
+        assert_eq!(entry.size, old.size);
+        assert_eq!(entry.metadata, serde_json::json!({ "etag": "abc" }));
+        assert_eq!(entry.raw_metadata, Some(b"raw".to_vec()));
+        assert_eq!(entry.expires(), Some(u128::MAX));
+        assert_eq!(crate::read_sync(&dir, "key").unwrap(), b"hello");
+
+        assert!(matches!(
+            crate::update_metadata_sync(&dir, "missing", |entry| entry),
+            Err(crate::Error::EntryNotFound(_, _))
+        ));
+    }
+
+    #[test]
+    fn update_metadata_concurrently() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::WriteOpts::new()
+            .metadata(serde_json::json!(0))
+            .open_sync(&dir, "key")
+            .and_then(|writer| writer.commit())
+            .unwrap();
+        let threads = (0..4)
+            .map(|_| {
+                let dir = dir.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..10 {
+                        crate::update_metadata_sync(&dir, "key", |mut entry| {
+                            entry.metadata =
+                                serde_json::json!(entry.metadata.as_u64().unwrap() + 1);
+                            entry
+                        })
+                        .unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let entry = crate::metadata_sync(&dir, "key").unwrap().unwrap();
+        assert_eq!(entry.metadata, serde_json::json!(40));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn update_metadata_refuses_sealed_metadata() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::WriteOpts::new()
+            .encrypt(crate::EncryptionKey::generate())
+            .encrypt_metadata(true)
+            .metadata(serde_json::json!("secret"))
+            .open_sync(&dir, "key")
+            .and_then(|writer| writer.commit())
+            .unwrap();
+        assert!(matches!(
+            crate::update_metadata_sync(&dir, "key", |entry| entry),
+            Err(crate::Error::MetadataSealed(_, _))
+        ));
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    #[async_test]
+    async fn update_metadata_async() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::write(&dir, "key", b"hello").await.unwrap();
+        crate::update_metadata(&dir, "key", |mut entry| {
+            entry.metadata = serde_json::json!("updated");
+            entry
+        })
+        .await
+        .unwrap();
+        let entry = crate::metadata(&dir, "key").await.unwrap().unwrap();
+        assert_eq!(entry.metadata, serde_json::json!("updated"));
+        assert_eq!(crate::read(&dir, "key").await.unwrap(), b"hello");
+    }
+
     #[test]
     fn hash_write_sync() {
         let tmp = tempfile::tempdir().unwrap();
@@ -0,0 +1,369 @@
+//! Functions for compacting packed content.
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use ssri::Integrity;
+use walkdir::WalkDir;
+
+use crate::content::pack::{self, PackEntry, PackWriter};
+use crate::content::path;
+use crate::errors::{IoErrorExt, Result};
+use crate::index;
+
+/// Summary of the work done by [`repack`] and [`repack_sync`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepackStats {
+    /// Number of content files that were moved into packs.
+    pub packed_count: usize,
+    /// Number of objects left in packs.
+    pub kept_count: usize,
+    /// Total size, in bytes, of the objects left in packs.
+    pub kept_size: u64,
+    /// Number of packed objects dropped, either because no index entry
+    /// referenced them or because they were corrupted.
+    pub reclaimed_count: usize,
+    /// Total size, in bytes, of all dropped objects.
+    pub reclaimed_size: u64,
+}
+
+/// Rewrites the cache's pack files, returning statistics about what was
+/// done. See [`crate::WriteOpts::pack_threshold`] for how content ends up
+/// packed.
+///
+/// This will:
+///
+/// * Move content files of at most `pack_threshold` bytes into packs, so
+///   caches that were written without packing can be converted. Pass `0` to
+///   leave content files where they are.
+/// * Drop packed objects that no index entry points to anymore, or that
+///   don't match their integrity hash, and reclaim the space they took up.
+/// * Copy everything else into fresh packs, and remove the old ones.
+///
+/// Writes that would be packed wait for this to finish, but readers can run
+/// into packs being removed from under them while it does.
+///
+/// ## Example
+/// ```no_run
+/// use async_attributes;
+///
+/// #[async_attributes::main]
+/// async fn main() -> cacache::Result<()> {
+///     let stats = cacache::repack("./my-cache", 4096).await?;
+///     println!("reclaimed {} bytes", stats.reclaimed_size);
+///     Ok(())
+/// }
+/// ```
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub async fn repack<P: AsRef<Path>>(cache: P, pack_threshold: usize) -> Result<RepackStats> {
+    let cache = cache.as_ref().to_path_buf();
+    crate::async_lib::unwrap_joinhandle_value(
+        crate::async_lib::spawn_blocking(move || repack_sync(cache, pack_threshold)).await,
+    )
+}
+
+/// Synchronously rewrites the cache's pack files, returning statistics about
+/// what was done. See [`repack`] for details.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache::Result<()> {
+///     let stats = cacache::repack_sync("./my-cache", 4096)?;
+///     println!("reclaimed {} bytes", stats.reclaimed_size);
+///     Ok(())
+/// }
+/// ```
+pub fn repack_sync<P: AsRef<Path>>(cache: P, pack_threshold: usize) -> Result<RepackStats> {
+    fn inner(cache: &Path, pack_threshold: usize) -> Result<RepackStats> {
+        let dir = pack::pack_dir(cache);
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create pack directory at {}", dir.display()))?;
+        let _lock = pack::lock_packs(cache, true)?;
+
+        let live = live_content(cache)?;
+        let old_packs = pack::packs(cache)?;
+        let mut writer = PackWriter::new(cache, old_packs.last().map_or(0, |last| last + 1));
+        let mut stats = RepackStats::default();
+
+        let mut buckets = pack::buckets(cache)?;
+        for entries in buckets.values_mut() {
+            let mut kept = Vec::new();
+            for entry in entries.drain(..) {
+                let Some(location) = entry.location else {
+                    continue;
+                };
+                // Content that's also stored in a file of its own is read
+                // from there, so the packed copy is dead weight.
+                let data = entry
+                    .integrity
+                    .parse::<Integrity>()
+                    .ok()
+                    .filter(|sri| live.contains(&path::content_path(cache, sri)))
+                    .filter(|sri| path::find_content(cache, sri).is_none())
+                    .and_then(|sri| {
+                        let data = pack::read_at(cache, location).ok()?;
+                        sri.check(&data).ok()?;
+                        Some(data)
+                    });
+                let Some(data) = data else {
+                    stats.reclaimed_count += 1;
+                    stats.reclaimed_size += location.size;
+                    continue;
+                };
+                kept.push(PackEntry {
+                    integrity: entry.integrity,
+                    location: Some(writer.append(&data)?),
+                });
+                stats.kept_count += 1;
+                stats.kept_size += location.size;
+            }
+            *entries = kept;
+        }
+
+        let mut moved = Vec::new();
+        if pack_threshold > 0 {
+            for (cpath, sri) in loose_content(cache, &live, pack_threshold)? {
+                // Corrupted content is left for `verify` to deal with.
+                let Ok(data) = fs::read(&cpath) else {
+                    continue;
+                };
+                if sri.check(&data).is_err() {
+                    continue;
+                }
+                let Some(bucket) = pack::bucket_path(cache, &sri) else {
+                    continue;
+                };
+                buckets.entry(bucket).or_default().push(PackEntry {
+                    integrity: sri.to_string(),
+                    location: Some(writer.append(&data)?),
+                });
+                moved.push(cpath);
+                stats.packed_count += 1;
+                stats.kept_count += 1;
+                stats.kept_size += data.len() as u64;
+            }
+        }
+        writer.finish()?;
+
+        // Nothing points at the old packs once every bucket has been
+        // rewritten, and nothing needs the moved files.
+        for (bucket, entries) in &buckets {
+            pack::write_bucket(bucket, entries)?;
+        }
+        for old in old_packs {
+            let ppath = pack::pack_path(cache, old);
+            fs::remove_file(&ppath)
+                .with_context(|| format!("Failed to remove pack at {}", ppath.display()))?;
+        }
+        // Dead objects are gone now, even if they're still remembered.
+        crate::memo::forget_all(cache);
+        for cpath in moved {
+            fs::remove_file(&cpath)
+                .with_context(|| format!("Failed to remove content file at {}", cpath.display()))?;
+        }
+        Ok(stats)
+    }
+    inner(cache.as_ref(), pack_threshold)
+}
+
+/// Content paths of everything the index points at.
+fn live_content(cache: &Path) -> Result<HashSet<PathBuf>> {
+    let mut live = HashSet::new();
+    if !index::index_dir(cache).exists() {
+        return Ok(live);
+    }
+    for entry in index::ls(cache) {
+        live.insert(path::content_path(cache, &entry?.integrity));
+    }
+    Ok(live)
+}
+
+/// Plain content files of at most `pack_threshold` bytes that are worth
+/// moving into packs.
+fn loose_content(
+    cache: &Path,
+    live: &HashSet<PathBuf>,
+    pack_threshold: usize,
+) -> Result<Vec<(PathBuf, Integrity)>> {
+    let content_dir = path::content_dir(cache);
+    let mut loose = Vec::new();
+    if !content_dir.exists() {
+        return Ok(loose);
+    }
+    for entry in WalkDir::new(&content_dir) {
+        let entry = entry
+            .map_err(|e| crate::errors::io_error(e.to_string()))
+            .with_context(|| {
+                format!(
+                    "Error while walking cache content directory at {}",
+                    content_dir.display()
+                )
+            })?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let cpath = entry.into_path();
+        let (logical, encoding) = path::split_encoding(&cpath);
+        if !encoding.is_plain() || !live.contains(&logical) {
+            continue;
+        }
+        let size = fs::metadata(&cpath)
+            .with_context(|| format!("Failed to stat content file at {}", cpath.display()))?
+            .len();
+        if size > pack_threshold as u64 {
+            continue;
+        }
+        if let Some(sri) = path::path_integrity(cache, &cpath) {
+            loose.push((cpath, sri));
+        }
+    }
+    Ok(loose)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[cfg(feature = "async-std")]
+    use async_attributes::test as async_test;
+    #[cfg(feature = "tokio")]
+    use tokio::test as async_test;
+
+    fn write_packed(dir: &Path, key: &str, data: &[u8]) -> Integrity {
+        let mut writer = crate::WriteOpts::new()
+            .pack_threshold(1024)
+            .open_sync(dir, key)
+            .unwrap();
+        writer.write_all(data).unwrap();
+        writer.commit().unwrap()
+    }
+
+    #[test]
+    fn packed_round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let dest = dir.join("data");
+        let sri = write_packed(dir, "hello", b"hello world");
+        assert!(path::find_content(dir, &sri).is_none());
+        assert_eq!(pack::packs(dir).unwrap(), vec![0]);
+
+        assert_eq!(crate::read_sync(dir, "hello").unwrap(), b"hello world");
+        assert_eq!(crate::read_hash_sync(dir, &sri).unwrap(), b"hello world");
+        assert_eq!(crate::read_range_sync(dir, "hello", ..5).unwrap(), b"hello");
+        assert!(crate::exists_sync(dir, &sri));
+        assert_eq!(crate::copy_sync(dir, "hello", &dest).unwrap(), 11);
+        assert_eq!(fs::read(&dest).unwrap(), b"hello world");
+        assert!(crate::hard_link_sync(dir, "hello", dir.join("link")).is_err());
+
+        // Verifying leaves packed entries alone.
+        crate::verify_sync(dir).unwrap();
+        assert_eq!(crate::read_sync(dir, "hello").unwrap(), b"hello world");
+
+        crate::remove_hash_sync(dir, &sri).unwrap();
+        assert!(!crate::exists_sync(dir, &sri));
+    }
+
+    #[test]
+    fn repack_drops_dead_objects() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        write_packed(dir, "dead", b"goodbye");
+        write_packed(dir, "live", b"hello");
+        crate::remove_sync(dir, "dead").unwrap();
+
+        let stats = repack_sync(dir, 0).unwrap();
+        assert_eq!(
+            stats,
+            RepackStats {
+                packed_count: 0,
+                kept_count: 1,
+                kept_size: 5,
+                reclaimed_count: 1,
+                reclaimed_size: 7,
+            }
+        );
+        assert_eq!(pack::packs(dir).unwrap(), vec![1]);
+        assert_eq!(fs::metadata(pack::pack_path(dir, 1)).unwrap().len(), 5);
+        assert_eq!(crate::read_sync(dir, "live").unwrap(), b"hello");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn repack_keeps_objects_until_indexed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri = Integrity::from(b"hello");
+        let lock = pack::insert(&dir, &sri, b"hello", crate::Durability::None).unwrap();
+        let repack = {
+            let dir = dir.clone();
+            std::thread::spawn(move || repack_sync(dir, 0).unwrap())
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!repack.is_finished());
+        index::insert(&dir, "hello", crate::WriteOpts::new().integrity(sri)).unwrap();
+        drop(lock);
+        assert_eq!(repack.join().unwrap().kept_count, 1);
+        assert_eq!(crate::read_sync(&dir, "hello").unwrap(), b"hello");
+    }
+
+    #[test]
+    fn packing_checks_integrity_first() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut writer = crate::WriteOpts::new()
+            .pack_threshold(1024)
+            .integrity(Integrity::from(b"expected"))
+            .open_sync(dir, "key")
+            .unwrap();
+        writer.write_all(b"actual").unwrap();
+        assert!(matches!(
+            writer.commit(),
+            Err(crate::Error::IntegrityError(_))
+        ));
+        assert_eq!(pack::find(dir, &Integrity::from(b"actual")).unwrap(), None);
+    }
+
+    #[test]
+    fn repack_moves_small_content_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let small = crate::write_sync(dir, "small", b"hello").unwrap();
+        let big = crate::write_sync(dir, "big", b"hello world, and then some").unwrap();
+
+        let stats = repack_sync(dir, 16).unwrap();
+        assert_eq!(stats.packed_count, 1);
+        assert!(path::find_content(dir, &small).is_none());
+        assert!(path::find_content(dir, &big).is_some());
+        assert_eq!(crate::read_sync(dir, "small").unwrap(), b"hello");
+        assert_eq!(
+            crate::read_sync(dir, "big").unwrap(),
+            b"hello world, and then some"
+        );
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    #[async_test]
+    async fn packed_round_trip_async() {
+        use crate::async_lib::{AsyncReadExt, AsyncWriteExt};
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut writer = crate::WriteOpts::new()
+            .pack_threshold(1024)
+            .open_hash(dir)
+            .await
+            .unwrap();
+        writer.write_all(b"hello world").await.unwrap();
+        let sri = writer.commit().await.unwrap();
+        assert!(path::find_content(dir, &sri).is_none());
+
+        assert_eq!(crate::read_hash(dir, &sri).await.unwrap(), b"hello world");
+        let mut reader = crate::Reader::open_hash(dir, sri.clone()).await.unwrap();
+        let mut buf = String::new();
+        reader.read_to_string(&mut buf).await.unwrap();
+        reader.check().unwrap();
+        assert_eq!(buf, "hello world");
+        assert_eq!(repack(dir, 0).await.unwrap().reclaimed_count, 1);
+    }
+}
@@ -0,0 +1,482 @@
+//! Functions for finding which keys refer to a piece of content.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use ssri::Integrity;
+
+use crate::durability::{self, Durability};
+use crate::errors::{IoErrorExt, Result};
+use crate::index;
+
+const REVERSE_VERSION: &str = "1";
+
+/// Lists the keys whose current index entries point at the content for
+/// `sri`, in sorted order. Expired entries still count, since their content
+/// can be read with [`crate::ReadOpts::allow_stale`].
+///
+/// Lookups go through a reverse index that every keyed write keeps up to
+/// date, whether or not this is ever called. That costs each write another
+/// file lock and an append to a second file, synced as hard as the write
+/// itself. Rewriting a key with the same content doesn't append again, but
+/// pointing it at new content leaves its old line behind until the next
+/// [`crate::index::compact`] or [`crate::verify`] rebuilds the index.
+///
+/// ## Example
+/// ```no_run
+/// use async_attributes;
+///
+/// #[async_attributes::main]
+/// async fn main() -> cacache::Result<()> {
+///     let sri = cacache::write("./my-cache", "my-key", b"hello").await?;
+///     let keys = cacache::keys_for_hash("./my-cache", &sri).await?;
+///     assert_eq!(keys, vec![String::from("my-key")]);
+///     Ok(())
+/// }
+/// ```
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub async fn keys_for_hash<P: AsRef<Path>>(cache: P, sri: &Integrity) -> Result<Vec<String>> {
+    // Lookups wait out rebuilds, which is a blocking file lock.
+    let (cache, sri) = (cache.as_ref().to_path_buf(), sri.clone());
+    crate::async_lib::unwrap_joinhandle_value(
+        crate::async_lib::spawn_blocking(move || keys_for_hash_sync(cache, &sri)).await,
+    )
+}
+
+/// Synchronously lists the keys whose current index entries point at the
+/// content for `sri`. See [`keys_for_hash`] for details.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache::Result<()> {
+///     let sri = cacache::write_sync("./my-cache", "my-key", b"hello")?;
+///     let keys = cacache::keys_for_hash_sync("./my-cache", &sri)?;
+///     assert_eq!(keys, vec![String::from("my-key")]);
+///     Ok(())
+/// }
+/// ```
+pub fn keys_for_hash_sync<P: AsRef<Path>>(cache: P, sri: &Integrity) -> Result<Vec<String>> {
+    fn inner(cache: &Path, sri: &Integrity) -> Result<Vec<String>> {
+        let _lock = lock_reverse_for_reading(cache)?;
+        keys_locked(cache, sri)
+    }
+    inner(cache.as_ref(), sri)
+}
+
+/// Lists the keys pointing at the content for `sri`, like
+/// [`keys_for_hash_sync`], with the reverse index already locked by the
+/// caller.
+pub(crate) fn keys_locked(cache: &Path, sri: &Integrity) -> Result<Vec<String>> {
+    if !reverse_dir(cache).exists() {
+        return scan_index(cache, sri);
+    }
+    let mut candidates = BTreeSet::new();
+    for bucket in reverse_paths(cache, sri) {
+        match fs::read(&bucket) {
+            Ok(contents) => candidates.extend(parse_bucket(&contents)),
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to read reverse index at {bucket:?}"))
+            }
+        }
+    }
+    let mut keys = Vec::new();
+    for key in candidates {
+        if let Some(entry) = index::find(cache, &key)? {
+            if entry.integrity.matches(sri).is_some() {
+                keys.push(key);
+            }
+        }
+    }
+    Ok(keys)
+}
+
+/// Lists the keys pointing at the content for `sri` by checking every entry
+/// in the index, without trusting the reverse index. Entries are added to
+/// the reverse index after they're written to the index, so it can miss
+/// keys whose writer crashed in between, until the next rebuild.
+pub(crate) fn scan_index(cache: &Path, sri: &Integrity) -> Result<Vec<String>> {
+    if !index::index_dir(cache).exists() {
+        return Ok(Vec::new());
+    }
+    scan(index::ls(cache), sri)
+}
+
+/// Records that `key` now points at the content for `sri`. Must be called
+/// after the entry has been written to the index, so a rebuild that misses
+/// this write still picks up the entry.
+///
+/// Caches written before the reverse index existed get theirs built from
+/// the index the first time a key is added. New caches, where the only key
+/// in the index is this one, don't need to.
+pub(crate) fn add(cache: &Path, key: &str, sri: &Integrity, durability: Durability) -> Result<()> {
+    if !reverse_dir(cache).exists() && indexes_others(cache, key) {
+        rebuild(cache)?;
+    }
+    let _lock = lock_reverse(cache, false)?;
+    for bucket in reverse_paths(cache, sri) {
+        append(&bucket, key, durability)?;
+    }
+    Ok(())
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub(crate) async fn add_async(
+    cache: &Path,
+    key: &str,
+    sri: &Integrity,
+    durability: Durability,
+) -> Result<()> {
+    let (cache, key, sri) = (cache.to_path_buf(), key.to_string(), sri.clone());
+    crate::async_lib::unwrap_joinhandle_value(
+        crate::async_lib::spawn_blocking(move || add(&cache, &key, &sri, durability)).await,
+    )
+}
+
+/// Whether the index has buckets for anything other than `key`. Stops at the
+/// first one it finds.
+fn indexes_others(cache: &Path, key: &str) -> bool {
+    let own = index::bucket_path(cache, key);
+    index::index_dir(cache).exists()
+        && index::bucket_paths(cache).any(|bucket| bucket.map_or(true, |bucket| bucket != own))
+}
+
+fn append(bucket: &Path, key: &str, durability: Durability) -> Result<()> {
+    durability::create_dir_all(bucket.parent().unwrap(), durability).with_context(|| {
+        format!(
+            "Failed to create reverse index directory: {:?}",
+            bucket.parent().unwrap()
+        )
+    })?;
+    let line = format_line(key);
+    OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(bucket)
+        .and_then(|mut buck| {
+            // Rewrites of a key that already points here have nothing to add.
+            if ends_with(&mut buck, line.as_bytes())? {
+                return Ok(());
+            }
+            buck.write_all(line.as_bytes())?;
+            durability::sync_file(&buck, durability, "reverse index synced")?;
+            durability::sync_parent(bucket, durability, "reverse index dir synced")
+        })
+        .with_context(|| format!("Failed to write to reverse index at {bucket:?}"))
+}
+
+/// Recreates the reverse index from the live entries in the index, dropping
+/// keys that no longer point at each piece of content.
+///
+/// The new index is built off to the side and swapped in while holding the
+/// reverse index lock exclusively, so lookups and additions never see it
+/// half-built.
+pub(crate) fn rebuild(cache: &Path) -> Result<()> {
+    let dir = reverse_dir(cache);
+    let tmp_path = cache.join("tmp");
+    fs::create_dir_all(&tmp_path)
+        .with_context(|| format!("Failed to create directory at {}", tmp_path.display()))?;
+    let tmp = tempfile::tempdir_in(&tmp_path).with_context(|| {
+        format!(
+            "Failed to create temp directory for reverse index, inside {}",
+            tmp_path.display()
+        )
+    })?;
+    let (built, old) = (tmp.path().join("new"), tmp.path().join("old"));
+    fs::create_dir(&built)
+        .with_context(|| format!("Failed to create directory at {}", built.display()))?;
+    let _lock = lock_reverse(cache, true)?;
+    if index::index_dir(cache).exists() {
+        let mut buckets: BTreeMap<PathBuf, BTreeSet<String>> = BTreeMap::new();
+        for entry in index::ls(cache) {
+            let entry = entry?;
+            for bucket in bucket_paths(&built, &entry.integrity) {
+                buckets.entry(bucket).or_default().insert(entry.key.clone());
+            }
+        }
+        for (bucket, keys) in buckets {
+            fs::create_dir_all(bucket.parent().unwrap())
+                .and_then(|_| {
+                    fs::write(
+                        &bucket,
+                        keys.iter().map(|key| format_line(key)).collect::<String>(),
+                    )
+                })
+                .with_context(|| format!("Failed to write to reverse index at {bucket:?}"))?;
+        }
+    }
+    match fs::rename(&dir, &old) {
+        Err(err) if err.kind() != ErrorKind::NotFound => {
+            return Err(err)
+                .with_context(|| format!("Failed to move reverse index at {}", dir.display()))
+        }
+        _ => {}
+    }
+    fs::rename(&built, &dir)
+        .with_context(|| format!("Failed to replace reverse index at {}", dir.display()))?;
+    // The old index is removed along with `tmp`.
+    Ok(())
+}
+
+/// Locks the reverse index. Lookups and additions share it, and rebuilds
+/// take it exclusively while they swap in the new index.
+pub(crate) fn lock_reverse(cache: &Path, exclusive: bool) -> Result<fs::File> {
+    let lpath = crate::lock::locks_dir(cache).join("reverse");
+    crate::flock::open_locked(&lpath, exclusive)
+        .with_context(|| format!("Failed to lock reverse index at {lpath:?}"))
+}
+
+/// Shares the reverse index lock with other lookups, like
+/// `lock_reverse(cache, false)`, but without creating the lock file if
+/// nothing has written to the reverse index yet.
+fn lock_reverse_for_reading(cache: &Path) -> Result<Option<fs::File>> {
+    let lpath = crate::lock::locks_dir(cache).join("reverse");
+    crate::flock::open_shared_if_exists(&lpath)
+        .with_context(|| format!("Failed to lock reverse index at {lpath:?}"))
+}
+
+// Current format of reverse index bucket paths, which are named after a
+// hash of each of the integrity's hashes, so content can be looked up by
+// any of them:
+//
+// sha512-BaSE64Hex= ->
+// ~/.my-cache/reverse-v1/41/b0/9c2f51d43099d30919c41fba42f7cfea879a
+//
+// Keys are only ever appended, so lookups check each one against the index
+// before returning it.
+fn reverse_paths(cache: &Path, sri: &Integrity) -> Vec<PathBuf> {
+    bucket_paths(&reverse_dir(cache), sri)
+}
+
+fn bucket_paths(dir: &Path, sri: &Integrity) -> Vec<PathBuf> {
+    sri.hashes
+        .iter()
+        .map(|hash| {
+            let hashed = index::hash_key(&hash.to_string());
+            dir.join(&hashed[0..2])
+                .join(&hashed[2..4])
+                .join(&hashed[4..])
+        })
+        .collect()
+}
+
+/// Whether `buck`'s last line is `line`.
+fn ends_with(buck: &mut fs::File, line: &[u8]) -> std::io::Result<bool> {
+    let len = buck.metadata()?.len();
+    let Some(start) = len.checked_sub(line.len() as u64) else {
+        return Ok(false);
+    };
+    buck.seek(SeekFrom::Start(start))?;
+    let mut last = vec![0; line.len()];
+    buck.read_exact(&mut last)?;
+    Ok(last == line)
+}
+
+fn reverse_dir(cache: &Path) -> PathBuf {
+    cache.join(format!("reverse-v{REVERSE_VERSION}"))
+}
+
+fn format_line(key: &str) -> String {
+    let stringified = serde_json::Value::from(key).to_string();
+    format!("\n{}\t{}", index::hash_entry(&stringified), stringified)
+}
+
+/// Unique, sorted keys listed in a reverse index bucket. Lines that don't
+/// hash correctly, e.g. from an interrupted write, are skipped.
+fn parse_bucket(contents: &[u8]) -> BTreeSet<String> {
+    String::from_utf8_lossy(contents)
+        .lines()
+        .filter_map(|line| match line.split('\t').collect::<Vec<&str>>()[..] {
+            [hash, stringified] if index::hash_entry(stringified) == hash => {
+                serde_json::from_str(stringified).ok()
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Falls back to checking every index entry, for caches without a reverse
+/// index.
+fn scan(
+    entries: impl IntoIterator<Item = Result<index::Metadata>>,
+    sri: &Integrity,
+) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.integrity.matches(sri).is_some() {
+            keys.push(entry.key);
+        }
+    }
+    keys.sort();
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "async-std")]
+    use async_attributes::test as async_test;
+    #[cfg(feature = "tokio")]
+    use tokio::test as async_test;
+
+    #[test]
+    fn keys_for_hash_basic() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let sri = crate::write_sync(dir, "b", b"hello").unwrap();
+        crate::write_sync(dir, "a", b"hello").unwrap();
+        crate::write_sync(dir, "c", b"goodbye").unwrap();
+        assert_eq!(keys_for_hash_sync(dir, &sri).unwrap(), vec!["a", "b"]);
+
+        // Repointed and deleted keys drop out.
+        crate::write_sync(dir, "a", b"something else").unwrap();
+        crate::remove_sync(dir, "b").unwrap();
+        assert!(keys_for_hash_sync(dir, &sri).unwrap().is_empty());
+
+        crate::write_sync(dir, "b", b"hello").unwrap();
+        assert_eq!(keys_for_hash_sync(dir, &sri).unwrap(), vec!["b"]);
+
+        // Verifying drops the stale keys from the reverse index itself.
+        crate::verify_sync(dir).unwrap();
+        let contents = fs::read(&reverse_paths(dir, &sri)[0]).unwrap();
+        assert_eq!(
+            parse_bucket(&contents).into_iter().collect::<Vec<_>>(),
+            vec!["b"]
+        );
+    }
+
+    #[test]
+    fn keys_for_hash_without_reverse_index() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let sri = crate::write_sync(dir, "a", b"hello").unwrap();
+        fs::remove_dir_all(reverse_dir(dir)).unwrap();
+        assert_eq!(keys_for_hash_sync(dir, &sri).unwrap(), vec!["a"]);
+
+        // The next write rebuilds it, including older entries.
+        crate::write_sync(dir, "b", b"hello").unwrap();
+        assert!(reverse_paths(dir, &sri)[0].exists());
+        assert_eq!(keys_for_hash_sync(dir, &sri).unwrap(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn rewrites_dont_grow_the_reverse_index() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let sri = Integrity::from(b"hello");
+        let opts = || crate::WriteOpts::new().integrity(sri.clone());
+        crate::index::insert(dir, "a", opts()).unwrap();
+        // A fresh cache has nothing to rebuild from.
+        assert!(!dir.join("tmp").exists());
+        let bucket = &reverse_paths(dir, &sri)[0];
+        let written = fs::read(bucket).unwrap();
+        crate::index::insert(dir, "a", opts()).unwrap();
+        assert_eq!(fs::read(bucket).unwrap(), written);
+
+        crate::index::insert(dir, "b", opts()).unwrap();
+        crate::index::insert(dir, "a", opts()).unwrap();
+        assert_eq!(keys_for_hash_sync(dir, &sri).unwrap(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn lookups_dont_create_lock_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let sri = crate::write_sync(dir, "a", b"hello").unwrap();
+        let locks = crate::lock::locks_dir(dir);
+        fs::remove_dir_all(&locks).unwrap();
+        assert_eq!(keys_for_hash_sync(dir, &sri).unwrap(), vec!["a"]);
+        assert!(!locks.exists());
+    }
+
+    #[test]
+    fn keys_for_hash_by_any_hash() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let sri = Integrity::from(b"hello").concat(
+            ssri::IntegrityOpts::new()
+                .algorithm(ssri::Algorithm::Sha1)
+                .chain(b"hello")
+                .result(),
+        );
+        crate::index::insert(dir, "a", crate::WriteOpts::new().integrity(sri.clone())).unwrap();
+        for hash in &sri.hashes {
+            let single = Integrity {
+                hashes: vec![hash.clone()],
+            };
+            assert_eq!(keys_for_hash_sync(dir, &single).unwrap(), vec!["a"]);
+        }
+    }
+
+    #[test]
+    fn compact_prunes_reverse_index() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let sri = crate::write_sync(dir, "a", b"hello").unwrap();
+        crate::write_sync(dir, "b", b"hello").unwrap();
+        crate::write_sync(dir, "a", b"something else").unwrap();
+        crate::index::compact(dir).unwrap();
+        let contents = fs::read(&reverse_paths(dir, &sri)[0]).unwrap();
+        assert_eq!(
+            parse_bucket(&contents).into_iter().collect::<Vec<_>>(),
+            vec!["b"]
+        );
+    }
+
+    #[test]
+    fn rebuilding_keeps_concurrent_adds() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_path_buf();
+        let sri = crate::write_sync(&dir, "base", b"hello").unwrap();
+        let writer = {
+            let dir = dir.clone();
+            std::thread::spawn(move || {
+                for i in 0..50 {
+                    crate::write_sync(&dir, format!("key-{i}"), b"hello").unwrap();
+                }
+            })
+        };
+        for _ in 0..20 {
+            rebuild(&dir).unwrap();
+            // Lookups never see a missing or half-built index.
+            assert!(keys_for_hash_sync(&dir, &sri)
+                .unwrap()
+                .contains(&String::from("base")));
+        }
+        writer.join().unwrap();
+        assert_eq!(keys_for_hash_sync(&dir, &sri).unwrap().len(), 51);
+    }
+
+    #[test]
+    fn compaction_fills_in_crashed_writers() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let sri = crate::write_sync(dir, "a", b"hello").unwrap();
+        crate::write_sync(dir, "b", b"hello").unwrap();
+        // As if the writer of "b" crashed before adding it here.
+        fs::write(&reverse_paths(dir, &sri)[0], format_line("a")).unwrap();
+        assert_eq!(crate::keys_for_hash_sync(dir, &sri).unwrap(), vec!["a"]);
+        crate::index::compact(dir).unwrap();
+        let report = crate::RemoveOpts::new()
+            .remove_fully(true)
+            .remove_with_report_sync(dir, "a")
+            .unwrap();
+        assert_eq!(report.kept_for, vec!["b"]);
+        assert_eq!(crate::read_sync(dir, "b").unwrap(), b"hello");
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    #[async_test]
+    async fn keys_for_hash_async() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let sri = crate::write(dir, "a", b"hello").await.unwrap();
+        crate::write(dir, "b", b"hello").await.unwrap();
+        assert_eq!(keys_for_hash(dir, &sri).await.unwrap(), vec!["a", "b"]);
+    }
+}
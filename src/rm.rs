@@ -5,7 +5,9 @@ use std::path::Path;
 use ssri::Integrity;
 
 use crate::content::rm;
+use crate::durability::Durability;
 use crate::errors::{IoErrorExt, Result};
+use crate::evict::POLICY_FILE;
 use crate::index;
 
 /// Removes an individual index metadata entry. The associated content will be
@@ -70,7 +72,8 @@ pub async fn remove_hash<P: AsRef<Path>>(cache: P, sri: &Integrity) -> Result<()
 }
 
 /// Removes entire contents of the cache, including temporary files, the entry
-/// index, and all content data.
+/// index, and all content data. Settings such as [`crate::set_max_size`] are
+/// kept.
 ///
 /// ## Example
 /// ```no_run
@@ -94,6 +97,7 @@ pub async fn remove_hash<P: AsRef<Path>>(cache: P, sri: &Integrity) -> Result<()
 #[cfg(any(feature = "async-std", feature = "tokio"))]
 pub async fn clear<P: AsRef<Path>>(cache: P) -> Result<()> {
     async fn inner(cache: &Path) -> Result<()> {
+        crate::memo::forget_all(cache);
         for entry in cache
             .read_dir()
             .with_context(|| {
@@ -104,6 +108,9 @@ pub async fn clear<P: AsRef<Path>>(cache: P) -> Result<()> {
             })?
             .flatten()
         {
+            if entry.file_name() == POLICY_FILE {
+                continue;
+            }
             crate::async_lib::remove_dir_all(entry.path())
                 .await
                 .with_context(|| format!("Failed to clear cache at {}", cache.display()))?;
@@ -113,6 +120,29 @@ pub async fn clear<P: AsRef<Path>>(cache: P) -> Result<()> {
     inner(cache.as_ref()).await
 }
 
+/// Removes the index entries for every key that has expired, returning how
+/// many were removed. The associated content is left in the cache until the
+/// next [`crate::verify`].
+///
+/// ## Example
+/// ```no_run
+/// use async_attributes;
+///
+/// #[async_attributes::main]
+/// async fn main() -> cacache::Result<()> {
+///     let removed = cacache::sweep_expired("./my-cache").await?;
+///     println!("removed {removed} expired entries");
+///     Ok(())
+/// }
+/// ```
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub async fn sweep_expired<P: AsRef<Path>>(cache: P) -> Result<usize> {
+    let cache = cache.as_ref().to_path_buf();
+    crate::async_lib::unwrap_joinhandle_value(
+        crate::async_lib::spawn_blocking(move || sweep_expired_sync(cache)).await,
+    )
+}
+
 /// Removes an individual index entry synchronously. The associated content
 /// will be left in the cache.
 ///
@@ -169,7 +199,8 @@ pub fn remove_hash_sync<P: AsRef<Path>>(cache: P, sri: &Integrity) -> Result<()>
 }
 
 /// Removes entire contents of the cache synchronously, including temporary
-/// files, the entry index, and all content data.
+/// files, the entry index, and all content data. Settings such as
+/// [`crate::set_max_size_sync`] are kept.
 ///
 /// ## Example
 /// ```no_run
@@ -190,6 +221,7 @@ pub fn remove_hash_sync<P: AsRef<Path>>(cache: P, sri: &Integrity) -> Result<()>
 /// ```
 pub fn clear_sync<P: AsRef<Path>>(cache: P) -> Result<()> {
     fn inner(cache: &Path) -> Result<()> {
+        crate::memo::forget_all(cache);
         for entry in cache
             .read_dir()
             .with_context(|| {
@@ -200,6 +232,9 @@ pub fn clear_sync<P: AsRef<Path>>(cache: P) -> Result<()> {
             })?
             .flatten()
         {
+            if entry.file_name() == POLICY_FILE {
+                continue;
+            }
             fs::remove_dir_all(entry.path())
                 .with_context(|| format!("Failed to clear cache at {}", cache.display()))?;
         }
@@ -208,6 +243,40 @@ pub fn clear_sync<P: AsRef<Path>>(cache: P) -> Result<()> {
     inner(cache.as_ref())
 }
 
+/// Synchronously removes the index entries for every key that has expired,
+/// returning how many were removed. The associated content is left in the
+/// cache until the next [`crate::verify_sync`].
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache::Result<()> {
+///     let removed = cacache::sweep_expired_sync("./my-cache")?;
+///     println!("removed {removed} expired entries");
+///     Ok(())
+/// }
+/// ```
+pub fn sweep_expired_sync<P: AsRef<Path>>(cache: P) -> Result<usize> {
+    fn inner(cache: &Path) -> Result<usize> {
+        if !index::index_dir(cache).exists() {
+            return Ok(0);
+        }
+        let mut removed = 0;
+        for entry in index::ls(cache) {
+            let entry = entry?;
+            if !entry.is_expired() {
+                continue;
+            }
+            // The key may have been rewritten since we listed it.
+            let expired = |current: &index::Metadata| current.is_expired();
+            if index::delete_if(cache, &entry.key, Durability::None, expired)? {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+    inner(cache.as_ref())
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -314,4 +383,29 @@ mod tests {
         let data_exists = crate::exists_sync(&dir, &sri);
         assert!(!data_exists);
     }
+
+    #[test]
+    fn test_sweep_expired_sync() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        assert_eq!(crate::sweep_expired_sync(&dir).unwrap(), 0);
+
+        crate::write_sync(&dir, "fresh", b"hello").unwrap();
+        let mut writer = crate::WriteOpts::new()
+            .expires_at(1)
+            .open_sync(&dir, "stale")
+            .unwrap();
+        std::io::Write::write_all(&mut writer, b"world").unwrap();
+        let sri = writer.commit().unwrap();
+
+        assert_eq!(crate::sweep_expired_sync(&dir).unwrap(), 1);
+        assert_eq!(crate::sweep_expired_sync(&dir).unwrap(), 0);
+        assert!(crate::ReadOpts::new()
+            .allow_stale(true)
+            .metadata_sync(&dir, "stale")
+            .unwrap()
+            .is_none());
+        assert!(crate::exists_sync(&dir, &sri));
+        assert_eq!(crate::read_sync(&dir, "fresh").unwrap(), b"hello");
+    }
 }
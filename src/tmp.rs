@@ -0,0 +1,182 @@
+//! Functions for cleaning up temporary files left behind by writers.
+use std::fs::{self, File};
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::errors::{IoErrorExt, Result};
+use crate::flock;
+
+/// Removes temporary files in `{cache}/tmp` that haven't been modified in at
+/// least `older_than`, returning how many were removed. These are left
+/// behind when a writer's process crashes, or a writer is dropped without
+/// being committed and can't clean up after itself. Temp files kept around
+/// for [`crate::WriteOpts::resume`] are removed the same way, so resumable
+/// writes left alone for longer than `older_than` start over.
+///
+/// Writers hold a lock on their temporary file for as long as they're alive,
+/// so files that are still being written are left alone no matter how old
+/// they are. On platforms without file locks, only their age is checked, so
+/// `older_than` should comfortably exceed the longest write you expect.
+/// Temporary files in a custom [`crate::WriteOpts::tmp_dir`] aren't touched.
+///
+/// ## Example
+/// ```no_run
+/// use async_attributes;
+/// use std::time::Duration;
+///
+/// #[async_attributes::main]
+/// async fn main() -> cacache::Result<()> {
+///     let removed = cacache::clean_tmp("./my-cache", Duration::from_secs(60 * 60)).await?;
+///     println!("removed {removed} abandoned temp files");
+///     Ok(())
+/// }
+/// ```
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub async fn clean_tmp<P: AsRef<Path>>(cache: P, older_than: Duration) -> Result<usize> {
+    let cache = cache.as_ref().to_path_buf();
+    crate::async_lib::unwrap_joinhandle_value(
+        crate::async_lib::spawn_blocking(move || clean_tmp_sync(cache, older_than)).await,
+    )
+}
+
+/// Synchronously removes temporary files in `{cache}/tmp` that haven't been
+/// modified in at least `older_than`. See [`clean_tmp`] for details.
+///
+/// ## Example
+/// ```no_run
+/// use std::time::Duration;
+///
+/// fn main() -> cacache::Result<()> {
+///     let removed = cacache::clean_tmp_sync("./my-cache", Duration::from_secs(60 * 60))?;
+///     println!("removed {removed} abandoned temp files");
+///     Ok(())
+/// }
+/// ```
+pub fn clean_tmp_sync<P: AsRef<Path>>(cache: P, older_than: Duration) -> Result<usize> {
+    clean_dir(&cache.as_ref().join("tmp"), older_than)
+}
+
+/// Removes abandoned temporary files directly inside `dir`, and the ones
+/// resumable writes left in it.
+pub(crate) fn clean_dir(dir: &Path, older_than: Duration) -> Result<usize> {
+    Ok(clean_files(dir, older_than)? + clean_files(&dir.join("resume"), older_than)?)
+}
+
+fn clean_files(dir: &Path, older_than: Duration) -> Result<usize> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to read temp directory at {}", dir.display()))
+        }
+    };
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for entry in entries {
+        let entry =
+            entry.with_context(|| format!("Failed to read temp directory at {}", dir.display()))?;
+        let path = entry.path();
+        if remove_if_abandoned(&path, now, older_than)
+            .with_context(|| format!("Failed to clean up temp file at {}", path.display()))?
+        {
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+fn remove_if_abandoned(path: &Path, now: SystemTime, older_than: Duration) -> io::Result<bool> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        // Committed or cleaned up by someone else in the meantime.
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+        // A resumable write that's in progress, which keeps others from
+        // opening its file on Windows.
+        #[cfg(windows)]
+        Err(err) if err.raw_os_error() == Some(32) => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    let meta = file.metadata()?;
+    if !meta.is_file() {
+        return Ok(false);
+    }
+    // Files with timestamps in the future count as fresh.
+    let age = now.duration_since(meta.modified()?).unwrap_or_default();
+    if age < older_than || !flock::try_lock(&file)? {
+        return Ok(false);
+    }
+    // The writer might have committed the file, and let go of its lock, just
+    // before we got it, so make sure we're about to remove what we locked.
+    if !flock::same_file(&file, path)? {
+        return Ok(false);
+    }
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[cfg(feature = "async-std")]
+    use async_attributes::test as async_test;
+    #[cfg(feature = "tokio")]
+    use tokio::test as async_test;
+
+    fn abandon(dir: &Path) -> std::path::PathBuf {
+        let tmp = dir.join("tmp");
+        fs::create_dir_all(&tmp).unwrap();
+        let (_, path) = tempfile::NamedTempFile::new_in(&tmp)
+            .unwrap()
+            .keep()
+            .unwrap();
+        path
+    }
+
+    #[test]
+    fn removes_abandoned_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let abandoned = abandon(dir);
+        assert_eq!(clean_tmp_sync(dir, Duration::from_secs(60)).unwrap(), 0);
+        assert!(abandoned.exists());
+        assert_eq!(clean_tmp_sync(dir, Duration::ZERO).unwrap(), 1);
+        assert!(!abandoned.exists());
+    }
+
+    #[test]
+    fn missing_tmp_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        assert_eq!(clean_tmp_sync(tmp.path(), Duration::ZERO).unwrap(), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn leaves_live_writers_alone() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut writer = crate::WriteOpts::new().open_sync(dir, "my-key").unwrap();
+        writer.write_all(b"hello").unwrap();
+        let abandoned = abandon(dir);
+        assert_eq!(clean_tmp_sync(dir, Duration::ZERO).unwrap(), 1);
+        assert!(!abandoned.exists());
+        writer.write_all(b" world").unwrap();
+        writer.commit().unwrap();
+        assert_eq!(crate::read_sync(dir, "my-key").unwrap(), b"hello world");
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    #[async_test]
+    async fn clean_tmp_async() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        abandon(dir);
+        assert_eq!(clean_tmp(dir, Duration::ZERO).await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,522 @@
+//! Functions for verifying and garbage collecting the cache.
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use ssri::{Integrity, IntegrityChecker};
+use walkdir::WalkDir;
+
+use crate::content::chunk;
+use crate::content::encrypt::EncryptionKey;
+use crate::content::path::{self, Encoding};
+use crate::content::read;
+use crate::errors::{IoErrorExt, Result};
+use crate::index;
+
+/// Summary of the work done by [`verify`] and [`verify_sync`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifyStats {
+    /// Number of content files that were found to be intact.
+    pub verified_content: usize,
+    /// Total size, in bytes, of intact content files.
+    pub kept_size: u64,
+    /// Number of content files removed, either because no index entry
+    /// referenced them or because they were corrupted.
+    pub reclaimed_count: usize,
+    /// Total size, in bytes, of all removed content files.
+    pub reclaimed_size: u64,
+    /// Number of content files whose data did not match their integrity hash.
+    /// These are moved into `{cache}/quarantine` rather than deleted.
+    pub bad_content_count: usize,
+    /// Number of index entries that were kept.
+    pub kept_entries: usize,
+    /// Number of index entries removed because their content was missing.
+    pub rejected_entries: usize,
+}
+
+/// Verifies the cache and garbage collects anything that isn't needed
+/// anymore, returning statistics about what was done.
+///
+/// This will:
+///
+/// * Rehash every content file and chunk, moving any whose data does not
+///   match its integrity hash into `{cache}/quarantine`.
+/// * Remove content files that no index entry points to, and chunks that no
+///   remaining chunked content lists.
+/// * Rewrite every index bucket so it only holds the latest entry for each
+///   key, dropping deleted keys and entries whose content is missing.
+/// * Rebuild the reverse index used by [`crate::keys_for_hash`] from what's
+///   left.
+///
+/// Packed content, written with [`crate::WriteOpts::pack_threshold`], is
+/// checked and garbage collected by [`crate::repack`] instead. Encrypted
+/// content can only be rehashed with its key, which can be passed to
+/// [`VerifyOpts::encryption_key`]. Otherwise, it's kept as long as something
+/// refers to it, without being checked.
+///
+/// Content written by a concurrent writer may be collected if its index entry
+/// has not been written yet, so avoid running this while the cache is being
+/// written to.
+///
+/// ## Example
+/// ```no_run
+/// use async_attributes;
+///
+/// #[async_attributes::main]
+/// async fn main() -> cacache::Result<()> {
+///     let stats = cacache::verify("./my-cache").await?;
+///     println!("reclaimed {} bytes", stats.reclaimed_size);
+///     Ok(())
+/// }
+/// ```
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub async fn verify<P: AsRef<Path>>(cache: P) -> Result<VerifyStats> {
+    VerifyOpts::new().verify(cache).await
+}
+
+/// Synchronously verifies the cache and garbage collects anything that isn't
+/// needed anymore, returning statistics about what was done. See [`verify`]
+/// for details.
+///
+/// ## Example
+/// ```no_run
+/// fn main() -> cacache::Result<()> {
+///     let stats = cacache::verify_sync("./my-cache")?;
+///     println!("reclaimed {} bytes", stats.reclaimed_size);
+///     Ok(())
+/// }
+/// ```
+pub fn verify_sync<P: AsRef<Path>>(cache: P) -> Result<VerifyStats> {
+    VerifyOpts::new().verify_sync(cache)
+}
+
+/// Options for [`verify`]ing a cache.
+#[derive(Clone, Default)]
+pub struct VerifyOpts {
+    pub(crate) encryption_key: Option<EncryptionKey>,
+}
+
+impl VerifyOpts {
+    /// Creates a default set of verification options.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Sets the key used to decrypt content written with
+    /// [`crate::WriteOpts::encrypt`], so it can be rehashed like any other
+    /// content. Encrypted content that doesn't decrypt with it is
+    /// quarantined, so only use this if all of the cache's encrypted content
+    /// was written with the same key.
+    #[cfg(feature = "encryption")]
+    pub fn encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Verifies the cache and garbage collects anything that isn't needed
+    /// anymore. See [`verify`] for details.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub async fn verify<P: AsRef<Path>>(self, cache: P) -> Result<VerifyStats> {
+        let cache = cache.as_ref().to_path_buf();
+        crate::async_lib::unwrap_joinhandle_value(
+            crate::async_lib::spawn_blocking(move || self.verify_sync(cache)).await,
+        )
+    }
+
+    /// Synchronously verifies the cache and garbage collects anything that
+    /// isn't needed anymore. See [`verify`] for details.
+    pub fn verify_sync<P: AsRef<Path>>(self, cache: P) -> Result<VerifyStats> {
+        fn inner(cache: &Path, key: Option<&EncryptionKey>) -> Result<VerifyStats> {
+            let mut stats = VerifyStats::default();
+            index::replay_journals(cache)?;
+            let has_index = index::index_dir(cache).exists();
+            let mut live = HashSet::new();
+            let mut live_chunks = HashSet::new();
+            if has_index {
+                for entry in index::ls(cache) {
+                    let sri = entry?.integrity;
+                    live.insert(path::content_path(cache, &sri));
+                    // Chunks are kept alive by whatever content lists them.
+                    // Manifests that can't be read are quarantined below anyway.
+                    if let Some((mpath, encoding)) = path::find_content(cache, &sri) {
+                        if encoding.chunked {
+                            for chunk in chunk::chunk_integrities(&mpath).unwrap_or_default() {
+                                live_chunks.insert(path::chunk_path(cache, &chunk));
+                            }
+                        }
+                    }
+                }
+            }
+            garbage_collect(cache, &live, key, &mut stats)?;
+            collect_chunks(cache, &live_chunks, &mut stats)?;
+            if has_index {
+                rebuild_index(cache, &mut stats)?;
+                crate::reverse::rebuild(cache)?;
+            }
+            let locks = crate::lock::locks_dir(cache);
+            crate::flock::remove_unused(&locks)
+                .with_context(|| format!("Failed to clean up locks at {}", locks.display()))?;
+            crate::memo::forget_all(cache);
+            Ok(stats)
+        }
+        inner(cache.as_ref(), self.encryption_key.as_ref())
+    }
+}
+
+fn garbage_collect(
+    cache: &Path,
+    live: &HashSet<PathBuf>,
+    key: Option<&EncryptionKey>,
+    stats: &mut VerifyStats,
+) -> Result<()> {
+    for (cpath, size) in stored_files(&path::content_dir(cache))? {
+        let (logical, encoding) = path::split_encoding(&cpath);
+        if !live.contains(&logical) {
+            reclaim(&cpath, size, stats)?;
+            continue;
+        }
+        let intact = match path::path_integrity(cache, &cpath) {
+            // Encrypted content can't be checked without its key, so it's
+            // kept as long as something still refers to it.
+            Some(_) if encoding.encrypted && key.is_none() => true,
+            Some(sri) if encoding.chunked => is_chunked_intact(cache, &cpath, sri),
+            Some(sri) => is_intact(cache, &cpath, sri, encoding, key)?,
+            None => false,
+        };
+        keep_if_intact(cache, &cpath, size, intact, stats)?;
+    }
+    Ok(())
+}
+
+/// Like [`garbage_collect`], for the chunks of chunked content, which are
+/// live as long as some live manifest lists them.
+fn collect_chunks(cache: &Path, live: &HashSet<PathBuf>, stats: &mut VerifyStats) -> Result<()> {
+    for (cpath, size) in stored_files(&path::chunk_dir(cache))? {
+        if !live.contains(&cpath) {
+            reclaim(&cpath, size, stats)?;
+            continue;
+        }
+        let intact = match path::chunk_integrity(cache, &cpath) {
+            Some(sri) => is_intact(cache, &cpath, sri, Encoding::default(), None)?,
+            None => false,
+        };
+        keep_if_intact(cache, &cpath, size, intact, stats)?;
+    }
+    Ok(())
+}
+
+/// Every file under `dir`, along with its size.
+fn stored_files(dir: &Path) -> Result<Vec<(PathBuf, u64)>> {
+    let mut files = Vec::new();
+    if !dir.exists() {
+        return Ok(files);
+    }
+    for entry in WalkDir::new(dir) {
+        let entry = entry
+            .map_err(|e| crate::errors::io_error(e.to_string()))
+            .with_context(|| {
+                format!(
+                    "Error while walking cache content directory at {}",
+                    dir.display()
+                )
+            })?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let cpath = entry.into_path();
+        let size = fs::metadata(&cpath)
+            .map(|m| m.len())
+            .with_context(|| format!("Failed to stat content file at {}", cpath.display()))?;
+        files.push((cpath, size));
+    }
+    Ok(files)
+}
+
+fn reclaim(cpath: &Path, size: u64, stats: &mut VerifyStats) -> Result<()> {
+    remove_content(cpath)?;
+    stats.reclaimed_count += 1;
+    stats.reclaimed_size += size;
+    Ok(())
+}
+
+fn keep_if_intact(
+    cache: &Path,
+    cpath: &Path,
+    size: u64,
+    intact: bool,
+    stats: &mut VerifyStats,
+) -> Result<()> {
+    if intact {
+        stats.verified_content += 1;
+        stats.kept_size += size;
+    } else {
+        quarantine(cache, cpath)?;
+        stats.bad_content_count += 1;
+        stats.reclaimed_count += 1;
+        stats.reclaimed_size += size;
+    }
+    Ok(())
+}
+
+fn is_intact(
+    cache: &Path,
+    cpath: &Path,
+    sri: Integrity,
+    encoding: Encoding,
+    key: Option<&EncryptionKey>,
+) -> Result<bool> {
+    let fd = File::open(cpath)
+        .with_context(|| format!("Failed to open content file at {}", cpath.display()))?;
+    let mut fd = match read::decoder(cache, fd, encoding, key) {
+        Ok(fd) => fd,
+        // Like below, for encrypted content whose header doesn't check out.
+        Err(_) if !encoding.is_plain() => return Ok(false),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("Failed to open content file at {}", cpath.display()))
+        }
+    };
+    let mut checker = IntegrityChecker::new(sri);
+    let mut buf = [0u8; 1024 * 8];
+    loop {
+        let read = match fd.read(&mut buf) {
+            Ok(read) => read,
+            // Data that can't be decompressed or decrypted is as corrupt as
+            // data that doesn't match its hash.
+            Err(_) if !encoding.is_plain() => return Ok(false),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "Failed to read cache contents while verifying integrity for {}",
+                        cpath.display()
+                    )
+                })
+            }
+        };
+        if read == 0 {
+            break;
+        }
+        checker.input(&buf[..read]);
+    }
+    Ok(checker.result().is_ok())
+}
+
+/// Chunked content is intact as long as all its chunks are, and they add up
+/// to the right data. Missing or corrupted chunks make it unreadable.
+fn is_chunked_intact(cache: &Path, mpath: &Path, sri: Integrity) -> bool {
+    let Ok(mut fd) = File::open(mpath).and_then(|fd| chunk::ChunkReader::new(cache, fd)) else {
+        return false;
+    };
+    let mut checker = IntegrityChecker::new(sri);
+    let mut buf = [0u8; 1024 * 8];
+    loop {
+        match fd.read(&mut buf) {
+            Ok(0) => return checker.result().is_ok(),
+            Ok(read) => checker.input(&buf[..read]),
+            Err(_) => return false,
+        }
+    }
+}
+
+fn remove_content(cpath: &Path) -> Result<()> {
+    fs::remove_file(cpath)
+        .with_context(|| format!("Failed to remove content file at {}", cpath.display()))
+}
+
+fn quarantine(cache: &Path, cpath: &Path) -> Result<()> {
+    let dir = cache.join("quarantine");
+    fs::create_dir_all(&dir)
+        .with_context(|| format!("Failed to create quarantine directory at {}", dir.display()))?;
+    // Flatten the content path into a single, unique file name.
+    let name = cpath
+        .strip_prefix(path::content_dir(cache))
+        .or_else(|_| cpath.strip_prefix(cache))
+        .unwrap_or(cpath)
+        .iter()
+        .map(|part| part.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("-");
+    let dest = dir.join(name);
+    fs::rename(cpath, &dest).with_context(|| {
+        format!(
+            "Failed to move corrupted content from {} to {}",
+            cpath.display(),
+            dest.display()
+        )
+    })
+}
+
+fn rebuild_index(cache: &Path, stats: &mut VerifyStats) -> Result<()> {
+    for bucket in index::bucket_paths(cache) {
+        let rebuilt = index::rebuild_bucket(cache, &bucket?, 1, |_, sri| {
+            read::has_content(cache, sri).is_some()
+        })?;
+        stats.kept_entries += rebuilt.kept;
+        stats.rejected_entries += rebuilt.rejected;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "async-std")]
+    use async_attributes::test as async_test;
+    #[cfg(feature = "tokio")]
+    use tokio::test as async_test;
+
+    #[test]
+    fn verify_empty() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        assert_eq!(verify_sync(&dir).unwrap(), VerifyStats::default());
+    }
+
+    #[test]
+    fn verify_removes_unreferenced_content() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let kept = crate::write_sync(&dir, "key", b"hello").unwrap();
+        let orphan = crate::write_hash_sync(&dir, b"goodbye world").unwrap();
+
+        let stats = verify_sync(&dir).unwrap();
+        assert_eq!(stats.verified_content, 1);
+        assert_eq!(stats.kept_size, 5);
+        assert_eq!(stats.reclaimed_count, 1);
+        assert_eq!(stats.reclaimed_size, 13);
+        assert_eq!(stats.kept_entries, 1);
+        assert!(crate::exists_sync(&dir, &kept));
+        assert!(!crate::exists_sync(&dir, &orphan));
+    }
+
+    #[test]
+    fn verify_quarantines_corrupted_content() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let sri = crate::write_sync(&dir, "key", b"hello").unwrap();
+        fs::write(path::content_path(&dir, &sri), b"jello").unwrap();
+
+        let stats = verify_sync(&dir).unwrap();
+        assert_eq!(stats.bad_content_count, 1);
+        assert_eq!(stats.rejected_entries, 1);
+        assert_eq!(stats.kept_entries, 0);
+        assert!(!crate::exists_sync(&dir, &sri));
+        assert_eq!(crate::metadata_sync(&dir, "key").unwrap(), None);
+        assert_eq!(fs::read_dir(dir.join("quarantine")).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn verify_compacts_index() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::write_sync(&dir, "key", b"hello").unwrap();
+        let sri = crate::write_sync(&dir, "key", b"world").unwrap();
+        crate::write_sync(&dir, "gone", b"bye").unwrap();
+        crate::remove_sync(&dir, "gone").unwrap();
+
+        let stats = verify_sync(&dir).unwrap();
+        assert_eq!(stats.kept_entries, 1);
+        assert_eq!(stats.reclaimed_count, 2);
+        assert_eq!(
+            crate::metadata_sync(&dir, "key")
+                .unwrap()
+                .unwrap()
+                .integrity,
+            sri
+        );
+        assert_eq!(crate::read_sync(&dir, "key").unwrap(), b"world");
+        assert_eq!(crate::metadata_sync(&dir, "gone").unwrap(), None);
+    }
+
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    #[async_test]
+    async fn verify_async() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        crate::write(&dir, "key", b"hello").await.unwrap();
+        let orphan = crate::write_hash(&dir, b"goodbye world").await.unwrap();
+
+        let stats = verify(&dir).await.unwrap();
+        assert_eq!(stats.verified_content, 1);
+        assert_eq!(stats.reclaimed_count, 1);
+        assert!(!crate::exists(&dir, &orphan).await);
+    }
+
+    #[cfg(any(feature = "zstd", feature = "gzip"))]
+    #[test]
+    fn verify_compressed_content() {
+        use std::io::Write;
+
+        for &compression in crate::Compression::ALL {
+            let tmp = tempfile::tempdir().unwrap();
+            let dir = tmp.path().to_owned();
+            let mut writer = crate::WriteOpts::new()
+                .compression(compression)
+                .open_sync(&dir, "key")
+                .unwrap();
+            writer.write_all(b"hello").unwrap();
+            let sri = writer.commit().unwrap();
+            let mut writer = crate::WriteOpts::new()
+                .compression(compression)
+                .open_hash_sync(&dir)
+                .unwrap();
+            writer.write_all(b"goodbye world").unwrap();
+            let orphan = writer.commit().unwrap();
+
+            let stats = verify_sync(&dir).unwrap();
+            assert_eq!(stats.verified_content, 1);
+            assert_eq!(stats.reclaimed_count, 1);
+            assert_eq!(stats.bad_content_count, 0);
+            assert_eq!(stats.kept_entries, 1);
+            assert!(crate::exists_sync(&dir, &sri));
+            assert!(!crate::exists_sync(&dir, &orphan));
+
+            let encoding = path::Encoding {
+                compression: Some(compression),
+                encrypted: false,
+                chunked: false,
+            };
+            fs::write(path::encoded_path(&dir, &sri, encoding), b"jello").unwrap();
+            let stats = verify_sync(&dir).unwrap();
+            assert_eq!(stats.bad_content_count, 1);
+            assert_eq!(stats.rejected_entries, 1);
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn verify_encrypted_content_with_key() {
+        use std::io::Write;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let key = crate::EncryptionKey::generate();
+        let mut writer = crate::WriteOpts::new()
+            .encrypt(key.clone())
+            .open_sync(&dir, "key")
+            .unwrap();
+        writer.write_all(b"hello").unwrap();
+        let sri = writer.commit().unwrap();
+        let opts = VerifyOpts::new().encryption_key(key);
+        let stats = opts.clone().verify_sync(&dir).unwrap();
+        assert_eq!(stats.verified_content, 1);
+        assert_eq!(stats.bad_content_count, 0);
+
+        let encoding = path::Encoding {
+            compression: None,
+            encrypted: true,
+            chunked: false,
+        };
+        let cpath = path::encoded_path(&dir, &sri, encoding);
+        let mut stored = fs::read(&cpath).unwrap();
+        *stored.last_mut().unwrap() ^= 1;
+        fs::write(&cpath, stored).unwrap();
+        // Without the key, there's no telling it's been tampered with.
+        assert_eq!(verify_sync(&dir).unwrap().bad_content_count, 0);
+        let stats = opts.verify_sync(&dir).unwrap();
+        assert_eq!(stats.bad_content_count, 1);
+        assert_eq!(stats.rejected_entries, 1);
+    }
+}